@@ -0,0 +1,105 @@
+//! Cost of a broadcast_3b gossip round as messages pile up, at 1k and 10k messages
+//! every neighbor already holds: `cargo bench --bench broadcast_alert`. A round only
+//! looks at what's pending per neighbor, so both should take about as long.
+//!
+//! The node runs as its own process; `resume_gossip` runs a round right away, and the
+//! timer's rounds are kept out by pausing again after each one.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    process::{Command, Stdio},
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
+};
+
+const NEIGHBORS: usize = 25;
+const ROUNDS: usize = 200;
+
+/// Read the node's lines until the reply to `msg_id`.
+fn await_reply(lines: &Receiver<String>, msg_id: usize) -> anyhow::Result<()> {
+    let reply = format!("\"in_reply_to\":{msg_id},");
+    loop {
+        let line = lines.recv_timeout(Duration::from_secs(30))?;
+        if line.contains(&reply) {
+            return Ok(());
+        }
+    }
+}
+
+/// The mean time of a round once `messages` are held everywhere.
+fn round_cost(messages: usize) -> anyhow::Result<Duration> {
+    let mut node = Command::new(env!("CARGO_BIN_EXE_broadcast_3b"))
+        .env_clear()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut input = node.stdin.take().expect("piped stdin");
+    let output = BufReader::new(node.stdout.take().expect("piped stdout"));
+    // drained on a thread, so the node never blocks on a full pipe
+    let (tx, lines) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for line in output.lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let node_ids = (1..=NEIGHBORS + 1)
+        .map(|i| format!("\"n{i}\""))
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut msg_id = 0;
+    let mut send = |input: &mut dyn Write, src: &str, body: String| -> anyhow::Result<usize> {
+        msg_id += 1;
+        writeln!(
+            input,
+            r#"{{"src":"{src}","dest":"n1","body":{{"msg_id":{msg_id},{body}}}}}"#
+        )?;
+        Ok(msg_id)
+    };
+    let init = format!(r#""type":"init","node_id":"n1","node_ids":[{node_ids}]"#);
+    send(&mut input, "c0", init)?;
+    send(&mut input, "c0", r#""type":"pause_gossip""#.to_string())?;
+    for message in 0..messages {
+        send(
+            &mut input,
+            "c1",
+            format!(r#""type":"broadcast","message":{message}"#),
+        )?;
+    }
+    // every neighbor tells it holds them all
+    for neighbor in 2..=NEIGHBORS + 1 {
+        let gossip = format!(
+            r#""type":"extended","Gossip":{{"messages":[],"have":[[0,{}]]}}"#,
+            messages - 1
+        );
+        send(&mut input, &format!("n{neighbor}"), gossip)?;
+    }
+    let ready = send(&mut input, "c0", r#""type":"pause_gossip""#.to_string())?;
+    input.flush()?;
+    await_reply(&lines, ready)?;
+
+    let start = Instant::now();
+    let mut last = 0;
+    for _ in 0..ROUNDS {
+        send(&mut input, "c0", r#""type":"resume_gossip""#.to_string())?;
+        last = send(&mut input, "c0", r#""type":"pause_gossip""#.to_string())?;
+    }
+    input.flush()?;
+    await_reply(&lines, last)?;
+    let took = start.elapsed() / ROUNDS as u32;
+
+    drop(input);
+    node.wait()?;
+    Ok(took)
+}
+
+fn main() -> anyhow::Result<()> {
+    for messages in [1_000, 10_000] {
+        let took = round_cost(messages)?;
+        println!("{messages} messages, {NEIGHBORS} neighbors: {took:?} per round");
+    }
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! Serial vs `parallel_serialize` encoding of a gossip round, 1000 messages to 25
+//! neighbors: `cargo bench --bench fanout`.
+
+use std::{collections::HashSet, time::Instant};
+
+use rustgen::{
+    fanout::{parallel_serialize, write_lines},
+    Body, Message,
+};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Gossip {
+    messages: HashSet<usize>,
+}
+
+/// A round gossiping `messages` values to each of `neighbors`.
+fn round(messages: usize, neighbors: usize) -> Vec<Message<Gossip>> {
+    (0..neighbors)
+        .map(|neighbor| Message {
+            src: "n0".to_string(),
+            dst: format!("n{}", neighbor + 1),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload: Gossip {
+                    messages: (0..messages).collect(),
+                },
+            },
+        })
+        .collect()
+}
+
+fn main() -> anyhow::Result<()> {
+    const RUNS: u32 = 200;
+    let msgs = round(1000, 25);
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        let mut output = Vec::new();
+        for msg in &msgs {
+            msg.send(&mut output)?;
+        }
+    }
+    let serial = start.elapsed() / RUNS;
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        write_lines(&parallel_serialize(&msgs)?, &mut Vec::new())?;
+    }
+    let parallel = start.elapsed() / RUNS;
+
+    println!("1000 messages to 25 neighbors: serial {serial:?}, parallel {parallel:?}");
+    Ok(())
+}
@@ -0,0 +1,347 @@
+//! An async flavor of `main_loop` on tokio, behind the `tokio` feature, for nodes with
+//! many requests of their own in flight like the kafka and txn workloads.
+//!
+//! `AsyncNode::step` is async, and the input keeps being read while a step awaits: an
+//! `Outbox::call` gets its reply from the reader as soon as it arrives. What nodes send
+//! goes through the `Outbox` to a single writer, so lines never interleave. The sync
+//! loops stay the way to run the simple binaries.
+
+use std::{collections::HashMap, future::Future, time::Duration};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    codec::JsonCodec, handshake, is_broken_pipe, log::Level, node_log, reject_malformed, rpc::Rpc,
+    Body, InitBody, InitMsg, LoopConfig, Message, RequestError,
+};
+
+/// A node run by `async_main_loop`, the async counterpart of `Node`.
+pub trait AsyncNode<MessageType>: Sized {
+    fn init(init: &InitBody, outbox: Outbox) -> anyhow::Result<Self>;
+
+    /// Act on a message, sending what it sends through `outbox`. A `RequestError`
+    /// returned is answered to the request like under `main_loop`.
+    fn step(
+        &mut self,
+        req: Message<MessageType>,
+        outbox: &Outbox,
+    ) -> impl Future<Output = anyhow::Result<()>>;
+
+    /// See `Node::init_ok_extra`.
+    fn init_ok_extra(_init: &InitBody) -> HashMap<String, serde_json::Value> {
+        HashMap::new()
+    }
+}
+
+/// What the writer of `async_main_loop` takes off its queue.
+enum Out {
+    Line(Vec<u8>),
+    /// the node is done, nothing it still sends goes out
+    Done,
+}
+
+/// Where an `AsyncNode` sends its messages, and awaits the replies to its requests.
+/// Clones share the writer, e.g. for a timer task.
+#[derive(Clone)]
+pub struct Outbox {
+    node_id: String,
+    lines: mpsc::UnboundedSender<Out>,
+    rpc: Rpc,
+}
+
+impl Outbox {
+    /// Queue `msg` for the writer. Fails once the loop is done.
+    pub fn send<T: Serialize>(&self, msg: &Message<T>) -> anyhow::Result<()> {
+        let mut line = Vec::new();
+        msg.send(&mut line)?;
+        self.lines
+            .send(Out::Line(line))
+            .map_err(|_| anyhow::anyhow!("the output is closed"))
+    }
+
+    /// Send `payload` to `dst` and wait up to `timeout` for the reply, like `Rpc::call`
+    /// without retries. The input keeps being read meanwhile, see
+    /// `async_main_loop_with_io`.
+    pub async fn call<Req, Resp>(
+        &self,
+        dst: impl Into<String>,
+        payload: Req,
+        timeout: Duration,
+    ) -> anyhow::Result<Message<Resp>>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned + Send + 'static,
+    {
+        let dst = dst.into();
+        let msg_id = self.rpc.next_call_id();
+        let (tx, rx) = oneshot::channel();
+        self.rpc.register_until(msg_id, timeout, move |reply| {
+            // the caller may have timed out and gone
+            let _ = tx.send(reply);
+        });
+        let request = Message {
+            src: self.node_id.clone(),
+            dst: dst.clone(),
+            body: Body {
+                id: Some(msg_id),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload,
+            },
+        };
+        if let Err(e) = self.send(&request) {
+            self.rpc.cancel(msg_id);
+            return Err(e).with_context(|| format!("send rpc to {dst}"));
+        }
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(reply)) => reply.with_context(|| format!("rpc {msg_id} to {dst}")),
+            // reaped, or the loop abandoned it
+            Ok(Err(_)) | Err(_) => {
+                self.rpc.cancel(msg_id);
+                Err(anyhow::anyhow!(
+                    "rpc {msg_id} to {dst} timed out after {timeout:?}"
+                ))
+            }
+        }
+    }
+}
+
+/// Run the node over STDIN/STDOUT on the calling tokio runtime.
+pub async fn async_main_loop<MessageType, N>() -> anyhow::Result<()>
+where
+    MessageType: DeserializeOwned + Serialize,
+    N: AsyncNode<MessageType>,
+{
+    async_main_loop_with_io::<MessageType, N>(
+        tokio::io::BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+    )
+    .await
+}
+
+/// Run the node over arbitrary async input/output, configured by the environment.
+///
+/// Reading, stepping and writing are concurrent: a reply to an `Outbox::call` is
+/// handed over by the reader while the step awaits it. Like `main_loop`, once
+/// `STEP_QUEUE_CAP` messages wait for the node the reader stops reading, so a step
+/// awaiting a reply queued behind them gets it only after its timeout. Malformed lines
+/// are skipped, answering the requests among them. The loop returns once the input
+/// ended and everything read was stepped and written, or the output was closed.
+pub async fn async_main_loop_with_io<MessageType, N>(
+    input: impl AsyncBufRead + Unpin,
+    mut output: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()>
+where
+    MessageType: DeserializeOwned + Serialize,
+    N: AsyncNode<MessageType>,
+{
+    let config = LoopConfig::from_env();
+    let mut lines = input.lines();
+    // the lines up to init, handed to the sync handshake
+    let mut head = Vec::new();
+    loop {
+        let line = lines
+            .next_line()
+            .await
+            .context("Maelstrom input from STDIN could not be read")?
+            .context("the input ended before the init message")?;
+        let is_init = serde_json::from_str::<Message<InitMsg>>(&line)
+            .is_ok_and(|msg| matches!(msg.body.payload, InitMsg::Init(..)));
+        head.push(line);
+        if is_init {
+            break;
+        }
+        anyhow::ensure!(
+            head.len() <= config.init_buffer_cap,
+            "more than {} messages arrived before init",
+            config.init_buffer_cap
+        );
+    }
+    let mut init_ok = Vec::new();
+    let (init_body, early) = handshake::<MessageType>(
+        &mut head.into_iter().map(Ok),
+        &mut init_ok,
+        &config,
+        &JsonCodec,
+        N::init_ok_extra,
+    )?;
+
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+    out_tx
+        .send(Out::Line(init_ok))
+        .expect("the receiver is alive");
+    let rpc = Rpc::new(&init_body.node_id);
+    let outbox = Outbox {
+        node_id: init_body.node_id.clone(),
+        lines: out_tx,
+        rpc: rpc.clone(),
+    };
+    let mut node =
+        N::init(&init_body, outbox.clone()).context("construct node from init message failed")?;
+    let (step_tx, mut step_rx) = mpsc::channel(config.step_queue_cap.max(1));
+
+    let read = {
+        let (rpc, outbox) = (&rpc, outbox.clone());
+        async move {
+            while let Some(line) = lines
+                .next_line()
+                .await
+                .context("Maelstrom input from STDIN could not be read")?
+            {
+                if line.trim().is_empty() || rpc.dispatch(&line) {
+                    continue;
+                }
+                match serde_json::from_str::<Message<MessageType>>(&line) {
+                    Ok(msg) => {
+                        // the node is gone, its error is what the loop returns
+                        if step_tx.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        let mut reply = Vec::new();
+                        reject_malformed::<MessageType>(
+                            &line,
+                            &e.into(),
+                            config.reply_not_supported,
+                            &mut reply,
+                        )?;
+                        if !reply.is_empty() && outbox.lines.send(Out::Line(reply)).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+    };
+    let step = {
+        let rpc = &rpc;
+        async move {
+            let mut early = early.into_iter();
+            loop {
+                let msg = match early.next() {
+                    Some(msg) => msg,
+                    None => match step_rx.recv().await {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                };
+                let request = (msg.body.id, msg.src.clone(), msg.dst.clone());
+                if let Err(e) = node.step(msg, &outbox).await {
+                    match (e.downcast::<RequestError>(), request) {
+                        (Ok(error), (Some(id), src, dst)) => {
+                            let request = Message {
+                                src,
+                                dst,
+                                body: Body {
+                                    id: Some(id),
+                                    in_reply_to: None,
+                                    lamport: None,
+                                    op_id: None,
+                                    payload: (),
+                                },
+                            };
+                            outbox.send(&request.into_error(error.code, error.text))?;
+                        }
+                        (Ok(error), _) => node_log!(
+                            Level::Warn,
+                            "drop error to a message expecting no reply: {error}"
+                        ),
+                        (Err(e), _) => return Err(e.context("step msg error")),
+                    }
+                }
+            }
+            rpc.abandon();
+            let _ = outbox.lines.send(Out::Done);
+            Ok(())
+        }
+    };
+    let write = async move {
+        while let Some(Out::Line(line)) = out_rx.recv().await {
+            output.write_all(&line).await?;
+            // one flush per batch, like the sync loop
+            if out_rx.is_empty() {
+                output.flush().await?;
+            }
+        }
+        Ok::<_, anyhow::Error>(output.flush().await?)
+    };
+
+    match tokio::try_join!(read, step, write) {
+        Err(e) if is_broken_pipe(&e) => {
+            node_log!(Level::Info, "output closed, shutting down");
+            Ok(())
+        }
+        result => result.map(drop),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::{Deserialize, Serialize};
+
+    use super::{async_main_loop_with_io, AsyncNode, Outbox};
+    use crate::{IdGen, InitBody, Message};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum EchoMessage {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    struct EchoNode {
+        msg_ids: IdGen,
+    }
+
+    impl AsyncNode<EchoMessage> for EchoNode {
+        fn init(_: &InitBody, _: Outbox) -> anyhow::Result<Self> {
+            Ok(Self {
+                msg_ids: IdGen::default(),
+            })
+        }
+
+        async fn step(&mut self, req: Message<EchoMessage>, outbox: &Outbox) -> anyhow::Result<()> {
+            if let EchoMessage::Echo { echo } = req.body.payload.clone() {
+                tokio::task::yield_now().await;
+                outbox.send(&req.reply_with(&self.msg_ids, EchoMessage::EchoOk { echo }))?;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_echo_round_trip() -> anyhow::Result<()> {
+        let input = [
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"early"}}"#,
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hello"}}"#,
+        ]
+        .join("\n");
+        let mut output = Vec::new();
+        async_main_loop_with_io::<EchoMessage, EchoNode>(input.as_bytes(), &mut output).await?;
+
+        let replies = output
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["body"]["type"], "init_ok");
+        assert_eq!(replies[1]["body"]["echo"], "early");
+        assert_eq!(replies[2]["body"]["type"], "echo_ok");
+        assert_eq!(replies[2]["body"]["echo"], "hello");
+        assert_eq!(replies[2]["body"]["in_reply_to"], 2);
+        assert_eq!(replies[2]["src"], "n1");
+        assert_eq!(replies[2]["dest"], "c1");
+        Ok(())
+    }
+}
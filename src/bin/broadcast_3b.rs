@@ -1,12 +1,26 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     io::Write,
     time::Duration,
 };
 
 use anyhow::Context;
-use rand::Rng;
-use rustgen::{main_loop, Body, Message};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+#[cfg(feature = "parallel")]
+use rustgen::fanout::{parallel_serialize, write_lines};
+use rustgen::{
+    crdt::GSet,
+    digest::{Digest, MerkleDigest},
+    gossip::{self, Gossip},
+    latency::AdaptiveInterval,
+    log::Level,
+    main_loop,
+    metrics::Counter,
+    node_log,
+    persist::{store_from_env, Store},
+    rpc::NodeContext,
+    topology, Body, Cluster, IdGen, MaelstromError, Message,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,33 +31,488 @@ pub enum BroadcastMessage {
         message: usize,
     },
     BroadcastOk,
-    Read,
+    BroadcastBatch {
+        messages: Vec<usize>,
+    },
+    BroadcastBatchOk,
+    Read {
+        /// only return messages stamped after this watermark, needs `INCREMENTAL_READ=1`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<usize>,
+    },
     ReadOk {
-        messages: HashSet<usize>,
+        messages: Vec<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        watermark: Option<usize>,
     },
     Topology {
         topology: HashMap<String, Vec<String>>,
     },
     TopologyOk,
+    GetTopology,
+    TopologyInfo {
+        neighbors: Vec<String>,
+    },
+    Converged,
+    ConvergedOk {
+        converged: bool,
+    },
+    /// per neighbor breakdown of the gossip still owed, to debug convergence
+    GossipState,
+    GossipStateOk {
+        neighbors: BTreeMap<String, NeighborState>,
+        /// timer ticks coalesced into a gossip round still in flight
+        skipped_rounds: usize,
+    },
+    /// stop emitting gossip, to script partition-like experiments; client ops and
+    /// incoming gossip are still served
+    PauseGossip,
+    PauseGossipOk,
+    /// emit gossip again, catching the neighbors up right away
+    ResumeGossip,
+    ResumeGossipOk,
 
     Extended(GossipProtocol),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NeighborState {
+    /// messages the neighbor is known to hold, on top of the globally known ones
+    known: usize,
+    /// messages we hold which the neighbor isn't known to hold yet
+    pending: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum GossipProtocol {
     GossipAlert,
-    Gossip { messages: HashSet<usize> },
+    Gossip {
+        messages: HashSet<usize>,
+        /// everything the sender holds, so the receiver also learns what it needn't
+        /// send back; this acks the receiver's earlier gossip for free
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        have: Option<Digest>,
+    },
+    /// acks the exact `messages` of a gossip, the sender then knows we hold them
+    GossipOk {
+        messages: Digest,
+    },
+    /// the sender's bucket fingerprints, answered with whatever we hold in the buckets
+    /// that differ
+    ReconcileRequest {
+        digest: MerkleDigest,
+    },
+    ReconcileResponse {
+        messages: HashSet<usize>,
+    },
+    /// messages to pass down a tree over `targets`, the receiver forwarding them to
+    /// its children there while `hops` are left; see `RELAY`
+    Relay {
+        messages: HashSet<usize>,
+        targets: Vec<String>,
+        hops: usize,
+    },
+}
+
+/// How far a relay is forwarded at most, deeper than the relay tree over any cluster
+/// Maelstrom runs so only a loop hits it.
+const RELAY_MAX_HOPS: usize = 8;
+
+/// Rounds of unacked gossip after which a neighbor is taken as partitioned away,
+/// unless `PARTITION_AFTER` says otherwise.
+const DEFAULT_PARTITION_AFTER: usize = 5;
+
+/// With `RELAY=1` a round sends the messages clients broadcast since the last one in
+/// a single relay per child of a tree over the cluster, instead of gossiping every
+/// message to every neighbor. Relayed messages are assumed delivered, nothing is left
+/// pending for them; `RECONCILE_EVERY` repairs what got lost.
+#[derive(Debug, Clone, Default)]
+struct Relaying {
+    /// every other node of the cluster
+    others: Vec<String>,
+    /// broadcast by clients and not relayed yet
+    fresh: Vec<usize>,
 }
 
 struct BroadcastNode {
     id: String,
-    msg_id: usize,
-    messages: HashSet<usize>,
-    neightbors: Vec<String>,
-    known: HashMap<String, HashSet<usize>>,
+    msg_ids: IdGen,
+    messages: GSet<usize>,
+    /// `messages` as runs, kept up to date for the digest attached to gossip
+    digest: Digest,
+    /// knows what each neighbor holds, they mostly hold runs of ids
+    gossip: Gossip<Digest>,
+    /// per neighbor the messages it isn't known to hold, queued as they arrive and
+    /// dropped as it's learned to hold them, so a round only looks at the delta
+    pending: HashMap<String, HashSet<usize>>,
+    /// neighbors picked by `TOPOLOGY`, the `topology` message is then ignored
+    topology_override: bool,
+    /// gossip only ever goes to other members
+    cluster: Cluster,
+    /// messages every neighbor knows, dropped from the per-neighbor `known` sets
+    globally_known: Digest,
+    /// the latest digest each neighbor attached to its gossip; what it covers is
+    /// confirmed held and never picked to gossip again
+    peer_digests: HashMap<String, Digest>,
+    /// whether to compact `known` into `globally_known`, off with `KNOWN_COMPACTION=0`
+    compact_known: bool,
+    /// attach our digest to every gossip, off with `GOSSIP_DIGEST=0`
+    gossip_digest: bool,
+    /// per neighbor, the size of `digest` when last sent to it; a neighbor with nothing
+    /// pending is only gossiped again once the digest grew
+    digest_sent: HashMap<String, usize>,
+    /// every this many ticks ask the neighbors for a bucket level reconcile, which
+    /// repairs what plain gossip lost; off unless `RECONCILE_EVERY` is set
+    reconcile_every: Option<usize>,
+    ticks: usize,
+    /// per neighbor, the rounds in a row it was sent gossip and hasn't acked since
+    ack_gaps: HashMap<String, usize>,
+    /// after this many such rounds a neighbor is taken as partitioned away, see
+    /// `DEFAULT_PARTITION_AFTER`
+    partition_after: usize,
+    /// neighbors taken as partitioned away; once one is heard from again it's sent
+    /// everything it isn't known to hold at once, rather than left to the rounds
+    partitioned: HashSet<String>,
+    /// where the message set is saved, picked by `STORE`; saves happen on the gossip
+    /// round, so a step never waits on the store
+    store: Option<Box<dyn Store>>,
+    /// messages were recorded since the last save
+    unsaved: bool,
+    /// with `CHECKPOINT_WINDOW`, only the latest this many messages stay in `messages`:
+    /// every gossip round saves the set and drops the rest. Reconciles and new
+    /// neighbors only see the window, reads are served from `digest`, which still
+    /// covers everything so an evicted message coming back isn't taken as new either.
+    window: Option<usize>,
+    /// neighbors a topology change added which don't hold our set yet. Writes are
+    /// unsafe until they all caught up or were taken as partitioned, reads keep
+    /// being served meanwhile.
+    joining: HashSet<String>,
+    /// whether `read {since}` is honored, read from `INCREMENTAL_READ`
+    incremental_read: bool,
+    /// messages in the order they were first seen, the index is the sequence; the
+    /// regossip also samples from it
+    sequence: Vec<usize>,
+    read: ReadConfig,
+    /// picks the known messages gossiped again, seeded from `GOSSIP_SEED` if set so a
+    /// run can be replayed
+    rng: StdRng,
+    relay: Option<Relaying>,
+    /// rounds run, reported with the loop's metrics under `METRICS=1`
+    rounds: Counter,
+}
+
+/// How `read` replies are built, from `READ_SORTED`, `READ_MAX` and `READ_STREAM`.
+#[derive(Debug, Clone)]
+struct ReadConfig {
+    /// reply the messages in ascending order, handy to diff in tests
+    sorted: bool,
+    /// reply at most this many messages; incremental reads page through the rest
+    max: Option<usize>,
+    /// serialize the full set straight from the node instead of copying it first,
+    /// on unless `READ_STREAM=0`. Sorted replies are always copied.
+    stream: bool,
+}
+
+impl ReadConfig {
+    fn from_env() -> Self {
+        Self {
+            sorted: std::env::var("READ_SORTED").is_ok_and(|flag| flag == "1"),
+            max: std::env::var("READ_MAX")
+                .ok()
+                .and_then(|max| max.parse().ok()),
+            stream: std::env::var("READ_STREAM").map_or(true, |flag| flag != "0"),
+        }
+    }
+
+    fn collect(&self, messages: impl IntoIterator<Item = usize>) -> Vec<usize> {
+        let mut messages = messages.into_iter().collect::<Vec<_>>();
+        if self.sorted {
+            messages.sort_unstable();
+        }
+        messages.truncate(self.max.unwrap_or(usize::MAX));
+        messages
+    }
+}
+
+/// A `read_ok` borrowing the node's message set, so it's serialized without a copy.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct StreamedReadOk<'a> {
+    messages: Capped<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    watermark: Option<usize>,
+}
+
+struct Capped<'a> {
+    messages: &'a HashSet<usize>,
+    max: usize,
+}
+
+impl Serialize for Capped<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.messages.iter().take(self.max))
+    }
 }
 
 impl BroadcastNode {
+    /// Build the node, gossiping every `pace()`.
+    fn start(
+        init_msg: &rustgen::InitBody,
+        pace: impl Fn() -> Duration + Send + 'static,
+        tx: crossbeam_channel::Sender<Message<BroadcastMessage>>,
+    ) -> anyhow::Result<Self> {
+        let mut gossip = Gossip::start_paced(init_msg, pace, tx, || {
+            BroadcastMessage::Extended(GossipProtocol::GossipAlert)
+        });
+        let mut topology = topology::from_env(&init_msg.node_ids);
+        let topology_override = topology.is_some();
+        if let Some(neighbors) = topology.as_mut().and_then(|t| t.remove(&init_msg.node_id)) {
+            gossip.set_neighbors(neighbors);
+        }
+        let pending = gossip.peers().map(|peer| (peer.clone(), HashSet::new()));
+        let pending = pending.collect();
+        Ok(Self {
+            id: init_msg.node_id.clone(),
+            msg_ids: IdGen::default(),
+            messages: GSet::default(),
+            digest: Digest::default(),
+            gossip,
+            pending,
+            topology_override,
+            cluster: Cluster::new(init_msg),
+            globally_known: Digest::default(),
+            peer_digests: HashMap::new(),
+            compact_known: std::env::var("KNOWN_COMPACTION").map_or(true, |flag| flag != "0"),
+            gossip_digest: std::env::var("GOSSIP_DIGEST").map_or(true, |flag| flag != "0"),
+            digest_sent: HashMap::new(),
+            reconcile_every: std::env::var("RECONCILE_EVERY")
+                .ok()
+                .and_then(|every| every.parse().ok())
+                .filter(|every| *every > 0),
+            ticks: 0,
+            ack_gaps: HashMap::new(),
+            partition_after: std::env::var("PARTITION_AFTER")
+                .ok()
+                .and_then(|after| after.parse().ok())
+                .filter(|after| *after > 0)
+                .unwrap_or(DEFAULT_PARTITION_AFTER),
+            partitioned: HashSet::new(),
+            store: None,
+            unsaved: false,
+            window: None,
+            joining: HashSet::new(),
+            incremental_read: std::env::var("INCREMENTAL_READ").is_ok_and(|flag| flag == "1"),
+            sequence: Vec::new(),
+            read: ReadConfig::from_env(),
+            rng: std::env::var("GOSSIP_SEED")
+                .ok()
+                .and_then(|seed| seed.parse().ok())
+                .map_or_else(StdRng::from_entropy, StdRng::seed_from_u64),
+            relay: std::env::var("RELAY")
+                .is_ok_and(|flag| flag == "1")
+                .then(|| Relaying {
+                    others: init_msg
+                        .node_ids
+                        .iter()
+                        .filter(|id| **id != init_msg.node_id)
+                        .cloned()
+                        .collect(),
+                    fresh: Vec::new(),
+                }),
+            rounds: Counter::default(),
+        })
+    }
+
+    /// Record messages, stamping the new ones with a sequence and queueing them for the
+    /// neighbors not known to hold them yet.
+    fn record(&mut self, messages: impl IntoIterator<Item = usize>) {
+        for message in messages {
+            if self.digest.insert(message) {
+                self.messages.insert(message);
+                self.unsaved = true;
+                self.sequence.push(message);
+                if self.relay.is_some() {
+                    continue;
+                }
+                for (peer, pending) in &mut self.pending {
+                    if !self
+                        .gossip
+                        .known(peer)
+                        .is_some_and(|known| known.contains(&message))
+                    {
+                        pending.insert(message);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Record messages broadcast by a client, queued to relay with `RELAY=1`.
+    fn accept(&mut self, messages: impl IntoIterator<Item = usize>) {
+        let fresh = messages
+            .into_iter()
+            .filter(|msg| !self.digest.contains(msg))
+            .collect::<Vec<_>>();
+        self.record(fresh.iter().copied());
+        if let Some(relay) = &mut self.relay {
+            relay.fresh.extend(fresh);
+        }
+    }
+
+    /// Record that `peer` holds `held`, none of it is pending for it any more.
+    fn learned(&mut self, peer: &str, held: Digest) {
+        if let Some(pending) = self.pending.get_mut(peer) {
+            pending.retain(|msg| !held.contains(msg));
+        }
+        self.gossip.on_gossip(peer, held);
+        self.settle_joins();
+    }
+
+    /// Drop from `joining` the neighbors which caught up or were taken as partitioned,
+    /// ending the reconfiguration once none is left.
+    fn settle_joins(&mut self) {
+        if self.joining.is_empty() {
+            return;
+        }
+        let (pending, partitioned) = (&self.pending, &self.partitioned);
+        self.joining.retain(|peer| {
+            !partitioned.contains(peer) && pending.get(peer).is_some_and(|p| !p.is_empty())
+        });
+        if self.joining.is_empty() {
+            node_log!(Level::Info, "reconfiguration done, taking writes again");
+        }
+    }
+
+    /// What of `held` we didn't know `peer` to hold, neither everyone holds. Worked out
+    /// range by range, so a digest covering the whole set costs what its ranges do.
+    fn news_from(&self, peer: &str, held: &Digest) -> Digest {
+        let news = held.subtract(&self.globally_known);
+        match self.gossip.known(peer) {
+            Some(known) => news.subtract(known),
+            None => news,
+        }
+    }
+
+    /// Gossip with `neighbors` from now on; a new one has everything it isn't known to
+    /// hold queued, and writes wait until it caught up, see `joining`.
+    fn set_neighbors(&mut self, neighbors: Vec<String>) {
+        self.gossip.set_neighbors(neighbors);
+        let peers = self.gossip.peers().cloned().collect::<Vec<_>>();
+        self.pending.retain(|peer, _| peers.contains(peer));
+        self.joining.retain(|peer| peers.contains(peer));
+        for peer in peers {
+            if self.pending.contains_key(&peer) {
+                continue;
+            }
+            let known = self.gossip.known(&peer);
+            let pending = self
+                .messages
+                .iter()
+                .filter(|msg| {
+                    !self.globally_known.contains(msg)
+                        && !known.is_some_and(|known| known.contains(msg))
+                })
+                .copied()
+                .collect::<HashSet<_>>();
+            if !pending.is_empty() {
+                self.joining.insert(peer.clone());
+            }
+            self.pending.insert(peer, pending);
+        }
+    }
+
+    /// Back the message set with `store`. What an earlier run saved there is picked up
+    /// by `load`, once the loop can carry the store's replies.
+    fn with_store(mut self, store: Box<dyn Store>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Pick up the message set an earlier run saved to the store.
+    fn load(&mut self, output: &mut dyn Write) -> anyhow::Result<()> {
+        let saved = self.checkpointed(output)?;
+        self.record(saved.iter());
+        self.unsaved = false;
+        Ok(())
+    }
+
+    /// Keep only the latest `window` messages in memory, see `window`.
+    fn with_window(mut self, window: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            self.store.is_some(),
+            "CHECKPOINT_WINDOW needs a STORE to checkpoint to"
+        );
+        anyhow::ensure!(
+            !self.incremental_read,
+            "CHECKPOINT_WINDOW drops the sequence INCREMENTAL_READ pages by"
+        );
+        self.window = Some(window);
+        Ok(self)
+    }
+
+    /// The message set as last saved, empty without a store or before the first save.
+    fn checkpointed(&mut self, output: &mut dyn Write) -> anyhow::Result<Digest> {
+        let key = self.store_key();
+        let Some(store) = &mut self.store else {
+            return Ok(Digest::default());
+        };
+        match store.get(&key, output)? {
+            Some(saved) => serde_json::from_value(saved).context("corrupted saved set"),
+            None => Ok(Digest::default()),
+        }
+    }
+
+    fn store_key(&self) -> String {
+        format!("broadcast-{}", self.id)
+    }
+
+    /// Save the message set if it grew since the last round, then drop all but the
+    /// latest `window` messages. The set is saved as `digest`, mostly runs of ids,
+    /// without reading the saved one back: the node is its key's only writer.
+    fn checkpoint(&mut self, output: &mut dyn Write) -> anyhow::Result<()> {
+        if self.unsaved {
+            let key = self.store_key();
+            if let Some(store) = &mut self.store {
+                store.put(&key, serde_json::to_value(&self.digest)?, output)?;
+            }
+            self.unsaved = false;
+        }
+        let Some(window) = self.window else {
+            return Ok(());
+        };
+        let evicted = self.sequence.len().saturating_sub(window);
+        if evicted > 0 {
+            self.sequence.drain(..evicted);
+            self.messages = self.sequence.iter().copied().collect();
+        }
+        Ok(())
+    }
+
+    /// Move the messages every neighbor knows out of the per-neighbor sets, so `known`
+    /// doesn't keep a copy of the whole message set for each neighbor. Only a message
+    /// a neighbor was just learned to hold can have become known to all, so the
+    /// `candidates` are those rather than everything it holds.
+    fn compact_known(&mut self, candidates: impl IntoIterator<Item = usize>) {
+        let known = self
+            .gossip
+            .peers()
+            .filter_map(|peer| self.gossip.known(peer))
+            .collect::<Vec<_>>();
+        if known.is_empty() {
+            return;
+        }
+        let universal = candidates
+            .into_iter()
+            .filter(|msg| known.iter().all(|known| known.contains(msg)))
+            .collect::<Vec<_>>();
+        for known in self.gossip.known_mut() {
+            universal.iter().for_each(|msg| {
+                known.remove(msg);
+            });
+        }
+        self.globally_known.extend(universal);
+    }
+
     fn handle_external(
         &mut self,
         req: &rustgen::Message<BroadcastMessage>,
@@ -51,82 +520,382 @@ impl BroadcastNode {
         external: &GossipProtocol,
     ) -> anyhow::Result<()> {
         match external {
-            GossipProtocol::GossipAlert => {
-                let mut rnd = rand::thread_rng();
-                // todo use parallel stream to speed up
-                for neighbor in &self.neightbors {
-                    let known_msg = &self.known[neighbor];
-                    let (known, mut unknown): (HashSet<usize>, HashSet<usize>) = self
-                        .messages
-                        .iter()
-                        .partition(|msg| known_msg.contains(msg));
-                    let additional_cap = unknown.len().min(3236 * known.len() / 10000) as u32;
-                    unknown.extend(
-                        known
-                            .iter()
-                            .filter(|_| rnd.gen_ratio(additional_cap, known.len() as u32)),
-                    );
-                    Message {
-                        src: self.id.clone(),
-                        dst: neighbor.clone(),
-                        body: Body {
-                            id: Default::default(),
-                            in_reply_to: Default::default(),
-                            payload: BroadcastMessage::Extended(GossipProtocol::Gossip {
-                                messages: unknown,
-                            }),
-                        },
-                    }
-                    .send(output)
-                    .with_context(|| format!("send gossip to {}", neighbor))?
+            GossipProtocol::GossipAlert => match self.gossip.on_alert() {
+                Some(_round) => self.gossip_round(output),
+                None => Ok(()),
+            },
+            GossipProtocol::Gossip { messages, have } => {
+                let mut held = messages.iter().copied().collect::<Digest>();
+                if let Some(have) = have {
+                    held.union(have);
                 }
-                Ok(())
+                let news = self.news_from(&req.src, &held);
+                self.learned(&req.src, news.clone());
+                self.record(messages.iter().copied());
+                if self.compact_known {
+                    self.compact_known(news.iter());
+                }
+                if let Some(have) = have {
+                    self.peer_digests.insert(req.src.clone(), have.clone());
+                }
+                self.heard_from(&req.src, output)?;
+                // our next gossip to the sender carries a digest covering these, which
+                // acks them without a message of its own
+                if messages.is_empty() || self.digest_due(&req.src) {
+                    return Ok(());
+                }
+                let ack = GossipProtocol::GossipOk {
+                    messages: messages.iter().copied().collect(),
+                };
+                let ack = req
+                    .clone()
+                    .reply_with(&self.msg_ids, BroadcastMessage::Extended(ack));
+                self.cluster
+                    .send_checked(&ack, output)
+                    .with_context(|| format!("ack gossip of {}", req.src))
+            }
+            GossipProtocol::GossipOk { messages } => {
+                // some may have been learned since, even compacted into `globally_known`
+                let news = self.news_from(&req.src, messages);
+                self.learned(&req.src, news.clone());
+                if self.compact_known {
+                    self.compact_known(news.iter());
+                }
+                self.heard_from(&req.src, output)
+            }
+            GossipProtocol::ReconcileRequest { digest } => {
+                let mine = MerkleDigest::of(&self.messages, digest.width());
+                let buckets = mine.differing_buckets(digest);
+                let messages = self
+                    .messages
+                    .iter()
+                    .filter(|msg| mine.in_buckets(&buckets, **msg))
+                    .copied()
+                    .collect();
+                req.clone()
+                    .reply_with(
+                        &self.msg_ids,
+                        BroadcastMessage::Extended(GossipProtocol::ReconcileResponse { messages }),
+                    )
+                    .send(output)
+                    .context("reply reconcile_response")
             }
-            GossipProtocol::Gossip { messages } => {
-                self.known
-                    .get_mut(&req.src)
-                    .with_context(|| format!("can't find the neighbor {}", req.src))
-                    .expect("update known message failed")
-                    .extend(messages);
-                self.messages.extend(messages.iter().copied());
+            GossipProtocol::ReconcileResponse { messages } => {
+                let held = messages
+                    .iter()
+                    .filter(|msg| !self.globally_known.contains(msg))
+                    .copied()
+                    .collect();
+                self.learned(&req.src, held);
+                self.record(messages.iter().copied());
                 Ok(())
             }
+            GossipProtocol::Relay {
+                messages,
+                targets,
+                hops,
+            } => {
+                self.learned(&req.src, messages.iter().copied().collect());
+                self.record(messages.iter().copied());
+                // never back up, nor to ourselves, should a tree come out looped
+                let targets = targets
+                    .iter()
+                    .filter(|target| **target != self.id && **target != req.src)
+                    .cloned()
+                    .collect();
+                self.relay(messages, targets, *hops, output)
+            }
+        }
+    }
+
+    /// Send `messages` to our children in a tree over `targets` hanging from us, each
+    /// with its own subtree to pass them on to.
+    fn relay(
+        &self,
+        messages: &HashSet<usize>,
+        targets: Vec<String>,
+        hops: usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        if messages.is_empty() || hops == 0 || targets.is_empty() {
+            return Ok(());
+        }
+        let mut nodes = targets;
+        nodes.push(self.id.clone());
+        let tree = topology::spanning_tree(&nodes, &self.id);
+        for child in &tree[&self.id] {
+            let relay = Message {
+                src: self.id.clone(),
+                dst: child.clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    lamport: None,
+                    op_id: None,
+                    payload: BroadcastMessage::Extended(GossipProtocol::Relay {
+                        messages: messages.clone(),
+                        targets: topology::subtree(&tree, child, &self.id),
+                        hops: hops - 1,
+                    }),
+                },
+            };
+            self.cluster
+                .send_checked(&relay, output)
+                .with_context(|| format!("relay to {child}"))?;
+        }
+        Ok(())
+    }
+
+    /// Gossip to the neighbors what they're missing, or relay what clients broadcast
+    /// with `RELAY=1`; a reconcile every few rounds.
+    fn gossip_round(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        match self.relay.as_mut() {
+            Some(relay) => {
+                let fresh = std::mem::take(&mut relay.fresh).into_iter().collect();
+                let others = relay.others.clone();
+                self.relay(&fresh, others, RELAY_MAX_HOPS, output)?
+            }
+            None => self.gossip_neighbors(output)?,
+        }
+        self.ticks += 1;
+        self.rounds.incr();
+        if self
+            .reconcile_every
+            .is_some_and(|every| self.ticks.is_multiple_of(every))
+        {
+            self.request_reconcile(output)?;
+        }
+        self.checkpoint(output)
+    }
+
+    fn gossip_neighbors(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let have = self.gossip_digest.then(|| self.digest.clone());
+        let (pending, digest_sent) = (&self.pending, &self.digest_sent);
+        let digest_due = |peer: &str| {
+            have.as_ref()
+                .is_some_and(|have| digest_sent.get(peer) != Some(&have.len()))
+        };
+        let neighbors = self.gossip.round_peers(|peer, _| {
+            pending.get(peer).map_or(0, HashSet::len) + usize::from(digest_due(peer))
+        });
+        let neighbors = neighbors
+            .into_iter()
+            .map(|peer| (digest_due(&peer), peer))
+            .collect::<Vec<_>>();
+        let mut gossips = Vec::with_capacity(neighbors.len());
+        for (digest_due, neighbor) in neighbors {
+            // collected rather than cloned, a drained queue keeps its capacity
+            let mut unknown = self
+                .pending
+                .get(&neighbor)
+                .map(|pending| pending.iter().copied().collect::<HashSet<_>>())
+                .unwrap_or_default();
+            // resend a sample of what the neighbor is only assumed to hold, picked by
+            // sequence so the cost follows the sample rather than the message count
+            let confirmed = self.peer_digests.get(&neighbor);
+            let assumed = self.sequence.len().saturating_sub(unknown.len());
+            for _ in 0..unknown.len().min(3236 * assumed / 10000) {
+                let msg = self.sequence[self.rng.gen_range(0..self.sequence.len())];
+                if !self.globally_known.contains(&msg)
+                    && !confirmed.is_some_and(|d| d.contains(&msg))
+                {
+                    unknown.insert(msg);
+                }
+            }
+            // neither news nor a grown digest, the neighbor has heard it all
+            if unknown.is_empty() && !digest_due {
+                continue;
+            }
+            if let Some(have) = &have {
+                self.digest_sent.insert(neighbor.clone(), have.len());
+            }
+            gossips.push(Message {
+                src: self.id.clone(),
+                dst: neighbor,
+                body: Body {
+                    id: Default::default(),
+                    in_reply_to: Default::default(),
+                    lamport: None,
+                    op_id: None,
+                    payload: BroadcastMessage::Extended(GossipProtocol::Gossip {
+                        messages: unknown,
+                        have: have.clone(),
+                    }),
+                },
+            });
         }
+        for gossip in &gossips {
+            if let BroadcastMessage::Extended(GossipProtocol::Gossip { messages, .. }) =
+                &gossip.body.payload
+            {
+                if !messages.is_empty() {
+                    self.unacked(&gossip.dst);
+                }
+            }
+        }
+        // built with the `parallel` feature, the round's gossip is encoded on rayon's pool
+        #[cfg(feature = "parallel")]
+        {
+            for gossip in &gossips {
+                self.cluster
+                    .check(gossip)
+                    .with_context(|| format!("send gossip to {}", gossip.dst))?;
+            }
+            write_lines(&parallel_serialize(&gossips)?, output)?;
+        }
+        #[cfg(not(feature = "parallel"))]
+        for gossip in &gossips {
+            self.cluster
+                .send_checked(gossip, output)
+                .with_context(|| format!("send gossip to {}", gossip.dst))?;
+        }
+        Ok(())
+    }
+
+    /// Whether the next round gossips to `peer` with our digest attached: it has
+    /// messages pending, or the digest grew since it was last sent one.
+    fn digest_due(&self, peer: &str) -> bool {
+        self.gossip_digest
+            && (self.digest_sent.get(peer) != Some(&self.digest.len())
+                || self
+                    .pending
+                    .get(peer)
+                    .is_some_and(|pending| !pending.is_empty()))
+    }
+
+    /// Count a round `peer` was sent gossip, taking it as partitioned away once that
+    /// went unacked for `partition_after` rounds in a row.
+    fn unacked(&mut self, peer: &str) {
+        let gap = self.ack_gaps.entry(peer.to_string()).or_default();
+        *gap += 1;
+        if *gap >= self.partition_after && self.partitioned.insert(peer.to_string()) {
+            node_log!(
+                Level::Warn,
+                "{peer} acked no gossip for {gap} rounds, taking it as partitioned"
+            );
+            self.settle_joins();
+        }
+    }
+
+    /// `peer` gossiped or acked, it's reachable. Coming back from a partition it's
+    /// sent everything it isn't known to hold.
+    fn heard_from(&mut self, peer: &str, output: &mut impl Write) -> anyhow::Result<()> {
+        self.ack_gaps.remove(peer);
+        if !self.partitioned.remove(peer) {
+            return Ok(());
+        }
+        node_log!(
+            Level::Info,
+            "{peer} is reachable again, syncing the full set"
+        );
+        self.resync(peer, output)
+    }
+
+    /// Send `peer` the whole set but what it's known or confirmed to hold, and keep it
+    /// pending until acked.
+    fn resync(&mut self, peer: &str, output: &mut impl Write) -> anyhow::Result<()> {
+        let known = self.gossip.known(peer);
+        let confirmed = self.peer_digests.get(peer);
+        let missing = self
+            .digest
+            .iter()
+            .filter(|msg| {
+                !self.globally_known.contains(msg)
+                    && !known.is_some_and(|known| known.contains(msg))
+                    && !confirmed.is_some_and(|confirmed| confirmed.contains(msg))
+            })
+            .collect::<HashSet<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        if let Some(pending) = self.pending.get_mut(peer) {
+            pending.extend(missing.iter().copied());
+        }
+        let gossip = Message {
+            src: self.id.clone(),
+            dst: peer.to_string(),
+            body: Body {
+                id: Default::default(),
+                in_reply_to: Default::default(),
+                lamport: None,
+                op_id: None,
+                payload: BroadcastMessage::Extended(GossipProtocol::Gossip {
+                    messages: missing,
+                    have: self.gossip_digest.then(|| self.digest.clone()),
+                }),
+            },
+        };
+        self.cluster
+            .send_checked(&gossip, output)
+            .with_context(|| format!("resync {peer}"))
+    }
+
+    fn request_reconcile(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let digest = MerkleDigest::of(&self.messages, MerkleDigest::DEFAULT_WIDTH);
+        for neighbor in self.gossip.peers() {
+            let request = Message {
+                src: self.id.clone(),
+                dst: neighbor.clone(),
+                body: Body {
+                    id: Some(self.msg_ids.next()),
+                    in_reply_to: None,
+                    lamport: None,
+                    op_id: None,
+                    payload: BroadcastMessage::Extended(GossipProtocol::ReconcileRequest {
+                        digest: digest.clone(),
+                    }),
+                },
+            };
+            self.cluster
+                .send_checked(&request, output)
+                .with_context(|| format!("send reconcile_request to {}", neighbor))?;
+        }
+        Ok(())
     }
 }
 
 impl rustgen::Node<BroadcastMessage> for BroadcastNode {
+    fn init_with(
+        init: &rustgen::InitBody,
+        ctx: NodeContext<BroadcastMessage>,
+    ) -> anyhow::Result<Self> {
+        // With `ADAPTIVE_GOSSIP=1` rounds follow the round trips of our rpc calls, e.g.
+        // to a kv store, starting from the static interval until enough were seen.
+        let interval = gossip::interval_from_env();
+        let rounds = ctx.counter("gossip_rounds");
+        let mut node = if std::env::var("ADAPTIVE_GOSSIP").is_ok_and(|flag| flag == "1") {
+            let policy = AdaptiveInterval {
+                base: interval,
+                ..Default::default()
+            };
+            Self::start(init, policy.pace(&ctx.rpc), ctx.tx)?
+        } else {
+            Self::start(init, move || interval, ctx.tx)?
+        };
+        node.rounds = rounds;
+        let node = match store_from_env(&ctx.rpc)? {
+            Some(store) => node.with_store(store),
+            None => node,
+        };
+        match std::env::var("CHECKPOINT_WINDOW") {
+            Ok(window) => node.with_window(
+                window
+                    .parse()
+                    .with_context(|| format!("CHECKPOINT_WINDOW {window:?} isn't a count"))?,
+            ),
+            Err(_) => Ok(node),
+        }
+    }
+
     fn init_from(
         init_msg: &rustgen::InitBody,
-        tx: std::sync::mpsc::Sender<Message<BroadcastMessage>>,
+        tx: crossbeam_channel::Sender<Message<BroadcastMessage>>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        // create a thread to send gossip notification in period
-        std::thread::spawn(move || loop {
-            std::thread::sleep(Duration::from_millis(100));
-            let _ = tx.send(Message {
-                src: Default::default(),
-                dst: Default::default(),
-                body: Body {
-                    id: None,
-                    in_reply_to: None,
-                    payload: BroadcastMessage::Extended(GossipProtocol::GossipAlert),
-                },
-            });
-        });
-        let neightbors = init_msg.node_ids.clone();
-        Ok(Self {
-            id: init_msg.node_id.clone(),
-            msg_id: 1,
-            messages: HashSet::new(),
-            known: neightbors
-                .iter()
-                .map(|node_id| (node_id.clone(), HashSet::default()))
-                .collect::<HashMap<String, HashSet<usize>>>(),
-            neightbors,
-        })
+        let interval = gossip::interval_from_env();
+        Self::start(init_msg, move || interval, tx)
     }
 
     fn step(
@@ -134,43 +903,173 @@ impl rustgen::Node<BroadcastMessage> for BroadcastNode {
         mut req: rustgen::Message<BroadcastMessage>,
         output: &mut impl Write,
     ) -> anyhow::Result<()> {
+        if !self.joining.is_empty()
+            && matches!(
+                req.body.payload,
+                BroadcastMessage::Broadcast { .. } | BroadcastMessage::BroadcastBatch { .. }
+            )
+        {
+            return req
+                .into_error(
+                    MaelstromError::TemporarilyUnavailable,
+                    "node is reconfiguring, retry later",
+                )
+                .send(output);
+        }
         match req.body.payload {
             BroadcastMessage::Broadcast { message } => {
-                self.messages.insert(message);
-                let mut reply = req.into_reply(Some(&mut self.msg_id));
-                reply.body.payload = BroadcastMessage::BroadcastOk;
-                reply.send(output)?
-            }
-            BroadcastMessage::Read => {
-                let mut reply = req.into_reply(Some(&mut self.msg_id));
-                let mut tmp_messages = HashSet::with_capacity(0);
-                std::mem::swap(&mut self.messages, &mut tmp_messages);
-                reply.body.payload = BroadcastMessage::ReadOk {
-                    messages: tmp_messages,
-                };
-                reply.send(output)?;
-                let BroadcastMessage::ReadOk { mut messages } = reply.body.payload else {
-                    unreachable!()
-                };
-                std::mem::swap(&mut self.messages, &mut messages);
+                self.accept([message]);
+                req.reply_with(&self.msg_ids, BroadcastMessage::BroadcastOk)
+                    .send(output)?
             }
+            BroadcastMessage::BroadcastBatch { ref messages } => {
+                self.accept(messages.iter().copied());
+                req.reply_with(&self.msg_ids, BroadcastMessage::BroadcastBatchOk)
+                    .send(output)?
+            }
+            BroadcastMessage::Read { .. } if self.window.is_some() => req
+                .reply_with(
+                    &self.msg_ids,
+                    BroadcastMessage::ReadOk {
+                        messages: self.read.collect(self.digest.iter()),
+                        watermark: None,
+                    },
+                )
+                .send(output)?,
+            BroadcastMessage::Read { since: Some(since) } if self.incremental_read => {
+                // page in sequence order, so the watermark covers exactly what's returned
+                let since = since.min(self.sequence.len());
+                let page = &self.sequence[since..];
+                let page = &page[..page.len().min(self.read.max.unwrap_or(usize::MAX))];
+                req.reply_with(
+                    &self.msg_ids,
+                    BroadcastMessage::ReadOk {
+                        messages: self.read.collect(page.iter().copied()),
+                        watermark: Some(since + page.len()),
+                    },
+                )
+                .send(output)?
+            }
+            BroadcastMessage::Read { .. } if self.read.stream && !self.read.sorted => {
+                let reply = req.into_reply(Some(&self.msg_ids));
+                Message {
+                    src: reply.src,
+                    dst: reply.dst,
+                    body: Body {
+                        id: reply.body.id,
+                        in_reply_to: reply.body.in_reply_to,
+                        lamport: None,
+                        op_id: None,
+                        payload: StreamedReadOk {
+                            messages: Capped {
+                                messages: &self.messages,
+                                max: self.read.max.unwrap_or(usize::MAX),
+                            },
+                            watermark: self.incremental_read.then_some(self.sequence.len()),
+                        },
+                    },
+                }
+                .send(output)?
+            }
+            BroadcastMessage::Read { .. } => req
+                .reply_with(
+                    &self.msg_ids,
+                    BroadcastMessage::ReadOk {
+                        messages: self.read.collect(self.messages.iter().copied()),
+                        watermark: self.incremental_read.then_some(self.sequence.len()),
+                    },
+                )
+                .send(output)?,
             BroadcastMessage::Topology { ref mut topology } => {
-                self.neightbors = topology
-                    .remove(&self.id)
-                    .unwrap_or_else(|| panic!("no topology given for node {}", self.id));
-                let mut reply = req.into_reply(Some(&mut self.msg_id));
-                reply.body.payload = BroadcastMessage::TopologyOk;
-                reply.send(output)?
+                let neighbors = topology.remove(&self.id).ok_or_else(|| {
+                    MaelstromError::MalformedRequest
+                        .because(format!("no topology given for node {}", self.id))
+                })?;
+                if !self.topology_override {
+                    self.set_neighbors(neighbors);
+                }
+                req.reply_with(&self.msg_ids, BroadcastMessage::TopologyOk)
+                    .send(output)?
+            }
+            BroadcastMessage::GetTopology => req
+                .reply_with(
+                    &self.msg_ids,
+                    BroadcastMessage::TopologyInfo {
+                        neighbors: self.gossip.neighbors().to_vec(),
+                    },
+                )
+                .send(output)?,
+            BroadcastMessage::Converged => req
+                .reply_with(
+                    &self.msg_ids,
+                    BroadcastMessage::ConvergedOk {
+                        converged: self.converged(),
+                    },
+                )
+                .send(output)?,
+            BroadcastMessage::GossipState => {
+                let neighbors = self
+                    .gossip
+                    .peers()
+                    .map(|neighbor| {
+                        let known = self.gossip.known(neighbor).expect("neighbors are tracked");
+                        let state = NeighborState {
+                            known: known.len(),
+                            pending: self.pending.get(neighbor).map_or(0, HashSet::len),
+                        };
+                        (neighbor.clone(), state)
+                    })
+                    .collect();
+                req.reply_with(
+                    &self.msg_ids,
+                    BroadcastMessage::GossipStateOk {
+                        neighbors,
+                        skipped_rounds: self.gossip.skipped_rounds(),
+                    },
+                )
+                .send(output)?
+            }
+            BroadcastMessage::PauseGossip => {
+                self.gossip.pause();
+                req.reply_with(&self.msg_ids, BroadcastMessage::PauseGossipOk)
+                    .send(output)?
+            }
+            BroadcastMessage::ResumeGossip => {
+                self.gossip.resume();
+                req.reply_with(&self.msg_ids, BroadcastMessage::ResumeGossipOk)
+                    .send(output)?;
+                // catch up at once, unless a round is in flight already
+                if let Some(_round) = self.gossip.try_round() {
+                    self.gossip_round(output)?
+                }
             }
             BroadcastMessage::TopologyOk
+            | BroadcastMessage::PauseGossipOk
+            | BroadcastMessage::ResumeGossipOk
+            | BroadcastMessage::GossipStateOk { .. }
+            | BroadcastMessage::TopologyInfo { .. }
+            | BroadcastMessage::ConvergedOk { .. }
             | BroadcastMessage::BroadcastOk
+            | BroadcastMessage::BroadcastBatchOk
             | BroadcastMessage::ReadOk { .. } => {}
             BroadcastMessage::Extended(ref external) => {
-                self.handle_external(&req, output, &external)?
+                self.handle_external(&req, output, external)?
             }
         }
         Ok(())
     }
+
+    /// Load the saved set, a kv store's reply only comes in once the loop runs.
+    fn after_init(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        self.load(output)
+    }
+
+    /// Every neighbor is known to hold every message we have.
+    fn converged(&self) -> bool {
+        self.gossip
+            .peers()
+            .all(|neighbor| self.pending.get(neighbor).is_none_or(HashSet::is_empty))
+    }
 }
 
 fn main() -> anyhow::Result<()> {
@@ -180,17 +1079,747 @@ fn main() -> anyhow::Result<()> {
 
 #[cfg(test)]
 mod test {
-    use std::collections::HashSet;
+    use std::{
+        collections::{HashMap, HashSet},
+        io::{BufReader, Write},
+        time::Duration,
+    };
+
+    use anyhow::Context;
 
-    use rustgen::{Body, Message};
+    use rand::{rngs::StdRng, SeedableRng};
+    use rustgen::{
+        digest::Digest,
+        kv::{KvClient, LIN_KV},
+        main_loop_with_io,
+        persist::{KvStore, MemStore, Store},
+        rpc::{NodeContext, Rpc},
+        test_util::{assert_replies_to, assert_wire_format, FakeKv, Network},
+        Body, IdGen, InitBody, Message, Node,
+    };
     use serde::Serialize;
 
-    use crate::{BroadcastMessage, GossipProtocol};
+    use crate::{
+        BroadcastMessage, BroadcastNode, GossipProtocol, NeighborState, Relaying,
+        DEFAULT_PARTITION_AFTER,
+    };
+
+    fn new_node(node_id: &str, node_ids: &[&str]) -> anyhow::Result<BroadcastNode> {
+        let (tx, _) = crossbeam_channel::unbounded();
+        BroadcastNode::init_from(
+            &InitBody {
+                node_id: node_id.to_string(),
+                node_ids: node_ids.iter().map(|id| id.to_string()).collect(),
+                extra: Default::default(),
+            },
+            tx,
+        )
+    }
+
+    fn message(src: &str, payload: BroadcastMessage) -> Message<BroadcastMessage> {
+        Message {
+            src: src.to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload,
+            },
+        }
+    }
+
+    fn sent(output: &[u8]) -> anyhow::Result<Vec<Message<BroadcastMessage>>> {
+        output
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| Ok(serde_json::from_slice(line)?))
+            .collect()
+    }
+
+    #[test]
+    fn test_gossip_cap_per_tick() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2", "n3", "n4"])?;
+        node.set_neighbors(vec!["n2".to_string(), "n3".to_string(), "n4".to_string()]);
+        node.gossip.set_max_per_round(1);
+        node.record(0..3);
+        node.learned("n3", [0].into_iter().collect());
+
+        let alert = || message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+        let mut output = Vec::new();
+        node.step(alert(), &mut output)?;
+        let gossips = sent(&output)?;
+        assert_eq!(gossips.len(), 1);
+        assert_eq!(gossips[0].dst, "n2");
+
+        // once n2 caught up, the next tick moves on to the next neighbor most behind
+        let catch_up = GossipProtocol::Gossip {
+            messages: (0..3).collect(),
+            have: None,
+        };
+        node.step(
+            message("n2", BroadcastMessage::Extended(catch_up)),
+            &mut output,
+        )?;
+        output.clear();
+        node.step(alert(), &mut output)?;
+        let gossips = sent(&output)?;
+        assert_eq!(gossips.len(), 1);
+        assert_eq!(gossips[0].dst, "n4");
+        Ok(())
+    }
+
+    #[test]
+    fn test_gossip_acked_marks_known() -> anyhow::Result<()> {
+        let mut n1 = new_node("n1", &["n1", "n2"])?;
+        let mut n2 = new_node("n2", &["n1", "n2"])?;
+        n1.compact_known = false;
+        // without digests every gossip is acked on its own
+        n1.gossip_digest = false;
+        n2.gossip_digest = false;
+        n1.record(0..10);
+        let alert = || message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+
+        let mut output = Vec::new();
+        n1.step(alert(), &mut output)?;
+        let gossip = sent(&output)?.remove(0);
+        output.clear();
+        n2.step(gossip, &mut output)?;
+        let ack = sent(&output)?.remove(0);
+        assert_eq!(ack.dst, "n1");
+        match &ack.body.payload {
+            BroadcastMessage::Extended(GossipProtocol::GossipOk { messages }) => {
+                assert_eq!(
+                    messages.iter().collect::<Vec<_>>(),
+                    (0..10).collect::<Vec<_>>()
+                )
+            }
+            payload => panic!("unexpected ack {payload:?}"),
+        }
+        n1.step(ack.clone(), &mut Vec::new())?;
+        assert_eq!(n1.gossip.known("n2"), Some(&(0..10).collect()));
+
+        // nothing left to resend to n2
+        output.clear();
+        n1.step(alert(), &mut output)?;
+        for gossip in sent(&output)? {
+            if let BroadcastMessage::Extended(GossipProtocol::Gossip { messages, .. }) =
+                gossip.body.payload
+            {
+                assert!(messages.is_empty(), "resent {messages:?}");
+            }
+        }
+
+        // an ack late for what was compacted meanwhile doesn't grow `known` back
+        n1.compact_known = true;
+        n1.compact_known(0..10);
+        n1.step(ack, &mut Vec::new())?;
+        assert_eq!(n1.gossip.known("n2"), Some(&Digest::default()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ack_folds_into_next_gossip() -> anyhow::Result<()> {
+        let mut n1 = new_node("n1", &["n1", "n2"])?;
+        let mut n2 = new_node("n2", &["n1", "n2"])?;
+        n1.compact_known = false;
+        n1.record(0..10);
+        let alert = || message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+
+        let mut output = Vec::new();
+        n1.step(alert(), &mut output)?;
+        let gossip = sent(&output)?.remove(0);
+        output.clear();
+        n2.step(gossip.clone(), &mut output)?;
+        assert!(sent(&output)?.is_empty(), "acked on its own");
+
+        // n2's grown digest goes back to n1 with its next round and acks the lot
+        n2.step(alert(), &mut output)?;
+        let back = sent(&output)?.remove(0);
+        assert_eq!(back.dst, "n1");
+        n1.step(back, &mut Vec::new())?;
+        assert_eq!(n1.gossip.known("n2"), Some(&(0..10).collect()));
+
+        // a resend n2's digest won't change for is acked right away
+        output.clear();
+        n2.step(gossip, &mut output)?;
+        let ack = sent(&output)?.remove(0);
+        assert!(matches!(
+            ack.body.payload,
+            BroadcastMessage::Extended(GossipProtocol::GossipOk { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_seeded_regossip_is_reproducible() -> anyhow::Result<()> {
+        let regossiped = |seed| -> anyhow::Result<HashSet<usize>> {
+            let mut node = new_node("n1", &["n1", "n2"])?;
+            node.rng = StdRng::seed_from_u64(seed);
+            node.compact_known = false;
+            node.record(0..100);
+            node.learned("n2", (0..90).collect());
+            let alert = message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+            let mut output = Vec::new();
+            node.step(alert, &mut output)?;
+            match sent(&output)?.remove(0).body.payload {
+                BroadcastMessage::Extended(GossipProtocol::Gossip { messages, .. }) => {
+                    Ok(messages.into_iter().filter(|msg| *msg < 90).collect())
+                }
+                payload => anyhow::bail!("unexpected gossip {payload:?}"),
+            }
+        };
+        let extra = regossiped(5)?;
+        assert!(!extra.is_empty());
+        assert_eq!(regossiped(5)?, extra);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconcile_transfers_only_differing_bucket() -> anyhow::Result<()> {
+        let mut n1 = new_node("n1", &["n1", "n2"])?;
+        let mut n2 = new_node("n2", &["n1", "n2"])?;
+        n1.messages.extend(0..1000);
+        n2.messages.extend((0..1000).filter(|msg| *msg != 500));
+        n1.reconcile_every = Some(1);
+
+        let mut output = Vec::new();
+        n1.request_reconcile(&mut output)?;
+        let request = sent(&output)?.remove(0);
+        assert_eq!(request.dst, "n2");
+        output.clear();
+        n2.step(request, &mut output)?;
+        let response = sent(&output)?.remove(0);
+        let BroadcastMessage::Extended(GossipProtocol::ReconcileResponse { messages }) =
+            &response.body.payload
+        else {
+            panic!(
+                "expected reconcile_response, got {:?}",
+                response.body.payload
+            );
+        };
+        // only the bucket holding 500 travels, minus 500 itself which n2 misses
+        let bucket = (500 / 64 * 64..500 / 64 * 64 + 64).filter(|msg| *msg != 500);
+        assert_eq!(*messages, bucket.collect());
+
+        // the other way round n2 learns just what it missed, and nothing is left to repair
+        output.clear();
+        n2.request_reconcile(&mut output)?;
+        let request = sent(&output)?.remove(0);
+        output.clear();
+        n1.step(request, &mut output)?;
+        let response = sent(&output)?.remove(0);
+        n2.step(response, &mut output)?;
+        assert_eq!(n2.messages, n1.messages);
+        Ok(())
+    }
+
+    #[test]
+    fn test_paused_gossip_catches_up_on_resume() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2"])?;
+        let alert = || message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+        let mut output = Vec::new();
+        node.step(message("c1", BroadcastMessage::PauseGossip), &mut output)?;
+        node.step(
+            message("c1", BroadcastMessage::Broadcast { message: 7 }),
+            &mut output,
+        )?;
+        node.step(alert(), &mut output)?;
+        let kinds = sent(&output)?
+            .into_iter()
+            .map(|msg| msg.body.payload)
+            .collect::<Vec<_>>();
+        assert!(
+            matches!(
+                kinds[..],
+                [
+                    BroadcastMessage::PauseGossipOk,
+                    BroadcastMessage::BroadcastOk
+                ]
+            ),
+            "sent while paused: {kinds:?}"
+        );
+
+        output.clear();
+        node.step(message("c1", BroadcastMessage::ResumeGossip), &mut output)?;
+        let sent = sent(&output)?;
+        assert!(matches!(
+            sent[0].body.payload,
+            BroadcastMessage::ResumeGossipOk
+        ));
+        let gossip = sent
+            .iter()
+            .find(|msg| msg.dst == "n2")
+            .context("no gossip to n2 on resume")?;
+        let BroadcastMessage::Extended(GossipProtocol::Gossip { messages, .. }) =
+            &gossip.body.payload
+        else {
+            panic!("expected gossip, got {:?}", gossip.body.payload);
+        };
+        assert_eq!(*messages, [7].into());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_leaves_round_in_flight_alone() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2"])?;
+        let mut output = Vec::new();
+        node.step(message("c1", BroadcastMessage::PauseGossip), &mut output)?;
+        node.step(
+            message("c1", BroadcastMessage::Broadcast { message: 7 }),
+            &mut output,
+        )?;
+        node.gossip.resume();
+        let in_flight = node.gossip.try_round().context("no round in flight")?;
+        node.gossip.pause();
+
+        output.clear();
+        node.step(message("c1", BroadcastMessage::ResumeGossip), &mut output)?;
+        let sent = sent(&output)?;
+        assert_eq!(sent.len(), 1, "gossiped beside the round in flight");
+        assert!(matches!(
+            sent[0].body.payload,
+            BroadcastMessage::ResumeGossipOk
+        ));
+        drop(in_flight);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_survives_restart_through_store() -> anyhow::Result<()> {
+        let disk = MemStore::default();
+        let mut node = new_node("n1", &["n1", "n2"])?.with_store(Box::new(disk.clone()));
+        let mut output = Vec::new();
+        node.step(
+            message("c1", BroadcastMessage::Broadcast { message: 1 }),
+            &mut output,
+        )?;
+        let gossip = GossipProtocol::Gossip {
+            messages: [2, 3].into(),
+            have: None,
+        };
+        node.step(
+            message("n2", BroadcastMessage::Extended(gossip)),
+            &mut output,
+        )?;
+        let mut store = disk.clone();
+        assert_eq!(
+            store.get("broadcast-n1", &mut output)?,
+            None,
+            "saved by a step"
+        );
+        let alert = message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+        node.step(alert, &mut output)?;
+        drop(node);
+
+        let mut restarted = new_node("n1", &["n1", "n2"])?.with_store(Box::new(disk.clone()));
+        restarted.after_init(&mut output)?;
+        assert_eq!(*restarted.messages, [1, 2, 3].into());
+        // another node's set is kept apart
+        let mut other = new_node("n2", &["n1", "n2"])?.with_store(Box::new(disk));
+        other.after_init(&mut output)?;
+        assert!(other.messages.is_empty());
+        Ok(())
+    }
+
+    /// The node `STORE=lin-kv` builds, without setting the env the other tests' nodes
+    /// are built from.
+    struct LinKvNode(BroadcastNode);
+
+    impl Node<BroadcastMessage> for LinKvNode {
+        fn init_from(
+            _: &InitBody,
+            _: crossbeam_channel::Sender<Message<BroadcastMessage>>,
+        ) -> anyhow::Result<Self> {
+            unreachable!("the loop builds nodes with init_with")
+        }
+
+        fn init_with(init: &InitBody, ctx: NodeContext<BroadcastMessage>) -> anyhow::Result<Self> {
+            let store = KvStore::new(KvClient::new(LIN_KV, ctx.rpc.clone()));
+            let node = BroadcastNode::init_with(init, ctx)?;
+            Ok(Self(node.with_store(Box::new(store))))
+        }
+
+        fn step(
+            &mut self,
+            req: Message<BroadcastMessage>,
+            output: &mut impl Write,
+        ) -> anyhow::Result<()> {
+            self.0.step(req, output)
+        }
+
+        fn after_init(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+            self.0.after_init(output)
+        }
+    }
+
+    /// Run the real loop as the single node n1 backed by lin-kv, the test playing
+    /// lin-kv with `kv`. The input closes once every request was answered and `until`
+    /// holds for what lin-kv was sent.
+    fn run_with_lin_kv(
+        kv: &mut FakeKv,
+        requests: &[BroadcastMessage],
+        until: impl Fn(&FakeKv) -> bool,
+    ) -> anyhow::Result<Vec<Message<BroadcastMessage>>> {
+        struct Lines(std::sync::mpsc::Sender<String>, Vec<u8>);
+        impl Write for Lines {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.1.extend_from_slice(buf);
+                while let Some(end) = self.1.iter().position(|b| *b == b'\n') {
+                    let line = self.1.drain(..=end).collect::<Vec<_>>();
+                    let _ = self
+                        .0
+                        .send(String::from_utf8_lossy(&line).trim_end().to_string());
+                }
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (input, mut feed) = std::io::pipe()?;
+        let (tx, lines) = std::sync::mpsc::channel();
+        let node = std::thread::spawn(move || {
+            main_loop_with_io::<BroadcastMessage, LinKvNode>(
+                BufReader::new(input),
+                Lines(tx, Vec::new()),
+            )
+        });
+        writeln!(
+            feed,
+            r#"{{"src":"c0","dest":"n1","body":{{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}}}"#
+        )?;
+        for (msg_id, payload) in (2..).zip(requests) {
+            let mut request = message("c1", payload.clone());
+            request.body.id = Some(msg_id);
+            writeln!(feed, "{}", serde_json::to_string(&request)?)?;
+        }
+        let mut replies = Vec::new();
+        while replies.len() < requests.len() || !until(kv) {
+            let line = lines
+                .recv_timeout(Duration::from_secs(5))
+                .context("the loop went quiet")?;
+            match kv.reply_to(&line) {
+                Some(reply) => writeln!(feed, "{reply}")?,
+                None if line.contains(r#""dest":"c1""#) => {
+                    replies.push(serde_json::from_str(&line)?)
+                }
+                None => {}
+            }
+        }
+        drop(feed);
+        node.join().expect("loop panicked")?;
+        Ok(replies)
+    }
+
+    #[test]
+    fn test_loop_loads_saved_set_from_lin_kv() -> anyhow::Result<()> {
+        let mut kv = FakeKv::new(Rpc::new("n1"));
+        let broadcast = |message| BroadcastMessage::Broadcast { message };
+        // a gossip round saves the set
+        let saved = |kv: &FakeKv| {
+            kv.get("broadcast-n1")
+                .and_then(|saved| serde_json::from_value::<Digest>(saved.clone()).ok())
+                .is_some_and(|saved| saved.contains(&1) && saved.contains(&2))
+        };
+        run_with_lin_kv(&mut kv, &[broadcast(1), broadcast(2)], saved)?;
+
+        // restarted, the read waits for the saved set to come back from lin-kv
+        let read = BroadcastMessage::Read { since: None };
+        let replies = run_with_lin_kv(&mut kv, &[broadcast(3), read], |_| true)?;
+        let BroadcastMessage::ReadOk { messages, .. } = &replies[1].body.payload else {
+            anyhow::bail!("unexpected reply {:?}", replies[1].body.payload);
+        };
+        assert_eq!(
+            messages.iter().copied().collect::<HashSet<_>>(),
+            (1..=3).collect()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_windowed_set_recovers_from_checkpoint() -> anyhow::Result<()> {
+        let kv = MemStore::default();
+        let mut node = new_node("n1", &["n1", "n2"])?
+            .with_store(Box::new(kv.clone()))
+            .with_window(2)?;
+        let mut output = Vec::new();
+        for broadcast in 1..=5 {
+            node.step(
+                message("c1", BroadcastMessage::Broadcast { message: broadcast }),
+                &mut output,
+            )?;
+        }
+        let alert = message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+        node.step(alert, &mut output)?;
+        assert_eq!(*node.messages, [4, 5].into());
+        // gossiped back, an evicted message isn't new
+        let gossip = GossipProtocol::Gossip {
+            messages: [1].into(),
+            have: None,
+        };
+        node.step(
+            message("n2", BroadcastMessage::Extended(gossip)),
+            &mut output,
+        )?;
+        assert_eq!(node.messages.len(), 2);
+
+        let read = |node: &mut BroadcastNode| -> anyhow::Result<HashSet<usize>> {
+            let mut output = Vec::new();
+            node.step(
+                message("c1", BroadcastMessage::Read { since: None }),
+                &mut output,
+            )?;
+            match sent(&output)?.remove(0).body.payload {
+                BroadcastMessage::ReadOk { messages, .. } => Ok(messages.into_iter().collect()),
+                reply => anyhow::bail!("unexpected reply {reply:?}"),
+            }
+        };
+        assert_eq!(read(&mut node)?, (1..=5).collect());
+        node.step(
+            message("c1", BroadcastMessage::Broadcast { message: 6 }),
+            &mut output,
+        )?;
+        assert_eq!(
+            read(&mut node)?,
+            (1..=6).collect(),
+            "the window joins the read"
+        );
+        drop(node);
+
+        // the window not checkpointed yet is lost with the node, gossip brings it back
+        let mut restarted = new_node("n1", &["n1", "n2"])?
+            .with_store(Box::new(kv))
+            .with_window(2)?;
+        restarted.after_init(&mut Vec::new())?;
+        assert_eq!(read(&mut restarted)?, (1..=5).collect());
+        Ok(())
+    }
+
+    #[test]
+    fn test_capped_gossip_round_robins_neighbors() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2", "n3", "n4", "n5"])?;
+        node.set_neighbors(["n2", "n3", "n4", "n5"].map(String::from).to_vec());
+        node.gossip.set_max_per_round(1);
+        node.record(0..3);
+
+        let mut served = HashSet::new();
+        for _ in 0..4 {
+            let mut output = Vec::new();
+            let alert = message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+            node.step(alert, &mut output)?;
+            served.extend(sent(&output)?.into_iter().map(|gossip| gossip.dst));
+        }
+        assert_eq!(served.len(), 4, "starved neighbors, served only {served:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_idle_neighbor_isnt_gossiped() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2"])?;
+        node.record(0..3);
+        let alert = || message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+        let mut output = Vec::new();
+        node.step(alert(), &mut output)?;
+        assert_eq!(sent(&output)?.len(), 1);
+
+        // n2 holds it all and was sent our digest, a round has nothing to tell it
+        let caught_up = GossipProtocol::Gossip {
+            messages: HashSet::new(),
+            have: Some((0..3).collect()),
+        };
+        node.step(
+            message("n2", BroadcastMessage::Extended(caught_up)),
+            &mut Vec::new(),
+        )?;
+        output.clear();
+        node.step(alert(), &mut output)?;
+        assert!(sent(&output)?.is_empty());
+
+        node.record([3]);
+        node.step(alert(), &mut output)?;
+        let gossips = sent(&output)?;
+        assert_eq!(gossips.len(), 1);
+        match &gossips[0].body.payload {
+            BroadcastMessage::Extended(GossipProtocol::Gossip { messages, have }) => {
+                assert_eq!(messages, &HashSet::from([3]));
+                assert_eq!(have.as_ref().map(Digest::len), Some(4));
+            }
+            payload => panic!("unexpected gossip {payload:?}"),
+        }
+        Ok(())
+    }
+
+    /// Gossip among fully connected nodes for `rounds`, returning the first round they
+    /// all converged in, how many message values were gossiped and how many of those
+    /// went to a neighbor whose digest had already covered them.
+    fn gossip_rounds(digest: bool, rounds: usize) -> anyhow::Result<(Option<usize>, usize, usize)> {
+        let ids = ["n1", "n2", "n3"];
+        let mut nodes = ids
+            .iter()
+            .map(|id| new_node(id, &ids))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for (i, node) in nodes.iter_mut().enumerate() {
+            node.gossip_digest = digest;
+            node.rng = StdRng::seed_from_u64(i as u64);
+            node.record(i * 100..(i + 1) * 100);
+        }
+        // (holder, sender) -> what the holder's digests told the sender it holds
+        let mut covered = HashMap::<(String, String), HashSet<usize>>::new();
+        let (mut converged, mut values, mut resent) = (None, 0, 0);
+        for round in 1..=rounds {
+            let mut output = Vec::new();
+            for node in &mut nodes {
+                let alert = message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+                node.step(alert, &mut output)?;
+            }
+            let gossips = sent(&output)?;
+            for gossip in &gossips {
+                if let BroadcastMessage::Extended(GossipProtocol::Gossip { messages, .. }) =
+                    &gossip.body.payload
+                {
+                    values += messages.len();
+                    let key = (gossip.dst.clone(), gossip.src.clone());
+                    let covered = covered.get(&key);
+                    resent += messages
+                        .iter()
+                        .filter(|msg| covered.is_some_and(|covered| covered.contains(msg)))
+                        .count();
+                }
+            }
+            for gossip in gossips {
+                if let BroadcastMessage::Extended(GossipProtocol::Gossip {
+                    have: Some(have), ..
+                }) = &gossip.body.payload
+                {
+                    let key = (gossip.src.clone(), gossip.dst.clone());
+                    covered.entry(key).or_default().extend(have.iter());
+                }
+                let dst = ids.iter().position(|id| *id == gossip.dst).unwrap();
+                nodes[dst].step(gossip, &mut Vec::new())?;
+            }
+            if converged.is_none() && nodes.iter().all(|node| node.converged()) {
+                converged = Some(round);
+            }
+        }
+        Ok((converged, values, resent))
+    }
+
+    fn relaying_node(node_id: &str, node_ids: &[&str]) -> anyhow::Result<BroadcastNode> {
+        let mut node = new_node(node_id, node_ids)?;
+        node.relay = Some(Relaying {
+            others: node.gossip.peers().cloned().collect(),
+            fresh: Vec::new(),
+        });
+        Ok(node)
+    }
+
+    #[test]
+    fn test_relay_reaches_every_node_once() -> anyhow::Result<()> {
+        let ids = (1..=20).map(|i| format!("n{i}")).collect::<Vec<_>>();
+        let ids = ids.iter().map(String::as_str).collect::<Vec<_>>();
+        let mut nodes = ids
+            .iter()
+            .map(|id| Ok((id.to_string(), relaying_node(id, &ids)?)))
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+        let origin = nodes.get_mut("n1").unwrap();
+        origin.step(
+            message(
+                "c1",
+                BroadcastMessage::BroadcastBatch {
+                    messages: vec![7, 8],
+                },
+            ),
+            &mut Vec::new(),
+        )?;
+        let mut output = Vec::new();
+        let alert = || message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+        origin.step(alert(), &mut output)?;
+
+        let mut in_flight = sent(&output)?;
+        let mut received = HashMap::<String, usize>::new();
+        while let Some(relay) = in_flight.pop() {
+            *received.entry(relay.dst.clone()).or_default() += 1;
+            let mut output = Vec::new();
+            nodes
+                .get_mut(&relay.dst)
+                .unwrap()
+                .step(relay, &mut output)?;
+            in_flight.extend(sent(&output)?);
+        }
+        assert_eq!(received.len(), ids.len() - 1);
+        assert!(received.values().all(|times| *times == 1), "{received:?}");
+        assert!(nodes
+            .values()
+            .all(|node| node.messages.contains(&7) && node.messages.contains(&8)));
+
+        // nothing new, nothing relayed; a relayed message is never relayed again
+        for node in nodes.values_mut() {
+            let mut output = Vec::new();
+            node.step(alert(), &mut output)?;
+            assert!(output.is_empty());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_relay_stops_at_hop_limit() -> anyhow::Result<()> {
+        let ids = ["n1", "n2", "n3", "n4"];
+        let mut node = relaying_node("n1", &ids)?;
+        let relay = |hops| {
+            message(
+                "n2",
+                BroadcastMessage::Extended(GossipProtocol::Relay {
+                    messages: [1].into(),
+                    targets: vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+                    hops,
+                }),
+            )
+        };
+        let mut output = Vec::new();
+        node.step(relay(0), &mut output)?;
+        assert!(output.is_empty());
+        assert!(node.messages.contains(&1));
+
+        // ourselves and the sender are dropped from the targets
+        node.step(relay(1), &mut output)?;
+        let forwarded = sent(&output)?;
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(forwarded[0].dst, "n3");
+        assert!(matches!(
+            &forwarded[0].body.payload,
+            BroadcastMessage::Extended(GossipProtocol::Relay { targets, hops: 0, .. })
+                if targets.is_empty()
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_gossip_acks_implicitly() -> anyhow::Result<()> {
+        let (converged, with_digest, resent) = gossip_rounds(true, 20)?;
+        assert!(converged.is_some_and(|round| round <= 3), "{converged:?}");
+        assert_eq!(
+            resent, 0,
+            "values resent after the neighbor's digest covered them"
+        );
+
+        // without digests nodes rarely learn their gossip arrived and keep resending it
+        let (_, without_digest, _) = gossip_rounds(false, 20)?;
+        assert!(
+            without_digest >= 2 * with_digest,
+            "{without_digest} values gossiped without digests, {with_digest} with"
+        );
+        Ok(())
+    }
 
     #[test]
     fn test_echo_node_msg() -> anyhow::Result<()> {
         let ext = BroadcastMessage::Extended(GossipProtocol::Gossip {
             messages: HashSet::default(),
+            have: None,
         });
         let msg = Message {
             src: "c1".to_string(),
@@ -199,12 +1828,577 @@ mod test {
                 payload: ext,
                 id: None,
                 in_reply_to: None,
+                lamport: None,
+                op_id: None,
             },
         }
-        .into_reply(Some(&mut 1));
+        .into_reply(Some(&IdGen::starting_at(1)));
         let stdout = std::io::stdout().lock();
         let mut output = serde_json::Serializer::new(stdout);
         msg.serialize(&mut output)?;
         Ok(())
     }
+
+    #[test]
+    fn test_broadcast_batch() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1"])?;
+        let mut batch = message(
+            "c1",
+            BroadcastMessage::BroadcastBatch {
+                messages: (0..100).collect(),
+            },
+        );
+        batch.body.id = Some(7);
+        let mut output = Vec::new();
+        node.step(batch.clone(), &mut output)?;
+
+        assert_eq!(node.messages, (0..100).collect());
+        let replies = sent(&output)?;
+        assert_eq!(replies.len(), 1);
+        assert_replies_to(&replies[0], &batch);
+        assert!(matches!(
+            replies[0].body.payload,
+            BroadcastMessage::BroadcastBatchOk
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let mut broadcast = message("c1", BroadcastMessage::Broadcast { message: 42 });
+        broadcast.body.id = Some(3);
+        assert_wire_format(
+            &broadcast,
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":3,"in_reply_to":null,"type":"broadcast","message":42}}"#,
+        );
+        let read_ok = message(
+            "n1",
+            BroadcastMessage::ReadOk {
+                messages: vec![42],
+                watermark: None,
+            },
+        );
+        assert_wire_format(
+            &read_ok,
+            r#"{"src":"n1","dest":"n1","body":{"msg_id":null,"in_reply_to":null,"type":"read_ok","messages":[42]}}"#,
+        );
+        let topology = message(
+            "c1",
+            BroadcastMessage::Topology {
+                topology: [("n1".to_string(), vec!["n2".to_string()])].into(),
+            },
+        );
+        assert_wire_format(
+            &topology,
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":null,"in_reply_to":null,"type":"topology","topology":{"n1":["n2"]}}}"#,
+        );
+        let gossip = message(
+            "n2",
+            BroadcastMessage::Extended(GossipProtocol::Gossip {
+                messages: HashSet::from([7]),
+                have: None,
+            }),
+        );
+        assert_wire_format(
+            &gossip,
+            r#"{"src":"n2","dest":"n1","body":{"msg_id":null,"in_reply_to":null,"type":"extended","Gossip":{"messages":[7]}}}"#,
+        );
+    }
+
+    #[test]
+    fn test_reject_writes_while_reconfiguring() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2", "n3"])?;
+        node.set_neighbors(vec!["n2".to_string()]);
+        node.record([1]);
+        let mut output = Vec::new();
+        let topology = BroadcastMessage::Topology {
+            topology: [("n1".to_string(), vec!["n2".to_string(), "n3".to_string()])].into(),
+        };
+        node.step(message("c1", topology), &mut output)?;
+        assert_eq!(node.joining, HashSet::from(["n3".to_string()]));
+
+        output.clear();
+        let mut broadcast = message("c1", BroadcastMessage::Broadcast { message: 2 });
+        broadcast.body.id = Some(5);
+        node.step(broadcast, &mut output)?;
+        let reply: serde_json::Value = serde_json::from_slice(&output)?;
+        assert_eq!(reply["body"]["type"], "error");
+        assert_eq!(reply["body"]["code"], 11);
+        assert_eq!(reply["body"]["in_reply_to"], 5);
+        assert!(!node.messages.contains(&2));
+
+        output.clear();
+        node.step(
+            message("c1", BroadcastMessage::Read { since: None }),
+            &mut output,
+        )?;
+        let replies = sent(&output)?;
+        assert!(matches!(
+            &replies[0].body.payload,
+            BroadcastMessage::ReadOk { messages, .. } if messages.contains(&1)
+        ));
+
+        // the reconfiguration ends on its own once n3 holds the set
+        let caught_up = GossipProtocol::Gossip {
+            messages: HashSet::new(),
+            have: Some([1].into_iter().collect()),
+        };
+        node.step(
+            message("n3", BroadcastMessage::Extended(caught_up)),
+            &mut output,
+        )?;
+        assert!(node.joining.is_empty());
+        node.step(
+            message("c1", BroadcastMessage::Broadcast { message: 2 }),
+            &mut output,
+        )?;
+        assert!(node.messages.contains(&2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_partitioned_neighbor_ends_reconfiguration() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2"])?;
+        node.set_neighbors(Vec::new());
+        node.record([1]);
+        node.set_neighbors(vec!["n2".to_string()]);
+        assert!(!node.joining.is_empty());
+
+        // n2 never answers, writes don't wait on it forever
+        let alert = || message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+        for _ in 0..node.partition_after {
+            node.step(alert(), &mut Vec::new())?;
+        }
+        assert!(node.partitioned.contains("n2"));
+        assert!(node.joining.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_incremental_read() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1"])?;
+        node.incremental_read = true;
+        let mut output = Vec::new();
+        let mut read = |node: &mut BroadcastNode, since| -> anyhow::Result<_> {
+            output.clear();
+            node.step(message("c1", BroadcastMessage::Read { since }), &mut output)?;
+            match sent(&output)?.remove(0).body.payload {
+                BroadcastMessage::ReadOk {
+                    messages,
+                    watermark,
+                } => Ok((messages, watermark.unwrap())),
+                _ => unreachable!(),
+            }
+        };
+
+        for value in [1, 2] {
+            node.step(
+                message("c1", BroadcastMessage::Broadcast { message: value }),
+                &mut Vec::new(),
+            )?;
+        }
+        let (messages, watermark) = read(&mut node, None)?;
+        assert_eq!(messages.into_iter().collect::<HashSet<_>>(), [1, 2].into());
+
+        for value in [2, 3, 4] {
+            node.step(
+                message("c1", BroadcastMessage::Broadcast { message: value }),
+                &mut Vec::new(),
+            )?;
+        }
+        let (messages, next) = read(&mut node, Some(watermark))?;
+        assert_eq!(messages, [3, 4]);
+        let (messages, _) = read(&mut node, Some(next))?;
+        assert!(messages.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_config() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1"])?;
+        node.record(0..50);
+        let read = |node: &mut BroadcastNode, since| -> anyhow::Result<_> {
+            let mut output = Vec::new();
+            node.step(message("c1", BroadcastMessage::Read { since }), &mut output)?;
+            match sent(&output)?.remove(0).body.payload {
+                BroadcastMessage::ReadOk {
+                    messages,
+                    watermark,
+                } => Ok((messages, watermark)),
+                payload => panic!("unexpected reply {payload:?}"),
+            }
+        };
+        let all = (0..50).collect::<Vec<usize>>();
+
+        // streamed and copied replies hold the same set
+        node.read.stream = true;
+        let (mut streamed, _) = read(&mut node, None)?;
+        streamed.sort_unstable();
+        assert_eq!(streamed, all);
+        node.read.stream = false;
+        let (mut copied, _) = read(&mut node, None)?;
+        copied.sort_unstable();
+        assert_eq!(copied, all);
+
+        node.read.sorted = true;
+        assert_eq!(read(&mut node, None)?.0, all);
+
+        node.read.sorted = false;
+        for stream in [true, false] {
+            node.read.stream = stream;
+            node.read.max = Some(10);
+            let (capped, _) = read(&mut node, None)?;
+            assert_eq!(capped.len(), 10);
+            assert!(capped.iter().all(|msg| *msg < 50));
+        }
+
+        // capped incremental reads page through the sequence
+        let mut node = new_node("n1", &["n1"])?;
+        node.incremental_read = true;
+        node.read.max = Some(20);
+        node.record(0..50);
+        let (mut pages, mut since) = (Vec::new(), 0);
+        while since < 50 {
+            let (page, watermark) = read(&mut node, Some(since))?;
+            assert!(page.len() <= 20);
+            pages.extend(page);
+            since = watermark.unwrap();
+        }
+        assert_eq!(pages, all);
+        Ok(())
+    }
+
+    /// Accept the first `budget` bytes, then fail every write.
+    struct FailingOutput {
+        budget: usize,
+    }
+
+    impl std::io::Write for FailingOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.budget == 0 {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            let n = buf.len().min(self.budget);
+            self.budget -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failed_read_reply_keeps_messages() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1"])?;
+        node.record(0..50);
+        for stream in [true, false] {
+            node.read.stream = stream;
+            // the reply breaks off halfway through the set
+            let mut output = FailingOutput { budget: 100 };
+            let read = message("c1", BroadcastMessage::Read { since: None });
+            assert!(node.step(read, &mut output).is_err());
+            assert_eq!(*node.messages, (0..50).collect());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_converged_after_gossip_propagates() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2"])?;
+        let mut output = Vec::new();
+        let mut converged = |node: &mut BroadcastNode| -> anyhow::Result<bool> {
+            output.clear();
+            node.step(message("c1", BroadcastMessage::Converged), &mut output)?;
+            match sent(&output)?.remove(0).body.payload {
+                BroadcastMessage::ConvergedOk { converged } => Ok(converged),
+                _ => unreachable!(),
+            }
+        };
+        assert!(converged(&mut node)?);
+
+        node.step(
+            message("c1", BroadcastMessage::Broadcast { message: 1 }),
+            &mut Vec::new(),
+        )?;
+        assert!(!converged(&mut node)?);
+
+        let gossip = GossipProtocol::Gossip {
+            messages: HashSet::from([1]),
+            have: None,
+        };
+        node.step(
+            message("n2", BroadcastMessage::Extended(gossip)),
+            &mut Vec::new(),
+        )?;
+        assert!(converged(&mut node)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_known_compaction() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2", "n3"])?;
+        node.compact_known = true;
+        let known_entries = |node: &BroadcastNode| {
+            node.gossip
+                .peers()
+                .filter_map(|peer| node.gossip.known(peer))
+                .map(Digest::len)
+                .sum::<usize>()
+        };
+
+        for round in 0..10 {
+            let batch = (round * 100..(round + 1) * 100).collect::<HashSet<usize>>();
+            for src in ["n2", "n3"] {
+                let gossip = GossipProtocol::Gossip {
+                    messages: batch.clone(),
+                    have: None,
+                };
+                node.step(
+                    message(src, BroadcastMessage::Extended(gossip)),
+                    &mut Vec::new(),
+                )?;
+            }
+            // whatever both neighbors know is folded into the single global set
+            assert_eq!(known_entries(&node), 0);
+            assert_eq!(node.globally_known.len(), (round + 1) * 100);
+        }
+
+        // partially known messages still live per neighbor
+        let gossip = GossipProtocol::Gossip {
+            messages: HashSet::from([5000]),
+            have: None,
+        };
+        node.step(
+            message("n2", BroadcastMessage::Extended(gossip)),
+            &mut Vec::new(),
+        )?;
+        assert_eq!(known_entries(&node), 1);
+        assert!(!node.converged());
+
+        // a digest of the whole set only tells what wasn't compacted yet
+        let have = (0..1000).chain([5000]).collect::<Digest>();
+        assert_eq!(node.news_from("n3", &have), [5000].into_iter().collect());
+        let gossip = GossipProtocol::Gossip {
+            messages: HashSet::new(),
+            have: Some(have.clone()),
+        };
+        node.step(
+            message("n3", BroadcastMessage::Extended(gossip)),
+            &mut Vec::new(),
+        )?;
+        assert_eq!(known_entries(&node), 0);
+        assert_eq!(node.globally_known.len(), 1001);
+        assert!(node.news_from("n3", &have).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gossip_state() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2", "n3"])?;
+        let gossip = GossipProtocol::Gossip {
+            messages: HashSet::from([1, 2]),
+            have: None,
+        };
+        node.step(
+            message("n2", BroadcastMessage::Extended(gossip)),
+            &mut Vec::new(),
+        )?;
+        node.step(
+            message("c1", BroadcastMessage::Broadcast { message: 3 }),
+            &mut Vec::new(),
+        )?;
+
+        let mut output = Vec::new();
+        node.step(message("c1", BroadcastMessage::GossipState), &mut output)?;
+        let BroadcastMessage::GossipStateOk { neighbors, .. } =
+            sent(&output)?.remove(0).body.payload
+        else {
+            panic!("expected gossip_state_ok");
+        };
+        let state = |known, pending| NeighborState { known, pending };
+        assert_eq!(
+            neighbors,
+            [
+                ("n2".to_string(), state(2, 1)),
+                ("n3".to_string(), state(0, 3)),
+            ]
+            .into()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_topology() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2", "n3"])?;
+        let topology = serde_json::from_str(r#"{"n1":["n2"],"n2":["n1","n3"],"n3":["n2"]}"#)?;
+        node.step(
+            message("c1", BroadcastMessage::Topology { topology }),
+            &mut Vec::new(),
+        )?;
+
+        let mut output = Vec::new();
+        node.step(message("c1", BroadcastMessage::GetTopology), &mut output)?;
+        match sent(&output)?.remove(0).body.payload {
+            BroadcastMessage::TopologyInfo { neighbors } => assert_eq!(neighbors, ["n2"]),
+            payload => panic!("unexpected reply {payload:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_maelstrom_topology_picks_neighbors() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2", "n3"])?;
+        let topology = r#"{"src":"c0","dest":"n1","body":{"type":"topology","msg_id":1,"topology":{"n1":["n2","n3"]}}}"#;
+        let topology = serde_json::from_str::<Message<BroadcastMessage>>(topology)?;
+        let mut output = Vec::new();
+        node.step(topology, &mut output)?;
+        let reply = sent(&output)?.remove(0);
+        assert!(matches!(reply.body.payload, BroadcastMessage::TopologyOk));
+        assert_eq!(reply.body.in_reply_to, Some(1));
+        assert_eq!(node.gossip.neighbors(), ["n2", "n3"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_topology_limits_fan_out() -> anyhow::Result<()> {
+        let ids = (0..25).map(|i| format!("n{i}")).collect::<Vec<_>>();
+        let line = (0..25)
+            .map(|i: usize| {
+                let neighbors = [i.checked_sub(1), Some(i + 1).filter(|next| *next < 25)];
+                let neighbors = neighbors.into_iter().flatten().map(|j| ids[j].clone());
+                (ids[i].clone(), neighbors.collect())
+            })
+            .collect::<HashMap<String, Vec<String>>>();
+        let alert = || message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+        for id in &ids {
+            let mut node = new_node(id, &ids.iter().map(String::as_str).collect::<Vec<_>>())?;
+            let topology = line.clone();
+            node.step(
+                message("c1", BroadcastMessage::Topology { topology }),
+                &mut Vec::new(),
+            )?;
+            assert!(node.gossip.neighbors().len() <= 2);
+
+            node.step(
+                message("c1", BroadcastMessage::Broadcast { message: 1 }),
+                &mut Vec::new(),
+            )?;
+            let mut output = Vec::new();
+            node.step(alert(), &mut output)?;
+            let gossips = sent(&output)?;
+            assert!(
+                !gossips.is_empty() && gossips.len() <= 2,
+                "{id} gossiped {gossips:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_lost_acks_mark_neighbor_partitioned_until_heard_from() -> anyhow::Result<()> {
+        let mut network = Network::<BroadcastMessage, BroadcastNode>::new(&["n1", "n2"])?;
+        // n1's gossip gets through, nothing n2 sends back does
+        network.cut("n2", "n1");
+        for (dst, value) in [("n1", 1), ("n2", 2)] {
+            let mut broadcast = Message {
+                dst: dst.to_string(),
+                ..message("c1", BroadcastMessage::Broadcast { message: value })
+            };
+            broadcast.body.id = Some(1);
+            network.send(broadcast);
+        }
+        network.run()?;
+
+        let alert = || BroadcastMessage::Extended(GossipProtocol::GossipAlert);
+        for _ in 0..DEFAULT_PARTITION_AFTER {
+            network.tick(alert);
+            network.run()?;
+        }
+        assert!(network.node("n1").partitioned.contains("n2"));
+        assert!(
+            network.node("n2").partitioned.is_empty(),
+            "n2 keeps hearing n1's gossip"
+        );
+
+        network.heal("n2", "n1");
+        network.tick(alert);
+        network.run()?;
+        for (id, node) in network.nodes() {
+            assert!(node.partitioned.is_empty(), "{id} still partitioned");
+            assert_eq!(*node.messages, [1, 2].into(), "{id} didn't converge");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_partitioned_neighbor_resynced_once_heard_from() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2"])?;
+        node.set_neighbors(vec!["n2".to_string()]);
+        node.record(0..5);
+        let alert = || message("", BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+        for _ in 0..DEFAULT_PARTITION_AFTER {
+            node.step(alert(), &mut Vec::new())?;
+        }
+        assert!(node.partitioned.contains("n2"));
+
+        // an ack of part of the set brings n2 back, the rest goes out without a round
+        let mut output = Vec::new();
+        let ack = GossipProtocol::GossipOk {
+            messages: [0].into_iter().collect(),
+        };
+        node.step(message("n2", BroadcastMessage::Extended(ack)), &mut output)?;
+        assert!(node.partitioned.is_empty());
+        let gossips = sent(&output)?;
+        assert_eq!(gossips.len(), 1);
+        let BroadcastMessage::Extended(GossipProtocol::Gossip { messages, .. }) =
+            &gossips[0].body.payload
+        else {
+            panic!("expected gossip, got {:?}", gossips[0].body.payload);
+        };
+        assert_eq!(gossips[0].dst, "n2");
+        assert_eq!(*messages, (1..5).collect());
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_probability_is_checked() -> anyhow::Result<()> {
+        let network = || Network::<BroadcastMessage, BroadcastNode>::new(&["n1"]);
+        for probability in [-0.1, 1.5, f64::NAN] {
+            assert!(network()?.with_drop_probability(probability).is_err());
+        }
+        network()?.with_drop_probability(1.0)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_broadcast_reaches_every_node_despite_drops() -> anyhow::Result<()> {
+        let mut network =
+            Network::<BroadcastMessage, BroadcastNode>::new(&["n1", "n2", "n3", "n4", "n5"])?
+                .with_seed(7)
+                .with_drop_probability(0.3)?
+                .with_max_latency(2);
+        let mut broadcast = message("c1", BroadcastMessage::Broadcast { message: 42 });
+        broadcast.body.id = Some(1);
+        network.send(broadcast);
+        network.run()?;
+
+        let mut ticks = 0;
+        while !network.nodes().all(|(_, node)| node.messages.contains(&42)) {
+            ticks += 1;
+            assert!(
+                ticks <= 20,
+                "not every node has the message after {ticks} ticks"
+            );
+            network.tick(|| BroadcastMessage::Extended(GossipProtocol::GossipAlert));
+            network.run()?;
+        }
+        assert!(network.dropped > 0);
+        assert_eq!(network.outside.len(), 1);
+        assert!(matches!(
+            network.outside[0].body.payload,
+            BroadcastMessage::BroadcastOk
+        ));
+        Ok(())
+    }
 }
@@ -1,121 +1,454 @@
-use std::{collections::HashMap, io::Write, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    sync::{mpsc::Sender, Arc, Mutex, MutexGuard},
+    time::Duration,
+};
 
 use anyhow::Context;
 
-use rustgen::{main_loop, Body, Message};
+use rustgen::{
+    crdt::{GCounter, Mergeable},
+    gossip::{self, Gossip},
+    main_loop,
+    persist::AppliedOps,
+    rpc::{NodeContext, Rpc, StepContext},
+    Body, IdGen, MaelstromError, Message,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 enum GlobalCounter {
-    Add { delta: usize },
+    Add {
+        delta: usize,
+        /// client chosen id making the add exactly-once, even across restarts
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        op_id: Option<String>,
+    },
     AddOk,
     Read,
-    ReadOk { value: usize },
+    ReadOk {
+        value: usize,
+        /// a best effort strong read missed some peers' slots, see `ReadPolicy`
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        stale: bool,
+    },
+    Converged,
+    ConvergedOk {
+        converged: bool,
+    },
+    /// stop emitting gossip, adds and incoming gossip are still served
+    PauseGossip,
+    PauseGossipOk,
+    /// emit gossip again, catching the neighbors up right away
+    ResumeGossip,
+    ResumeGossipOk,
     Extended(GossipProtocol),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum GossipProtocol {
     GossipAlert,
-    Gossip { counter: Counter },
+    Gossip {
+        counter: GCounter,
+    },
+    /// Quorum write: the origin's slot after an add, applied by max so a replay is harmless
+    Replicate {
+        op_id: usize,
+        slot: usize,
+    },
+    ReplicateOk {
+        op_id: usize,
+    },
+    /// Strong read: the receiver's slots, as it holds them right now
+    ReadSlots,
+    ReadSlotsOk {
+        counter: GCounter,
+    },
+    /// Strong read: a peer's slots for the read `read`, `None` if they didn't come in
+    /// time. Only the node raises it, see `on_internal`
+    ReadGathered {
+        read: usize,
+        counter: Option<GCounter>,
+    },
 }
 
+/// What a strong read, with `STRONG_READ`, does about the peers whose slots didn't
+/// come back before `STRONG_READ_DEADLINE_MS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadPolicy {
+    /// fail the read with a timeout, `STRONG_READ=strict`
+    Strict,
+    /// reply the merge of the slots which came, marked `stale`,
+    /// `STRONG_READ=best_effort`
+    BestEffort,
+}
+
+impl ReadPolicy {
+    fn from_env() -> anyhow::Result<Option<Self>> {
+        match std::env::var("STRONG_READ").as_deref() {
+            Err(_) => Ok(None),
+            Ok("strict") => Ok(Some(Self::Strict)),
+            Ok("best_effort") => Ok(Some(Self::BestEffort)),
+            Ok(policy) => {
+                anyhow::bail!("unknown STRONG_READ {policy:?}, expected strict or best_effort")
+            }
+        }
+    }
+}
+
+/// How long a strong read waits for the peers' slots, unless
+/// `STRONG_READ_DEADLINE_MS` says otherwise.
+const DEFAULT_READ_DEADLINE: Duration = Duration::from_millis(500);
+
 struct BroadcastNode {
     id: String,
-    msg_id: usize,
-    neightbors: Vec<String>,
-    inner: Counter,
+    msg_ids: IdGen,
+    replica: Arc<Mutex<Replica>>,
+    /// hands received gossip to the background merge thread, see `BACKGROUND_MERGE`
+    merger: Option<Sender<(String, GCounter)>>,
+    /// with `QUORUM_WRITE=1` an add is acknowledged once a majority holds it
+    quorum: bool,
+    next_op_id: usize,
+    /// adds waiting for a majority, by op id
+    pending: HashMap<usize, PendingAdd>,
+    /// op ids of the adds applied, persisted under `OP_ID_DIR` if set
+    applied: AppliedOps,
+    /// every other node, whose slots a strong read gathers
+    others: Vec<String>,
+    /// with `STRONG_READ`, a read first gathers every other node's slots
+    strong_read: Option<ReadPolicy>,
+    read_deadline: Duration,
+    /// strong reads waiting for the peers' slots, by an id of their own
+    reads: HashMap<usize, PendingRead>,
+    rpc: Rpc,
+    /// carries the peers' slots for the strong reads back to the node
+    tx: crossbeam_channel::Sender<Message<GlobalCounter>>,
+}
+
+struct PendingAdd {
+    request: Message<GlobalCounter>,
+    slot: usize,
+    /// peers which acked, a replayed ack counts once
+    acks: HashSet<String>,
+    /// resends of the add meanwhile, acknowledged along with it
+    resent: Vec<Message<GlobalCounter>>,
+}
+
+struct PendingRead {
+    request: Message<GlobalCounter>,
+    policy: ReadPolicy,
+    /// the merge of the peers' slots come so far
+    gathered: GCounter,
+    /// peers whose slots are yet to come or time out
+    awaited: usize,
+    /// peers whose slots didn't come in time
+    missing: usize,
+}
+
+impl PendingAdd {
+    /// The client's op id of the add, if it set one.
+    fn client_op_id(&self) -> Option<&str> {
+        match &self.request.body.payload {
+            GlobalCounter::Add { op_id, .. } => op_id.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// The replicated state, shared with the background merge thread if there is one.
+struct Replica {
+    counter: GCounter,
+    /// knows the last counter each neighbor gossiped to us
+    gossip: Gossip<GCounter>,
+}
+
+impl Replica {
+    fn merge_from(&mut self, src: String, counter: GCounter) {
+        self.gossip.on_gossip(&src, counter.clone());
+        self.counter.merge(counter)
+    }
 }
 
 impl BroadcastNode {
-    fn counter(&self) -> &Counter {
-        &self.inner
+    fn replica(&self) -> MutexGuard<'_, Replica> {
+        self.replica.lock().expect("replica lock poisoned")
+    }
+
+    /// Take over `applied`, restoring our own slot from the state logged with it: a
+    /// peer may not hold the latest adds, so gossip alone can't bring it back.
+    fn restore(&mut self, applied: AppliedOps) -> anyhow::Result<()> {
+        if let Some(state) = applied.state() {
+            let slot = state
+                .parse::<usize>()
+                .with_context(|| format!("parse logged slot {state:?}"))?;
+            let mut replica = self.replica();
+            let held = replica.counter.get(&self.id);
+            replica
+                .counter
+                .increment(&self.id, slot.saturating_sub(held));
+        }
+        self.applied = applied;
+        Ok(())
+    }
+
+    /// The neighbors but ourselves.
+    fn peers(&self) -> Vec<String> {
+        self.replica().gossip.peers().cloned().collect()
     }
 
-    fn counter_mut(&mut self) -> &mut Counter {
-        &mut self.inner
+    fn gossip_round(&self, output: &mut impl Write) -> anyhow::Result<()> {
+        let (counter, neighbors) = {
+            let mut replica = self.replica();
+            let counter = replica.counter.clone();
+            // the peers whose last gossip differs from our counter go first
+            let neighbors = replica
+                .gossip
+                .round_peers(|_, known| usize::from(*known != counter));
+            (counter, neighbors)
+        };
+        for neighbor in neighbors {
+            let gossip = GossipProtocol::Gossip {
+                counter: counter.clone(),
+            };
+            self.send_internal(&neighbor, gossip, output)
+                .with_context(|| format!("send gossip to {}", neighbor))?
+        }
+        // the transport may drop messages, retry adds still short of a majority
+        for op_id in self.pending.keys() {
+            self.replicate(*op_id, output)?
+        }
+        Ok(())
     }
 
-    fn send_to_neighbor(&self, neighbor: &str, output: &mut impl Write) -> anyhow::Result<()> {
+    fn send_internal(
+        &self,
+        neighbor: &str,
+        payload: GossipProtocol,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
         Message {
             src: self.id.clone(),
             dst: neighbor.to_string(),
             body: Body {
                 id: Default::default(),
                 in_reply_to: Default::default(),
-                payload: GlobalCounter::Extended(GossipProtocol::Gossip {
-                    counter: self.counter().clone(),
-                }),
+                lamport: None,
+                op_id: None,
+                payload: GlobalCounter::Extended(payload),
             },
         }
         .send(output)
-        .with_context(|| format!("send gossip to {}", neighbor))
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Counter {
-    counter: HashMap<String, usize>,
-}
+    fn majority(&self) -> usize {
+        self.replica().gossip.neighbors().len() / 2 + 1
+    }
 
-impl Counter {
-    fn add(&mut self, key: String, delta: usize) {
-        self.counter
-            .entry(key)
-            .and_modify(|v| *v += delta)
-            .or_insert(delta);
+    /// Send the pending add to every peer which hasn't acked it yet.
+    fn replicate(&self, op_id: usize, output: &mut impl Write) -> anyhow::Result<()> {
+        let pending = &self.pending[&op_id];
+        for peer in self
+            .peers()
+            .iter()
+            .filter(|node| !pending.acks.contains(*node))
+        {
+            let payload = GossipProtocol::Replicate {
+                op_id,
+                slot: pending.slot,
+            };
+            self.send_internal(peer, payload, output)
+                .with_context(|| format!("replicate add {op_id} to {peer}"))?;
+        }
+        Ok(())
     }
 
-    fn sum(&self) -> usize {
-        self.counter.values().sum()
+    /// Ask every other node for its slots, the read is answered once they all came or
+    /// `read_deadline` passed, see `finish_read`. The step doesn't wait for them, so
+    /// the node still answers the peers' own strong reads meanwhile.
+    fn strong_read(
+        &mut self,
+        req: Message<GlobalCounter>,
+        policy: ReadPolicy,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let read = self.msg_ids.next();
+        let mut calls = Vec::new();
+        for peer in &self.others {
+            let call = self.msg_ids.next();
+            let tx = self.tx.clone();
+            self.rpc
+                .register_until(call, self.read_deadline, move |reply| {
+                    let counter = reply
+                        .ok()
+                        .and_then(|reply: Message<GlobalCounter>| match reply.body.payload {
+                            GlobalCounter::Extended(GossipProtocol::ReadSlotsOk { counter }) => {
+                                Some(counter)
+                            }
+                            _ => None,
+                        });
+                    let gathered = GossipProtocol::ReadGathered { read, counter };
+                    let _ = tx.send(Message::internal(GlobalCounter::Extended(gathered)));
+                });
+            calls.push(call);
+            let read_slots = Message {
+                src: self.id.clone(),
+                dst: peer.clone(),
+                body: Body {
+                    id: Some(call),
+                    in_reply_to: None,
+                    lamport: None,
+                    op_id: None,
+                    payload: GlobalCounter::Extended(GossipProtocol::ReadSlots),
+                },
+            };
+            if let Err(e) = read_slots.send(output) {
+                for call in calls {
+                    self.rpc.cancel(call);
+                }
+                return Err(e).with_context(|| format!("ask {peer} for its slots"));
+            }
+        }
+        let pending = PendingRead {
+            request: req,
+            policy,
+            gathered: GCounter::default(),
+            awaited: self.others.len(),
+            missing: 0,
+        };
+        if pending.awaited == 0 {
+            return self.finish_read(pending, output);
+        }
+        self.reads.insert(read, pending);
+        Ok(())
     }
 
-    fn merge(&mut self, counter: Counter) {
-        counter.counter.into_iter().for_each(|(k, v)| {
-            self.counter
-                .entry(k)
-                .and_modify(|value| *value = v.max(*value))
-                .or_insert(v);
-        });
+    /// A peer's slots for the strong read `read` came, or didn't in time.
+    fn on_gathered(
+        &mut self,
+        read: usize,
+        counter: Option<GCounter>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let Some(pending) = self.reads.get_mut(&read) else {
+            return Ok(());
+        };
+        pending.awaited -= 1;
+        match counter {
+            Some(counter) => pending.gathered.merge(counter),
+            None => pending.missing += 1,
+        }
+        if pending.awaited > 0 {
+            return Ok(());
+        }
+        let pending = self.reads.remove(&read).expect("pending read");
+        self.finish_read(pending, output)
+    }
+
+    /// Reply the value after merging the peers' slots gathered into ours. If some
+    /// didn't come a `Strict` read fails with a timeout, a `BestEffort` one replies
+    /// what the slots which came add up to.
+    fn finish_read(&self, read: PendingRead, output: &mut impl Write) -> anyhow::Result<()> {
+        let mut counter = self.replica().counter.clone();
+        counter.merge(read.gathered);
+        if read.missing > 0 && read.policy == ReadPolicy::Strict {
+            let text = format!(
+                "{} of {} peers sent no slots within {:?}",
+                read.missing,
+                self.others.len(),
+                self.read_deadline
+            );
+            return read
+                .request
+                .into_error(MaelstromError::Timeout, text)
+                .send(output);
+        }
+        let read_ok = GlobalCounter::ReadOk {
+            value: counter.value(),
+            stale: read.missing > 0,
+        };
+        read.request.reply_with(&self.msg_ids, read_ok).send(output)
+    }
+
+    /// Acknowledge the add once the majority, ourselves included, holds it.
+    fn ack_if_durable(&mut self, op_id: usize, output: &mut impl Write) -> anyhow::Result<()> {
+        if self.pending[&op_id].acks.len() + 1 < self.majority() {
+            return Ok(());
+        }
+        let pending = self.pending.remove(&op_id).expect("pending add");
+        for request in std::iter::once(pending.request).chain(pending.resent) {
+            request
+                .reply_with(&self.msg_ids, GlobalCounter::AddOk)
+                .send(output)?;
+        }
+        Ok(())
     }
 }
 
 impl rustgen::Node<GlobalCounter> for BroadcastNode {
+    fn init_with(
+        init: &rustgen::InitBody,
+        ctx: NodeContext<GlobalCounter>,
+    ) -> anyhow::Result<Self> {
+        let mut node = Self::init_from(init, ctx.tx)?;
+        node.rpc = ctx.rpc;
+        Ok(node)
+    }
+
     fn init_from(
         init_msg: &rustgen::InitBody,
-        tx: std::sync::mpsc::Sender<Message<GlobalCounter>>,
+        tx: crossbeam_channel::Sender<Message<GlobalCounter>>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        // create a thread to send gossip notification in period
-        std::thread::spawn(move || loop {
-            std::thread::sleep(Duration::from_millis(100));
-            let _ = tx.send(Message {
-                src: Default::default(),
-                dst: Default::default(),
-                body: Body {
-                    id: None,
-                    in_reply_to: None,
-                    payload: GlobalCounter::Extended(GossipProtocol::GossipAlert),
-                },
-            });
+        let gossip = Gossip::start(init_msg, gossip::interval_from_env(), tx.clone(), || {
+            GlobalCounter::Extended(GossipProtocol::GossipAlert)
         });
-        let neightbors = init_msg.node_ids.clone();
-        let counter = Counter {
-            counter: neightbors
+        let counter = init_msg
+            .node_ids
+            .iter()
+            .map(|node_id| (node_id.clone(), 0))
+            .collect::<GCounter>();
+        let replica = Arc::new(Mutex::new(Replica { counter, gossip }));
+        // With `BACKGROUND_MERGE=1` gossip is merged off the step thread, so a large
+        // merge doesn't hold client replies back. The price is that a read right after
+        // a gossip may not reflect it yet.
+        let op_log = std::env::var("OP_ID_DIR")
+            .ok()
+            .map(|dir| std::path::Path::new(&dir).join(format!("{}.ops", init_msg.node_id)));
+        let merger = std::env::var("BACKGROUND_MERGE")
+            .is_ok_and(|flag| flag == "1")
+            .then(|| spawn_merger(Arc::clone(&replica)));
+        let mut node = Self {
+            id: init_msg.node_id.clone(),
+            msg_ids: IdGen::default(),
+            replica,
+            merger,
+            quorum: std::env::var("QUORUM_WRITE").is_ok_and(|flag| flag == "1"),
+            next_op_id: 1,
+            pending: HashMap::new(),
+            applied: AppliedOps::open(None, AppliedOps::DEFAULT_WINDOW)?,
+            others: init_msg
+                .node_ids
                 .iter()
-                .map(|node_id| (node_id.clone(), usize::default()))
-                .collect::<HashMap<String, usize>>(),
+                .filter(|id| **id != init_msg.node_id)
+                .cloned()
+                .collect(),
+            strong_read: ReadPolicy::from_env()?,
+            read_deadline: std::env::var("STRONG_READ_DEADLINE_MS")
+                .ok()
+                .and_then(|ms| ms.parse().ok())
+                .map_or(DEFAULT_READ_DEADLINE, Duration::from_millis),
+            reads: HashMap::new(),
+            // the loop's, see `init_with`
+            rpc: Rpc::new(&init_msg.node_id),
+            tx,
         };
-        Ok(Self {
-            id: init_msg.node_id.clone(),
-            msg_id: 1,
-            neightbors,
-            inner: counter,
-        })
+        node.restore(AppliedOps::open(op_log, AppliedOps::DEFAULT_WINDOW)?)?;
+        Ok(node)
     }
 
     fn step(
@@ -123,35 +456,719 @@ impl rustgen::Node<GlobalCounter> for BroadcastNode {
         req: rustgen::Message<GlobalCounter>,
         output: &mut impl Write,
     ) -> anyhow::Result<()> {
+        if let GlobalCounter::Add { delta, op_id } = &req.body.payload {
+            if let Some(op_id) = op_id
+                .as_deref()
+                .filter(|op_id| !AppliedOps::is_valid_op_id(op_id))
+            {
+                return Err(MaelstromError::MalformedRequest
+                    .because(format!(
+                        "op id {op_id:?} should be a non-empty line without tabs"
+                    ))
+                    .into());
+            }
+            // our slot is logged with the op id, a restart restores both together
+            let slot = (self.replica().counter.get(&self.id) + delta).to_string();
+            match op_id {
+                Some(op_id) if !self.applied.apply_once_with(op_id, &slot)? => {
+                    // a resend of an add still short of a majority waits for it
+                    let waiting = self
+                        .pending
+                        .values_mut()
+                        .find(|pending| pending.client_op_id() == Some(op_id.as_str()));
+                    if let Some(pending) = waiting {
+                        pending.resent.push(req);
+                        return Ok(());
+                    }
+                    // a resend of an add applied already, maybe before a restart
+                    return req
+                        .reply_with(&self.msg_ids, GlobalCounter::AddOk)
+                        .send(output);
+                }
+                Some(_) => {}
+                None => self.applied.checkpoint(&slot)?,
+            }
+        }
         match req.body.payload {
-            GlobalCounter::Add { delta } => {
-                self.inner.add(req.dst.clone(), delta);
-                let mut reply = req.into_reply(Some(&mut self.msg_id));
-                reply.body.payload = GlobalCounter::AddOk;
-                reply.send(output)?
-            }
-            GlobalCounter::Read => {
-                let mut reply = req.into_reply(Some(&mut self.msg_id));
-                reply.body.payload = GlobalCounter::ReadOk {
-                    value: self.counter().sum(),
+            GlobalCounter::Add { delta, .. } if self.quorum => {
+                let slot = {
+                    let mut replica = self.replica();
+                    replica.counter.increment(&self.id, delta);
+                    replica.counter.get(&self.id)
+                };
+                let op_id = self.next_op_id;
+                self.next_op_id += 1;
+                let pending = PendingAdd {
+                    request: req,
+                    slot,
+                    acks: HashSet::new(),
+                    resent: Vec::new(),
                 };
-                reply.send(output)?;
+                self.pending.insert(op_id, pending);
+                self.replicate(op_id, output)?;
+                self.ack_if_durable(op_id, output)?
+            }
+            GlobalCounter::Add { delta, .. } => {
+                self.replica().counter.increment(&self.id, delta);
+                req.reply_with(&self.msg_ids, GlobalCounter::AddOk)
+                    .send(output)?
             }
+            GlobalCounter::Read => match self.strong_read {
+                Some(policy) => self.strong_read(req, policy, output)?,
+                None => req
+                    .reply_with(
+                        &self.msg_ids,
+                        GlobalCounter::ReadOk {
+                            value: self.replica().counter.value(),
+                            stale: false,
+                        },
+                    )
+                    .send(output)?,
+            },
             GlobalCounter::Extended(GossipProtocol::GossipAlert) => {
-                for neighbor in self.neightbors.iter().filter(|node| **node != self.id) {
-                    self.send_to_neighbor(neighbor.as_str(), output)?
+                let round = self.replica().gossip.on_alert();
+                if let Some(_round) = round {
+                    self.gossip_round(output)?
                 }
             }
-            GlobalCounter::Extended(GossipProtocol::Gossip { counter }) => {
-                self.counter_mut().merge(counter)
+            GlobalCounter::Extended(GossipProtocol::Replicate { op_id, slot }) => {
+                self.replica()
+                    .counter
+                    .merge(GCounter::from_iter([(req.src.clone(), slot)]));
+                req.reply_with(
+                    &self.msg_ids,
+                    GlobalCounter::Extended(GossipProtocol::ReplicateOk { op_id }),
+                )
+                .send(output)?
+            }
+            GlobalCounter::Extended(GossipProtocol::ReadSlots) => {
+                let counter = self.replica().counter.clone();
+                req.reply_with(
+                    &self.msg_ids,
+                    GlobalCounter::Extended(GossipProtocol::ReadSlotsOk { counter }),
+                )
+                .send(output)?
+            }
+            GlobalCounter::Extended(GossipProtocol::ReadSlotsOk { .. }) => {
+                // came after its read gave up on it
+            }
+            GlobalCounter::Extended(GossipProtocol::ReadGathered { .. }) => {
+                // only the node raises it, see `on_internal`
+            }
+            GlobalCounter::Extended(GossipProtocol::ReplicateOk { op_id }) => {
+                // a late ack for an add which already reached its majority
+                let Some(pending) = self.pending.get_mut(&op_id) else {
+                    return Ok(());
+                };
+                pending.acks.insert(req.src);
+                self.ack_if_durable(op_id, output)?
+            }
+            GlobalCounter::Converged => {
+                req.reply_with(
+                    &self.msg_ids,
+                    GlobalCounter::ConvergedOk {
+                        converged: self.converged(),
+                    },
+                )
+                .send(output)?;
             }
-            GlobalCounter::ReadOk { .. } | GlobalCounter::AddOk => unreachable!(),
+            GlobalCounter::Extended(GossipProtocol::Gossip { counter }) => match &self.merger {
+                Some(merger) => merger
+                    .send((req.src, counter))
+                    .context("background merge thread is gone")?,
+                None => self.replica().merge_from(req.src, counter),
+            },
+            GlobalCounter::PauseGossip => {
+                self.replica().gossip.pause();
+                req.reply_with(&self.msg_ids, GlobalCounter::PauseGossipOk)
+                    .send(output)?
+            }
+            GlobalCounter::ResumeGossip => {
+                self.replica().gossip.resume();
+                req.reply_with(&self.msg_ids, GlobalCounter::ResumeGossipOk)
+                    .send(output)?;
+                // catch up at once, unless a round is in flight already
+                if let Some(_round) = self.replica().gossip.try_round() {
+                    self.gossip_round(output)?
+                }
+            }
+            GlobalCounter::ReadOk { .. }
+            | GlobalCounter::PauseGossipOk
+            | GlobalCounter::ResumeGossipOk
+            | GlobalCounter::AddOk
+            | GlobalCounter::ConvergedOk { .. } => {}
         }
         Ok(())
     }
+
+    fn on_internal(
+        &mut self,
+        payload: GlobalCounter,
+        output: &mut impl Write,
+        ctx: &StepContext<'_, GlobalCounter>,
+    ) -> anyhow::Result<()> {
+        match payload {
+            GlobalCounter::Extended(GossipProtocol::ReadGathered { read, counter }) => {
+                self.on_gathered(read, counter, output)
+            }
+            payload => self.step_with(Message::internal(payload), output, ctx),
+        }
+    }
+
+    /// Every neighbor last gossiped exactly the slots we hold, so no slot is moving.
+    fn converged(&self) -> bool {
+        let replica = self.replica();
+        let converged = replica
+            .gossip
+            .peers()
+            .all(|node| replica.gossip.known(node) == Some(&replica.counter));
+        converged
+    }
+}
+
+fn spawn_merger(replica: Arc<Mutex<Replica>>) -> Sender<(String, GCounter)> {
+    let (merger, merges) = std::sync::mpsc::channel::<(String, GCounter)>();
+    std::thread::spawn(move || {
+        for (src, counter) in merges {
+            replica
+                .lock()
+                .expect("replica lock poisoned")
+                .merge_from(src, counter);
+        }
+    });
+    merger
 }
 
 fn main() -> anyhow::Result<()> {
     main_loop::<GlobalCounter, BroadcastNode>()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        io::Write,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use crossbeam_channel::Receiver;
+    use rustgen::{
+        persist::AppliedOps,
+        rpc::{Rpc, StepContext},
+        test_util::{assert_wire_format, dispatch_reply},
+        Body, InitBody, MaelstromError, Message, Node, RequestError,
+    };
+    use serde_json::Value;
+
+    use crate::{spawn_merger, BroadcastNode, GCounter, GlobalCounter, GossipProtocol, ReadPolicy};
+
+    fn new_node(node_id: &str, node_ids: &[&str]) -> anyhow::Result<BroadcastNode> {
+        let (tx, _) = crossbeam_channel::unbounded();
+        BroadcastNode::init_from(
+            &InitBody {
+                node_id: node_id.to_string(),
+                node_ids: node_ids.iter().map(|id| id.to_string()).collect(),
+                extra: Default::default(),
+            },
+            tx,
+        )
+    }
+
+    fn message(payload: GlobalCounter) -> Message<GlobalCounter> {
+        from("c1", payload)
+    }
+
+    fn from(src: &str, payload: GlobalCounter) -> Message<GlobalCounter> {
+        Message {
+            src: src.to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload,
+            },
+        }
+    }
+
+    #[test]
+    fn test_wire_format() {
+        assert_wire_format(
+            &message(GlobalCounter::Add {
+                delta: 3,
+                op_id: None,
+            }),
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":1,"in_reply_to":null,"type":"add","delta":3}}"#,
+        );
+        assert_wire_format(
+            &message(GlobalCounter::ReadOk {
+                value: 10,
+                stale: false,
+            }),
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":1,"in_reply_to":null,"type":"read_ok","value":10}}"#,
+        );
+        assert_wire_format(
+            &message(GlobalCounter::ReadOk {
+                value: 10,
+                stale: true,
+            }),
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":1,"in_reply_to":null,"type":"read_ok","value":10,"stale":true}}"#,
+        );
+        let counter = GCounter::from_iter([("n1".to_string(), 3)]);
+        assert_wire_format(
+            &message(GlobalCounter::Extended(GossipProtocol::Gossip { counter })),
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":1,"in_reply_to":null,"type":"extended","Gossip":{"counter":{"n1":3}}}}"#,
+        );
+    }
+
+    #[test]
+    fn test_add_lands_in_own_slot_whatever_dst() -> anyhow::Result<()> {
+        let mut n1 = new_node("n1", &["n1", "n2"])?;
+        let mut relayed = message(GlobalCounter::Add {
+            delta: 3,
+            op_id: None,
+        });
+        relayed.dst = "proxy".to_string();
+        n1.step(relayed, &mut Vec::new())?;
+        let replica = n1.replica();
+        assert_eq!(replica.counter.get("n1"), 3);
+        assert!(!replica.counter.contains("proxy"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stray_replies_ignored() -> anyhow::Result<()> {
+        let mut n1 = new_node("n1", &["n1", "n2"])?;
+        let mut output = Vec::new();
+        for reply in [
+            GlobalCounter::PauseGossipOk,
+            GlobalCounter::ResumeGossipOk,
+            GlobalCounter::ConvergedOk { converged: true },
+        ] {
+            n1.step(from("n2", reply), &mut output)?;
+        }
+        assert!(output.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_converged_after_gossip_propagates() -> anyhow::Result<()> {
+        let mut n1 = new_node("n1", &["n1", "n2"])?;
+        let mut n2 = new_node("n2", &["n1", "n2"])?;
+        n1.step(
+            message(GlobalCounter::Add {
+                delta: 2,
+                op_id: None,
+            }),
+            &mut Vec::new(),
+        )?;
+        assert!(!n1.converged());
+
+        // n2 catches up with n1's slot, but n1 only knows once n2 gossips back
+        let gossip = |node: &BroadcastNode| {
+            GlobalCounter::Extended(GossipProtocol::Gossip {
+                counter: node.replica().counter.clone(),
+            })
+        };
+        n2.step(from("n1", gossip(&n1)), &mut Vec::new())?;
+        assert!(n2.converged());
+        assert!(!n1.converged());
+        n1.step(from("n2", gossip(&n2)), &mut Vec::new())?;
+        assert!(n1.converged());
+        Ok(())
+    }
+
+    fn sent(output: &[u8]) -> anyhow::Result<Vec<Message<GlobalCounter>>> {
+        output
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| Ok(serde_json::from_slice(line)?))
+            .collect()
+    }
+
+    /// Plays the other nodes answering `read_slots` through the node's rpc, those in
+    /// `slots`; the rest are partitioned away and never answer. The other lines are
+    /// kept in `sent`, as JSON since an error reply isn't a `GlobalCounter`.
+    struct Peers {
+        rpc: Rpc,
+        slots: HashMap<String, GCounter>,
+        line: Vec<u8>,
+        sent: Vec<Value>,
+    }
+
+    impl Write for Peers {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.line.extend_from_slice(buf);
+            if !self.line.ends_with(b"\n") {
+                return Ok(buf.len());
+            }
+            let line = std::mem::take(&mut self.line);
+            let request = serde_json::from_slice::<Message<GlobalCounter>>(&line)
+                .ok()
+                .filter(|msg| {
+                    matches!(
+                        msg.body.payload,
+                        GlobalCounter::Extended(GossipProtocol::ReadSlots)
+                    )
+                });
+            match request {
+                Some(request) => {
+                    if let Some(counter) = self.slots.get(&request.dst).cloned() {
+                        let mut reply = request.into_reply(None);
+                        reply.body.payload =
+                            GlobalCounter::Extended(GossipProtocol::ReadSlotsOk { counter });
+                        assert!(dispatch_reply(&self.rpc, &serde_json::to_string(&reply)?));
+                    }
+                }
+                None => self.sent.push(serde_json::from_slice(&line)?),
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Time out the node's rpc calls past their deadline, then act on what came back.
+    fn settle(
+        node: &mut BroadcastNode,
+        rx: &Receiver<Message<GlobalCounter>>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        node.rpc.reap();
+        let tx = node.tx.clone();
+        for msg in rx.try_iter() {
+            node.on_internal(msg.body.payload, output, &StepContext { tx: &tx })?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_strong_read_under_partition() -> anyhow::Result<()> {
+        // n1 added 1, each reachable peer holds 2 in its own slot
+        let read = |policy, reachable: &[&str]| -> anyhow::Result<Value> {
+            let mut n1 = new_node("n1", &["n1", "n2", "n3"])?;
+            let (tx, rx) = crossbeam_channel::unbounded();
+            n1.tx = tx;
+            n1.strong_read = Some(policy);
+            // the partitioned peers are timed out by the reap below
+            n1.read_deadline = Duration::ZERO;
+            let add = GlobalCounter::Add {
+                delta: 1,
+                op_id: None,
+            };
+            n1.step(message(add), &mut Vec::new())?;
+            let slots = reachable.iter().map(|peer| {
+                let counter = GCounter::from_iter([(peer.to_string(), 2)]);
+                (peer.to_string(), counter)
+            });
+            let mut peers = Peers {
+                rpc: n1.rpc.clone(),
+                slots: slots.collect(),
+                line: Vec::new(),
+                sent: Vec::new(),
+            };
+            n1.step(message(GlobalCounter::Read), &mut peers)?;
+            assert!(peers.sent.is_empty(), "{:?}", peers.sent);
+            settle(&mut n1, &rx, &mut peers)?;
+            assert_eq!(peers.sent.len(), 1, "{:?}", peers.sent);
+            Ok(peers.sent.remove(0)["body"].take())
+        };
+
+        for policy in [ReadPolicy::Strict, ReadPolicy::BestEffort] {
+            let reply = read(policy, &["n2", "n3"])?;
+            assert_eq!(reply["type"], "read_ok", "{policy:?}");
+            assert_eq!(reply["value"], 5, "{policy:?}");
+            assert_eq!(reply.get("stale"), None, "{policy:?}");
+        }
+        // n3 is partitioned away
+        let partial = read(ReadPolicy::BestEffort, &["n2"])?;
+        assert_eq!(partial["type"], "read_ok");
+        assert_eq!(partial["value"], 3);
+        assert_eq!(partial["stale"], true);
+        let timed_out = read(ReadPolicy::Strict, &["n2"])?;
+        assert_eq!(timed_out["type"], "error");
+        assert_eq!(timed_out["code"], MaelstromError::Timeout.code());
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_strong_reads_answer_each_other() -> anyhow::Result<()> {
+        let nodes = ["n1", "n2"];
+        let mut outputs = Vec::new();
+        let mut reading = Vec::new();
+        for (id, delta) in [("n1", 1), ("n2", 2)] {
+            let mut node = new_node(id, &nodes)?;
+            let (tx, rx) = crossbeam_channel::unbounded();
+            node.tx = tx;
+            node.strong_read = Some(ReadPolicy::Strict);
+            let add = GlobalCounter::Add { delta, op_id: None };
+            node.step(message(add), &mut Vec::new())?;
+            // the read only asks the peer, it doesn't wait on it
+            let mut output = Vec::new();
+            node.step(message(GlobalCounter::Read), &mut output)?;
+            outputs.push(sent(&output)?);
+            reading.push((node, rx));
+        }
+
+        // each answers the other's read_slots while its own read is in flight
+        for (asker, answerer) in [(0, 1), (1, 0)] {
+            let [read_slots] = &outputs[asker][..] else {
+                panic!("expected a read_slots, got {:?}", outputs[asker]);
+            };
+            let mut answer = Vec::new();
+            reading[answerer].0.step(read_slots.clone(), &mut answer)?;
+            let answer = String::from_utf8(answer)?;
+            assert!(dispatch_reply(&reading[asker].0.rpc, answer.trim_end()));
+        }
+        for (node, rx) in &mut reading {
+            let mut output = Vec::new();
+            settle(node, rx, &mut output)?;
+            assert!(matches!(
+                sent(&output)?[..],
+                [Message {
+                    body: Body {
+                        payload: GlobalCounter::ReadOk {
+                            value: 3,
+                            stale: false
+                        },
+                        ..
+                    },
+                    ..
+                }]
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_quorum_write() -> anyhow::Result<()> {
+        let nodes = ["n1", "n2", "n3", "n4", "n5"];
+        let mut n1 = new_node("n1", &nodes)?;
+        n1.quorum = true;
+        let mut output = Vec::new();
+        n1.step(
+            message(GlobalCounter::Add {
+                delta: 3,
+                op_id: None,
+            }),
+            &mut output,
+        )?;
+        let replicates = sent(&output)?;
+        assert_eq!(replicates.len(), 4);
+        let GlobalCounter::Extended(GossipProtocol::Replicate { op_id, slot: 3 }) =
+            replicates[0].body.payload
+        else {
+            panic!("expected a replicate, got {:?}", replicates[0].body.payload);
+        };
+
+        // replaying the replicate doesn't double count on the peer
+        let mut n2 = new_node("n2", &nodes)?;
+        let mut acks = Vec::new();
+        for _ in 0..2 {
+            n2.step(replicates[0].clone(), &mut acks)?;
+        }
+        assert_eq!(n2.replica().counter.value(), 3);
+        let acks = sent(&acks)?;
+        assert_eq!(acks.len(), 2);
+
+        // 3 of 5 make a majority, and a replayed ack counts once
+        let ack = |src: &str| {
+            from(
+                src,
+                GlobalCounter::Extended(GossipProtocol::ReplicateOk { op_id }),
+            )
+        };
+        for msg in [acks[0].clone(), acks[1].clone()] {
+            output.clear();
+            n1.step(msg, &mut output)?;
+            assert!(output.is_empty());
+        }
+        n1.step(ack("n3"), &mut output)?;
+        let add_ok = sent(&output)?;
+        assert!(matches!(
+            add_ok[..],
+            [Message {
+                body: Body {
+                    payload: GlobalCounter::AddOk,
+                    in_reply_to: Some(1),
+                    lamport: None,
+                    op_id: None,
+                    ..
+                },
+                ..
+            }]
+        ));
+
+        // late acks are ignored
+        output.clear();
+        n1.step(ack("n4"), &mut output)?;
+        assert!(output.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resent_quorum_add_waits_for_majority() -> anyhow::Result<()> {
+        let mut n1 = new_node("n1", &["n1", "n2", "n3"])?;
+        n1.quorum = true;
+        let mut add = message(GlobalCounter::Add {
+            delta: 3,
+            op_id: Some("op1".to_string()),
+        });
+        let mut output = Vec::new();
+        n1.step(add.clone(), &mut output)?;
+        let GlobalCounter::Extended(GossipProtocol::Replicate { op_id, .. }) =
+            sent(&output)?[0].body.payload
+        else {
+            panic!("expected a replicate");
+        };
+
+        // the resend is neither acknowledged before the majority nor applied again
+        add.body.id = Some(2);
+        output.clear();
+        n1.step(add, &mut output)?;
+        assert!(output.is_empty());
+        assert_eq!(n1.replica().counter.value(), 3);
+
+        let ack = from(
+            "n2",
+            GlobalCounter::Extended(GossipProtocol::ReplicateOk { op_id }),
+        );
+        n1.step(ack, &mut output)?;
+        let acked = sent(&output)?
+            .into_iter()
+            .filter(|msg| matches!(msg.body.payload, GlobalCounter::AddOk))
+            .map(|msg| msg.body.in_reply_to)
+            .collect::<Vec<_>>();
+        assert_eq!(acked, [Some(1), Some(2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resent_add_after_restart_applies_once() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("counter_ops_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let add = |op_id: &str| {
+            message(GlobalCounter::Add {
+                delta: 5,
+                op_id: Some(op_id.to_string()),
+            })
+        };
+
+        let mut node = new_node("n1", &["n1", "n2"])?;
+        node.restore(AppliedOps::open(Some(path.clone()), 16)?)?;
+        node.step(add("op1"), &mut Vec::new())?;
+        node.step(add("op1"), &mut Vec::new())?;
+        assert_eq!(node.replica().counter.value(), 5);
+        drop(node);
+
+        // the restarted node gets its slot back from the log, no gossip needed
+        let mut restarted = new_node("n1", &["n1", "n2"])?;
+        restarted.restore(AppliedOps::open(Some(path.clone()), 16)?)?;
+        assert_eq!(restarted.replica().counter.get("n1"), 5);
+        let mut output = Vec::new();
+        restarted.step(add("op1"), &mut output)?;
+        assert!(matches!(
+            sent(&output)?[..],
+            [Message {
+                body: Body {
+                    payload: GlobalCounter::AddOk,
+                    ..
+                },
+                ..
+            }]
+        ));
+        assert_eq!(restarted.replica().counter.value(), 5);
+
+        restarted.step(add("op2"), &mut Vec::new())?;
+        assert_eq!(restarted.replica().counter.value(), 10);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_with_empty_op_id_is_malformed() -> anyhow::Result<()> {
+        let mut node = new_node("n1", &["n1", "n2"])?;
+        let add = message(GlobalCounter::Add {
+            delta: 5,
+            op_id: Some(String::new()),
+        });
+        let mut output = Vec::new();
+        let err = node
+            .step(add, &mut output)
+            .expect_err("an empty op id is rejected");
+        // answered with code 12 by the loop rather than crashing the node
+        assert_eq!(
+            err.downcast_ref::<RequestError>().map(|error| error.code),
+            Some(MaelstromError::MalformedRequest)
+        );
+        assert!(output.is_empty());
+        assert_eq!(node.replica().counter.value(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_right_after_restart_keeps_slot() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("counter_slot_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let add = |delta: usize, op_id: Option<&str>| {
+            message(GlobalCounter::Add {
+                delta,
+                op_id: op_id.map(str::to_string),
+            })
+        };
+
+        let mut node = new_node("n1", &["n1", "n2"])?;
+        node.restore(AppliedOps::open(Some(path.clone()), 16)?)?;
+        node.step(add(5, Some("op1")), &mut Vec::new())?;
+        node.step(add(2, None), &mut Vec::new())?;
+        drop(node);
+
+        // a fresh add before any gossip builds on the logged slot, not on zero, so the
+        // peers' older copy of the slot can't hide it
+        let mut restarted = new_node("n1", &["n1", "n2"])?;
+        restarted.restore(AppliedOps::open(Some(path.clone()), 16)?)?;
+        restarted.step(add(3, Some("op2")), &mut Vec::new())?;
+        assert_eq!(restarted.replica().counter.get("n1"), 10);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_background_merge() -> anyhow::Result<()> {
+        let large = || {
+            (0..200_000)
+                .map(|i| (format!("n{i}"), 1))
+                .collect::<GCounter>()
+        };
+        let gossip = || {
+            from(
+                "n2",
+                GlobalCounter::Extended(GossipProtocol::Gossip { counter: large() }),
+            )
+        };
+
+        let mut inline = new_node("n1", &["n1", "n2"])?;
+        let (msg, start) = (gossip(), Instant::now());
+        inline.step(msg, &mut Vec::new())?;
+        let inline_latency = start.elapsed();
+
+        let mut background = new_node("n1", &["n1", "n2"])?;
+        background.merger = Some(spawn_merger(Arc::clone(&background.replica)));
+        let (msg, start) = (gossip(), Instant::now());
+        background.step(msg, &mut Vec::new())?;
+        assert!(start.elapsed() < inline_latency);
+
+        // the merge lands eventually
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while background.replica().counter.value() != 200_000 {
+            assert!(Instant::now() < deadline, "background merge never landed");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,6 @@
 use std::io::Write;
 
-use anyhow::Context;
-use rustgen::{main_loop, Message};
+use rustgen::{handle_rpc, main_loop_single_threaded, IdGen, Message, Request};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -9,46 +8,51 @@ use serde::{Deserialize, Serialize};
 #[serde(tag = "type")]
 enum EchoMessage {
     Echo { echo: String },
-    EchoOk { echo: String },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum EchoOk {
+    EchoOk { echo: String },
+}
+
+impl Request for EchoMessage {
+    type Ok = EchoOk;
+}
+
+/// The echo moves into the reply, however large it is.
+fn echo_ok(req: EchoMessage) -> EchoOk {
+    match req {
+        EchoMessage::Echo { echo } => EchoOk::EchoOk { echo },
+    }
+}
+
+#[derive(Debug)]
 struct EchoNode {
-    msg_id: usize,
+    msg_ids: IdGen,
 }
 
 impl rustgen::Node<EchoMessage> for EchoNode {
     fn init_from(
         _: &rustgen::InitBody,
-        _: std::sync::mpsc::Sender<Message<EchoMessage>>,
+        _: crossbeam_channel::Sender<Message<EchoMessage>>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        Ok(Self { msg_id: 1 })
+        Ok(Self {
+            msg_ids: IdGen::default(),
+        })
     }
 
-    fn step(
-        &mut self,
-        req: rustgen::Message<EchoMessage>,
-        output: &mut impl Write,
-    ) -> anyhow::Result<()> {
-        let mut msg = req.into_reply(Some(&mut self.msg_id));
-        match msg.body.payload {
-            EchoMessage::Echo { echo } => {
-                msg.body.payload = EchoMessage::EchoOk { echo };
-                msg.serialize(&mut serde_json::Serializer::new(&mut *output))
-                    .context("serialize echo_ok message failed")?;
-                output.write_all(b"\n")?;
-            }
-            EchoMessage::EchoOk { .. } => unreachable!(),
-        }
-        Ok(())
+    fn step(&mut self, req: Message<EchoMessage>, output: &mut impl Write) -> anyhow::Result<()> {
+        handle_rpc(req, Some(&self.msg_ids), output, |req| Ok(echo_ok(req)))
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<EchoMessage, EchoNode>()?;
+    main_loop_single_threaded::<EchoMessage, EchoNode>()?;
     Ok(())
 }
 
@@ -56,14 +60,102 @@ fn main() -> anyhow::Result<()> {
 mod test {
     use std::io::Write;
 
-    use rustgen::{Body, Message};
+    use rustgen::{
+        test_util::{assert_replies_to, assert_wire_format},
+        Body, IdGen, InitBody, Message, Node,
+    };
     use serde::Serialize;
 
-    use crate::EchoMessage;
+    use crate::{echo_ok, EchoMessage, EchoNode, EchoOk};
+
+    #[test]
+    fn test_wire_format() {
+        let echo = Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload: EchoMessage::Echo {
+                    echo: "hi".to_string(),
+                },
+            },
+        };
+        assert_wire_format(
+            &echo,
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":1,"in_reply_to":null,"type":"echo","echo":"hi"}}"#,
+        );
+        let echo_ok = echo.clone().reply_ok(
+            Some(&IdGen::starting_at(5)),
+            EchoOk::EchoOk {
+                echo: "hi".to_string(),
+            },
+        );
+        assert_replies_to(&echo_ok, &echo);
+        assert_wire_format(
+            &echo_ok,
+            r#"{"src":"n1","dest":"c1","body":{"msg_id":5,"in_reply_to":1,"type":"echo_ok","echo":"hi"}}"#,
+        );
+    }
+
+    #[test]
+    fn test_echo_ok_reuses_the_echo() {
+        let echo = "x".repeat(1 << 16);
+        let buffer = echo.as_ptr();
+        let req = Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                id: Some(2),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload: EchoMessage::Echo { echo },
+            },
+        };
+        let EchoOk::EchoOk { echo } = echo_ok(req.body.payload);
+        assert_eq!(echo.as_ptr(), buffer, "the echo string was copied");
+    }
+
+    #[test]
+    fn test_step_replies_once_to_the_sender() -> anyhow::Result<()> {
+        let init = InitBody {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string()],
+            extra: Default::default(),
+        };
+        let mut node = EchoNode::init_from(&init, crossbeam_channel::unbounded().0)?;
+        let echo = Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                id: Some(3),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload: EchoMessage::Echo {
+                    echo: "hi".to_string(),
+                },
+            },
+        };
+        let mut output = Vec::new();
+        node.step(echo.clone(), &mut output)?;
+        let replies = output
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice::<Message<EchoOk>>)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(replies.len(), 1);
+        assert_replies_to(&replies[0], &echo);
+        assert!(matches!(&replies[0].body.payload, EchoOk::EchoOk { echo } if echo == "hi"));
+        Ok(())
+    }
 
     #[test]
     fn test_echo_node_msg() -> anyhow::Result<()> {
-        let echo_ok_msg = EchoMessage::EchoOk {
+        let echo_ok_msg = EchoOk::EchoOk {
             echo: "echo".to_string(),
         };
         let msg = Message {
@@ -73,9 +165,11 @@ mod test {
                 payload: echo_ok_msg,
                 id: None,
                 in_reply_to: None,
+                lamport: None,
+                op_id: None,
             },
         }
-        .into_reply(Some(&mut 1));
+        .into_reply(Some(&IdGen::starting_at(1)));
         let stdout = std::io::stdout().lock();
         let mut output = serde_json::Serializer::new(stdout);
         msg.serialize(&mut output)?;
@@ -85,9 +179,9 @@ mod test {
     #[test]
     fn test_stdout() -> anyhow::Result<()> {
         let mut out = std::io::stdout().lock();
-        out.write(b"hello")?;
+        out.write_all(b"hello")?;
         out.flush()?;
-        out.write(b"zxk")?;
+        out.write_all(b"zxk")?;
         // out.write_all(b"hello2")?;
         Ok(())
     }
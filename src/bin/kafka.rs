@@ -0,0 +1,1332 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use rustgen::{
+    dedup::SeenSet,
+    kv::{KvClient, KvError, LIN_KV},
+    main_loop,
+    rpc::{NodeContext, Rpc, StepContext},
+    shard,
+    ticker::{spawn_ticker, RoundGuard},
+    Body, Cluster, IdGen, MaelstromError, Message, RequestError,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum KafkaMessage {
+    Send {
+        key: String,
+        msg: Value,
+    },
+    SendOk {
+        offset: usize,
+    },
+    /// for each key the offset to read from
+    Poll {
+        offsets: HashMap<String, usize>,
+    },
+    /// for each key `[offset, msg]` pairs, in offset order
+    PollOk {
+        msgs: HashMap<String, Vec<(usize, Value)>>,
+        /// another `poll_ok` for the same poll follows, only with `POLL_STREAM=1`
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        more: bool,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, usize>,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, usize>,
+    },
+    /// a `send` or `commit_offsets` of keys the receiver owns, see `OWNER_CACHE`
+    Forward {
+        request: Box<KafkaMessage>,
+    },
+    ForwardOk {
+        reply: Box<KafkaMessage>,
+    },
+    /// the end of a `SEND_BATCH_MS` window, the sends buffered meanwhile are appended
+    FlushSends,
+    /// the owner never answered the forward sent as `forward`
+    ForwardExpired {
+        forward: usize,
+    },
+}
+
+/// Keeps every log in lin-kv, so any node serves any key and nothing is lost with a
+/// node. Each key has its messages in kv entries of one or more, a counter hinting at
+/// its next free offset, and its committed offset.
+///
+/// With `SEND_BATCH_MS` set the sends are buffered for that long, then the sends to a
+/// key are appended together, as one entry under one cas.
+///
+/// With `OWNER_CACHE=1` every key has an owner by `shard::owner`, the only node which
+/// writes it: sends and commits of other keys are forwarded to their owner. The owner
+/// then also keeps its keys in memory, so it serves their polls and committed offsets
+/// without asking lin-kv; other nodes still read them from there.
+///
+/// The kv calls block the step, so it must run under `main_loop`.
+struct KafkaNode {
+    id: String,
+    node_ids: Vec<String>,
+    cluster: Cluster,
+    msg_ids: IdGen,
+    counters: KvClient<usize>,
+    /// each entry is a batch of messages, stored under the offset of its first one
+    logs: KvClient<Vec<Value>>,
+    /// with `POLL_STREAM=1` a poll is answered by several `poll_ok`, each with at most
+    /// this many messages
+    poll_stream: Option<usize>,
+    /// with `OWNER_CACHE=1`, the keys we own, loaded from lin-kv when first used
+    owned: Option<HashMap<String, CachedLog>>,
+    /// forwarded parts of a client request by the forward's msg id, to its request's
+    forwards: HashMap<usize, usize>,
+    /// carries the owners' answers to the forwards, or their expiry, back to the node
+    rpc: Rpc,
+    tx: crossbeam_channel::Sender<Message<KafkaMessage>>,
+    /// how long an owner has to answer a forward, see `FORWARD_TIMEOUT`
+    forward_timeout: Duration,
+    /// client requests waiting on owners, by an id of their own
+    pending: HashMap<usize, Forwarded>,
+    /// with `SEND_BATCH_MS` set, the sends waiting for the window's end by key
+    buffered: Option<BTreeMap<String, Vec<Message<KafkaMessage>>>>,
+    /// keeps one flush of the buffered sends in flight
+    flushes: Arc<RoundGuard>,
+    /// the offsets of the sends answered lately by their op id: a resent send is
+    /// answered with its offset rather than appended again
+    sent: SeenSet<usize>,
+    /// resends of the sends still forwarded or buffered, by op id: answered along with
+    /// the first rather than appended again
+    resent: HashMap<Uuid, Vec<Message<KafkaMessage>>>,
+}
+
+/// A key we own as it is in lin-kv, where only we write it.
+#[derive(Debug, Default)]
+struct CachedLog {
+    /// by offset, appends fill the offsets in order
+    msgs: Vec<Value>,
+    committed: Option<usize>,
+}
+
+/// A client request parts of which were forwarded to their owners.
+struct Forwarded {
+    request: Message<KafkaMessage>,
+    /// owners yet to answer
+    owners: usize,
+    /// the latest owner's reply, they only differ for a send, which has one owner
+    reply: Option<KafkaMessage>,
+}
+
+/// Messages returned per key by a single poll.
+const POLL_MAX: usize = 16;
+
+/// Messages in each `poll_ok` of a streamed poll.
+const POLL_STREAM_CHUNK: usize = 32;
+
+/// Messages in a batch, a poll starting in the middle of one looks back this far.
+const BATCH_MAX: usize = 64;
+
+/// How long an owner has to answer a forward before the client is answered with a
+/// timeout: the owner crashed, or its answer was lost.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Sends remembered by op id, at most this many and for this long.
+const SENT_CAP: usize = 4096;
+const SENT_TTL: Duration = Duration::from_secs(60);
+
+fn next_offset_key(key: &str) -> String {
+    format!("next/{key}")
+}
+
+fn message_key(key: &str, offset: usize) -> String {
+    format!("log/{key}/{offset}")
+}
+
+fn committed_key(key: &str) -> String {
+    format!("committed/{key}")
+}
+
+/// The op id of a send, forwarded or not: its resends are answered alike.
+fn send_op_id(req: &Message<KafkaMessage>) -> Option<Uuid> {
+    let send = match &req.body.payload {
+        KafkaMessage::Forward { request } => request.as_ref(),
+        payload => payload,
+    };
+    req.body
+        .op_id
+        .filter(|_| matches!(send, KafkaMessage::Send { .. }))
+}
+
+/// A kv failure replied to the client: a call which timed out may still have been
+/// applied, so that one is indefinite.
+fn kv_failed(e: KvError) -> anyhow::Error {
+    match e {
+        KvError::Rpc(_) => MaelstromError::Timeout.because(e.to_string()).into(),
+        e => MaelstromError::Crash.because(e.to_string()).into(),
+    }
+}
+
+impl KafkaNode {
+    /// Append `msgs`, at most `BATCH_MAX` of them, to the log of `key`, racing the other
+    /// nodes through cas, and return the offset of the first.
+    ///
+    /// The messages claim their offsets themselves, their entry created by a cas on the
+    /// first free offset, so no offset is ever taken without its message: a failed
+    /// append leaves no hole for a poll to stop at. The counter is only raised
+    /// afterwards, past the entry, to save the next append walking over the taken
+    /// ones; it's only ever raised to an entry's end, so a walk starting from it lands
+    /// on the first offset of an entry.
+    fn append(
+        &mut self,
+        key: &str,
+        msgs: &[Value],
+        output: &mut impl Write,
+    ) -> anyhow::Result<usize> {
+        let counter = next_offset_key(key);
+        let mut offset = match self.cached(key, output)? {
+            Some(log) => log.msgs.len(),
+            None => self
+                .counters
+                .read(&*counter, output)
+                .map_err(kv_failed)?
+                .unwrap_or(0),
+        };
+        loop {
+            let claimed = self.logs.cas(
+                message_key(key, offset),
+                Vec::new(),
+                msgs.to_vec(),
+                true,
+                output,
+            );
+            match claimed {
+                Ok(()) => break,
+                // step over the entry taken there
+                Err(KvError::CasFailed(_)) => {
+                    let taken = self
+                        .logs
+                        .read(message_key(key, offset), output)
+                        .map_err(kv_failed)?;
+                    offset += taken.map_or(0, |taken| taken.len());
+                }
+                Err(e) => return Err(kv_failed(e)),
+            }
+        }
+        // the messages are in, a counter left behind only costs a later append some reads
+        let _ = self.raise(&counter, offset + msgs.len(), output);
+        if let Some(owned) = &mut self.owned {
+            match owned.get_mut(key) {
+                Some(log) if log.msgs.len() == offset => log.msgs.extend_from_slice(msgs),
+                // somebody else appended after all, read the key again when next used
+                _ => {
+                    owned.remove(key);
+                }
+            }
+        }
+        Ok(offset)
+    }
+
+    /// The other node owning `key` with `OWNER_CACHE=1`, `None` if we serve it.
+    fn remote_owner(&self, key: &str) -> Option<String> {
+        self.owned.as_ref()?;
+        shard::owner(key, &self.node_ids)
+            .filter(|owner| *owner != self.id)
+            .map(str::to_string)
+    }
+
+    /// The cached state of `key` if we own it, loaded from lin-kv the first time.
+    fn cached(
+        &mut self,
+        key: &str,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Option<&mut CachedLog>> {
+        if self.owned.is_none() || self.remote_owner(key).is_some() {
+            return Ok(None);
+        }
+        if !self
+            .owned
+            .as_ref()
+            .is_some_and(|owned| owned.contains_key(key))
+        {
+            let mut log = CachedLog::default();
+            while let Some(batch) = self
+                .logs
+                .read(message_key(key, log.msgs.len()), output)
+                .map_err(kv_failed)?
+            {
+                log.msgs.extend(batch);
+            }
+            log.committed = self
+                .counters
+                .read(committed_key(key), output)
+                .map_err(kv_failed)?;
+            self.owned
+                .get_or_insert_with(HashMap::new)
+                .insert(key.to_string(), log);
+        }
+        Ok(self.owned.as_mut().and_then(|owned| owned.get_mut(key)))
+    }
+
+    /// Raise the committed offset of `key` to `offset`, never lowering it.
+    fn commit(&mut self, key: &str, offset: usize, output: &mut impl Write) -> anyhow::Result<()> {
+        self.raise(&committed_key(key), offset, output)?;
+        if let Some(log) = self.cached(key, output)? {
+            log.committed = log.committed.max(Some(offset));
+        }
+        Ok(())
+    }
+
+    /// The committed offset of `key`.
+    fn committed(&mut self, key: &str, output: &mut impl Write) -> anyhow::Result<Option<usize>> {
+        if let Some(log) = self.cached(key, output)? {
+            return Ok(log.committed);
+        }
+        self.counters
+            .read(committed_key(key), output)
+            .map_err(kv_failed)
+    }
+
+    /// Send each of `parts` to its owner, `req` is answered once they all replied, or
+    /// with a timeout once one of them didn't in time.
+    fn forward(
+        &mut self,
+        req: Message<KafkaMessage>,
+        parts: Vec<(String, KafkaMessage)>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let id = self.msg_ids.next();
+        for (owner, request) in &parts {
+            let forward_id = self.msg_ids.next();
+            let tx = self.tx.clone();
+            // the answer is stepped like any message, the expiry as an internal one
+            self.rpc
+                .register_until(forward_id, self.forward_timeout, move |reply| {
+                    let reply = reply.unwrap_or_else(|_| {
+                        Message::internal(KafkaMessage::ForwardExpired {
+                            forward: forward_id,
+                        })
+                    });
+                    let _ = tx.send(reply);
+                });
+            let forward = Message {
+                src: self.id.clone(),
+                dst: owner.clone(),
+                body: Body {
+                    id: Some(forward_id),
+                    in_reply_to: None,
+                    lamport: None,
+                    op_id: req.body.op_id,
+                    payload: KafkaMessage::Forward {
+                        request: Box::new(request.clone()),
+                    },
+                },
+            };
+            if let Err(e) = self.cluster.send_checked(&forward, output) {
+                self.rpc.cancel(forward_id);
+                return Err(e).with_context(|| format!("forward to {owner}"));
+            }
+            self.forwards.insert(forward_id, id);
+        }
+        if let Some(op_id) = send_op_id(&req) {
+            self.resent.insert(op_id, Vec::new());
+        }
+        let forwarded = Forwarded {
+            request: req,
+            owners: parts.len(),
+            reply: None,
+        };
+        self.pending.insert(id, forwarded);
+        Ok(())
+    }
+
+    /// An owner answered the forward `in_reply_to`, answer its request if it was the
+    /// last one.
+    fn on_forward_ok(
+        &mut self,
+        in_reply_to: Option<usize>,
+        reply: KafkaMessage,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        // a late reply to a forward answered already
+        let Some(id) = in_reply_to.and_then(|forward| self.forwards.remove(&forward)) else {
+            return Ok(());
+        };
+        let Some(forwarded) = self.pending.get_mut(&id) else {
+            return Ok(());
+        };
+        forwarded.owners -= 1;
+        forwarded.reply = Some(reply);
+        if forwarded.owners > 0 {
+            return Ok(());
+        }
+        match self.pending.remove(&id) {
+            Some(Forwarded {
+                request,
+                reply: Some(reply),
+                ..
+            }) => {
+                self.answered(&request, &reply, output)?;
+                request.reply_with(&self.msg_ids, reply).send(output)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// An owner didn't answer the forward `forward` in time: answer its request with a
+    /// timeout, the part may still have been applied, and forget the other parts.
+    fn on_forward_expired(
+        &mut self,
+        forward: usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        // answered meanwhile, or expired with another part already
+        let Some(id) = self.forwards.remove(&forward) else {
+            return Ok(());
+        };
+        let Some(forwarded) = self.pending.remove(&id) else {
+            return Ok(());
+        };
+        let parts = self
+            .forwards
+            .iter()
+            .filter(|(_, request)| **request == id)
+            .map(|(forward, _)| *forward)
+            .collect::<Vec<_>>();
+        for part in parts {
+            self.forwards.remove(&part);
+            self.rpc.cancel(part);
+        }
+        let text = "the key's owner didn't answer in time";
+        self.failed(&forwarded.request, MaelstromError::Timeout, text, output)?;
+        forwarded
+            .request
+            .into_error(MaelstromError::Timeout, text)
+            .send(output)
+    }
+
+    /// Remember the offset a send with an op id was answered with, see `sent`, and
+    /// answer its resends alike, see `resent`.
+    fn answered(
+        &mut self,
+        request: &Message<KafkaMessage>,
+        reply: &KafkaMessage,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let Some(op_id) = send_op_id(request) else {
+            return Ok(());
+        };
+        let reply = match reply {
+            KafkaMessage::ForwardOk { reply } => reply.as_ref(),
+            reply => reply,
+        };
+        if let KafkaMessage::SendOk { offset } = reply {
+            self.sent.remember(op_id, *offset);
+        }
+        for resend in self.resent.remove(&op_id).unwrap_or_default() {
+            let reply = match resend.body.payload {
+                KafkaMessage::Forward { .. } => KafkaMessage::ForwardOk {
+                    reply: Box::new(reply.clone()),
+                },
+                _ => reply.clone(),
+            };
+            resend.reply_with(&self.msg_ids, reply).send(output)?;
+        }
+        Ok(())
+    }
+
+    /// Answer the resends of a send which failed with its error, see `resent`.
+    fn failed(
+        &mut self,
+        request: &Message<KafkaMessage>,
+        code: MaelstromError,
+        text: &str,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let resent = send_op_id(request).and_then(|op_id| self.resent.remove(&op_id));
+        for resend in resent.unwrap_or_default() {
+            resend.into_error(code, text).send(output)?;
+        }
+        Ok(())
+    }
+
+    /// Apply a send or commit of keys we own, forwarded by another node. A send whose
+    /// op id was answered already isn't appended again.
+    fn apply_forwarded(
+        &mut self,
+        request: &KafkaMessage,
+        op_id: Option<Uuid>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<KafkaMessage> {
+        match request {
+            KafkaMessage::Send { key, msg } => {
+                if let Some(&offset) = op_id.and_then(|op_id| self.sent.get(&op_id)) {
+                    return Ok(KafkaMessage::SendOk { offset });
+                }
+                Ok(KafkaMessage::SendOk {
+                    offset: self.append(key, std::slice::from_ref(msg), output)?,
+                })
+            }
+            KafkaMessage::CommitOffsets { offsets } => {
+                for (key, offset) in offsets {
+                    self.commit(key, *offset, output)?;
+                }
+                Ok(KafkaMessage::CommitOffsetsOk)
+            }
+            request => Err(MaelstromError::NotSupported
+                .because(format!("forwarded {request:?}"))
+                .into()),
+        }
+    }
+
+    /// The messages of `key` from `offset` on, at least `POLL_MAX` of them unless the
+    /// log ends first: the last entry read is returned whole. Stops at the first offset
+    /// without an entry, appends fill the offsets in order so that's the log's end.
+    fn poll(
+        &mut self,
+        key: &str,
+        offset: usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Vec<(usize, Value)>> {
+        if let Some(log) = self.cached(key, output)? {
+            let polled = log.msgs.iter().cloned().enumerate().skip(offset);
+            return Ok(polled.take(POLL_MAX).collect());
+        }
+        let mut at = offset;
+        let mut entry = self
+            .logs
+            .read(message_key(key, offset), output)
+            .map_err(kv_failed)?;
+        if entry.is_none() {
+            if let Some((start, batch)) = self.batch_holding(key, offset, output)? {
+                (at, entry) = (start, Some(batch));
+            }
+        }
+        let mut msgs = Vec::new();
+        while let Some(batch) = entry {
+            let len = batch.len();
+            let polled = batch.into_iter().enumerate().map(|(i, msg)| (at + i, msg));
+            msgs.extend(polled.filter(|(polled, _)| *polled >= offset));
+            at += len;
+            if msgs.len() >= POLL_MAX {
+                break;
+            }
+            entry = self
+                .logs
+                .read(message_key(key, at), output)
+                .map_err(kv_failed)?;
+        }
+        Ok(msgs)
+    }
+
+    /// The entry of `key` holding `offset` though stored under an earlier one, with
+    /// that earlier offset. Past the counter there's nothing to look for.
+    fn batch_holding(
+        &self,
+        key: &str,
+        offset: usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Option<(usize, Vec<Value>)>> {
+        let next = self
+            .counters
+            .read(next_offset_key(key), output)
+            .map_err(kv_failed)?;
+        if next.is_none_or(|next| next <= offset) {
+            return Ok(None);
+        }
+        for start in (offset.saturating_sub(BATCH_MAX - 1)..offset).rev() {
+            let entry = self
+                .logs
+                .read(message_key(key, start), output)
+                .map_err(kv_failed)?;
+            if let Some(batch) = entry {
+                return Ok((start + batch.len() > offset).then_some((start, batch)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Append the sends buffered for each key as one batch, replying each its offset.
+    fn flush_sends(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let buffered = self
+            .buffered
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default();
+        for (key, sends) in buffered {
+            for sends in sends.chunks(BATCH_MAX) {
+                let msgs = sends
+                    .iter()
+                    .filter_map(|send| match &send.body.payload {
+                        KafkaMessage::Send { msg, .. } => Some(msg.clone()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                match self.append(&key, &msgs, output) {
+                    Ok(first) => {
+                        for (i, send) in sends.iter().enumerate() {
+                            let reply = KafkaMessage::SendOk { offset: first + i };
+                            self.answered(send, &reply, output)?;
+                            send.clone().reply_with(&self.msg_ids, reply).send(output)?;
+                        }
+                    }
+                    Err(e) => {
+                        let (code, text) = match e.downcast_ref::<RequestError>() {
+                            Some(error) => (error.code, error.text.clone()),
+                            None => (MaelstromError::Crash, format!("{e:#}")),
+                        };
+                        for send in sends {
+                            self.failed(send, code, &text, output)?;
+                            send.clone().into_error(code, text.clone()).send(output)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer `req` with `msgs` split over several `poll_ok` of at most `chunk` messages,
+    /// all replying to it; `more` is set on every one but the last. A key's messages
+    /// keep their order across the replies.
+    fn stream_poll(
+        &self,
+        req: Message<KafkaMessage>,
+        msgs: HashMap<String, Vec<(usize, Value)>>,
+        chunk: usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let mut msgs = msgs.into_iter().collect::<Vec<_>>();
+        msgs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let flat = msgs
+            .into_iter()
+            .flat_map(|(key, polled)| polled.into_iter().map(move |msg| (key.clone(), msg)))
+            .collect::<Vec<_>>();
+        let chunks = flat.chunks(chunk.max(1)).collect::<Vec<_>>();
+        // an empty poll still gets its one reply
+        let last = chunks.len().saturating_sub(1);
+        for i in 0..=last {
+            let mut msgs = HashMap::<_, Vec<_>>::new();
+            for (key, msg) in chunks.get(i).copied().unwrap_or_default() {
+                msgs.entry(key.clone()).or_default().push(msg.clone());
+            }
+            let more = i < last;
+            req.clone()
+                .reply_with(&self.msg_ids, KafkaMessage::PollOk { msgs, more })
+                .send(output)?;
+        }
+        Ok(())
+    }
+
+    /// Raise the counter `counter` to `to`, never lowering it.
+    fn raise(&self, counter: &str, to: usize, output: &mut impl Write) -> anyhow::Result<()> {
+        loop {
+            let current = self.counters.read(counter, output).map_err(kv_failed)?;
+            if current.is_some_and(|current| current >= to) {
+                return Ok(());
+            }
+            let raised =
+                self.counters
+                    .cas(counter, current.unwrap_or(0), to, current.is_none(), output);
+            match raised {
+                Ok(()) => return Ok(()),
+                Err(KvError::CasFailed(_)) => continue,
+                Err(e) => return Err(kv_failed(e)),
+            }
+        }
+    }
+}
+
+impl rustgen::Node<KafkaMessage> for KafkaNode {
+    fn init_from(
+        _: &rustgen::InitBody,
+        _: crossbeam_channel::Sender<Message<KafkaMessage>>,
+    ) -> anyhow::Result<Self> {
+        anyhow::bail!("the kafka node needs the rpc handle init_with gets")
+    }
+
+    fn init_with(init: &rustgen::InitBody, ctx: NodeContext<KafkaMessage>) -> anyhow::Result<Self> {
+        let window = std::env::var("SEND_BATCH_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis);
+        let flushes = Arc::new(RoundGuard::default());
+        if let Some(window) = window {
+            spawn_ticker(window, Arc::clone(&flushes), ctx.tx.clone(), || {
+                Message::internal(KafkaMessage::FlushSends)
+            });
+        }
+        Ok(Self {
+            id: init.node_id.clone(),
+            node_ids: init.node_ids.clone(),
+            cluster: ctx.cluster,
+            msg_ids: IdGen::default(),
+            counters: KvClient::new(LIN_KV, ctx.rpc.clone()),
+            logs: KvClient::new(LIN_KV, ctx.rpc.clone()),
+            poll_stream: std::env::var("POLL_STREAM")
+                .is_ok_and(|flag| flag == "1")
+                .then_some(POLL_STREAM_CHUNK),
+            owned: std::env::var("OWNER_CACHE")
+                .is_ok_and(|flag| flag == "1")
+                .then(HashMap::new),
+            forwards: HashMap::new(),
+            rpc: ctx.rpc,
+            tx: ctx.tx.clone(),
+            forward_timeout: FORWARD_TIMEOUT,
+            pending: HashMap::new(),
+            buffered: window.map(|_| BTreeMap::new()),
+            flushes,
+            sent: SeenSet::new(SENT_CAP, SENT_TTL),
+            resent: HashMap::new(),
+        })
+    }
+
+    fn step(
+        &mut self,
+        req: rustgen::Message<KafkaMessage>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        if let Some(resends) = send_op_id(&req).and_then(|op_id| self.resent.get_mut(&op_id)) {
+            resends.push(req);
+            return Ok(());
+        }
+        if let (KafkaMessage::Send { .. }, Some(op_id)) = (&req.body.payload, req.body.op_id) {
+            if let Some(&offset) = self.sent.get(&op_id) {
+                return req
+                    .reply_with(&self.msg_ids, KafkaMessage::SendOk { offset })
+                    .send(output);
+            }
+        }
+        let payload = match &req.body.payload {
+            KafkaMessage::Send { key, msg } => match self.remote_owner(key) {
+                Some(owner) => {
+                    let parts = vec![(owner, req.body.payload.clone())];
+                    return self.forward(req, parts, output);
+                }
+                None if self.buffered.is_some() => {
+                    let key = key.clone();
+                    if let Some(op_id) = req.body.op_id {
+                        self.resent.insert(op_id, Vec::new());
+                    }
+                    if let Some(buffered) = &mut self.buffered {
+                        buffered.entry(key).or_default().push(req);
+                    }
+                    return Ok(());
+                }
+                None => KafkaMessage::SendOk {
+                    offset: self.append(key, std::slice::from_ref(msg), output)?,
+                },
+            },
+            KafkaMessage::Poll { offsets } => {
+                let mut msgs = HashMap::new();
+                for (key, offset) in offsets {
+                    let polled = self.poll(key, *offset, output)?;
+                    if !polled.is_empty() {
+                        msgs.insert(key.clone(), polled);
+                    }
+                }
+                if let Some(chunk) = self.poll_stream {
+                    return self.stream_poll(req, msgs, chunk, output);
+                }
+                KafkaMessage::PollOk { msgs, more: false }
+            }
+            KafkaMessage::CommitOffsets { offsets } => {
+                let mut remote = HashMap::<String, HashMap<String, usize>>::new();
+                for (key, offset) in offsets {
+                    match self.remote_owner(key) {
+                        Some(owner) => {
+                            remote
+                                .entry(owner)
+                                .or_default()
+                                .insert(key.clone(), *offset);
+                        }
+                        None => self.commit(key, *offset, output)?,
+                    }
+                }
+                if !remote.is_empty() {
+                    let parts = remote
+                        .into_iter()
+                        .map(|(owner, offsets)| (owner, KafkaMessage::CommitOffsets { offsets }))
+                        .collect();
+                    return self.forward(req, parts, output);
+                }
+                KafkaMessage::CommitOffsetsOk
+            }
+            KafkaMessage::ListCommittedOffsets { keys } => {
+                let mut offsets = HashMap::new();
+                for key in keys {
+                    if let Some(offset) = self.committed(key, output)? {
+                        offsets.insert(key.clone(), offset);
+                    }
+                }
+                KafkaMessage::ListCommittedOffsetsOk { offsets }
+            }
+            KafkaMessage::Forward { request } => KafkaMessage::ForwardOk {
+                reply: Box::new(self.apply_forwarded(request, req.body.op_id, output)?),
+            },
+            KafkaMessage::ForwardOk { reply } => {
+                let reply = (**reply).clone();
+                return self.on_forward_ok(req.body.in_reply_to, reply, output);
+            }
+            KafkaMessage::SendOk { .. }
+            | KafkaMessage::PollOk { .. }
+            | KafkaMessage::CommitOffsetsOk
+            | KafkaMessage::ListCommittedOffsetsOk { .. } => return Ok(()),
+            // only the node raises these, see `on_internal`; from anyone else a flush
+            // would release the flush in flight
+            KafkaMessage::FlushSends | KafkaMessage::ForwardExpired { .. } => return Ok(()),
+        };
+        self.answered(&req, &payload, output)?;
+        req.reply_with(&self.msg_ids, payload).send(output)
+    }
+
+    fn on_internal(
+        &mut self,
+        payload: KafkaMessage,
+        output: &mut impl Write,
+        ctx: &StepContext<'_, KafkaMessage>,
+    ) -> anyhow::Result<()> {
+        match payload {
+            KafkaMessage::FlushSends => {
+                let flushed = self.flush_sends(output);
+                self.flushes.finish();
+                flushed
+            }
+            KafkaMessage::ForwardExpired { forward } => self.on_forward_expired(forward, output),
+            payload => self.step_with(Message::internal(payload), output, ctx),
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<KafkaMessage, KafkaNode>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use rustgen::{
+        clock::SystemClock,
+        rpc::{NodeContext, Rpc, StepContext},
+        shard,
+        test_util::{assert_wire_format, FakeKv},
+        Body, Cluster, InitBody, Message, Node,
+    };
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+
+    use crate::{KafkaMessage, KafkaNode};
+
+    fn request(payload: KafkaMessage) -> Message<KafkaMessage> {
+        Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload,
+            },
+        }
+    }
+
+    /// Node n1 of `node_ids` and the kv it talks to.
+    fn new_node(node_ids: &[&str]) -> anyhow::Result<(KafkaNode, FakeKv)> {
+        let init = InitBody {
+            node_id: "n1".to_string(),
+            node_ids: node_ids.iter().map(|id| id.to_string()).collect(),
+            extra: Default::default(),
+        };
+        let (tx, _) = crossbeam_channel::unbounded();
+        let rpc = Rpc::new("n1");
+        let kv = FakeKv::new(rpc.clone());
+        let ctx = NodeContext {
+            tx,
+            rpc,
+            cluster: Cluster::new(&init),
+            clock: Arc::new(SystemClock),
+            metrics: None,
+        };
+        Ok((KafkaNode::init_with(&init, ctx)?, kv))
+    }
+
+    /// End the `SEND_BATCH_MS` window, the way the node's ticker does.
+    fn flush(node: &mut KafkaNode, kv: &mut FakeKv) -> anyhow::Result<()> {
+        let tx = node.tx.clone();
+        node.on_internal(KafkaMessage::FlushSends, kv, &StepContext { tx: &tx })
+    }
+
+    /// Step `payload` and return the reply.
+    fn call(
+        node: &mut KafkaNode,
+        kv: &mut FakeKv,
+        payload: KafkaMessage,
+    ) -> anyhow::Result<KafkaMessage> {
+        node.step(request(payload), kv)?;
+        let reply = kv.sent.pop().expect("a reply");
+        Ok(serde_json::from_str::<Message<KafkaMessage>>(&reply)?
+            .body
+            .payload)
+    }
+
+    #[test]
+    fn test_send_poll_commit() -> anyhow::Result<()> {
+        let (mut node, mut kv) = new_node(&["n1"])?;
+
+        let send = |key: &str, msg| KafkaMessage::Send {
+            key: key.to_string(),
+            msg: json!(msg),
+        };
+        for (expected, msg) in [(0, 10), (1, 11), (2, 12)] {
+            let reply = call(&mut node, &mut kv, send("k1", msg))?;
+            assert!(matches!(reply, KafkaMessage::SendOk { offset } if offset == expected));
+        }
+        let reply = call(&mut node, &mut kv, send("k2", 20))?;
+        assert!(matches!(reply, KafkaMessage::SendOk { offset: 0 }));
+
+        let offsets = HashMap::from([("k1".to_string(), 1), ("k3".to_string(), 0)]);
+        match call(&mut node, &mut kv, KafkaMessage::Poll { offsets })? {
+            KafkaMessage::PollOk { msgs, .. } => assert_eq!(
+                msgs,
+                HashMap::from([("k1".to_string(), vec![(1, json!(11)), (2, json!(12))])])
+            ),
+            reply => panic!("unexpected reply {reply:?}"),
+        }
+
+        // a stale commit doesn't move the offset back
+        for offset in [2, 1] {
+            let offsets = HashMap::from([("k1".to_string(), offset)]);
+            call(&mut node, &mut kv, KafkaMessage::CommitOffsets { offsets })?;
+        }
+        let keys = vec!["k1".to_string(), "k2".to_string()];
+        match call(
+            &mut node,
+            &mut kv,
+            KafkaMessage::ListCommittedOffsets { keys },
+        )? {
+            KafkaMessage::ListCommittedOffsetsOk { offsets } => {
+                assert_eq!(offsets, HashMap::from([("k1".to_string(), 2)]))
+            }
+            reply => panic!("unexpected reply {reply:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_resent_send_appended_once() -> anyhow::Result<()> {
+        let (mut node, mut kv) = new_node(&["n1"])?;
+        let send = |msg| KafkaMessage::Send {
+            key: "k1".to_string(),
+            msg: json!(msg),
+        };
+        let mut first = request(send(10));
+        first.body.op_id = Some(Uuid::new_v4());
+        for _ in 0..2 {
+            node.step(first.clone(), &mut kv)?;
+            let reply = serde_json::from_str::<Message<KafkaMessage>>(&kv.sent.pop().unwrap())?;
+            assert!(matches!(
+                reply.body.payload,
+                KafkaMessage::SendOk { offset: 0 }
+            ));
+        }
+        // the replay took no offset
+        let reply = call(&mut node, &mut kv, send(11))?;
+        assert!(matches!(reply, KafkaMessage::SendOk { offset: 1 }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resent_forward_appended_once() -> anyhow::Result<()> {
+        let (mut node, mut kv) = new_node(&["n1"])?;
+        let mut forward = request(KafkaMessage::Forward {
+            request: Box::new(KafkaMessage::Send {
+                key: "k1".to_string(),
+                msg: json!(10),
+            }),
+        });
+        forward.src = "n2".to_string();
+        forward.body.op_id = Some(Uuid::new_v4());
+        for _ in 0..2 {
+            node.step(forward.clone(), &mut kv)?;
+            let reply = serde_json::from_str::<Message<KafkaMessage>>(&kv.sent.pop().unwrap())?;
+            assert!(matches!(
+                reply.body.payload,
+                KafkaMessage::ForwardOk { reply } if matches!(*reply, KafkaMessage::SendOk { offset: 0 })
+            ));
+        }
+        let send = KafkaMessage::Send {
+            key: "k1".to_string(),
+            msg: json!(11),
+        };
+        let reply = call(&mut node, &mut kv, send)?;
+        assert!(matches!(reply, KafkaMessage::SendOk { offset: 1 }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resend_waits_for_the_forwarded_send() -> anyhow::Result<()> {
+        let node_ids = ["n1", "n2"];
+        let (mut node, mut kv) = new_node(&node_ids)?;
+        node.owned = Some(HashMap::new());
+        let cluster = node_ids.map(str::to_string);
+        let key = (0..)
+            .map(|i| format!("k{i}"))
+            .find(|key| shard::owner(key, &cluster) == Some("n2"))
+            .unwrap();
+
+        let mut send = request(KafkaMessage::Send { key, msg: json!(7) });
+        send.body.op_id = Some(Uuid::new_v4());
+        node.step(send.clone(), &mut kv)?;
+        let forward = serde_json::from_str::<Message<KafkaMessage>>(&kv.sent.pop().unwrap())?;
+        assert_eq!(forward.body.op_id, send.body.op_id);
+
+        // the resend neither forwards again nor gets an answer of its own yet
+        send.body.id = Some(2);
+        node.step(send, &mut kv)?;
+        assert!(kv.sent.is_empty());
+
+        let mut forward_ok = forward.into_reply(None);
+        forward_ok.body.payload = KafkaMessage::ForwardOk {
+            reply: Box::new(KafkaMessage::SendOk { offset: 4 }),
+        };
+        node.step(forward_ok, &mut kv)?;
+        let mut replied = std::mem::take(&mut kv.sent)
+            .iter()
+            .map(|line| serde_json::from_str::<Message<KafkaMessage>>(line))
+            .collect::<Result<Vec<_>, _>>()?;
+        replied.sort_by_key(|reply| reply.body.in_reply_to);
+        assert_eq!(replied.len(), 2);
+        for (reply, id) in replied.into_iter().zip([1, 2]) {
+            assert_eq!(reply.body.in_reply_to, Some(id));
+            assert!(matches!(
+                reply.body.payload,
+                KafkaMessage::SendOk { offset: 4 }
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_resend_waits_for_the_buffered_send() -> anyhow::Result<()> {
+        let (mut node, mut kv) = new_node(&["n1"])?;
+        node.buffered = Some(Default::default());
+        let mut send = request(KafkaMessage::Send {
+            key: "k1".to_string(),
+            msg: json!(10),
+        });
+        send.body.op_id = Some(Uuid::new_v4());
+        node.step(send.clone(), &mut kv)?;
+        send.body.id = Some(2);
+        node.step(send, &mut kv)?;
+
+        flush(&mut node, &mut kv)?;
+        let replied = std::mem::take(&mut kv.sent)
+            .iter()
+            .map(|line| serde_json::from_str::<Message<KafkaMessage>>(line))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(replied.len(), 2);
+        for reply in replied {
+            assert!(matches!(
+                reply.body.payload,
+                KafkaMessage::SendOk { offset: 0 }
+            ));
+        }
+        // the resend took no offset
+        let send = KafkaMessage::Send {
+            key: "k1".to_string(),
+            msg: json!(11),
+        };
+        node.step(request(send), &mut kv)?;
+        flush(&mut node, &mut kv)?;
+        let reply = serde_json::from_str::<Message<KafkaMessage>>(&kv.sent.pop().unwrap())?;
+        assert!(matches!(
+            reply.body.payload,
+            KafkaMessage::SendOk { offset: 1 }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_append_leaves_no_hole() -> anyhow::Result<()> {
+        let (mut node, mut kv) = new_node(&["n1"])?;
+        let send = |msg| KafkaMessage::Send {
+            key: "k1".to_string(),
+            msg: json!(msg),
+        };
+
+        call(&mut node, &mut kv, send(10))?;
+        // the write of the second message fails once it's past allocating
+        kv.fail_once("log/k1/1");
+        assert!(node.step(request(send(11)), &mut kv).is_err());
+        let reply = call(&mut node, &mut kv, send(12))?;
+        assert!(matches!(reply, KafkaMessage::SendOk { offset: 1 }));
+
+        let offsets = HashMap::from([("k1".to_string(), 0)]);
+        match call(&mut node, &mut kv, KafkaMessage::Poll { offsets })? {
+            KafkaMessage::PollOk { msgs, .. } => assert_eq!(
+                msgs,
+                HashMap::from([("k1".to_string(), vec![(0, json!(10)), (1, json!(12))])])
+            ),
+            reply => panic!("unexpected reply {reply:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_streamed_poll_concatenates_to_full_result() -> anyhow::Result<()> {
+        let (mut node, mut kv) = new_node(&["n1"])?;
+        for key in ["k1", "k2"] {
+            for msg in 0..10 {
+                let send = KafkaMessage::Send {
+                    key: key.to_string(),
+                    msg: json!(msg),
+                };
+                call(&mut node, &mut kv, send)?;
+            }
+        }
+        let offsets = HashMap::from([("k1".to_string(), 2), ("k2".to_string(), 0)]);
+        let full = match call(
+            &mut node,
+            &mut kv,
+            KafkaMessage::Poll {
+                offsets: offsets.clone(),
+            },
+        )? {
+            KafkaMessage::PollOk { msgs, more: false } => msgs,
+            reply => panic!("unexpected reply {reply:?}"),
+        };
+
+        node.poll_stream = Some(4);
+        node.step(request(KafkaMessage::Poll { offsets }), &mut kv)?;
+        let replies = std::mem::take(&mut kv.sent)
+            .iter()
+            .map(|line| serde_json::from_str::<Message<KafkaMessage>>(line))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(replies.len(), 5);
+        let mut streamed = HashMap::<String, Vec<_>>::new();
+        for (i, reply) in replies.into_iter().enumerate() {
+            assert_eq!(reply.body.in_reply_to, Some(1));
+            match reply.body.payload {
+                KafkaMessage::PollOk { msgs, more } => {
+                    assert_eq!(more, i < 4);
+                    assert!(msgs.values().map(Vec::len).sum::<usize>() <= 4);
+                    for (key, msgs) in msgs {
+                        streamed.entry(key).or_default().extend(msgs);
+                    }
+                }
+                reply => panic!("unexpected reply {reply:?}"),
+            }
+        }
+        assert_eq!(streamed, full);
+        Ok(())
+    }
+
+    #[test]
+    fn test_owned_keys_served_from_memory() -> anyhow::Result<()> {
+        let (mut node, mut kv) = new_node(&["n1"])?;
+        node.owned = Some(HashMap::new());
+        for msg in 0..3 {
+            let send = KafkaMessage::Send {
+                key: "k1".to_string(),
+                msg: json!(msg),
+            };
+            call(&mut node, &mut kv, send)?;
+        }
+        let offsets = HashMap::from([("k1".to_string(), 2)]);
+        call(&mut node, &mut kv, KafkaMessage::CommitOffsets { offsets })?;
+
+        let requests = kv.requests;
+        let offsets = HashMap::from([("k1".to_string(), 1)]);
+        match call(&mut node, &mut kv, KafkaMessage::Poll { offsets })? {
+            KafkaMessage::PollOk { msgs, .. } => assert_eq!(
+                msgs,
+                HashMap::from([("k1".to_string(), vec![(1, json!(1)), (2, json!(2))])])
+            ),
+            reply => panic!("unexpected reply {reply:?}"),
+        }
+        let keys = vec!["k1".to_string()];
+        match call(
+            &mut node,
+            &mut kv,
+            KafkaMessage::ListCommittedOffsets { keys },
+        )? {
+            KafkaMessage::ListCommittedOffsetsOk { offsets } => {
+                assert_eq!(offsets, HashMap::from([("k1".to_string(), 2)]))
+            }
+            reply => panic!("unexpected reply {reply:?}"),
+        }
+        assert_eq!(kv.requests, requests, "owned keys were read from the kv");
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_forwarded_to_owner() -> anyhow::Result<()> {
+        let node_ids = ["n1", "n2"];
+        let (mut node, mut kv) = new_node(&node_ids)?;
+        node.owned = Some(HashMap::new());
+        let cluster = node_ids.map(str::to_string);
+        let key = (0..)
+            .map(|i| format!("k{i}"))
+            .find(|key| shard::owner(key, &cluster) == Some("n2"))
+            .unwrap();
+
+        let send = KafkaMessage::Send {
+            key: key.clone(),
+            msg: json!(7),
+        };
+        node.step(request(send), &mut kv)?;
+        assert_eq!(kv.requests, 0);
+        let forward = serde_json::from_str::<Message<KafkaMessage>>(&kv.sent.pop().unwrap())?;
+        assert_eq!(forward.dst, "n2");
+        assert!(matches!(
+            &forward.body.payload,
+            KafkaMessage::Forward { request } if matches!(**request, KafkaMessage::Send { .. })
+        ));
+
+        // the owner's reply is relayed to the client
+        let mut forward_ok = forward.into_reply(None);
+        forward_ok.body.payload = KafkaMessage::ForwardOk {
+            reply: Box::new(KafkaMessage::SendOk { offset: 4 }),
+        };
+        node.step(forward_ok, &mut kv)?;
+        let reply = serde_json::from_str::<Message<KafkaMessage>>(&kv.sent.pop().unwrap())?;
+        assert_eq!(
+            (reply.dst.as_str(), reply.body.in_reply_to),
+            ("c1", Some(1))
+        );
+        assert!(matches!(
+            reply.body.payload,
+            KafkaMessage::SendOk { offset: 4 }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_times_out_when_owner_never_answers() -> anyhow::Result<()> {
+        let node_ids = ["n1", "n2"];
+        let (mut node, mut kv) = new_node(&node_ids)?;
+        let (tx, rx) = crossbeam_channel::unbounded();
+        node.tx = tx;
+        node.forward_timeout = Duration::ZERO;
+        node.owned = Some(HashMap::new());
+        let cluster = node_ids.map(str::to_string);
+        let key = (0..)
+            .map(|i| format!("k{i}"))
+            .find(|key| shard::owner(key, &cluster) == Some("n2"))
+            .unwrap();
+
+        let send = KafkaMessage::Send { key, msg: json!(7) };
+        node.step(request(send), &mut kv)?;
+        assert_eq!(kv.sent.len(), 1);
+        kv.sent.clear();
+
+        // n2 never answers, so the forward expires and the client gets a timeout
+        assert_eq!(node.rpc.reap(), 1);
+        let expired = rx.try_recv()?;
+        assert!(expired.is_internal());
+        node.on_internal(
+            expired.body.payload,
+            &mut kv,
+            &StepContext {
+                tx: &node.tx.clone(),
+            },
+        )?;
+        let reply = serde_json::from_str::<Value>(&kv.sent.pop().unwrap())?;
+        assert_eq!(reply["dest"], "c1");
+        assert_eq!(reply["body"]["in_reply_to"], 1);
+        assert_eq!(reply["body"]["code"], 0);
+        assert!(node.forwards.is_empty());
+        assert!(node.pending.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_sends_share_a_cas() -> anyhow::Result<()> {
+        let (mut node, mut kv) = new_node(&["n1"])?;
+        node.buffered = Some(Default::default());
+        for id in 0..20 {
+            let mut send = request(KafkaMessage::Send {
+                key: "k1".to_string(),
+                msg: json!(id),
+            });
+            send.body.id = Some(id);
+            node.step(send, &mut kv)?;
+        }
+        assert_eq!((kv.requests, kv.sent.len()), (0, 0));
+
+        flush(&mut node, &mut kv)?;
+        // the entry and the counter, rather than both for every send
+        assert_eq!(kv.cas, 2);
+        let replies = std::mem::take(&mut kv.sent)
+            .iter()
+            .map(|line| serde_json::from_str::<Message<KafkaMessage>>(line))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(replies.len(), 20);
+        for reply in replies {
+            let id = reply.body.in_reply_to.expect("a reply");
+            assert!(matches!(reply.body.payload, KafkaMessage::SendOk { offset } if offset == id));
+        }
+
+        // a poll from the middle of the batch finds it
+        let offsets = HashMap::from([("k1".to_string(), 5)]);
+        match call(&mut node, &mut kv, KafkaMessage::Poll { offsets })? {
+            KafkaMessage::PollOk { msgs, .. } => {
+                let expected = (5..20).map(|offset| (offset, json!(offset))).collect();
+                assert_eq!(msgs, HashMap::from([("k1".to_string(), expected)]))
+            }
+            reply => panic!("unexpected reply {reply:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_from_a_client_ignored() -> anyhow::Result<()> {
+        let (mut node, mut kv) = new_node(&["n1"])?;
+        node.buffered = Some(Default::default());
+        let send = KafkaMessage::Send {
+            key: "k1".to_string(),
+            msg: json!(10),
+        };
+        node.step(request(send), &mut kv)?;
+        assert!(node.flushes.try_start());
+
+        node.step(request(KafkaMessage::FlushSends), &mut kv)?;
+        assert_eq!((kv.requests, kv.sent.len()), (0, 0));
+        // the ticker's flush is still in flight
+        assert!(!node.flushes.try_start());
+
+        flush(&mut node, &mut kv)?;
+        assert_eq!(kv.sent.len(), 1);
+        assert!(node.flushes.try_start());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let mut poll_ok = request(KafkaMessage::PollOk {
+            msgs: HashMap::from([("k1".to_string(), vec![(1000, json!(9))])]),
+            more: false,
+        });
+        poll_ok.body.in_reply_to = Some(3);
+        assert_wire_format(
+            &poll_ok,
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":1,"in_reply_to":3,"type":"poll_ok","msgs":{"k1":[[1000,9]]}}}"#,
+        );
+    }
+}
@@ -0,0 +1,413 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::Write,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use rustgen::{
+    clock::{Clock, SystemClock},
+    crdt::{LwwRegister, Mergeable},
+    gossip::{self, Gossip},
+    main_loop,
+    rpc::NodeContext,
+    Body, IdGen, MaelstromError, Message,
+};
+use serde::{Deserialize, Serialize};
+
+/// A key-value store of last-writer-wins registers, stamped with wall time. Always
+/// available, eventually consistent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum LwwMessage {
+    Read {
+        key: usize,
+    },
+    ReadOk {
+        value: usize,
+    },
+    Write {
+        key: usize,
+        value: usize,
+    },
+    WriteOk,
+    /// the last winning writes of `key` this node saw, newest first, to debug races
+    ReadHistory {
+        key: usize,
+    },
+    ReadHistoryOk {
+        versions: Vec<Version>,
+    },
+    Extended(GossipProtocol),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum GossipProtocol {
+    GossipAlert,
+    Gossip { registers: Registers },
+}
+
+/// A register per key, merged key by key. Serialized as `[key, register]` pairs since
+/// integer map keys don't survive the flattened message payload.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Vec<(usize, LwwRegister<usize>)>")]
+#[serde(into = "Vec<(usize, LwwRegister<usize>)>")]
+struct Registers(BTreeMap<usize, LwwRegister<usize>>);
+
+impl Registers {
+    /// Keys whose register differs in `other`.
+    fn differing(&self, other: &Registers) -> usize {
+        self.0
+            .iter()
+            .filter(|(key, register)| other.0.get(key) != Some(register))
+            .count()
+    }
+}
+
+impl From<Vec<(usize, LwwRegister<usize>)>> for Registers {
+    fn from(pairs: Vec<(usize, LwwRegister<usize>)>) -> Self {
+        Self(pairs.into_iter().collect())
+    }
+}
+
+impl From<Registers> for Vec<(usize, LwwRegister<usize>)> {
+    fn from(registers: Registers) -> Self {
+        registers.0.into_iter().collect()
+    }
+}
+
+impl Mergeable for Registers {
+    fn merge(&mut self, other: Self) {
+        for (key, register) in other.0 {
+            self.0.entry(key).or_default().merge(register);
+        }
+    }
+}
+
+/// A write which held a register.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Version {
+    value: usize,
+    timestamp: u64,
+    node: String,
+}
+
+/// How many versions `History` keeps per key unless `LWW_HISTORY_DEPTH` says
+/// otherwise, just the current one.
+const DEFAULT_HISTORY_DEPTH: usize = 1;
+
+/// The recent versions of each key, local to the node and never gossiped. At most
+/// `depth` per key, the oldest go first.
+#[derive(Debug)]
+struct History {
+    depth: usize,
+    versions: HashMap<usize, VecDeque<Version>>,
+}
+
+impl History {
+    fn new(depth: usize) -> Self {
+        Self {
+            depth: depth.max(1),
+            versions: HashMap::new(),
+        }
+    }
+
+    /// Note what `register` holds now, unless it's still the newest version known.
+    fn record(&mut self, key: usize, register: &LwwRegister<usize>) {
+        let Some(&value) = register.get() else {
+            return;
+        };
+        let version = Version {
+            value,
+            timestamp: register.timestamp(),
+            node: register.node().to_string(),
+        };
+        let versions = self.versions.entry(key).or_default();
+        if versions.back() == Some(&version) {
+            return;
+        }
+        versions.push_back(version);
+        if versions.len() > self.depth {
+            versions.pop_front();
+        }
+    }
+
+    fn newest_first(&self, key: usize) -> Vec<Version> {
+        self.versions
+            .get(&key)
+            .map(|versions| versions.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Writes land in the local register at the node's wall time, peers learn them by
+/// gossip. The later `(timestamp, node)` wins everywhere, so a node whose clock runs
+/// ahead wins its concurrent races, but every replica agrees on the winner.
+struct LwwNode {
+    id: String,
+    msg_ids: IdGen,
+    clock: Arc<dyn Clock>,
+    registers: Registers,
+    history: History,
+    /// knows the last registers each peer gossiped to us
+    gossip: Gossip<Registers>,
+}
+
+impl LwwNode {
+    fn start(
+        init_msg: &rustgen::InitBody,
+        tx: crossbeam_channel::Sender<Message<LwwMessage>>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let gossip = Gossip::start(init_msg, gossip::interval_from_env(), tx, || {
+            LwwMessage::Extended(GossipProtocol::GossipAlert)
+        });
+        Self {
+            id: init_msg.node_id.clone(),
+            msg_ids: IdGen::default(),
+            clock,
+            registers: Registers::default(),
+            history: History::new(
+                std::env::var("LWW_HISTORY_DEPTH")
+                    .ok()
+                    .and_then(|depth| depth.parse().ok())
+                    .unwrap_or(DEFAULT_HISTORY_DEPTH),
+            ),
+            gossip,
+        }
+    }
+
+    fn gossip_round(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let registers = &self.registers;
+        let peers = self
+            .gossip
+            .round_peers(|_, known| registers.differing(known));
+        for peer in peers {
+            Message {
+                src: self.id.clone(),
+                dst: peer.clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    lamport: None,
+                    op_id: None,
+                    payload: LwwMessage::Extended(GossipProtocol::Gossip {
+                        registers: self.registers.clone(),
+                    }),
+                },
+            }
+            .send(output)
+            .with_context(|| format!("send gossip to {peer}"))?
+        }
+        Ok(())
+    }
+}
+
+impl rustgen::Node<LwwMessage> for LwwNode {
+    fn init_with(init: &rustgen::InitBody, ctx: NodeContext<LwwMessage>) -> anyhow::Result<Self> {
+        Ok(Self::start(init, ctx.tx, ctx.clock))
+    }
+
+    fn init_from(
+        init_msg: &rustgen::InitBody,
+        tx: crossbeam_channel::Sender<Message<LwwMessage>>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self::start(init_msg, tx, Arc::new(SystemClock)))
+    }
+
+    fn step(
+        &mut self,
+        req: rustgen::Message<LwwMessage>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        match req.body.payload {
+            LwwMessage::Read { key } => {
+                match self
+                    .registers
+                    .0
+                    .get(&key)
+                    .and_then(|register| register.get())
+                {
+                    Some(&value) => req
+                        .reply_with(&self.msg_ids, LwwMessage::ReadOk { value })
+                        .send(output)?,
+                    None => req
+                        .into_error(MaelstromError::KeyDoesNotExist, format!("no key {key}"))
+                        .send(output)?,
+                }
+            }
+            LwwMessage::Write { key, value } => {
+                let now = self.clock.now_millis();
+                let register = self.registers.0.entry(key).or_default();
+                register.set_at(&self.id, value, now);
+                self.history.record(key, register);
+                req.reply_with(&self.msg_ids, LwwMessage::WriteOk)
+                    .send(output)?
+            }
+            LwwMessage::ReadHistory { key } => req
+                .reply_with(
+                    &self.msg_ids,
+                    LwwMessage::ReadHistoryOk {
+                        versions: self.history.newest_first(key),
+                    },
+                )
+                .send(output)?,
+            LwwMessage::Extended(GossipProtocol::GossipAlert) => {
+                if let Some(_round) = self.gossip.on_alert() {
+                    self.gossip_round(output)?
+                }
+            }
+            LwwMessage::Extended(GossipProtocol::Gossip { registers }) => {
+                self.gossip.on_gossip(&req.src, registers.clone());
+                let keys = registers.0.keys().copied().collect::<Vec<_>>();
+                self.registers.merge(registers);
+                for key in keys {
+                    self.history.record(key, &self.registers.0[&key]);
+                }
+            }
+            LwwMessage::ReadOk { .. } | LwwMessage::WriteOk | LwwMessage::ReadHistoryOk { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Every peer last gossiped exactly the registers we hold.
+    fn converged(&self) -> bool {
+        self.gossip
+            .peers()
+            .all(|peer| self.gossip.known(peer) == Some(&self.registers))
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<LwwMessage, LwwNode>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use rustgen::{clock::MockClock, test_util::Network, Body, InitBody, Message, Node};
+
+    use crate::{GossipProtocol, History, LwwMessage, LwwNode};
+
+    fn request(dst: &str, msg_id: usize, payload: LwwMessage) -> Message<LwwMessage> {
+        Message {
+            src: "c1".to_string(),
+            dst: dst.to_string(),
+            body: Body {
+                id: Some(msg_id),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload,
+            },
+        }
+    }
+
+    fn gossip_until_converged(network: &mut Network<LwwMessage, LwwNode>) -> anyhow::Result<()> {
+        for _ in 0..10 {
+            network.tick(|| LwwMessage::Extended(GossipProtocol::GossipAlert));
+            network.run()?;
+            if network.nodes().all(|(_, node)| node.converged()) {
+                return Ok(());
+            }
+        }
+        anyhow::bail!("no convergence after 10 gossip rounds")
+    }
+
+    fn values(network: &Network<LwwMessage, LwwNode>, key: usize) -> Vec<Option<usize>> {
+        network
+            .nodes()
+            .map(|(_, node)| node.registers.0[&key].get().copied())
+            .collect()
+    }
+
+    #[test]
+    fn test_skewed_clocks_agree_on_winner() -> anyhow::Result<()> {
+        let time = MockClock::default();
+        let skew = |node_id: &str| match node_id {
+            "n1" => Duration::ZERO,
+            // n2 and n3 run equally far ahead, their writes tie on the timestamp
+            _ => Duration::from_millis(500),
+        };
+        let mut network = Network::<LwwMessage, LwwNode>::with_clocks(&["n1", "n2", "n3"], |id| {
+            Arc::new(time.with_offset(skew(id)))
+        })?;
+
+        for (msg_id, node) in [(1, "n1"), (2, "n2"), (3, "n3")] {
+            let write = LwwMessage::Write {
+                key: 7,
+                value: msg_id,
+            };
+            network.send(request(node, msg_id, write));
+        }
+        network.run()?;
+        gossip_until_converged(&mut network)?;
+        // the tie between the fast clocks goes to the larger node id
+        assert_eq!(values(&network, 7), [Some(3); 3]);
+
+        // n1's slow clock reads earlier than the write it holds, still its next write
+        // follows that one and must win
+        time.advance(Duration::from_millis(100));
+        network.send(request("n1", 4, LwwMessage::Write { key: 7, value: 4 }));
+        network.run()?;
+        gossip_until_converged(&mut network)?;
+        assert_eq!(values(&network, 7), [Some(4); 3]);
+        Ok(())
+    }
+
+    /// Step `payload` on `node`, returning the reply.
+    fn call(node: &mut LwwNode, payload: LwwMessage) -> anyhow::Result<LwwMessage> {
+        let mut output = Vec::new();
+        node.step(request("n1", 1, payload), &mut output)?;
+        Ok(serde_json::from_slice::<Message<LwwMessage>>(&output)?
+            .body
+            .payload)
+    }
+
+    #[test]
+    fn test_history_capped_read_newest() -> anyhow::Result<()> {
+        let (tx, _) = crossbeam_channel::unbounded();
+        let init = InitBody {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string()],
+            extra: Default::default(),
+        };
+        let mut node = LwwNode::init_from(&init, tx)?;
+        // just the current version by default
+        call(&mut node, LwwMessage::Write { key: 1, value: 10 })?;
+        call(&mut node, LwwMessage::Write { key: 1, value: 11 })?;
+        match call(&mut node, LwwMessage::ReadHistory { key: 1 })? {
+            LwwMessage::ReadHistoryOk { versions } => {
+                assert_eq!(versions.len(), 1);
+                assert_eq!(versions[0].value, 11);
+            }
+            reply => anyhow::bail!("unexpected reply {reply:?}"),
+        }
+
+        node.history = History::new(3);
+        for value in 0..5 {
+            call(&mut node, LwwMessage::Write { key: 1, value })?;
+            assert!(matches!(
+                call(&mut node, LwwMessage::Read { key: 1 })?,
+                LwwMessage::ReadOk { value: read } if read == value
+            ));
+        }
+        match call(&mut node, LwwMessage::ReadHistory { key: 1 })? {
+            LwwMessage::ReadHistoryOk { versions } => {
+                let values = versions.iter().map(|v| v.value).collect::<Vec<_>>();
+                assert_eq!(values, [4, 3, 2]);
+                assert!(versions.windows(2).all(|w| w[0].timestamp > w[1].timestamp));
+            }
+            reply => anyhow::bail!("unexpected reply {reply:?}"),
+        }
+        // a key never written has no history
+        assert!(matches!(
+            call(&mut node, LwwMessage::ReadHistory { key: 2 })?,
+            LwwMessage::ReadHistoryOk { versions } if versions.is_empty()
+        ));
+        Ok(())
+    }
+}
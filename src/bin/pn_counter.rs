@@ -0,0 +1,202 @@
+use std::io::Write;
+
+use anyhow::Context;
+use rustgen::{
+    crdt::{Mergeable, PnCounter},
+    gossip::{self, Gossip},
+    main_loop, Body, IdGen, Message,
+};
+use serde::{Deserialize, Serialize};
+
+/// The `counter` workload, but `delta` may be negative.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum PnMessage {
+    Add { delta: i64 },
+    AddOk,
+    Read,
+    ReadOk { value: i64 },
+    Extended(GossipProtocol),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum GossipProtocol {
+    GossipAlert,
+    Gossip { counter: PnCounter },
+}
+
+/// Counts in its own slots of a `PnCounter` and gossips the whole counter to every
+/// peer, which merges it in.
+struct PnCounterNode {
+    id: String,
+    msg_ids: IdGen,
+    counter: PnCounter,
+    /// knows the last counter each peer gossiped to us
+    gossip: Gossip<PnCounter>,
+}
+
+impl PnCounterNode {
+    fn gossip_round(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let peers = self
+            .gossip
+            .round_peers(|_, known| usize::from(*known != self.counter));
+        for peer in peers {
+            Message {
+                src: self.id.clone(),
+                dst: peer.clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    lamport: None,
+                    op_id: None,
+                    payload: PnMessage::Extended(GossipProtocol::Gossip {
+                        counter: self.counter.clone(),
+                    }),
+                },
+            }
+            .send(output)
+            .with_context(|| format!("send gossip to {peer}"))?
+        }
+        Ok(())
+    }
+}
+
+impl rustgen::Node<PnMessage> for PnCounterNode {
+    fn init_from(
+        init_msg: &rustgen::InitBody,
+        tx: crossbeam_channel::Sender<Message<PnMessage>>,
+    ) -> anyhow::Result<Self> {
+        let gossip = Gossip::start(init_msg, gossip::interval_from_env(), tx, || {
+            PnMessage::Extended(GossipProtocol::GossipAlert)
+        });
+        Ok(Self {
+            id: init_msg.node_id.clone(),
+            msg_ids: IdGen::default(),
+            counter: PnCounter::default(),
+            gossip,
+        })
+    }
+
+    fn step(
+        &mut self,
+        req: rustgen::Message<PnMessage>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        match req.body.payload {
+            PnMessage::Add { delta } => {
+                self.counter.add(&self.id, delta);
+                req.reply_with(&self.msg_ids, PnMessage::AddOk)
+                    .send(output)?
+            }
+            PnMessage::Read => req
+                .reply_with(
+                    &self.msg_ids,
+                    PnMessage::ReadOk {
+                        value: self.counter.value(),
+                    },
+                )
+                .send(output)?,
+            PnMessage::Extended(GossipProtocol::GossipAlert) => {
+                if let Some(_round) = self.gossip.on_alert() {
+                    self.gossip_round(output)?
+                }
+            }
+            PnMessage::Extended(GossipProtocol::Gossip { counter }) => {
+                self.gossip.on_gossip(&req.src, counter.clone());
+                self.counter.merge(counter)
+            }
+            PnMessage::AddOk | PnMessage::ReadOk { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Every peer last gossiped exactly the counter we hold.
+    fn converged(&self) -> bool {
+        self.gossip
+            .peers()
+            .all(|peer| self.gossip.known(peer) == Some(&self.counter))
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<PnMessage, PnCounterNode>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use rustgen::{Body, InitBody, Message, Node};
+
+    use crate::{GossipProtocol, PnCounterNode, PnMessage};
+
+    fn new_node(node_id: &str) -> anyhow::Result<PnCounterNode> {
+        let (tx, _) = crossbeam_channel::unbounded();
+        PnCounterNode::init_from(
+            &InitBody {
+                node_id: node_id.to_string(),
+                node_ids: vec!["n1".to_string(), "n2".to_string()],
+                extra: Default::default(),
+            },
+            tx,
+        )
+    }
+
+    fn message(src: &str, payload: PnMessage) -> Message<PnMessage> {
+        Message {
+            src: src.to_string(),
+            dst: Default::default(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload,
+            },
+        }
+    }
+
+    /// Run a gossip round on `from`, delivering it to `to`.
+    fn gossip(from: &mut PnCounterNode, to: &mut PnCounterNode) -> anyhow::Result<()> {
+        let mut output = Vec::new();
+        let alert = message("", PnMessage::Extended(GossipProtocol::GossipAlert));
+        from.step(alert, &mut output)?;
+        for gossip in serde_json::Deserializer::from_slice(&output).into_iter() {
+            to.step(gossip?, &mut Vec::new())?;
+        }
+        Ok(())
+    }
+
+    fn read(node: &mut PnCounterNode) -> anyhow::Result<i64> {
+        let mut output = Vec::new();
+        node.step(message("c1", PnMessage::Read), &mut output)?;
+        match serde_json::from_slice::<Message<PnMessage>>(&output)?
+            .body
+            .payload
+        {
+            PnMessage::ReadOk { value } => Ok(value),
+            payload => anyhow::bail!("unexpected reply {payload:?}"),
+        }
+    }
+
+    #[test]
+    fn test_concurrent_adds_converge() -> anyhow::Result<()> {
+        let (mut n1, mut n2) = (new_node("n1")?, new_node("n2")?);
+        let add = |delta| message("c1", PnMessage::Add { delta });
+        n1.step(add(5), &mut Vec::new())?;
+        n2.step(add(-7), &mut Vec::new())?;
+        n1.step(add(-2), &mut Vec::new())?;
+        n2.step(add(1), &mut Vec::new())?;
+        assert_eq!(read(&mut n1)?, 3);
+        assert_eq!(read(&mut n2)?, -6);
+
+        gossip(&mut n1, &mut n2)?;
+        gossip(&mut n2, &mut n1)?;
+        assert_eq!(read(&mut n1)?, -3);
+        assert_eq!(read(&mut n2)?, -3);
+        // n1 hasn't heard that n2 holds its latest yet
+        gossip(&mut n1, &mut n2)?;
+        assert!(n1.converged() && n2.converged());
+        Ok(())
+    }
+}
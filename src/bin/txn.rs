@@ -0,0 +1,748 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use anyhow::Context;
+use crossbeam_channel::Sender;
+use rustgen::{
+    clock::LamportClock,
+    crdt::Mergeable,
+    gossip::{self, Gossip},
+    main_loop, Body, IdGen, MaelstromError, Message,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum TxnMessage {
+    Txn {
+        txn: Vec<Op>,
+    },
+    TxnOk {
+        txn: Vec<Op>,
+    },
+    /// `[key, value]` pairs in key order of what every committed transaction wrote,
+    /// to debug isolation
+    ReadCommitted,
+    ReadCommittedOk {
+        values: Vec<(usize, usize)>,
+    },
+    /// the committed values overlaid with the writes of the transaction in flight
+    ReadUncommitted,
+    ReadUncommittedOk {
+        values: Vec<(usize, usize)>,
+    },
+    Extended(GossipProtocol),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum GossipProtocol {
+    GossipAlert,
+    Gossip {
+        writes: Vec<Write>,
+    },
+    /// the writes of a gossip, which the receiver now holds
+    GossipOk {
+        writes: Vec<Write>,
+    },
+    /// install the next chunk of the commit in progress, sent to ourselves
+    CommitNext,
+}
+
+/// `["r", key, null]` or `["w", key, value]`, a read is replied with the value filled
+/// in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Op(OpKind, usize, Option<usize>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum OpKind {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+}
+
+/// A write as gossiped, the later stamp wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Write {
+    key: usize,
+    value: usize,
+    stamp: Stamp,
+}
+
+/// Logical time of a write, ties broken by the writing node.
+type Stamp = (u64, String);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stamped {
+    value: usize,
+    stamp: Stamp,
+}
+
+/// Last-writer-wins registers. Merging keeps the write with the later stamp per key,
+/// so nodes applying the same writes in any order end with the same values.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Registers(HashMap<usize, Stamped>);
+
+impl Registers {
+    fn get(&self, key: usize) -> Option<usize> {
+        self.0.get(&key).map(|held| held.value)
+    }
+
+    fn values(&self) -> BTreeMap<usize, usize> {
+        self.0
+            .iter()
+            .map(|(key, held)| (*key, held.value))
+            .collect()
+    }
+
+    fn apply(&mut self, write: Write) {
+        let newer = self
+            .0
+            .get(&write.key)
+            .is_none_or(|held| held.stamp < write.stamp);
+        if newer {
+            let stamped = Stamped {
+                value: write.value,
+                stamp: write.stamp,
+            };
+            self.0.insert(write.key, stamped);
+        }
+    }
+
+    /// The writes `other` doesn't hold yet.
+    fn newer_than<'a>(&'a self, other: &'a Registers) -> impl Iterator<Item = Write> + 'a {
+        self.0
+            .iter()
+            .filter(|(key, mine)| {
+                other
+                    .0
+                    .get(key)
+                    .is_none_or(|theirs| theirs.stamp < mine.stamp)
+            })
+            .map(|(key, stamped)| Write {
+                key: *key,
+                value: stamped.value,
+                stamp: stamped.stamp.clone(),
+            })
+    }
+}
+
+impl Mergeable for Registers {
+    fn merge(&mut self, other: Self) {
+        for (key, stamped) in other.0 {
+            self.apply(Write {
+                key,
+                value: stamped.value,
+                stamp: stamped.stamp,
+            });
+        }
+    }
+}
+
+/// Every committed version of each key, tagged with the sequence number of the commit
+/// which installed it. A read at snapshot `s` sees the latest stamped version among
+/// those installed by commits up to `s`, so a commit installed piece by piece stays
+/// invisible until it's whole.
+#[derive(Debug, Default)]
+struct Versions(HashMap<usize, Vec<(u64, Stamped)>>);
+
+impl Versions {
+    fn install(&mut self, seq: u64, write: Write) {
+        let stamped = Stamped {
+            value: write.value,
+            stamp: write.stamp,
+        };
+        self.0.entry(write.key).or_default().push((seq, stamped));
+    }
+
+    /// Index of the version of `versions` visible at `snapshot`.
+    fn visible(versions: &[(u64, Stamped)], snapshot: u64) -> Option<usize> {
+        versions
+            .iter()
+            .enumerate()
+            .filter(|(_, (seq, _))| *seq <= snapshot)
+            .max_by(|(_, (_, a)), (_, (_, b))| a.stamp.cmp(&b.stamp))
+            .map(|(i, _)| i)
+    }
+
+    fn read(&self, key: usize, snapshot: u64) -> Option<usize> {
+        let versions = self.0.get(&key)?;
+        Self::visible(versions, snapshot).map(|i| versions[i].1.value)
+    }
+
+    fn values(&self, snapshot: u64) -> BTreeMap<usize, usize> {
+        self.0
+            .keys()
+            .filter_map(|key| Some((*key, self.read(*key, snapshot)?)))
+            .collect()
+    }
+
+    /// Drop the versions no reader at `oldest` or later can see: per key, whatever
+    /// was installed up to `oldest` except the version visible there.
+    fn collect_garbage(&mut self, oldest: u64) {
+        for versions in self.0.values_mut() {
+            let Some(keep) = Self::visible(versions, oldest) else {
+                continue;
+            };
+            let mut i = 0;
+            versions.retain(|(seq, _)| {
+                let retained = *seq > oldest || i == keep;
+                i += 1;
+                retained
+            });
+        }
+    }
+}
+
+/// A transaction whose writes are being installed, a chunk per step so the reads
+/// arriving meanwhile aren't held up behind it.
+#[derive(Debug)]
+struct Commit {
+    seq: u64,
+    /// writes not installed yet
+    remaining: Vec<Write>,
+    /// all of them, merged into `registers` once installed
+    writes: Registers,
+    /// the transaction's reply, sent once it's installed
+    reply: Option<Message<TxnMessage>>,
+}
+
+impl FromIterator<Write> for Registers {
+    fn from_iter<T: IntoIterator<Item = Write>>(writes: T) -> Self {
+        let mut registers = Registers::default();
+        writes.into_iter().for_each(|write| registers.apply(write));
+        registers
+    }
+}
+
+/// Applies transactions locally right away, totally available, and replicates the
+/// writes to the other nodes by gossip. A transaction's reads see its own earlier
+/// writes; other nodes see them at the next gossip round, not necessarily together.
+///
+/// Reads go to a snapshot of `versions`, the last commit installed whole. A large
+/// commit is installed `commit_chunk` writes per step, reads meanwhile see the values
+/// from before it; transactions writing wait for it in `waiting`.
+struct TxnNode {
+    id: String,
+    msg_ids: IdGen,
+    tx: Sender<Message<TxnMessage>>,
+    /// what committed transactions and gossip wrote, as gossiped
+    registers: Registers,
+    /// what reads see, at snapshot `visible`
+    versions: Versions,
+    /// sequence number of the last commit, local or from gossip
+    last_seq: u64,
+    /// every commit up to this one is installed whole
+    visible: u64,
+    committing: Option<Commit>,
+    waiting: VecDeque<Message<TxnMessage>>,
+    commit_chunk: usize,
+    /// the writes of the transaction being executed, staged until it commits
+    in_flight: Option<Registers>,
+    /// Lamport clock stamping our writes, past every stamp seen so far
+    clock: LamportClock,
+    /// knows which writes each peer holds, as far as its gossip and acks told us
+    gossip: Gossip<Registers>,
+    rounds: usize,
+}
+
+impl TxnNode {
+    /// Every this many rounds a peer gets all our writes, in case gossip was lost.
+    const FULL_SYNC_EVERY: usize = 10;
+
+    /// Writes a commit installs per step.
+    const COMMIT_CHUNK: usize = 64;
+
+    /// Run `txn`, its writes staged for `commit`.
+    fn execute(&mut self, txn: Vec<Op>) -> Vec<Op> {
+        self.begin();
+        txn.into_iter().map(|op| self.apply(op)).collect()
+    }
+
+    fn begin(&mut self) {
+        self.in_flight = Some(Registers::default());
+    }
+
+    /// Run `op` in the transaction in flight, its writes staged until `commit`.
+    fn apply(&mut self, Op(kind, key, value): Op) -> Op {
+        let staged = self.in_flight.get_or_insert_with(Registers::default);
+        match kind {
+            OpKind::Read => {
+                let read = staged
+                    .get(key)
+                    .or_else(|| self.versions.read(key, self.visible));
+                Op(kind, key, read)
+            }
+            OpKind::Write => {
+                // `step` turns a write without a value down before it runs
+                if let Some(value) = value {
+                    staged.apply(Write {
+                        key,
+                        value,
+                        stamp: (self.clock.tick(), self.id.clone()),
+                    });
+                }
+                Op(kind, key, value)
+            }
+        }
+    }
+
+    /// Install the staged writes, sending `reply` once they're all in. A commit of
+    /// more than `commit_chunk` writes goes on in later steps.
+    fn commit(
+        &mut self,
+        reply: Option<Message<TxnMessage>>,
+        output: &mut impl std::io::Write,
+    ) -> anyhow::Result<()> {
+        let staged = self.in_flight.take().unwrap_or_default();
+        let mut remaining = staged.newer_than(&Registers::default()).collect::<Vec<_>>();
+        remaining.sort_unstable_by_key(|write| write.key);
+        self.last_seq += 1;
+        self.committing = Some(Commit {
+            seq: self.last_seq,
+            remaining,
+            writes: staged,
+            reply,
+        });
+        self.install_chunk(output)
+    }
+
+    fn install_chunk(&mut self, output: &mut impl std::io::Write) -> anyhow::Result<()> {
+        let Some(commit) = &mut self.committing else {
+            return Ok(());
+        };
+        let chunk = commit.remaining.len().min(self.commit_chunk);
+        for write in commit.remaining.drain(..chunk) {
+            self.versions.install(commit.seq, write);
+        }
+        if !commit.remaining.is_empty() {
+            let next = Message::internal(TxnMessage::Extended(GossipProtocol::CommitNext));
+            return self.tx.send(next).context("commit the next chunk");
+        }
+
+        let commit = self.committing.take().expect("commit in progress");
+        self.registers.merge(commit.writes);
+        // gossip installed meanwhile is whole already
+        self.visible = self.last_seq;
+        self.versions.collect_garbage(self.visible);
+        if let Some(reply) = commit.reply {
+            reply.send(output)?;
+        }
+        while self.committing.is_none() {
+            match self.waiting.pop_front() {
+                Some(req) => rustgen::Node::step(self, req, output)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn uncommitted(&self) -> BTreeMap<usize, usize> {
+        let mut values = self.versions.values(self.visible);
+        if let Some(commit) = &self.committing {
+            values.extend(commit.writes.values());
+        }
+        if let Some(staged) = &self.in_flight {
+            values.extend(staged.values());
+        }
+        values
+    }
+
+    fn gossip_round(&mut self, output: &mut impl std::io::Write) -> anyhow::Result<()> {
+        self.rounds += 1;
+        let full = self.rounds.is_multiple_of(Self::FULL_SYNC_EVERY);
+        let registers = &self.registers;
+        let peers = self
+            .gossip
+            .round_peers(|_, known| registers.newer_than(known).count());
+        for peer in peers {
+            let writes = match self.gossip.known(&peer) {
+                Some(known) if !full => self.registers.newer_than(known).collect::<Vec<_>>(),
+                _ => self.registers.newer_than(&Registers::default()).collect(),
+            };
+            if writes.is_empty() {
+                continue;
+            }
+            Message {
+                src: self.id.clone(),
+                dst: peer.clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    lamport: None,
+                    op_id: None,
+                    payload: TxnMessage::Extended(GossipProtocol::Gossip {
+                        writes: writes.clone(),
+                    }),
+                },
+            }
+            .send(output)
+            .with_context(|| format!("send gossip to {peer}"))?;
+        }
+        Ok(())
+    }
+}
+
+impl rustgen::Node<TxnMessage> for TxnNode {
+    fn init_from(
+        init_msg: &rustgen::InitBody,
+        tx: crossbeam_channel::Sender<Message<TxnMessage>>,
+    ) -> anyhow::Result<Self> {
+        let gossip = Gossip::start(init_msg, gossip::interval_from_env(), tx.clone(), || {
+            TxnMessage::Extended(GossipProtocol::GossipAlert)
+        });
+        Ok(Self {
+            id: init_msg.node_id.clone(),
+            msg_ids: IdGen::default(),
+            tx,
+            registers: Registers::default(),
+            versions: Versions::default(),
+            last_seq: 0,
+            visible: 0,
+            committing: None,
+            waiting: VecDeque::new(),
+            commit_chunk: Self::COMMIT_CHUNK,
+            in_flight: None,
+            clock: LamportClock::default(),
+            gossip,
+            rounds: 0,
+        })
+    }
+
+    fn step(
+        &mut self,
+        req: rustgen::Message<TxnMessage>,
+        output: &mut impl std::io::Write,
+    ) -> anyhow::Result<()> {
+        match req.body.payload {
+            TxnMessage::Txn { ref txn } => {
+                let unwritten = txn
+                    .iter()
+                    .find(|Op(kind, _, value)| *kind == OpKind::Write && value.is_none());
+                if let Some(Op(_, key, _)) = unwritten {
+                    return Err(MaelstromError::MalformedRequest
+                        .because(format!("write of key {key} without a value"))
+                        .into());
+                }
+                let writes = txn.iter().any(|Op(kind, ..)| *kind == OpKind::Write);
+                if writes && self.committing.is_some() {
+                    self.waiting.push_back(req);
+                    return Ok(());
+                }
+                let txn = self.execute(txn.clone());
+                let reply = req.reply_with(&self.msg_ids, TxnMessage::TxnOk { txn });
+                if writes {
+                    self.commit(Some(reply), output)?
+                } else {
+                    self.in_flight = None;
+                    reply.send(output)?
+                }
+            }
+            TxnMessage::ReadCommitted => {
+                let values = self.versions.values(self.visible).into_iter().collect();
+                req.reply_with(&self.msg_ids, TxnMessage::ReadCommittedOk { values })
+                    .send(output)?
+            }
+            TxnMessage::ReadUncommitted => {
+                let values = self.uncommitted().into_iter().collect();
+                req.reply_with(&self.msg_ids, TxnMessage::ReadUncommittedOk { values })
+                    .send(output)?
+            }
+            TxnMessage::Extended(GossipProtocol::GossipAlert) => {
+                if let Some(_round) = self.gossip.on_alert() {
+                    self.gossip_round(output)?
+                }
+            }
+            TxnMessage::Extended(GossipProtocol::Gossip { ref writes }) => {
+                if let Some(latest) = writes.iter().map(|write| write.stamp.0).max() {
+                    self.clock.observe(latest);
+                }
+                self.last_seq += 1;
+                for write in writes {
+                    self.versions.install(self.last_seq, write.clone());
+                }
+                if self.committing.is_none() {
+                    self.visible = self.last_seq;
+                    self.versions.collect_garbage(self.visible);
+                }
+                let held = writes.iter().cloned().collect::<Registers>();
+                self.gossip.on_gossip(&req.src, held.clone());
+                self.registers.merge(held);
+                let ack = GossipProtocol::GossipOk {
+                    writes: writes.clone(),
+                };
+                req.reply_with(&self.msg_ids, TxnMessage::Extended(ack))
+                    .send(output)?
+            }
+            TxnMessage::Extended(GossipProtocol::GossipOk { writes }) => self
+                .gossip
+                .on_gossip(&req.src, writes.into_iter().collect()),
+            TxnMessage::Extended(GossipProtocol::CommitNext) => self.install_chunk(output)?,
+            TxnMessage::TxnOk { .. }
+            | TxnMessage::ReadCommittedOk { .. }
+            | TxnMessage::ReadUncommittedOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<TxnMessage, TxnNode>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crossbeam_channel::Sender;
+    use rustgen::{
+        test_util::assert_wire_format, Body, InitBody, MaelstromError, Message, Node, RequestError,
+    };
+
+    use crate::{GossipProtocol, Op, OpKind, TxnMessage, TxnNode};
+
+    fn new_node(node_id: &str) -> anyhow::Result<TxnNode> {
+        let (tx, _) = crossbeam_channel::unbounded();
+        new_node_with(node_id, tx)
+    }
+
+    fn new_node_with(node_id: &str, tx: Sender<Message<TxnMessage>>) -> anyhow::Result<TxnNode> {
+        TxnNode::init_from(
+            &InitBody {
+                node_id: node_id.to_string(),
+                node_ids: vec!["n1".to_string(), "n2".to_string()],
+                extra: Default::default(),
+            },
+            tx,
+        )
+    }
+
+    fn message(src: &str, dst: &str, payload: TxnMessage) -> Message<TxnMessage> {
+        Message {
+            src: src.to_string(),
+            dst: dst.to_string(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload,
+            },
+        }
+    }
+
+    fn sent(output: &[u8]) -> anyhow::Result<Vec<Message<TxnMessage>>> {
+        serde_json::Deserializer::from_slice(output)
+            .into_iter()
+            .map(|msg| Ok(msg?))
+            .collect()
+    }
+
+    /// Run a gossip round on `from`, returning the gossip it sent.
+    fn gossip_round(from: &mut TxnNode) -> anyhow::Result<Vec<Message<TxnMessage>>> {
+        let alert = message("", "", TxnMessage::Extended(GossipProtocol::GossipAlert));
+        let mut output = Vec::new();
+        from.step(alert, &mut output)?;
+        sent(&output)
+    }
+
+    /// Run a gossip round on `from`, delivering it to `to` and the acks back.
+    fn gossip(from: &mut TxnNode, to: &mut TxnNode) -> anyhow::Result<()> {
+        for gossip in gossip_round(from)? {
+            let mut acks = Vec::new();
+            to.step(gossip, &mut acks)?;
+            for ack in sent(&acks)? {
+                from.step(ack, &mut Vec::new())?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let txn = message(
+            "c1",
+            "n1",
+            TxnMessage::Txn {
+                txn: vec![Op(OpKind::Read, 1, None), Op(OpKind::Write, 1, Some(6))],
+            },
+        );
+        assert_wire_format(
+            &txn,
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":1,"in_reply_to":null,"type":"txn","txn":[["r",1,null],["w",1,6]]}}"#,
+        );
+    }
+
+    #[test]
+    fn test_reads_see_earlier_writes_and_replicate() -> anyhow::Result<()> {
+        let (mut n1, mut n2) = (new_node("n1")?, new_node("n2")?);
+        let txn = vec![
+            Op(OpKind::Read, 1, None),
+            Op(OpKind::Write, 1, Some(3)),
+            Op(OpKind::Read, 1, None),
+            Op(OpKind::Write, 2, Some(4)),
+        ];
+        let mut output = Vec::new();
+        n1.step(message("c1", "n1", TxnMessage::Txn { txn }), &mut output)?;
+        match &sent(&output)?[0].body.payload {
+            TxnMessage::TxnOk { txn } => assert_eq!(
+                txn,
+                &[
+                    Op(OpKind::Read, 1, None),
+                    Op(OpKind::Write, 1, Some(3)),
+                    Op(OpKind::Read, 1, Some(3)),
+                    Op(OpKind::Write, 2, Some(4)),
+                ]
+            ),
+            payload => panic!("unexpected reply {payload:?}"),
+        }
+
+        // n2 wrote key 1 concurrently, the later stamp wins on both nodes
+        let write = vec![Op(OpKind::Write, 1, Some(7))];
+        n2.step(
+            message("c2", "n2", TxnMessage::Txn { txn: write }),
+            &mut Vec::new(),
+        )?;
+        gossip(&mut n1, &mut n2)?;
+        gossip(&mut n2, &mut n1)?;
+        assert_eq!(n1.registers, n2.registers);
+        assert_eq!(n1.registers.0[&2].value, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lost_gossip_sent_again_until_acked() -> anyhow::Result<()> {
+        let (mut n1, mut n2) = (new_node("n1")?, new_node("n2")?);
+        let txn = vec![Op(OpKind::Write, 1, Some(3))];
+        n1.step(
+            message("c1", "n1", TxnMessage::Txn { txn }),
+            &mut Vec::new(),
+        )?;
+
+        // the first round's gossip is lost, so the next one carries the write again
+        assert_eq!(gossip_round(&mut n1)?.len(), 1);
+        let resent = gossip_round(&mut n1)?;
+        assert!(matches!(
+            &resent[..],
+            [Message {
+                body: Body {
+                    payload: TxnMessage::Extended(GossipProtocol::Gossip { writes }),
+                    ..
+                },
+                ..
+            }] if writes.len() == 1
+        ));
+
+        // once n2 acked it, n1 knows n2 holds the write
+        let mut acks = Vec::new();
+        n2.step(resent[0].clone(), &mut acks)?;
+        for ack in sent(&acks)? {
+            n1.step(ack, &mut Vec::new())?;
+        }
+        assert_eq!(n2.registers, n1.registers);
+        assert!(gossip_round(&mut n1)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_without_value_is_malformed() -> anyhow::Result<()> {
+        let mut node = new_node("n1")?;
+        let txn = vec![Op(OpKind::Write, 1, Some(3)), Op(OpKind::Write, 2, None)];
+        let mut output = Vec::new();
+        let err = node
+            .step(message("c1", "n1", TxnMessage::Txn { txn }), &mut output)
+            .expect_err("a write without a value is rejected");
+        // answered with code 12 by the loop, nothing of the transaction staged
+        assert_eq!(
+            err.downcast_ref::<RequestError>().map(|error| error.code),
+            Some(MaelstromError::MalformedRequest)
+        );
+        assert!(output.is_empty());
+        assert!(node.in_flight.is_none());
+        assert_eq!(node.uncommitted().len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncommitted_writes_only_in_read_uncommitted() -> anyhow::Result<()> {
+        let mut node = new_node("n1")?;
+        let write = |key, value| vec![Op(OpKind::Write, key, Some(value))];
+        let txn = TxnMessage::Txn { txn: write(1, 3) };
+        node.step(message("c1", "n1", txn), &mut Vec::new())?;
+
+        let read = |node: &mut TxnNode, payload| -> anyhow::Result<_> {
+            let mut output = Vec::new();
+            node.step(message("c1", "n1", payload), &mut output)?;
+            match sent(&output)?.remove(0).body.payload {
+                TxnMessage::ReadCommittedOk { values }
+                | TxnMessage::ReadUncommittedOk { values } => Ok(values),
+                payload => anyhow::bail!("unexpected reply {payload:?}"),
+            }
+        };
+        // a transaction in flight overwrote 1 and wrote 2
+        node.begin();
+        node.apply(Op(OpKind::Write, 1, Some(4)));
+        node.apply(Op(OpKind::Write, 2, Some(5)));
+        assert_eq!(read(&mut node, TxnMessage::ReadCommitted)?, [(1, 3)]);
+        assert_eq!(
+            read(&mut node, TxnMessage::ReadUncommitted)?,
+            [(1, 4), (2, 5)]
+        );
+
+        node.commit(None, &mut Vec::new())?;
+        assert_eq!(
+            read(&mut node, TxnMessage::ReadCommitted)?,
+            [(1, 4), (2, 5)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_during_commit_sees_snapshot() -> anyhow::Result<()> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut node = new_node_with("n1", tx)?;
+        node.commit_chunk = 2;
+        let write = |key, value| Op(OpKind::Write, key, Some(value));
+        let txn = |txn| TxnMessage::Txn { txn };
+        node.step(message("c1", "n1", txn(vec![write(1, 3)])), &mut Vec::new())?;
+
+        // five writes, installed two per step in key order
+        let large = (1..=5).map(|key| write(key, 10 + key)).collect();
+        let mut output = Vec::new();
+        node.step(message("c1", "n1", txn(large)), &mut output)?;
+        assert!(output.is_empty(), "replied before the commit is in");
+        assert_eq!(node.versions.0[&1].len(), 2);
+
+        // a read goes right through, at the snapshot from before the commit
+        node.step(
+            message("c2", "n1", txn(vec![Op(OpKind::Read, 1, None)])),
+            &mut output,
+        )?;
+        match &sent(&output)?[..] {
+            [reply] => assert!(matches!(
+                &reply.body.payload,
+                TxnMessage::TxnOk { txn } if txn == &[Op(OpKind::Read, 1, Some(3))]
+            )),
+            replies => panic!("unexpected replies {replies:?}"),
+        }
+        // a write waits for it
+        output.clear();
+        node.step(message("c3", "n1", txn(vec![write(6, 1)])), &mut output)?;
+        assert!(output.is_empty());
+
+        // the node queues the rest of the commit for itself
+        node.step(rx.try_recv()?, &mut output)?;
+        assert!(output.is_empty());
+        node.step(rx.try_recv()?, &mut output)?;
+        assert!(rx.try_recv().is_err());
+        let replies = sent(&output)?;
+        assert_eq!(replies.len(), 2, "large commit and the waiting write");
+        assert_eq!(replies[0].dst, "c1");
+        assert_eq!(replies[1].dst, "c3");
+        assert!(node.committing.is_none());
+
+        assert_eq!(node.versions.read(1, node.visible), Some(11));
+        // nobody reads below the last commit, one version per key is left
+        assert!(node.versions.0.values().all(|versions| versions.len() == 1));
+        Ok(())
+    }
+}
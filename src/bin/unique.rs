@@ -1,7 +1,6 @@
 use std::io::Write;
 
-use anyhow::Context;
-use rustgen::{main_loop, Message};
+use rustgen::{main_loop, persist::PersistentIds, Message, Request};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -9,29 +8,43 @@ use serde::{Deserialize, Serialize};
 #[serde(tag = "type")]
 enum Generation {
     Generate,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum GenerationOk {
     GenerateOk {
         #[serde(rename = "id")]
         unique_id: String,
     },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Request for Generation {
+    type Ok = GenerationOk;
+}
+
+#[derive(Debug)]
 struct UniqueNode {
     id: String,
-    msg_id: usize,
+    /// persisted under `MSG_ID_DIR` if set, so ids are never reused after a restart
+    msg_ids: PersistentIds,
 }
 
 impl rustgen::Node<Generation> for UniqueNode {
     fn init_from(
         init_msg: &rustgen::InitBody,
-        _: std::sync::mpsc::Sender<Message<Generation>>,
+        _: crossbeam_channel::Sender<Message<Generation>>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
+        let path = std::env::var("MSG_ID_DIR")
+            .ok()
+            .map(|dir| std::path::Path::new(&dir).join(format!("{}.msg_id", init_msg.node_id)));
         Ok(Self {
             id: init_msg.node_id.clone(),
-            msg_id: 1,
+            msg_ids: PersistentIds::open(path, PersistentIds::DEFAULT_CHUNK)?,
         })
     }
 
@@ -40,19 +53,13 @@ impl rustgen::Node<Generation> for UniqueNode {
         req: rustgen::Message<Generation>,
         output: &mut impl Write,
     ) -> anyhow::Result<()> {
-        let mut msg = req.into_reply(Some(&mut self.msg_id));
-        match msg.body.payload {
-            Generation::Generate => {
-                msg.body.payload = Generation::GenerateOk {
-                    unique_id: format!("{}-{}", self.id, self.msg_id),
-                };
-                msg.serialize(&mut serde_json::Serializer::new(&mut *output))
-                    .context("serialize echo_ok message failed")?;
-                output.write_all(b"\n")?;
-            }
-            Generation::GenerateOk { .. } => unreachable!(),
-        }
-        Ok(())
+        let msg_id = self.msg_ids.next_id()?;
+        let unique_id = match req.body.payload {
+            Generation::Generate => format!("{}-{}", self.id, msg_id),
+        };
+        let mut msg = req.reply_ok(None, GenerationOk::GenerateOk { unique_id });
+        msg.body.id = Some(msg_id);
+        msg.send(output)
     }
 }
 
@@ -60,3 +67,39 @@ fn main() -> anyhow::Result<()> {
     main_loop::<Generation, UniqueNode>()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use rustgen::{test_util::assert_wire_format, Body, IdGen, Message};
+
+    use crate::{Generation, GenerationOk};
+
+    #[test]
+    fn test_wire_format() {
+        let generate = Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload: Generation::Generate,
+            },
+        };
+        assert_wire_format(
+            &generate,
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":1,"in_reply_to":null,"type":"generate"}}"#,
+        );
+        let generate_ok = generate.reply_ok(
+            Some(&IdGen::starting_at(2)),
+            GenerationOk::GenerateOk {
+                unique_id: "n1-3".to_string(),
+            },
+        );
+        assert_wire_format(
+            &generate_ok,
+            r#"{"src":"n1","dest":"c1","body":{"msg_id":2,"in_reply_to":1,"type":"generate_ok","id":"n1-3"}}"#,
+        );
+    }
+}
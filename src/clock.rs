@@ -0,0 +1,194 @@
+//! Logical clocks, for ordering events across nodes without synchronized time, and
+//! the wall clock for the features stamping physical time.
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Lamport clock: every local event ticks it, every message received moves it past
+/// the sender's time, so an event which causally follows another always has a larger
+/// time. Equal times say nothing, break ties by node id if a total order is needed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LamportClock(u64);
+
+impl LamportClock {
+    /// Advance for a local event, e.g. a send, returning its time.
+    pub fn tick(&mut self) -> u64 {
+        self.0 += 1;
+        self.0
+    }
+
+    /// Advance past `remote`, the time carried by a received message.
+    pub fn observe(&mut self, remote: u64) {
+        self.0 = self.0.max(remote) + 1;
+    }
+
+    pub fn current(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Wall time in milliseconds, injected so tests control it.
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> u64;
+}
+
+/// The machine's clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX)
+    }
+}
+
+/// A clock which only moves when told to. Clones share the time, each may run ahead
+/// of it by its own offset, simulating the skew between the nodes' clocks.
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    millis: Arc<AtomicU64>,
+    offset: u64,
+}
+
+impl MockClock {
+    /// A clock sharing our time, running `offset` ahead of it.
+    pub fn with_offset(&self, offset: Duration) -> Self {
+        Self {
+            millis: Arc::clone(&self.millis),
+            offset: u64::try_from(offset.as_millis()).unwrap_or(u64::MAX),
+        }
+    }
+
+    /// Move every clock sharing our time forward.
+    pub fn advance(&self, by: Duration) {
+        let by = u64::try_from(by.as_millis()).unwrap_or(u64::MAX);
+        self.millis.fetch_add(by, AtomicOrdering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_millis(&self) -> u64 {
+        self.millis
+            .load(AtomicOrdering::Relaxed)
+            .saturating_add(self.offset)
+    }
+}
+
+/// Vector clock: a counter per node, so unlike a Lamport time it also tells when two
+/// events are concurrent. A node absent from the map is at 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock(HashMap<String, u64>);
+
+impl VectorClock {
+    /// Count an event of `node`, e.g. our own broadcast.
+    pub fn increment(&mut self, node: &str) {
+        *self.0.entry(node.to_string()).or_default() += 1;
+    }
+
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or_default()
+    }
+
+    /// Take the later counter of each node, after delivering a message stamped
+    /// `other`.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, time) in &other.0 {
+            let mine = self.0.entry(node.clone()).or_default();
+            *mine = (*mine).max(*time);
+        }
+    }
+
+    /// `Less` if `self` happened before `other`, `Greater` if after, `Equal` for the
+    /// same time, and `None` for concurrent events.
+    pub fn happens_before(&self, other: &VectorClock) -> Option<Ordering> {
+        let nodes = self.0.keys().chain(other.0.keys());
+        let (mut before, mut after) = (false, false);
+        for node in nodes {
+            match self.get(node).cmp(&other.get(node)) {
+                Ordering::Less => before = true,
+                Ordering::Greater => after = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (before, after) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (true, true) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cmp::Ordering, time::Duration};
+
+    use super::{Clock, LamportClock, MockClock, VectorClock};
+
+    #[test]
+    fn test_lamport_clock() {
+        let mut clock = LamportClock::default();
+        assert_eq!(clock.current(), 0);
+        assert_eq!(clock.tick(), 1);
+        assert_eq!(clock.tick(), 2);
+
+        // a remote time ahead of ours is jumped past
+        clock.observe(10);
+        assert_eq!(clock.current(), 11);
+        // one behind still advances
+        clock.observe(3);
+        assert_eq!(clock.current(), 12);
+        assert_eq!(clock.tick(), 13);
+    }
+
+    #[test]
+    fn test_vector_clock() -> anyhow::Result<()> {
+        let mut n1 = VectorClock::default();
+        let mut n2 = VectorClock::default();
+        assert_eq!(n1.happens_before(&n2), Some(Ordering::Equal));
+
+        n1.increment("n1");
+        assert_eq!(n1.happens_before(&n2), Some(Ordering::Greater));
+        assert_eq!(n2.happens_before(&n1), Some(Ordering::Less));
+
+        // n2 broadcasts without having seen n1's
+        n2.increment("n2");
+        assert_eq!(n1.happens_before(&n2), None);
+
+        // once delivered, n2's next event follows both
+        n2.merge(&n1);
+        n2.increment("n2");
+        assert_eq!(n1.happens_before(&n2), Some(Ordering::Less));
+        assert_eq!(n2.get("n1"), 1);
+        assert_eq!(n2.get("n2"), 2);
+
+        // a missing node reads as 0
+        let n3 = serde_json::from_str::<VectorClock>(r#"{"n1":1,"n2":2,"n3":0}"#)?;
+        assert_eq!(n2.happens_before(&n3), Some(Ordering::Equal));
+        Ok(())
+    }
+
+    #[test]
+    fn test_mock_clock_offsets_share_time() {
+        let base = MockClock::default();
+        let fast = base.with_offset(Duration::from_millis(250));
+        assert_eq!(base.now_millis(), 0);
+        assert_eq!(fast.now_millis(), 250);
+        fast.advance(Duration::from_millis(10));
+        assert_eq!(base.now_millis(), 10);
+        assert_eq!(fast.now_millis(), 260);
+    }
+}
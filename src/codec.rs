@@ -0,0 +1,49 @@
+//! How messages are turned into lines and back. Maelstrom speaks JSON, which
+//! `JsonCodec` writes; tests and benchmarks hand the loops another codec through
+//! `main_loop_with_codec` to measure what the encoding costs.
+
+use std::io::Write;
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Encodes one message per line. The loops frame the input by lines, so an encoding
+/// must never contain a newline.
+pub trait Codec: Send + Sync {
+    fn encode<T: Serialize>(&self, value: &T, output: &mut impl Write) -> anyhow::Result<()>;
+
+    fn decode<T: DeserializeOwned>(&self, line: &str) -> anyhow::Result<T>;
+}
+
+/// The JSON Maelstrom expects, the codec unless told otherwise.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T, output: &mut impl Write) -> anyhow::Result<()> {
+        serde_json::to_writer(output, value).context("encode message as json")
+    }
+
+    fn decode<T: DeserializeOwned>(&self, line: &str) -> anyhow::Result<T> {
+        Ok(serde_json::from_str(line)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{Codec, JsonCodec};
+
+    #[test]
+    fn test_json_codec_round_trip() -> anyhow::Result<()> {
+        let value = json!({"type": "echo", "echo": "a\nb"});
+        let mut line = Vec::new();
+        JsonCodec.encode(&value, &mut line)?;
+        assert!(!line.contains(&b'\n'));
+        let decoded: serde_json::Value = JsonCodec.decode(std::str::from_utf8(&line)?)?;
+        assert_eq!(decoded, value);
+        assert!(JsonCodec.decode::<serde_json::Value>("{").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+//! State-based CRDTs: replicas converge by merging each other's state in any order.
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    ops::Deref,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{clock::LamportClock, digest::Digest};
+
+/// State which converges by exchanging it in any order: merging is commutative,
+/// associative and idempotent, so lost, duplicated or reordered gossip is harmless.
+pub trait Mergeable {
+    fn merge(&mut self, other: Self);
+}
+
+impl<T: Eq + Hash> Mergeable for HashSet<T> {
+    fn merge(&mut self, other: Self) {
+        self.extend(other)
+    }
+}
+
+impl Mergeable for Digest {
+    fn merge(&mut self, other: Self) {
+        self.union(&other)
+    }
+}
+
+/// Grow-only set, merged by union. Reads go through the underlying `HashSet`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GSet<T: Eq + Hash>(HashSet<T>);
+
+impl<T: Eq + Hash> GSet<T> {
+    /// Returns whether `x` wasn't in the set yet.
+    pub fn insert(&mut self, x: T) -> bool {
+        self.0.insert(x)
+    }
+}
+
+impl<T: Eq + Hash> Default for GSet<T> {
+    fn default() -> Self {
+        Self(HashSet::new())
+    }
+}
+
+impl<T: Eq + Hash> Deref for GSet<T> {
+    type Target = HashSet<T>;
+
+    fn deref(&self) -> &HashSet<T> {
+        &self.0
+    }
+}
+
+impl<'a, T: Eq + Hash> IntoIterator for &'a GSet<T> {
+    type Item = &'a T;
+    type IntoIter = std::collections::hash_set::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl<T: Eq + Hash> Extend<T> for GSet<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.0.extend(iter)
+    }
+}
+
+impl<T: Eq + Hash> FromIterator<T> for GSet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl<T: Eq + Hash> Mergeable for GSet<T> {
+    fn merge(&mut self, other: Self) {
+        self.0.merge(other.0)
+    }
+}
+
+/// Grow-only counter: a slot per node, only ever bumped by its own node, merged by
+/// slot-wise maximum. A node missing from the map is at 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GCounter(HashMap<String, usize>);
+
+impl GCounter {
+    /// Add `delta` to the slot of `node`, which should be ourselves.
+    pub fn increment(&mut self, node: &str, delta: usize) {
+        *self.0.entry(node.to_string()).or_default() += delta;
+    }
+
+    pub fn get(&self, node: &str) -> usize {
+        self.0.get(node).copied().unwrap_or_default()
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        self.0.contains_key(node)
+    }
+
+    pub fn value(&self) -> usize {
+        self.0.values().sum()
+    }
+}
+
+impl FromIterator<(String, usize)> for GCounter {
+    fn from_iter<I: IntoIterator<Item = (String, usize)>>(slots: I) -> Self {
+        Self(slots.into_iter().collect())
+    }
+}
+
+impl Mergeable for GCounter {
+    fn merge(&mut self, other: Self) {
+        for (node, count) in other.0 {
+            let mine = self.0.entry(node).or_default();
+            *mine = (*mine).max(count);
+        }
+    }
+}
+
+/// Counter which also goes down: increments and decrements are kept in a `GCounter`
+/// each, the value is their difference.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PnCounter {
+    #[serde(rename = "p")]
+    increments: GCounter,
+    #[serde(rename = "n")]
+    decrements: GCounter,
+}
+
+impl PnCounter {
+    /// Add `delta` to the slots of `node`, which should be ourselves.
+    pub fn add(&mut self, node: &str, delta: i64) {
+        let magnitude = delta.unsigned_abs() as usize;
+        if delta < 0 {
+            self.decrements.increment(node, magnitude)
+        } else {
+            self.increments.increment(node, magnitude)
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Mergeable for PnCounter {
+    fn merge(&mut self, other: Self) {
+        self.increments.merge(other.increments);
+        self.decrements.merge(other.decrements);
+    }
+}
+
+/// Last-writer-wins register: the write with the later Lamport time wins, ties going
+/// to the larger node id, so every replica picks the same one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwRegister<V> {
+    /// `None` until first written
+    value: Option<V>,
+    timestamp: u64,
+    node: String,
+}
+
+impl<V> LwwRegister<V> {
+    pub fn get(&self) -> Option<&V> {
+        self.value.as_ref()
+    }
+
+    /// Lamport time of the write held, 0 if never written.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Node which wrote the value held, empty if never written.
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Write `value` as `node`, which should be ourselves, at the next time of `clock`.
+    /// The clock first catches up with the write held, which may have been merged from
+    /// a peer ahead of us, so the new write always supersedes it.
+    pub fn set(&mut self, node: &str, value: V, clock: &mut LamportClock) {
+        clock.observe(self.timestamp);
+        self.value = Some(value);
+        self.timestamp = clock.tick();
+        self.node = node.to_string();
+    }
+
+    /// Write `value` as `node` at `timestamp`, e.g. a `Clock`'s wall time. A clock
+    /// behind the write held is bumped past it, our write supersedes what we saw.
+    pub fn set_at(&mut self, node: &str, value: V, timestamp: u64) {
+        self.value = Some(value);
+        self.timestamp = timestamp.max(self.timestamp + 1);
+        self.node = node.to_string();
+    }
+}
+
+impl<V> Default for LwwRegister<V> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            timestamp: 0,
+            node: String::new(),
+        }
+    }
+}
+
+impl<V> Mergeable for LwwRegister<V> {
+    fn merge(&mut self, other: Self) {
+        if (other.timestamp, &other.node) > (self.timestamp, &self.node) {
+            *self = other;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::clock::LamportClock;
+
+    use super::{GCounter, GSet, LwwRegister, Mergeable, PnCounter};
+
+    #[test]
+    fn test_gset_merge_is_union() {
+        let mut a = GSet::from_iter([1, 2]);
+        let b = GSet::from_iter([2, 3]);
+        a.merge(b.clone());
+        assert_eq!(a, GSet::from_iter([1, 2, 3]));
+        // idempotent
+        a.merge(b);
+        assert_eq!(a.len(), 3);
+        assert!(!a.insert(1));
+    }
+
+    #[test]
+    fn test_gcounter_merge_is_slot_max() {
+        let mut n1 = GCounter::default();
+        let mut n2 = GCounter::default();
+        n1.increment("n1", 3);
+        n2.increment("n2", 2);
+        let stale = n1.clone();
+        n1.increment("n1", 1);
+
+        n2.merge(n1.clone());
+        // a stale state merged late doesn't roll a slot back
+        n2.merge(stale);
+        n1.merge(n2.clone());
+        assert_eq!(n1, n2);
+        assert_eq!(n1.value(), 6);
+        assert_eq!(n1.get("n1"), 4);
+        assert_eq!(n1.get("n3"), 0);
+    }
+
+    #[test]
+    fn test_pn_counter_converges() {
+        let mut n1 = PnCounter::default();
+        let mut n2 = PnCounter::default();
+        n1.add("n1", 5);
+        n2.add("n2", -3);
+        n1.add("n1", -1);
+        n2.add("n2", 1);
+        assert_eq!(n1.value(), 4);
+        assert_eq!(n2.value(), -2);
+
+        let n1_state = n1.clone();
+        n1.merge(n2.clone());
+        n2.merge(n1_state);
+        assert_eq!(n1, n2);
+        assert_eq!(n1.value(), 2);
+    }
+
+    #[test]
+    fn test_lww_register_merge_order_independent() {
+        let mut clocks = [LamportClock::default(); 3];
+        let mut writes = Vec::new();
+        for (i, clock) in clocks.iter_mut().enumerate() {
+            let mut register = LwwRegister::default();
+            register.set(&format!("n{i}"), i * 10, clock);
+            writes.push(register);
+        }
+        // n2 writes again, later than anything else
+        writes[2].set("n2", 21, &mut clocks[2]);
+
+        let orders = [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ];
+        for order in orders {
+            let mut replica = LwwRegister::default();
+            for i in order {
+                replica.merge(writes[i].clone());
+                // merging twice changes nothing
+                replica.merge(writes[i].clone());
+            }
+            assert_eq!(replica, writes[2], "merged in order {order:?}");
+        }
+
+        // same time, the larger node id wins whichever merges into which
+        let (mut a, mut b) = (writes[0].clone(), writes[1].clone());
+        assert_eq!(a.timestamp(), b.timestamp());
+        a.merge(writes[1].clone());
+        b.merge(writes[0].clone());
+        assert_eq!(a.get(), Some(&10));
+        assert_eq!(b.get(), Some(&10));
+    }
+
+    #[test]
+    fn test_set_after_merging_newer_remote_write_wins() {
+        let (mut local, mut remote) = (LamportClock::default(), LamportClock::default());
+        let mut theirs = LwwRegister::default();
+        for value in 0..5 {
+            theirs.set("n2", value, &mut remote);
+        }
+
+        let mut ours = LwwRegister::default();
+        ours.merge(theirs.clone());
+        // our clock never saw n2's writes, only the register did
+        ours.set("n1", 100, &mut local);
+        assert!(ours.timestamp() > theirs.timestamp());
+        theirs.merge(ours.clone());
+        assert_eq!(ours.get(), Some(&100));
+        assert_eq!(theirs, ours);
+    }
+}
@@ -0,0 +1,161 @@
+//! Deduplicate operations by id, for handlers whose effect isn't idempotent.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+use uuid::Uuid;
+
+use crate::clock::{Clock, SystemClock};
+
+/// The op ids seen lately, each with what applying it gave, e.g. to answer a replay
+/// the way the original was. An id is forgotten once `ttl` passed since it was first
+/// seen, or when `capacity` newer ones push it out, so a replay arriving later than
+/// that is applied again.
+pub struct SeenSet<V = ()> {
+    capacity: usize,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    seen: HashMap<Uuid, V>,
+    /// (millis first seen, id), oldest first
+    order: VecDeque<(u64, Uuid)>,
+}
+
+impl<V> SeenSet<V> {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self::with_clock(capacity, ttl, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(capacity: usize, ttl: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            capacity,
+            ttl,
+            clock,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remember `id` with `value`, `true` if it's new. A seen id keeps its value.
+    pub fn remember(&mut self, id: Uuid, value: V) -> bool {
+        let now = self.clock.now_millis();
+        self.evict(now);
+        if self.seen.contains_key(&id) {
+            return false;
+        }
+        self.seen.insert(id, value);
+        self.order.push_back((now, id));
+        if self.order.len() > self.capacity {
+            if let Some((_, oldest)) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.seen.contains_key(id)
+    }
+
+    /// What `id` was remembered with, if it's still remembered.
+    pub fn get(&self, id: &Uuid) -> Option<&V> {
+        self.seen.get(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    fn evict(&mut self, now: u64) {
+        let ttl = u64::try_from(self.ttl.as_millis()).unwrap_or(u64::MAX);
+        while let Some(&(first_seen, id)) = self.order.front() {
+            if now.saturating_sub(first_seen) < ttl {
+                break;
+            }
+            self.order.pop_front();
+            self.seen.remove(&id);
+        }
+    }
+}
+
+impl SeenSet {
+    /// Remember `id`, `true` if it's new and the operation should be applied.
+    pub fn insert(&mut self, id: Uuid) -> bool {
+        self.remember(id, ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use crate::{clock::MockClock, test_util::assert_wire_format, Body, Message};
+
+    use super::SeenSet;
+
+    #[test]
+    fn test_replayed_op_applied_once() {
+        let mut seen = SeenSet::new(16, Duration::from_secs(60));
+        let mut total = 0;
+        let op = Uuid::new_v4();
+        for op_id in [op, Uuid::new_v4(), op] {
+            if seen.insert(op_id) {
+                total += 1;
+            }
+        }
+        assert_eq!(total, 2);
+        assert!(seen.contains(&op));
+
+        // a replay finds what the original gave
+        let mut offsets = SeenSet::new(16, Duration::from_secs(60));
+        assert!(offsets.remember(op, 7));
+        assert!(!offsets.remember(op, 8));
+        assert_eq!(offsets.get(&op), Some(&7));
+    }
+
+    #[test]
+    fn test_eviction() {
+        let clock = MockClock::default();
+        let mut seen = SeenSet::with_clock(2, Duration::from_secs(1), Arc::new(clock.clone()));
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        assert!(seen.insert(a));
+        assert!(seen.insert(b));
+        // over capacity, the oldest goes
+        assert!(seen.insert(c));
+        assert!(!seen.contains(&a));
+        assert_eq!(seen.len(), 2);
+
+        clock.advance(Duration::from_secs(1));
+        assert!(seen.insert(a), "expired ids are forgotten");
+        assert_eq!(seen.len(), 1);
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let op_id = Uuid::parse_str("0a1b2c3d-4e5f-4a6b-8c7d-8e9fa0b1c2d3").unwrap();
+        let msg = Message {
+            src: "n1".to_string(),
+            dst: "n2".to_string(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: None,
+                lamport: None,
+                op_id: Some(op_id),
+                payload: json!({"delta": 1, "type": "add"}),
+            },
+        };
+        assert_wire_format(
+            &msg,
+            r#"{"src":"n1","dest":"n2","body":{"msg_id":1,"in_reply_to":null,"op_id":"0a1b2c3d-4e5f-4a6b-8c7d-8e9fa0b1c2d3","delta":1,"type":"add"}}"#,
+        );
+    }
+}
@@ -0,0 +1,474 @@
+//! Helpers for anti-entropy: finding what a peer misses, and telling cheaply
+//! whether two message sets differ at all.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// `a - b` for sorted, deduplicated slices, in a single linear pass.
+pub fn sorted_difference(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let mut diff = Vec::new();
+    let mut b = b.iter().peekable();
+    for x in a {
+        while b.next_if(|y| *y < x).is_some() {}
+        if b.peek() != Some(&x) {
+            diff.push(*x);
+        }
+    }
+    diff
+}
+
+/// Order independent summary of a set of integers. Equal sets always have equal
+/// fingerprints, different sets almost surely don't, so peers can compare these
+/// before shipping the sets themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub struct Fingerprint {
+    len: usize,
+    hash: u64,
+}
+
+impl Fingerprint {
+    pub fn of<'a>(set: impl IntoIterator<Item = &'a usize>) -> Self {
+        set.into_iter().fold(Self::default(), |mut fp, x| {
+            fp.insert(*x);
+            fp
+        })
+    }
+
+    /// Account for one more element, which must not be in the set already.
+    pub fn insert(&mut self, x: usize) {
+        self.len += 1;
+        self.hash = self.hash.wrapping_add(mix(x as u64));
+    }
+
+    pub fn differs(&self, other: &Fingerprint) -> bool {
+        self != other
+    }
+}
+
+/// Set of integers stored as disjoint inclusive ranges, so the mostly consecutive ids
+/// of a run stay a handful of entries. Serialized as a list of `[start, end]` pairs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Digest {
+    /// start -> end, ranges neither overlap nor touch
+    ranges: BTreeMap<usize, usize>,
+    len: usize,
+}
+
+impl Digest {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains(&self, x: &usize) -> bool {
+        self.ranges
+            .range(..=x)
+            .next_back()
+            .is_some_and(|(_, end)| x <= end)
+    }
+
+    /// Returns whether `x` wasn't in the set yet.
+    pub fn insert(&mut self, x: usize) -> bool {
+        if self.contains(&x) {
+            return false;
+        }
+        let before = self
+            .ranges
+            .range(..x)
+            .next_back()
+            .filter(|(_, end)| **end + 1 == x)
+            .map(|(start, _)| *start);
+        let after = x.checked_add(1).and_then(|next| self.ranges.remove(&next));
+        self.ranges.insert(before.unwrap_or(x), after.unwrap_or(x));
+        self.len += 1;
+        true
+    }
+
+    /// Returns whether `x` was in the set.
+    pub fn remove(&mut self, x: &usize) -> bool {
+        let Some((&start, &end)) = self.ranges.range(..=x).next_back() else {
+            return false;
+        };
+        if end < *x {
+            return false;
+        }
+        self.ranges.remove(&start);
+        if start < *x {
+            self.ranges.insert(start, x - 1);
+        }
+        if *x < end {
+            self.ranges.insert(x + 1, end);
+        }
+        self.len -= 1;
+        true
+    }
+
+    /// Ascending elements.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + Clone + '_ {
+        self.ranges.iter().flat_map(|(start, end)| *start..=*end)
+    }
+
+    /// `self - other`, ascending.
+    pub fn difference<'a>(&'a self, other: &'a Digest) -> impl Iterator<Item = usize> + 'a {
+        self.iter().filter(|x| !other.contains(x))
+    }
+
+    /// Add everything in `other`, range by range: the cost follows the number of
+    /// ranges, not of ids.
+    pub fn union(&mut self, other: &Digest) {
+        for (start, end) in &other.ranges {
+            self.insert_range(*start, *end);
+        }
+    }
+
+    /// `self - other` as a digest, built range by range like `union`.
+    pub fn subtract(&self, other: &Digest) -> Digest {
+        let mut rest = Digest::default();
+        let mut keep = |start: usize, end: usize| {
+            rest.ranges.insert(start, end);
+            rest.len += end - start + 1;
+        };
+        for (&start, &end) in &self.ranges {
+            // the ranges of `other` overlapping this one, the last first
+            let mut overlapping = other
+                .ranges
+                .range(..=end)
+                .rev()
+                .take_while(|(_, other_end)| **other_end >= start)
+                .collect::<Vec<_>>();
+            let mut from = Some(start);
+            while let (Some(next), Some((other_start, other_end))) = (from, overlapping.pop()) {
+                if next < *other_start {
+                    keep(next, other_start - 1);
+                }
+                from = other_end.checked_add(1);
+            }
+            if let Some(next) = from.filter(|next| *next <= end) {
+                keep(next, end);
+            }
+        }
+        rest
+    }
+
+    /// Insert `start..=end`, merging the ranges it overlaps or touches.
+    fn insert_range(&mut self, start: usize, end: usize) {
+        let absorbed = self
+            .ranges
+            .range(..=end.saturating_add(1))
+            .rev()
+            .take_while(|(_, other_end)| **other_end >= start.saturating_sub(1))
+            .map(|(start, end)| (*start, *end))
+            .collect::<Vec<_>>();
+        let (mut first, mut last) = (start, end);
+        for (start, end) in absorbed {
+            self.ranges.remove(&start);
+            self.len -= end - start + 1;
+            first = first.min(start);
+            last = last.max(end);
+        }
+        self.ranges.insert(first, last);
+        self.len += last - first + 1;
+    }
+}
+
+impl Extend<usize> for Digest {
+    fn extend<T: IntoIterator<Item = usize>>(&mut self, iter: T) {
+        iter.into_iter().for_each(|x| {
+            self.insert(x);
+        });
+    }
+}
+
+impl<'a> Extend<&'a usize> for Digest {
+    fn extend<T: IntoIterator<Item = &'a usize>>(&mut self, iter: T) {
+        self.extend(iter.into_iter().copied())
+    }
+}
+
+impl FromIterator<usize> for Digest {
+    fn from_iter<T: IntoIterator<Item = usize>>(iter: T) -> Self {
+        let mut digest = Self::default();
+        digest.extend(iter);
+        digest
+    }
+}
+
+impl Serialize for Digest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.ranges.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for Digest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut ranges = Vec::<(usize, usize)>::deserialize(deserializer)?;
+        if let Some((start, end)) = ranges.iter().find(|(start, end)| start > end) {
+            return Err(serde::de::Error::custom(format!(
+                "empty digest range [{start}, {end}]"
+            )));
+        }
+        // built range by range, a single huge range costs no more than a small one
+        ranges.sort_unstable();
+        let mut merged = BTreeMap::<usize, usize>::new();
+        let mut last: Option<(usize, usize)> = None;
+        for (start, end) in ranges {
+            last = match last {
+                // overlapping or touching the previous range
+                Some((first, previous))
+                    if previous.checked_add(1).is_none_or(|next| start <= next) =>
+                {
+                    Some((first, previous.max(end)))
+                }
+                Some((first, previous)) => {
+                    merged.insert(first, previous);
+                    Some((start, end))
+                }
+                None => Some((start, end)),
+            };
+        }
+        merged.extend(last);
+        let len = merged
+            .iter()
+            .try_fold(0usize, |len, (start, end)| {
+                (end - start).checked_add(1)?.checked_add(len)
+            })
+            .ok_or_else(|| serde::de::Error::custom("digest holds more than usize::MAX ids"))?;
+        Ok(Self {
+            ranges: merged,
+            len,
+        })
+    }
+}
+
+/// Per bucket fingerprints of a set, the id space cut into buckets of `width` ids.
+///
+/// Two nodes reconcile by comparing these and only shipping the buckets whose
+/// fingerprints differ, which is next to nothing when their sets are mostly the same.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleDigest {
+    width: usize,
+    /// empty buckets are left out; serialized as `[bucket, fingerprint]` pairs since
+    /// integer map keys don't survive the flattened message payload
+    #[serde(with = "bucket_pairs")]
+    buckets: BTreeMap<usize, Fingerprint>,
+}
+
+mod bucket_pairs {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::Fingerprint;
+
+    pub fn serialize<S: Serializer>(
+        buckets: &BTreeMap<usize, Fingerprint>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(buckets)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<usize, Fingerprint>, D::Error> {
+        Ok(Vec::<(usize, Fingerprint)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+impl MerkleDigest {
+    pub const DEFAULT_WIDTH: usize = 64;
+
+    pub fn of<'a>(set: impl IntoIterator<Item = &'a usize>, width: usize) -> Self {
+        assert!(width > 0, "bucket width should be positive");
+        let mut buckets = BTreeMap::<usize, Fingerprint>::new();
+        for x in set {
+            buckets.entry(x / width).or_default().insert(*x);
+        }
+        Self { width, buckets }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Buckets whose content differs between the two digests, which must share a width.
+    pub fn differing_buckets(&self, other: &MerkleDigest) -> Vec<usize> {
+        assert_eq!(self.width, other.width, "digests bucket differently");
+        let mut buckets = self
+            .buckets
+            .keys()
+            .chain(other.buckets.keys())
+            .filter(|bucket| self.buckets.get(bucket) != other.buckets.get(bucket))
+            .copied()
+            .collect::<Vec<_>>();
+        buckets.sort_unstable();
+        buckets.dedup();
+        buckets
+    }
+
+    /// Whether `x` falls into one of the sorted `buckets`.
+    pub fn in_buckets(&self, buckets: &[usize], x: usize) -> bool {
+        buckets.binary_search(&(x / self.width)).is_ok()
+    }
+}
+
+/// splitmix64 finalizer, spreads consecutive ids over the whole u64 range
+pub(crate) fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::{sorted_difference, Digest, Fingerprint, MerkleDigest};
+
+    #[test]
+    fn test_sorted_difference() {
+        let a = [1, 3, 4, 7, 9, 10];
+        let b = [0, 3, 5, 7, 10, 12];
+        assert_eq!(sorted_difference(&a, &b), [1, 4, 9]);
+        assert_eq!(sorted_difference(&a, &[]), a);
+        assert!(sorted_difference(&[], &b).is_empty());
+
+        let a = (0..1000).step_by(3).collect::<Vec<usize>>();
+        let b = (0..1000).step_by(5).collect::<Vec<usize>>();
+        let expected = a
+            .iter()
+            .collect::<HashSet<_>>()
+            .difference(&b.iter().collect())
+            .map(|x| **x)
+            .collect::<HashSet<usize>>();
+        let diff = sorted_difference(&a, &b);
+        assert_eq!(diff.iter().copied().collect::<HashSet<_>>(), expected);
+        assert!(diff.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_digest_difference() {
+        let mine = [1, 2, 3, 4, 7, 8, 20].into_iter().collect::<Digest>();
+        let theirs = [0, 2, 3, 8, 9].into_iter().collect::<Digest>();
+        assert_eq!(mine.difference(&theirs).collect::<Vec<_>>(), [1, 4, 7, 20]);
+        assert_eq!(theirs.difference(&mine).collect::<Vec<_>>(), [0, 9]);
+        assert_eq!(mine.difference(&Digest::default()).count(), mine.len());
+
+        let mut digest = (0..10).collect::<Digest>();
+        assert!(digest.remove(&5));
+        assert!(!digest.remove(&5));
+        assert!(!digest.contains(&5));
+        assert_eq!(digest.len(), 9);
+        assert!(digest.insert(5));
+        assert_eq!(digest, (0..10).rev().collect());
+    }
+
+    #[test]
+    fn test_digest_set_ops() {
+        let mine = (0..10)
+            .chain(20..30)
+            .chain([40, 45, 50])
+            .collect::<Digest>();
+        let theirs = (5..22).chain(29..41).chain([50, 60]).collect::<Digest>();
+        let as_set = |digest: &Digest| digest.iter().collect::<HashSet<_>>();
+
+        let mut union = mine.clone();
+        union.union(&theirs);
+        assert_eq!(as_set(&union), &as_set(&mine) | &as_set(&theirs));
+        assert_eq!(union.len(), as_set(&union).len());
+        // touching ranges are merged, like `insert` does
+        assert_eq!(union, (0..41).chain([45, 50, 60]).collect());
+
+        let rest = mine.subtract(&theirs);
+        assert_eq!(rest, mine.difference(&theirs).collect());
+        assert_eq!(rest.len(), rest.iter().count());
+        assert_eq!(theirs.subtract(&mine), theirs.difference(&mine).collect());
+        assert!(mine.subtract(&union).is_empty());
+        assert_eq!(mine.subtract(&Digest::default()), mine);
+
+        // whole ranges at a time, even at the end of the id space
+        let ranges = |json: String| serde_json::from_str::<Digest>(&json).unwrap();
+        let max = usize::MAX;
+        let huge = ranges(format!("[[0,{}]]", max - 1));
+        let holes = ranges(format!("[[1,9],[100,{}]]", max - 2));
+        let rest = huge.subtract(&holes);
+        assert_eq!(rest, ranges(format!("[[0,0],[10,99],[{0},{0}]]", max - 1)));
+        assert_eq!(rest.len(), 92);
+        let mut refilled = holes;
+        refilled.union(&rest);
+        assert_eq!(refilled, huge);
+        assert_eq!(refilled.len(), max);
+    }
+
+    #[test]
+    fn test_digest_serialization() -> anyhow::Result<()> {
+        let digest = (0..1000).chain([2000, 2001, 5000]).collect::<Digest>();
+        let encoded = serde_json::to_string(&digest)?;
+        assert_eq!(encoded, "[[0,999],[2000,2001],[5000,5000]]");
+        assert_eq!(serde_json::from_str::<Digest>(&encoded)?, digest);
+        assert!(serde_json::from_str::<Digest>("[[3,1]]").is_err());
+
+        // overlapping and unordered ranges are coalesced
+        let digest = serde_json::from_str::<Digest>("[[5,9],[0,3],[4,6],[20,20]]")?;
+        assert_eq!(digest, (0..10).chain([20]).collect());
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_huge_range() -> anyhow::Result<()> {
+        let max = usize::MAX;
+        // would take forever to expand id by id
+        let digest = serde_json::from_str::<Digest>(&format!("[[0,{}]]", max - 1))?;
+        assert_eq!(digest.len(), max);
+        assert!(digest.contains(&(max - 1)));
+        assert!(!digest.contains(&max));
+        assert_eq!(
+            serde_json::to_string(&digest)?,
+            format!("[[0,{}]]", max - 1)
+        );
+        // one more id than a usize counts
+        assert!(serde_json::from_str::<Digest>(&format!("[[0,{max}]]")).is_err());
+        assert!(
+            serde_json::from_str::<Digest>(&format!("[[0,{}],[{max},{max}]]", max - 1)).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merkle_digest() -> anyhow::Result<()> {
+        let mine = (0..1000).collect::<Vec<usize>>();
+        let mut theirs = mine.clone();
+        theirs[500] = 5000;
+        let digest = |set: &[usize]| MerkleDigest::of(set, 64);
+        assert!(digest(&mine).differing_buckets(&digest(&mine)).is_empty());
+
+        let differing = digest(&mine).differing_buckets(&digest(&theirs));
+        assert_eq!(differing, [500 / 64, 5000 / 64]);
+        let encoded = serde_json::to_string(&digest(&theirs))?;
+        assert_eq!(
+            serde_json::from_str::<MerkleDigest>(&encoded)?,
+            digest(&theirs)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let set = (0..500).collect::<Vec<usize>>();
+        let shuffled = set.iter().rev().copied().collect::<HashSet<usize>>();
+        assert!(!Fingerprint::of(&set).differs(&Fingerprint::of(&shuffled)));
+
+        let mut diverged = shuffled.clone();
+        diverged.remove(&42);
+        diverged.insert(4242);
+        assert!(Fingerprint::of(&set).differs(&Fingerprint::of(&diverged)));
+        diverged.remove(&4242);
+        assert!(Fingerprint::of(&set).differs(&Fingerprint::of(&diverged)));
+    }
+}
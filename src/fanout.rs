@@ -0,0 +1,85 @@
+//! Serializing a batch of messages, e.g. a gossip round's, on rayon's thread pool,
+//! with the `parallel` feature. The writes to the single output stay sequential, only
+//! the JSON encoding is spread.
+
+use std::io::Write;
+
+use anyhow::Context;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::Message;
+
+/// Each of `msgs` as the line `Message::send` would write, newline included, in the
+/// same order. The pool's threads live across calls, a round spawns none.
+pub fn parallel_serialize<M: Serialize + Sync>(
+    msgs: &[Message<M>],
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    msgs.par_iter().map(serialize_line).collect()
+}
+
+/// Write the lines `parallel_serialize` returned, in order.
+pub fn write_lines(lines: &[Vec<u8>], output: &mut impl Write) -> anyhow::Result<()> {
+    lines
+        .iter()
+        .try_for_each(|line| output.write_all(line).context("write serialized message"))
+}
+
+fn serialize_line<M: Serialize>(msg: &Message<M>) -> anyhow::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(msg).context("serialize message")?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use serde::Serialize;
+
+    use crate::{Body, Message};
+
+    use super::{parallel_serialize, write_lines};
+
+    #[derive(Serialize)]
+    struct Gossip {
+        messages: HashSet<usize>,
+    }
+
+    /// A round gossiping `messages` values to each of `neighbors`.
+    fn round(messages: usize, neighbors: usize) -> Vec<Message<Gossip>> {
+        (0..neighbors)
+            .map(|neighbor| Message {
+                src: "n0".to_string(),
+                dst: format!("n{}", neighbor + 1),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    lamport: None,
+                    op_id: None,
+                    payload: Gossip {
+                        messages: (0..messages).collect(),
+                    },
+                },
+            })
+            .collect()
+    }
+
+    fn serial(msgs: &[Message<Gossip>]) -> anyhow::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        for msg in msgs {
+            msg.send(&mut output)?;
+        }
+        Ok(output)
+    }
+
+    #[test]
+    fn test_same_output_as_serial() -> anyhow::Result<()> {
+        let msgs = round(50, 7);
+        let mut output = Vec::new();
+        write_lines(&parallel_serialize(&msgs)?, &mut output)?;
+        assert_eq!(output, serial(&msgs)?);
+        assert!(parallel_serialize::<Gossip>(&[])?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Message;
+
+/// Version of the messages nodes exchange with each other, bumped whenever a peer
+/// running an older build would misread them.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Optional modes switched by env flags, with whether each is on when unset.
+const FLAGS: &[(&str, bool)] = &[
+    ("GOSSIP_DIGEST", true),
+    ("KNOWN_COMPACTION", true),
+    ("READ_STREAM", true),
+    ("READ_SORTED", false),
+    ("INCREMENTAL_READ", false),
+    ("QUORUM_WRITE", false),
+    ("REPLY_NOT_SUPPORTED", false),
+    ("METRICS", false),
+    ("ADAPTIVE_GOSSIP", false),
+    ("POLL_STREAM", false),
+    ("OWNER_CACHE", false),
+    ("RELAY", false),
+];
+
+/// Tunables and paths, reported only when set.
+const SETTINGS: &[&str] = &[
+    "GOSSIP_MAX_PER_TICK",
+    "RECONCILE_EVERY",
+    "READ_MAX",
+    "MAX_PEER_MESSAGE_BYTES",
+    "INIT_BUFFER_CAP",
+    "STEP_QUEUE_CAP",
+    "SIMULATE_STEP_DELAY_MS",
+    "BACKGROUND_MERGE",
+    "MSG_ID_DIR",
+    "OP_ID_DIR",
+    "STORE",
+    "TOPOLOGY",
+    "GOSSIP_SEED",
+    "GOSSIP_INTERVAL_MS",
+    "LWW_HISTORY_DEPTH",
+    "SEND_BATCH_MS",
+];
+
+/// The configuration a node runs with, read once when the loop starts. The loop
+/// answers `features` with it, the node never sees those requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Features {
+    pub protocol_version: u32,
+    /// flag name in snake case -> whether it's on
+    pub flags: BTreeMap<String, bool>,
+    pub settings: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum FeaturesMsg {
+    Features,
+    FeaturesOk {
+        protocol_version: u32,
+        version: String,
+        flags: BTreeMap<String, bool>,
+        settings: BTreeMap<String, String>,
+    },
+}
+
+impl Features {
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Like `from_env`, looking the variables up with `var` instead, e.g. in tests.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let flags = FLAGS
+            .iter()
+            .map(|(name, default)| {
+                // default on flags are turned off by 0, default off ones on by 1
+                let on = match var(name) {
+                    Some(flag) if *default => flag != "0",
+                    Some(flag) => flag == "1",
+                    None => *default,
+                };
+                (name.to_lowercase(), on)
+            })
+            .collect();
+        let settings = SETTINGS
+            .iter()
+            .filter_map(|name| Some((name.to_lowercase(), var(name)?)))
+            .collect();
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            flags,
+            settings,
+        }
+    }
+
+    /// The reply to a `features` request, `None` if the line isn't one. A line
+    /// answered here shouldn't reach the node.
+    pub(crate) fn intercept(&self, line: &str) -> Option<Message<FeaturesMsg>> {
+        // skip parsing the bulk of the traffic
+        if !line.contains(r#""features""#) {
+            return None;
+        }
+        let req = serde_json::from_str::<Message<FeaturesMsg>>(line).ok()?;
+        if !matches!(req.body.payload, FeaturesMsg::Features) {
+            return None;
+        }
+        let mut reply = req.into_reply(None);
+        reply.body.payload = FeaturesMsg::FeaturesOk {
+            protocol_version: self.protocol_version,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            flags: self.flags.clone(),
+            settings: self.settings.clone(),
+        };
+        Some(reply)
+    }
+}
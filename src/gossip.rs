@@ -0,0 +1,314 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crossbeam_channel::Sender;
+
+use crate::{
+    crdt::Mergeable,
+    ticker::{spawn_paced_ticker, RoundGuard},
+    InitBody, Message,
+};
+
+/// Time between gossip rounds unless `GOSSIP_INTERVAL_MS` says otherwise.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The most peers a round gossips with, from `GOSSIP_MAX_PER_TICK`, unbounded if unset.
+fn max_per_round_from_env() -> usize {
+    std::env::var("GOSSIP_MAX_PER_TICK")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(usize::MAX)
+}
+
+/// The round interval from `GOSSIP_INTERVAL_MS`. Shorter rounds spread updates faster
+/// but send more messages, a round costs a message per peer even when little
+/// changed; longer ones batch more updates per message at the price of latency.
+pub fn interval_from_env() -> Duration {
+    parse_interval(std::env::var("GOSSIP_INTERVAL_MS").ok().as_deref())
+}
+
+fn parse_interval(ms: Option<&str>) -> Duration {
+    ms.and_then(|ms| ms.parse().ok())
+        .filter(|ms| *ms > 0)
+        .map_or(DEFAULT_INTERVAL, Duration::from_millis)
+}
+
+/// The timer-driven gossip shared by the nodes: who the neighbors are, the cadence of
+/// the rounds, and what each peer is known to hold, as the merge of what it gossiped.
+///
+/// What goes into a round is up to the node. It runs one when `on_alert` hands it a
+/// `Round`, and records what a peer told it with `on_gossip`.
+#[derive(Debug)]
+pub struct Gossip<S> {
+    id: String,
+    neighbors: Vec<String>,
+    known: HashMap<String, S>,
+    rounds: Arc<RoundGuard>,
+    /// no round runs while set, see `pause`
+    paused: bool,
+    /// hard cap of peers gossiped with per round, see `round_peers`
+    max_per_round: usize,
+    /// where the next round starts walking the peers, so a capped round doesn't always
+    /// favor the first ones
+    cursor: usize,
+}
+
+/// A round in progress, the timer alerts again once it's dropped.
+pub struct Round(Arc<RoundGuard>);
+
+impl Drop for Round {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+impl<S: Mergeable + Default> Gossip<S> {
+    /// Gossip with every node of the cluster, sending `alert` to the node through `tx`
+    /// every `interval` to start a round.
+    pub fn start<M: Send + 'static>(
+        init: &InitBody,
+        interval: Duration,
+        tx: Sender<Message<M>>,
+        alert: impl Fn() -> M + Send + 'static,
+    ) -> Self {
+        Self::start_paced(init, move || interval, tx, alert)
+    }
+
+    /// Like `start`, with the interval taken from `pace` before every round, see
+    /// `AdaptiveInterval::pace`.
+    pub fn start_paced<M: Send + 'static>(
+        init: &InitBody,
+        pace: impl Fn() -> Duration + Send + 'static,
+        tx: Sender<Message<M>>,
+        alert: impl Fn() -> M + Send + 'static,
+    ) -> Self {
+        let rounds = Arc::new(RoundGuard::default());
+        spawn_paced_ticker(pace, Arc::clone(&rounds), tx, move || {
+            Message::internal(alert())
+        });
+        let mut gossip = Self {
+            id: init.node_id.clone(),
+            neighbors: Vec::new(),
+            known: HashMap::new(),
+            rounds,
+            paused: false,
+            max_per_round: max_per_round_from_env(),
+            cursor: 0,
+        };
+        gossip.set_neighbors(init.node_ids.clone());
+        gossip
+    }
+
+    pub fn neighbors(&self) -> &[String] {
+        &self.neighbors
+    }
+
+    /// The neighbors but ourselves.
+    pub fn peers(&self) -> impl Iterator<Item = &String> + Clone {
+        self.neighbors.iter().filter(|node| **node != self.id)
+    }
+
+    /// Gossip with `neighbors` from now on, remembering what the former ones hold in
+    /// case they come back.
+    pub fn set_neighbors(&mut self, neighbors: Vec<String>) {
+        for neighbor in &neighbors {
+            self.known.entry(neighbor.clone()).or_default();
+        }
+        self.neighbors = neighbors;
+    }
+
+    /// What `peer` is known to hold, `None` for a node never gossiped with.
+    pub fn known(&self, peer: &str) -> Option<&S> {
+        self.known.get(peer)
+    }
+
+    pub fn known_mut(&mut self) -> impl Iterator<Item = &mut S> {
+        self.known.values_mut()
+    }
+
+    /// Record that `src` holds `delta`.
+    pub fn on_gossip(&mut self, src: &str, delta: S) {
+        self.known.entry(src.to_string()).or_default().merge(delta)
+    }
+
+    /// The round the timer asked for, `None` while paused. Either way the timer is
+    /// free to alert again once the round is dropped.
+    pub fn on_alert(&self) -> Option<Round> {
+        let round = Round(Arc::clone(&self.rounds));
+        (!self.paused).then_some(round)
+    }
+
+    /// Claim a round off the timer, e.g. to catch up at once on `resume`. `None` while
+    /// paused or while a round is in flight, which then does the job instead.
+    pub fn try_round(&self) -> Option<Round> {
+        (!self.paused && self.rounds.try_start()).then(|| Round(Arc::clone(&self.rounds)))
+    }
+
+    /// The peers this round gossips with, at most `max_per_round` of them so a round's
+    /// bandwidth is predictable. The peers furthest `behind`, given each peer and what
+    /// it's known to hold, go first. Ties are served round-robin from where the last
+    /// round stopped, so under a tight cap every peer gets its turn. The rest wait for
+    /// a later round. Pick them before building any payload, so the
+    /// skipped peers cost nothing.
+    pub fn round_peers(&mut self, behind: impl Fn(&str, &S) -> usize) -> Vec<String> {
+        let peers = self.peers().collect::<Vec<_>>();
+        let start = self.cursor % peers.len().max(1);
+        let (tail, head) = peers.split_at(start);
+        let mut ranked = head
+            .iter()
+            .chain(tail)
+            .map(|peer| {
+                let behind = self
+                    .known
+                    .get(*peer)
+                    .map_or(usize::MAX, |known| behind(peer, known));
+                (behind, *peer)
+            })
+            .collect::<Vec<_>>();
+        // stable, the ties keep their order
+        ranked.sort_by_key(|(behind, _)| std::cmp::Reverse(*behind));
+        let served = ranked
+            .into_iter()
+            .take(self.max_per_round)
+            .map(|(_, peer)| peer.clone())
+            .collect::<Vec<_>>();
+        self.cursor = start + served.len();
+        served
+    }
+
+    pub fn set_max_per_round(&mut self, max: usize) {
+        self.max_per_round = max;
+    }
+
+    /// Stop running rounds, to script partition-like experiments. Incoming gossip is
+    /// still recorded.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Timer ticks coalesced into a round still in flight so far.
+    pub fn skipped_rounds(&self) -> usize {
+        self.rounds.skipped()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashSet, time::Duration};
+
+    use crate::InitBody;
+
+    use super::{parse_interval, Gossip, DEFAULT_INTERVAL};
+
+    #[test]
+    fn test_rounds_and_known() -> anyhow::Result<()> {
+        let init = InitBody {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+            extra: Default::default(),
+        };
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut gossip =
+            Gossip::<HashSet<usize>>::start(&init, Duration::from_millis(1), tx, || ());
+        assert_eq!(gossip.peers().collect::<Vec<_>>(), ["n2", "n3"]);
+
+        // the next alert waits for the round to end
+        rx.recv_timeout(Duration::from_secs(5))?;
+        let round = gossip.on_alert().expect("not paused");
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(rx.try_recv().is_err());
+        drop(round);
+        rx.recv_timeout(Duration::from_secs(5))?;
+
+        // a paused alert still ends its round
+        gossip.pause();
+        assert!(gossip.on_alert().is_none());
+        rx.recv_timeout(Duration::from_secs(5))?;
+
+        gossip.on_gossip("n2", [1, 2].into());
+        gossip.on_gossip("n2", [3].into());
+        assert_eq!(gossip.known("n2"), Some(&[1, 2, 3].into()));
+        assert_eq!(gossip.known("n3"), Some(&HashSet::new()));
+        assert_eq!(gossip.known("n4"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_round_waits_for_round_in_flight() {
+        let init = InitBody {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+            extra: Default::default(),
+        };
+        let (tx, _rx) = crossbeam_channel::unbounded();
+        let mut gossip =
+            Gossip::<HashSet<usize>>::start(&init, Duration::from_secs(3600), tx, || ());
+        let round = gossip.try_round().expect("no round in flight");
+        assert!(gossip.try_round().is_none());
+        drop(round);
+        assert!(gossip.try_round().is_some());
+
+        gossip.pause();
+        assert!(gossip.try_round().is_none());
+    }
+
+    #[test]
+    fn test_round_peers_capped_most_behind_first() {
+        let init = InitBody {
+            node_id: "n1".to_string(),
+            node_ids: ["n1", "n2", "n3", "n4", "n5"].map(String::from).to_vec(),
+            extra: Default::default(),
+        };
+        let (tx, _) = crossbeam_channel::unbounded();
+        let mut gossip = Gossip::<HashSet<usize>>::start(&init, DEFAULT_INTERVAL, tx, || ());
+        gossip.set_max_per_round(2);
+        let held = (0..4).collect::<HashSet<usize>>();
+        gossip.on_gossip("n2", [0, 1, 2].into());
+        gossip.on_gossip("n4", [0].into());
+        let behind = |known: &HashSet<usize>| held.difference(known).count();
+
+        assert_eq!(gossip.round_peers(|_, known| behind(known)), ["n3", "n5"]);
+        // served peers catch up, every round makes progress until none is behind
+        let mut rounds = 0;
+        while gossip
+            .peers()
+            .any(|peer| behind(gossip.known(peer).unwrap()) > 0)
+        {
+            let peers = gossip.round_peers(|_, known| behind(known));
+            assert!(peers.len() <= 2);
+            for peer in peers {
+                gossip.on_gossip(&peer, held.clone());
+            }
+            rounds += 1;
+        }
+        assert_eq!(rounds, 2);
+    }
+
+    #[test]
+    fn test_capped_rounds_serve_every_peer() {
+        let init = InitBody {
+            node_id: "n1".to_string(),
+            node_ids: ["n1", "n2", "n3", "n4", "n5"].map(String::from).to_vec(),
+            extra: Default::default(),
+        };
+        let (tx, _) = crossbeam_channel::unbounded();
+        let mut gossip = Gossip::<HashSet<usize>>::start(&init, DEFAULT_INTERVAL, tx, || ());
+        gossip.set_max_per_round(1);
+        // every peer is equally behind and stays so, only the cursor moves
+        let served = (0..4)
+            .flat_map(|_| gossip.round_peers(|_, _| 1))
+            .collect::<Vec<_>>();
+        assert_eq!(served, ["n2", "n3", "n4", "n5"]);
+    }
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval(Some("250")), Duration::from_millis(250));
+        for fallback in [None, Some("0"), Some("fast")] {
+            assert_eq!(parse_interval(fallback), DEFAULT_INTERVAL);
+        }
+    }
+}
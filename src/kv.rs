@@ -0,0 +1,276 @@
+//! Client for Maelstrom's built-in key/value services.
+
+use std::{io::Write, marker::PhantomData, time::Duration};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    rpc::{RetryPolicy, Rpc},
+    MaelstromError, Message,
+};
+
+/// Sequentially consistent store.
+pub const SEQ_KV: &str = "seq-kv";
+/// Linearizable store.
+pub const LIN_KV: &str = "lin-kv";
+/// Last write wins store, may lose concurrent writes.
+pub const LWW_KV: &str = "lww-kv";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum KvMsg<V> {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: V,
+    },
+    Write {
+        key: Value,
+        value: V,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: V,
+        to: V,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: u64,
+        text: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum KvError {
+    /// a cas found another value than its `from`, the service's code 22
+    CasFailed(String),
+    /// any other error the service replied
+    Service { code: u64, text: String },
+    /// no reply in time, or one which isn't a kv reply
+    Rpc(anyhow::Error),
+}
+
+impl std::fmt::Display for KvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvError::CasFailed(text) => write!(f, "cas failed: {text}"),
+            KvError::Service { code, text } => write!(f, "kv error {code}: {text}"),
+            KvError::Rpc(e) => write!(f, "kv rpc failed: {e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+/// Reads and writes values of type `V` in one of the kv services, blocking on each
+/// reply through `Rpc::call`, with the same caveats.
+pub struct KvClient<V> {
+    service: String,
+    rpc: Rpc,
+    timeout: Duration,
+    value: PhantomData<fn() -> V>,
+}
+
+impl<V> KvClient<V>
+where
+    V: Serialize + DeserializeOwned + Send + 'static,
+{
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub fn new(service: impl Into<String>, rpc: Rpc) -> Self {
+        Self {
+            service: service.into(),
+            rpc,
+            timeout: Self::DEFAULT_TIMEOUT,
+            value: PhantomData,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Retry timed out requests and transient errors by `policy`, see `Rpc::call`.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.rpc = self.rpc.with_retry(policy);
+        self
+    }
+
+    /// The value under `key`, `None` if the key doesn't exist.
+    pub fn read(
+        &self,
+        key: impl Into<Value>,
+        output: &mut impl Write,
+    ) -> Result<Option<V>, KvError> {
+        let read = KvMsg::Read { key: key.into() };
+        match self.request(read, output) {
+            Ok(KvMsg::ReadOk { value }) => Ok(Some(value)),
+            Err(KvError::Service { code, .. })
+                if code == MaelstromError::KeyDoesNotExist.code() =>
+            {
+                Ok(None)
+            }
+            Ok(reply) => Err(unexpected(reply)),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn write(
+        &self,
+        key: impl Into<Value>,
+        value: V,
+        output: &mut impl Write,
+    ) -> Result<(), KvError> {
+        let write = KvMsg::Write {
+            key: key.into(),
+            value,
+        };
+        match self.request(write, output)? {
+            KvMsg::WriteOk => Ok(()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Swap `from` for `to`, failing with `KvError::CasFailed` if `key` holds anything
+    /// else. A missing key fails with code 20 unless `create_if_not_exists`.
+    pub fn cas(
+        &self,
+        key: impl Into<Value>,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+        output: &mut impl Write,
+    ) -> Result<(), KvError> {
+        let cas = KvMsg::Cas {
+            key: key.into(),
+            from,
+            to,
+            create_if_not_exists,
+        };
+        match self.request(cas, output)? {
+            KvMsg::CasOk => Ok(()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    fn request(&self, payload: KvMsg<V>, output: &mut impl Write) -> Result<KvMsg<V>, KvError> {
+        let reply: Message<KvMsg<V>> = self
+            .rpc
+            .call(self.service.as_str(), payload, output, self.timeout)
+            .map_err(KvError::Rpc)?;
+        match reply.body.payload {
+            KvMsg::Error { code, text } if code == MaelstromError::PreconditionFailed.code() => {
+                Err(KvError::CasFailed(text))
+            }
+            KvMsg::Error { code, text } => Err(KvError::Service { code, text }),
+            reply => Ok(reply),
+        }
+    }
+}
+
+fn unexpected<V>(reply: KvMsg<V>) -> KvError {
+    let kind = match reply {
+        KvMsg::Read { .. } => "read",
+        KvMsg::ReadOk { .. } => "read_ok",
+        KvMsg::Write { .. } => "write",
+        KvMsg::WriteOk => "write_ok",
+        KvMsg::Cas { .. } => "cas",
+        KvMsg::CasOk => "cas_ok",
+        KvMsg::Error { .. } => "error",
+    };
+    KvError::Rpc(anyhow::anyhow!("unexpected kv reply {kind}"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::{
+        rpc::{RetryPolicy, Rpc},
+        test_util::{assert_wire_format, FakeKv},
+        Body, Message,
+    };
+
+    use super::{KvClient, KvError, KvMsg, SEQ_KV};
+
+    #[test]
+    fn test_kv_client() -> anyhow::Result<()> {
+        let rpc = Rpc::new("n1");
+        let mut wire = FakeKv::new(rpc.clone());
+        let kv = KvClient::<u64>::new(SEQ_KV, rpc);
+
+        assert_eq!(kv.read("counter", &mut wire)?, None);
+        kv.write("counter", 3, &mut wire)?;
+        assert_eq!(kv.read("counter", &mut wire)?, Some(3));
+        let stale = kv.cas("counter", 2, 5, false, &mut wire);
+        assert!(matches!(stale, Err(KvError::CasFailed(_))), "{stale:?}");
+        kv.cas("counter", 3, 5, false, &mut wire)?;
+        assert_eq!(kv.read("counter", &mut wire)?, Some(5));
+
+        let missing = kv.cas(7, 0, 1, false, &mut wire);
+        assert!(matches!(missing, Err(KvError::Service { code: 20, .. })));
+        kv.cas(7, 0, 1, true, &mut wire)?;
+        assert_eq!(kv.read(7, &mut wire)?, Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_lost_replies() -> anyhow::Result<()> {
+        let rpc = Rpc::new("n1");
+        let mut wire = FakeKv::new(rpc.clone());
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+        };
+        let kv = KvClient::<u64>::new(SEQ_KV, rpc)
+            .with_timeout(Duration::from_millis(20))
+            .with_retry(policy);
+
+        wire.drop_replies(2);
+        kv.write("counter", 3, &mut wire)?;
+        assert_eq!(wire.requests, 1, "the third attempt is the one answered");
+        assert_eq!(kv.read("counter", &mut wire)?, Some(3));
+
+        // out of attempts, the last timeout surfaces
+        wire.drop_replies(3);
+        let lost = kv.read("counter", &mut wire);
+        assert!(matches!(lost, Err(KvError::Rpc(_))), "{lost:?}");
+        assert_eq!(policy.delay(0), Duration::from_millis(1));
+        assert_eq!(policy.delay(5), policy.max_delay);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let cas = Message {
+            src: "n1".to_string(),
+            dst: "lin-kv".to_string(),
+            body: Body {
+                id: Some(3),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload: KvMsg::Cas {
+                    key: json!("counter"),
+                    from: 1,
+                    to: 2,
+                    create_if_not_exists: true,
+                },
+            },
+        };
+        assert_wire_format(
+            &cas,
+            r#"{"src":"n1","dest":"lin-kv","body":{"msg_id":3,"in_reply_to":null,"type":"cas","key":"counter","from":1,"to":2,"create_if_not_exists":true}}"#,
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use crate::rpc::Rpc;
+
+/// Exponential moving average of the observed inter-node round-trip latency.
+///
+/// The average is only reported once `warmup` samples were observed, before that
+/// callers should fall back to their static defaults.
+#[derive(Debug, Clone)]
+pub struct EmaLatency {
+    alpha: f64,
+    warmup: usize,
+    samples: usize,
+    ema: f64,
+}
+
+impl EmaLatency {
+    /// `alpha` is the weight of the newest sample, in `(0, 1]`.
+    pub fn new(alpha: f64, warmup: usize) -> Self {
+        assert!(alpha > 0.0 && alpha <= 1.0, "alpha should be in (0, 1]");
+        Self {
+            alpha,
+            warmup,
+            samples: 0,
+            ema: 0.0,
+        }
+    }
+
+    /// Record the round trip from sending an RPC to receiving its ack.
+    pub fn observe(&mut self, rtt: Duration) {
+        let rtt = rtt.as_secs_f64();
+        self.ema = if self.samples == 0 {
+            rtt
+        } else {
+            self.alpha * rtt + (1.0 - self.alpha) * self.ema
+        };
+        self.samples += 1;
+    }
+
+    /// `None` while still warming up.
+    pub fn average(&self) -> Option<Duration> {
+        (self.samples > 0 && self.samples >= self.warmup).then(|| Duration::from_secs_f64(self.ema))
+    }
+}
+
+impl Default for EmaLatency {
+    fn default() -> Self {
+        Self::new(0.2, 5)
+    }
+}
+
+/// Derive the gossip interval from the latency: gossip more aggressively when the
+/// network is fast, back off when it's slow.
+#[derive(Debug, Clone)]
+pub struct AdaptiveInterval {
+    /// interval used until the latency tracker is warmed up
+    pub base: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    /// how many round trips make up one gossip interval
+    pub rtt_multiple: u32,
+}
+
+impl AdaptiveInterval {
+    pub fn interval(&self, latency: &EmaLatency) -> Duration {
+        match latency.average() {
+            Some(rtt) => (rtt * self.rtt_multiple).clamp(self.min, self.max),
+            None => self.base,
+        }
+    }
+
+    /// The interval following the round trips of the calls made through `rpc`, to
+    /// drive `Gossip::start_paced`.
+    pub fn pace(self, rpc: &Rpc) -> impl Fn() -> Duration + Send + 'static {
+        let rpc = rpc.clone();
+        move || self.interval(&rpc.latency())
+    }
+}
+
+impl Default for AdaptiveInterval {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_millis(100),
+            min: Duration::from_millis(20),
+            max: Duration::from_millis(500),
+            rtt_multiple: 4,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{AdaptiveInterval, EmaLatency};
+
+    #[test]
+    fn test_warmup_uses_base_interval() {
+        let policy = AdaptiveInterval::default();
+        let mut latency = EmaLatency::new(0.5, 3);
+        latency.observe(Duration::from_millis(1));
+        latency.observe(Duration::from_millis(1));
+        assert_eq!(latency.average(), None);
+        assert_eq!(policy.interval(&latency), policy.base);
+        latency.observe(Duration::from_millis(1));
+        assert!(latency.average().is_some());
+    }
+
+    #[test]
+    fn test_falling_latency_shortens_interval() {
+        let policy = AdaptiveInterval::default();
+        let mut latency = EmaLatency::new(0.5, 1);
+        latency.observe(Duration::from_millis(200));
+        let slow = policy.interval(&latency);
+        assert_eq!(slow, policy.max);
+
+        let mut previous = slow;
+        for _ in 0..10 {
+            latency.observe(Duration::from_millis(10));
+            let interval = policy.interval(&latency);
+            assert!(interval <= previous);
+            assert!(policy.min <= interval && interval <= policy.max);
+            previous = interval;
+        }
+        // converged close to 4 round trips of 10ms
+        assert!(previous < Duration::from_millis(45));
+    }
+}
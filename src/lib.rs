@@ -1,174 +1,2478 @@
+#[cfg(feature = "tokio")]
+pub mod async_loop;
+pub mod clock;
+pub mod codec;
+pub mod crdt;
+pub mod dedup;
+pub mod digest;
+#[cfg(feature = "parallel")]
+pub mod fanout;
+pub mod features;
+pub mod gossip;
+pub mod kv;
+pub mod latency;
+pub mod log;
+pub mod metrics;
+pub mod middleware;
+pub mod persist;
+pub mod protocol;
+pub mod rpc;
+pub mod shard;
+pub mod test_util;
+pub mod ticker;
+pub mod topology;
+
 use std::{
+    collections::{HashMap, HashSet},
     fmt::Debug,
-    io::{stdout, BufReader, Write},
+    io::{stdout, BufRead, BufReader, BufWriter, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
+use clock::SystemClock;
+use codec::{Codec, JsonCodec};
+use features::Features;
+use log::Level;
+use metrics::{Metered, Metrics};
+use middleware::{SlowStep, Stack};
+use rpc::{NodeContext, Rpc, StepContext};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Message<MessageType> {
-    pub src: String,
-    #[serde(rename = "dest")]
-    pub dst: String,
-    pub body: Body<MessageType>,
-}
+#[cfg(feature = "tokio")]
+pub use async_loop::{async_main_loop, async_main_loop_with_io, AsyncNode, Outbox};
+pub use protocol::{
+    init::{Cluster, InitBody, InitError, InitMsg},
+    message::{handle_rpc, Body, ErrorMsg, IdGen, Message, Request},
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Body<MessageType> {
-    #[serde(rename = "msg_id")]
-    pub id: Option<usize>,
-    pub in_reply_to: Option<usize>,
-    #[serde(flatten)]
-    pub payload: MessageType,
+/// Maelstrom's standard error codes. The definite ones tell the client the request
+/// had no effect, the others that it may or may not have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaelstromError {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
 }
 
-impl<M: Serialize> Message<M> {
-    pub fn into_reply(self, msg_id: Option<&mut usize>) -> Self {
-        Self {
-            src: self.dst,
-            dst: self.src,
-            body: Body {
-                payload: self.body.payload,
-                id: msg_id.map(|id| {
-                    let mid = *id;
-                    *id += 1;
-                    mid
-                }),
-                in_reply_to: self.body.id,
-            },
+impl MaelstromError {
+    pub fn code(self) -> u64 {
+        match self {
+            MaelstromError::Timeout => 0,
+            MaelstromError::NodeNotFound => 1,
+            MaelstromError::NotSupported => 10,
+            MaelstromError::TemporarilyUnavailable => 11,
+            MaelstromError::MalformedRequest => 12,
+            MaelstromError::Crash => 13,
+            MaelstromError::Abort => 14,
+            MaelstromError::KeyDoesNotExist => 20,
+            MaelstromError::KeyAlreadyExists => 21,
+            MaelstromError::PreconditionFailed => 22,
+            MaelstromError::TxnConflict => 30,
         }
     }
 
-    pub fn send(&self, output: &mut impl Write) -> anyhow::Result<()> {
-        serde_json::to_writer(&mut *output, &self)
-            .context("serde to broadcast_ok message filed")?;
-        output.write_all(b"\n").context("flush message error")
+    pub fn from_code(code: u64) -> Option<Self> {
+        [
+            MaelstromError::Timeout,
+            MaelstromError::NodeNotFound,
+            MaelstromError::NotSupported,
+            MaelstromError::TemporarilyUnavailable,
+            MaelstromError::MalformedRequest,
+            MaelstromError::Crash,
+            MaelstromError::Abort,
+            MaelstromError::KeyDoesNotExist,
+            MaelstromError::KeyAlreadyExists,
+            MaelstromError::PreconditionFailed,
+            MaelstromError::TxnConflict,
+        ]
+        .into_iter()
+        .find(|error| error.code() == code)
+    }
+
+    /// Whether the request surely had no effect, so the client may retry it.
+    pub fn is_definite(self) -> bool {
+        !matches!(self, MaelstromError::Timeout | MaelstromError::Crash)
+    }
+
+    /// An error a node can return from `step` to have the request answered with it.
+    pub fn because(self, text: impl Into<String>) -> RequestError {
+        RequestError {
+            code: self,
+            text: text.into(),
+        }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-pub enum InitMsg {
-    Init(InitBody),
-    InitOk,
+/// Returned from `Node::step` instead of bailing, the loop then replies it as an
+/// `error` to the request and keeps going rather than crashing the node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestError {
+    pub code: MaelstromError,
+    pub text: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct InitBody {
-    pub node_id: String,
-    pub node_ids: Vec<String>,
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} ({}): {}", self.code, self.code.code(), self.text)
+    }
 }
 
-impl Message<InitMsg> {
-    pub fn into_init_ok(&self) -> anyhow::Result<Self> {
-        match &self.body.payload {
-            InitMsg::Init(..) => Ok(Message {
-                src: self.dst.clone(),
-                dst: self.src.clone(),
-                body: Body {
-                    id: None,
-                    in_reply_to: self.body.id,
-                    payload: InitMsg::InitOk,
-                },
-            }),
-            InitMsg::InitOk => anyhow::bail!("can't convert from init_ok messag"),
-        }
+impl std::error::Error for RequestError {}
+
+/// Step `msg`, answering a `RequestError` out of it as an `error` reply when the
+/// sender expects one. Other errors are passed on. Internal messages go to
+/// `Node::on_internal`.
+fn step_or_reply<M: Serialize, N: Node<M>>(
+    node: &mut N,
+    msg: Message<M>,
+    output: &mut impl Write,
+    ctx: &StepContext<'_, M>,
+) -> anyhow::Result<()> {
+    let request = Message {
+        src: msg.src.clone(),
+        dst: msg.dst.clone(),
+        body: Body {
+            id: msg.body.id,
+            in_reply_to: None,
+            lamport: None,
+            op_id: None,
+            payload: (),
+        },
+    };
+    let stepped = if msg.is_internal() {
+        node.on_internal(msg.body.payload, output, ctx)
+    } else {
+        node.step_with(msg, output, ctx)
+    };
+    match stepped {
+        Err(e) => match e.downcast::<RequestError>() {
+            Ok(error) if request.body.id.is_some() && !request.is_internal() => {
+                request.into_error(error.code, error.text).send(output)
+            }
+            Ok(error) => {
+                node_log!(
+                    Level::Warn,
+                    "drop error to a message expecting no reply: {error}"
+                );
+                Ok(())
+            }
+            Err(e) => Err(e),
+        },
+        ok => ok,
     }
 }
 
 pub trait Node<MessageType> {
     fn init_from(
         init: &InitBody,
-        tx: std::sync::mpsc::Sender<Message<MessageType>>,
+        tx: crossbeam_channel::Sender<Message<MessageType>>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized;
 
-    fn step(&mut self, req: Message<MessageType>, output: &mut impl Write) -> anyhow::Result<()>;
+    /// Build the node with every handle the loop offers. Nodes awaiting replies to
+    /// their own requests override this to keep the `Rpc`, the others just `init_from`.
+    fn init_with(init: &InitBody, ctx: NodeContext<MessageType>) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::init_from(init, ctx.tx)
+    }
+
+    /// Act on a message, writing whatever it sends to `output`. By default the
+    /// messages `handle` returns are written.
+    fn step(&mut self, req: Message<MessageType>, output: &mut impl Write) -> anyhow::Result<()>
+    where
+        MessageType: Serialize,
+    {
+        for msg in self.handle(req)? {
+            msg.send(output)?
+        }
+        Ok(())
+    }
+
+    /// `step` with the loop's handles, e.g. to send the node itself a message. The loops
+    /// call this one, by default it's just `step`.
+    fn step_with(
+        &mut self,
+        req: Message<MessageType>,
+        output: &mut impl Write,
+        _ctx: &StepContext<'_, MessageType>,
+    ) -> anyhow::Result<()>
+    where
+        MessageType: Serialize,
+    {
+        self.step(req, output)
+    }
+
+    /// Act on an event the node raised for itself with `Message::internal`, which no
+    /// one awaits a reply to. By default it's stepped like any message.
+    fn on_internal(
+        &mut self,
+        payload: MessageType,
+        output: &mut impl Write,
+        ctx: &StepContext<'_, MessageType>,
+    ) -> anyhow::Result<()>
+    where
+        MessageType: Serialize,
+    {
+        self.step_with(Message::internal(payload), output, ctx)
+    }
+
+    /// `step` returning the messages to send rather than writing them, so tests can
+    /// assert on them directly. A node implements either one.
+    fn handle(&mut self, _req: Message<MessageType>) -> anyhow::Result<Vec<Message<MessageType>>> {
+        anyhow::bail!("the node implements neither handle nor step")
+    }
+
+    /// Extra fields spliced into the init_ok body, e.g. an auth token or a protocol
+    /// version for harnesses which want one. Maelstrom gets a plain init_ok.
+    fn init_ok_extra(_init: &InitBody) -> HashMap<String, serde_json::Value>
+    where
+        Self: Sized,
+    {
+        HashMap::new()
+    }
+
+    /// Slow startup work, e.g. loading state from a kv store. It runs after init_ok went
+    /// out, while the messages arriving meanwhile queue up to be stepped once it's done.
+    fn after_init(&mut self, _output: &mut impl Write) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Whether this node believes the cluster converged, as far as it can tell locally.
+    /// Nodes without replicated state are always converged.
+    fn converged(&self) -> bool {
+        true
+    }
+
+    /// Last words once the input ended and every message read before was stepped,
+    /// e.g. flushing state. The output is flushed right after. Timer threads holding
+    /// the node's `Sender` stop once their next send fails.
+    fn on_shutdown(&mut self, _output: &mut impl Write) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 pub fn main_loop<MessageType, N>() -> anyhow::Result<()>
 where
-    MessageType: DeserializeOwned + Send + 'static,
+    MessageType: DeserializeOwned + Serialize + Clone + Send + 'static,
     N: Node<MessageType> + Send + 'static,
 {
-    let mut stdin = BufReader::new(std::io::stdin().lock());
-    let init_msg = serde_json::Deserializer::from_reader(&mut stdin)
-        .into_iter::<Message<InitMsg>>()
-        .next()
-        .expect("no init msg received at first")
-        .context("construct init message failed")?;
-    let InitMsg::Init ( ref init_body ) = init_msg.body.payload else {
-        panic!("first message should be init.");
+    main_loop_with_io::<MessageType, N>(
+        BufReader::new(std::io::stdin().lock()),
+        BufWriter::new(stdout()),
+    )
+}
+
+/// Skip a line which can't be parsed, replying a malformed-request error if the
+/// sender expects a reply. With `LoopConfig::reply_not_supported` a type the node
+/// doesn't model gets a not-supported error naming it instead, which helps during
+/// protocol work.
+fn reject_malformed<MessageType: DeserializeOwned>(
+    line: &str,
+    error: &anyhow::Error,
+    reply_not_supported: bool,
+    output: &mut impl Write,
+) -> anyhow::Result<()> {
+    node_log!(Level::Warn, "skip malformed message {line}: {error}");
+    let msg = match serde_json::from_str::<Message<serde_json::Value>>(line) {
+        Ok(msg) if msg.body.id.is_some() => msg,
+        _ => return Ok(()),
     };
+    let kind = msg.body.payload["type"].as_str().unwrap_or_default();
+    if reply_not_supported && !is_known_type::<MessageType>(kind) {
+        let text = format!("message type {kind} is not supported");
+        msg.into_error(MaelstromError::NotSupported, text)
+            .send(output)
+    } else {
+        msg.into_error(
+            MaelstromError::MalformedRequest,
+            format!("malformed request: {error}"),
+        )
+        .send(output)
+    }
+}
 
-    {
-        let mut stdout = stdout().lock();
-        serde_json::to_writer(&mut stdout, &init_msg.into_init_ok()?)?;
-        stdout.write_all(b"\n")?;
+/// Whether `kind` is one of the `type` tags of `MessageType`. Only the tag is fed to
+/// it, a known one fails on its missing fields rather than as an unknown variant.
+fn is_known_type<MessageType: DeserializeOwned>(kind: &str) -> bool {
+    use serde::de::value::MapDeserializer;
+
+    /// Tells an unknown tag apart from any other deserialization error.
+    #[derive(Debug)]
+    enum TagProbe {
+        Unknown,
+        Other,
+    }
+
+    impl std::fmt::Display for TagProbe {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{self:?}")
+        }
+    }
+
+    impl std::error::Error for TagProbe {}
+
+    impl serde::de::Error for TagProbe {
+        fn custom<T: std::fmt::Display>(_msg: T) -> Self {
+            TagProbe::Other
+        }
+
+        fn unknown_variant(_variant: &str, _expected: &'static [&'static str]) -> Self {
+            TagProbe::Unknown
+        }
+    }
+
+    let probe = MapDeserializer::<_, TagProbe>::new(std::iter::once(("type", kind)));
+    !matches!(MessageType::deserialize(probe), Err(TagProbe::Unknown))
+}
+
+/// What the loops take from the environment when they start, see `from_env`. Tests
+/// build one directly rather than setting variables other tests would see.
+#[derive(Debug, Clone)]
+pub struct LoopConfig {
+    /// count the traffic, see [`Metrics`], `METRICS=1`
+    pub metrics: bool,
+    /// answer unknown message types with not-supported, `REPLY_NOT_SUPPORTED=1`
+    pub reply_not_supported: bool,
+    /// `MAX_PEER_MESSAGE_BYTES`
+    pub max_peer_message_bytes: usize,
+    /// `INIT_BUFFER_CAP`
+    pub init_buffer_cap: usize,
+    /// `STEP_QUEUE_CAP`
+    pub step_queue_cap: usize,
+    /// answered to `features` requests
+    pub features: Features,
+}
+
+impl LoopConfig {
+    pub fn from_env() -> Self {
+        let flag = |name| std::env::var(name).is_ok_and(|flag| flag == "1");
+        let number = |name, default| {
+            std::env::var(name)
+                .ok()
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            metrics: flag("METRICS"),
+            reply_not_supported: flag("REPLY_NOT_SUPPORTED"),
+            max_peer_message_bytes: number(
+                "MAX_PEER_MESSAGE_BYTES",
+                DEFAULT_MAX_PEER_MESSAGE_BYTES,
+            ),
+            init_buffer_cap: number("INIT_BUFFER_CAP", DEFAULT_INIT_BUFFER_CAP),
+            step_queue_cap: number("STEP_QUEUE_CAP", DEFAULT_STEP_QUEUE_CAP),
+            features: Features::from_env(),
+        }
+    }
+}
+
+impl Default for LoopConfig {
+    /// What an empty environment gives.
+    fn default() -> Self {
+        Self {
+            metrics: false,
+            reply_not_supported: false,
+            max_peer_message_bytes: DEFAULT_MAX_PEER_MESSAGE_BYTES,
+            init_buffer_cap: DEFAULT_INIT_BUFFER_CAP,
+            step_queue_cap: DEFAULT_STEP_QUEUE_CAP,
+            features: Features::from_vars(|_| None),
+        }
+    }
+}
+
+/// How many messages may arrive before init, overridden by `INIT_BUFFER_CAP`.
+pub const DEFAULT_INIT_BUFFER_CAP: usize = 1024;
+
+/// How many input messages may wait for the step thread before the reader blocks,
+/// overridden by `STEP_QUEUE_CAP`.
+pub const DEFAULT_STEP_QUEUE_CAP: usize = 1024;
+
+/// Largest line accepted from another node, overridden by `MAX_PEER_MESSAGE_BYTES`.
+pub const DEFAULT_MAX_PEER_MESSAGE_BYTES: usize = 64 << 20;
+
+/// Drops oversized lines from other nodes before they're deserialized, so one buggy
+/// peer gossiping an enormous set can't exhaust our memory. Clients aren't limited.
+struct PeerSizeLimit {
+    node_ids: HashSet<String>,
+    max_bytes: usize,
+}
+
+impl PeerSizeLimit {
+    fn new(node_ids: &[String], max_bytes: usize) -> Self {
+        Self {
+            node_ids: node_ids.iter().cloned().collect(),
+            max_bytes,
+        }
+    }
+
+    fn rejects(&self, line: &str) -> bool {
+        #[derive(Deserialize)]
+        struct Src {
+            src: String,
+        }
+        if line.len() <= self.max_bytes {
+            return false;
+        }
+        // skims the line without materializing its body
+        match serde_json::from_str::<Src>(line) {
+            Ok(Src { src }) if self.node_ids.contains(&src) => {
+                node_log!(
+                    Level::Warn,
+                    "drop a {} bytes message from {src}, over the {} bytes limit",
+                    line.len(),
+                    self.max_bytes
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Run the node over arbitrary input/output instead of STDIN/STDOUT.
+///
+/// The input is framed by lines, one message per line. A line which can't be parsed
+/// is reported to STDERR and skipped, so it doesn't poison the messages after it; a
+/// request among those gets a malformed-request error back. Once the output pipe is
+/// closed the loop stops reading and returns `Ok`.
+/// Messages arriving ahead of init are buffered and processed once the node is built.
+/// init_ok goes out first, then `Node::after_init` runs while the input keeps queueing.
+/// Lines from other nodes over `MAX_PEER_MESSAGE_BYTES` are dropped unparsed.
+/// With `METRICS=1` the traffic is counted per type, see [`Metrics`].
+/// What steps queue for the node through their `StepContext` is stepped before
+/// `Node::on_shutdown`, even once the input ended.
+/// The output is flushed whenever the queue of messages to step runs empty, so a
+/// buffered output sees one write per batch rather than one per message.
+/// Once `STEP_QUEUE_CAP` messages wait for a slow step, the reader stops reading until
+/// the step thread catches up, unless a step is blocked on an rpc reply: only the
+/// reader can hand that over, so it keeps reading and queues past the cap meanwhile.
+pub fn main_loop_with_io<MessageType, N>(
+    input: impl BufRead,
+    output: impl Write + Send,
+) -> anyhow::Result<()>
+where
+    MessageType: DeserializeOwned + Serialize + Clone + Send + 'static,
+    N: Node<MessageType> + Send,
+{
+    let mut middleware = Stack::default();
+    if let Some(slow) = SlowStep::from_env() {
+        middleware = middleware.with(slow);
+    }
+    main_loop_with_middleware::<MessageType, N>(input, output, middleware)
+}
+
+/// Same as `main_loop_with_io`, running every message through the middleware stack
+/// around `Node::step`.
+pub fn main_loop_with_middleware<MessageType, N>(
+    input: impl BufRead,
+    output: impl Write + Send,
+    middleware: Stack<MessageType>,
+) -> anyhow::Result<()>
+where
+    MessageType: DeserializeOwned + Serialize + Clone + Send + 'static,
+    N: Node<MessageType> + Send,
+{
+    main_loop_with_config::<MessageType, N>(input, output, middleware, LoopConfig::from_env())
+}
+
+/// Same as `main_loop_with_middleware`, configured by `config` rather than the
+/// environment.
+pub fn main_loop_with_config<MessageType, N>(
+    input: impl BufRead,
+    output: impl Write + Send,
+    middleware: Stack<MessageType>,
+    config: LoopConfig,
+) -> anyhow::Result<()>
+where
+    MessageType: DeserializeOwned + Serialize + Clone + Send + 'static,
+    N: Node<MessageType> + Send,
+{
+    main_loop_with_codec::<MessageType, N>(input, output, middleware, config, JsonCodec)
+}
+
+/// Same as `main_loop_with_config`, decoding the node's messages with `codec`. Init
+/// and the messages the loop answers itself stay JSON, and so does what the node
+/// sends unless it sends with `Message::send_with`.
+pub fn main_loop_with_codec<MessageType, N>(
+    input: impl BufRead,
+    mut output: impl Write + Send,
+    mut middleware: Stack<MessageType>,
+    config: LoopConfig,
+    codec: impl Codec,
+) -> anyhow::Result<()>
+where
+    MessageType: DeserializeOwned + Serialize + Clone + Send + 'static,
+    N: Node<MessageType> + Send,
+{
+    let mut lines = framed(input);
+    let (init_body, early) =
+        handshake::<MessageType>(&mut lines, &mut output, &config, &codec, N::init_ok_extra)?;
+
+    let metrics = config.metrics.then(|| Arc::new(Metrics::default()));
+    // bounded, a node falling behind stalls the reader rather than piling the input up
+    let (tx, rx) = crossbeam_channel::bounded(config.step_queue_cap);
+    // what the reader queues past the cap while a step is blocked on an rpc reply,
+    // stepped after `rx`
+    let (spill_tx, spill_rx) = crossbeam_channel::unbounded();
+    // the node's own messages, e.g. timer ticks, queue apart from the input; the step
+    // thread waits on both at once
+    let (node_tx, node_rx) = crossbeam_channel::unbounded();
+    let rpc = Rpc::new(&init_body.node_id);
+    // a step may queue messages for the node too
+    let step_tx = node_tx.clone();
+    let ctx = NodeContext {
+        tx: node_tx,
+        rpc: rpc.clone(),
+        cluster: Cluster::new(&init_body),
+        clock: Arc::new(SystemClock),
+        metrics: metrics.clone(),
+    };
+
+    let mut node: N =
+        Node::init_with(&init_body, ctx).context("construct node from init message failed")?;
+
+    let peer_limit = PeerSizeLimit::new(&init_body.node_ids, config.max_peer_message_bytes);
+    // the reader's own replies, e.g. to malformed requests, go out from the step thread
+    let features = &config.features;
+    let metrics = metrics.as_deref();
+    let output = Mutex::new(Metered::new(output, metrics));
+    std::thread::scope(|s| {
+        let output = &output;
+        let (node_rx, spill_rx, rpc) = (&node_rx, &spill_rx, &rpc);
+        // what waits for the step thread, the input's queue holding `queued` of it
+        let observe_depth = move |queued: usize| {
+            if let Some(metrics) = metrics {
+                metrics.observe_queue_depth(queued + node_rx.len());
+            }
+        };
+        let jh = s.spawn(move || {
+            let step_ctx = StepContext { tx: &step_tx };
+            let after_init = node.after_init(&mut *output.lock().expect("output lock poisoned"));
+            match after_init {
+                Err(e) if is_broken_pipe(&e) => {
+                    node_log!(Level::Info, "output closed, shutting down");
+                    return;
+                }
+                result => result.expect("node after_init failed"),
+            }
+            let mut early = early.into_iter();
+            let mut select = crossbeam_channel::Select::new();
+            let from_node = select.recv(node_rx);
+            select.recv(&rx);
+            select.recv(spill_rx);
+            // past the shutdown marker, only what steps queued for the node since the
+            // input ended is left
+            let mut draining = false;
+            loop {
+                let msg = if let Some(msg) = early.next() {
+                    msg
+                } else if draining {
+                    match node_rx.try_recv() {
+                        Ok(msg) => msg,
+                        Err(_) => break,
+                    }
+                } else {
+                    // reap the rpc replies which never came as their deadlines pass
+                    if rpc
+                        .next_deadline()
+                        .is_some_and(|deadline| deadline <= Instant::now())
+                    {
+                        rpc.reap();
+                    }
+                    let ready = match select.try_ready() {
+                        Ok(ready) => ready,
+                        // flush the batch stepped so far before waiting for more
+                        Err(_) => {
+                            match output.lock().expect("output lock poisoned").flush() {
+                                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                                    node_log!(Level::Info, "output closed, shutting down");
+                                    return;
+                                }
+                                result => result.expect("flush output failed"),
+                            }
+                            let ready = match rpc.next_deadline() {
+                                Some(deadline) => select.ready_deadline(deadline),
+                                None => Ok(select.ready()),
+                            };
+                            match ready {
+                                Ok(ready) => ready,
+                                // a deadline passed, reaped above
+                                Err(_) => continue,
+                            }
+                        }
+                    };
+                    // the input in order: the spilled messages came after the queued ones
+                    let queued = if ready == from_node {
+                        node_rx.try_recv().map(Queued::Msg)
+                    } else {
+                        rx.try_recv().or_else(|_| spill_rx.try_recv())
+                    };
+                    observe_depth(rx.len() + spill_rx.len());
+                    match queued {
+                        // taken by nothing else, but readiness may be spurious
+                        Err(crossbeam_channel::TryRecvError::Empty) => continue,
+                        Ok(Queued::Msg(msg)) => msg,
+                        Ok(Queued::Reply { line, counted }) => {
+                            let mut output = output.lock().expect("output lock poisoned");
+                            let written = if counted {
+                                output.write_all(&line)
+                            } else {
+                                output.get_mut().write_all(&line)
+                            };
+                            match written {
+                                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                                    node_log!(Level::Info, "output closed, shutting down");
+                                    return;
+                                }
+                                result => result.expect("write reply failed"),
+                            }
+                            continue;
+                        }
+                        Ok(Queued::Shutdown) | Err(_) => {
+                            draining = true;
+                            continue;
+                        }
+                    }
+                };
+                let mut output = output.lock().expect("output lock poisoned");
+                if let Err(e) = middleware.run(msg, |msg| {
+                    step_or_reply(&mut node, msg, &mut *output, &step_ctx)
+                }) {
+                    // dropping the receiver makes the reader stop too
+                    if is_broken_pipe(&e) {
+                        node_log!(Level::Info, "output closed, shutting down");
+                        return;
+                    }
+                    panic!("step msg error: {e:?}");
+                }
+            }
+            let mut output = output.lock().expect("output lock poisoned");
+            let shutdown = node
+                .on_shutdown(&mut *output)
+                .and_then(|()| Ok(output.flush()?));
+            match shutdown {
+                Err(e) if is_broken_pipe(&e) => {
+                    node_log!(Level::Info, "output closed, shutting down")
+                }
+                result => result.expect("node shutdown failed"),
+            }
+            // the timer threads stop once their sends fail, after the loop returned
+        });
+
+        // a message the gone step thread didn't take is of no use, only the failure is
+        // kept
+        let enqueue = |queued| {
+            loop {
+                // a step blocked on an rpc reply gets it only from here: keep reading,
+                // queueing past the cap, and behind what was queued so meanwhile
+                if rpc.blocked() || !spill_tx.is_empty() {
+                    spill_tx.send(queued).map_err(drop)?;
+                    break;
+                }
+                let mut select = crossbeam_channel::Select::new();
+                let send = select.send(&tx);
+                select.recv(rpc.on_block());
+                let ready = select.select();
+                if ready.index() == send {
+                    ready.send(&tx, queued).map_err(drop)?;
+                    break;
+                }
+                let _ = ready.recv(rpc.on_block());
+            }
+            observe_depth(tx.len() + spill_tx.len());
+            Ok::<_, ()>(())
+        };
+        let read = (|| {
+            for line in lines {
+                let line = line.context("Maelstrom input from STDIN could not be read")?;
+                if peer_limit.rejects(&line) {
+                    continue;
+                }
+                // the output is never locked here, a step may hold it while it waits on
+                // an rpc reply coming through here: the loop's own replies are rendered
+                // and queued for the step thread to write
+                let mut reply = Vec::new();
+                let counted = if let Some(answer) = features.intercept(&line) {
+                    answer.send(&mut reply)?;
+                    false
+                } else if let Some(answer) =
+                    metrics.and_then(|metrics| metrics.intercept(&line, rpc))
+                {
+                    answer.send(&mut reply)?;
+                    false
+                } else if rpc.dispatch(&line) {
+                    continue;
+                } else {
+                    match codec.decode::<Message<MessageType>>(&line) {
+                        Ok(msg) => {
+                            if enqueue(Queued::Msg(msg)).is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                        Err(e) => {
+                            reject_malformed::<MessageType>(
+                                &line,
+                                &e,
+                                config.reply_not_supported,
+                                &mut reply,
+                            )?;
+                            true
+                        }
+                    }
+                };
+                let reply = Queued::Reply {
+                    line: reply,
+                    counted,
+                };
+                if enqueue(reply).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        })();
+
+        // whatever stopped the reading, let the step thread finish what is queued,
+        // including what rpc callbacks queued for the node just before
+        let _ = enqueue(Queued::Shutdown);
+        rpc.abandon();
+        jh.join().expect("stdout thread error");
+        if let Some(metrics) = metrics {
+            metrics.log_report(rpc);
+        }
+        read
+    })
+}
+
+/// What the step thread of `main_loop` takes off its queue.
+enum Queued<M> {
+    Msg(Message<M>),
+    /// a reply the reader rendered itself, written as is; `counted` by the metrics
+    /// unless it's their own report
+    Reply {
+        line: Vec<u8>,
+        counted: bool,
+    },
+    /// the input ended, stop once the messages queued before are stepped
+    Shutdown,
+}
+
+pub fn main_loop_single_threaded<MessageType, N>() -> anyhow::Result<()>
+where
+    MessageType: DeserializeOwned + Serialize,
+    N: Node<MessageType>,
+{
+    main_loop_single_threaded_with_io::<MessageType, N>(
+        BufReader::new(std::io::stdin().lock()),
+        stdout().lock(),
+    )
+}
+
+/// Read, step and write all on the calling thread, skipping the writer thread and the
+/// channel hop in front of it.
+///
+/// Only suitable for nodes without timers: nothing else runs while waiting for input.
+/// Messages a node sends itself through its `Sender` are stepped right after the
+/// message which triggered them.
+pub fn main_loop_single_threaded_with_io<MessageType, N>(
+    input: impl BufRead,
+    output: impl Write,
+) -> anyhow::Result<()>
+where
+    MessageType: DeserializeOwned + Serialize,
+    N: Node<MessageType>,
+{
+    main_loop_single_threaded_with_config::<MessageType, N>(input, output, LoopConfig::from_env())
+}
+
+/// Same as `main_loop_single_threaded_with_io`, configured by `config` rather than
+/// the environment.
+pub fn main_loop_single_threaded_with_config<MessageType, N>(
+    input: impl BufRead,
+    output: impl Write,
+    config: LoopConfig,
+) -> anyhow::Result<()>
+where
+    MessageType: DeserializeOwned + Serialize,
+    N: Node<MessageType>,
+{
+    main_loop_single_threaded_with_codec::<MessageType, N>(input, output, config, JsonCodec)
+}
+
+/// Same as `main_loop_single_threaded_with_config`, decoding the node's messages with
+/// `codec` like `main_loop_with_codec`.
+pub fn main_loop_single_threaded_with_codec<MessageType, N>(
+    input: impl BufRead,
+    mut output: impl Write,
+    config: LoopConfig,
+    codec: impl Codec,
+) -> anyhow::Result<()>
+where
+    MessageType: DeserializeOwned + Serialize,
+    N: Node<MessageType>,
+{
+    let mut lines = framed(input);
+    let (init_body, early) =
+        handshake::<MessageType>(&mut lines, &mut output, &config, &codec, N::init_ok_extra)?;
+    let peer_limit = PeerSizeLimit::new(&init_body.node_ids, config.max_peer_message_bytes);
+    let metrics = config.metrics.then(|| Arc::new(Metrics::default()));
+    let features = &config.features;
+    let mut output = Metered::new(output, metrics.as_deref());
+
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let rpc = Rpc::new(&init_body.node_id);
+    let step_tx = tx.clone();
+    let step_ctx = StepContext { tx: &step_tx };
+    let ctx = NodeContext {
+        tx,
+        rpc: rpc.clone(),
+        cluster: Cluster::new(&init_body),
+        clock: Arc::new(SystemClock),
+        metrics: metrics.clone(),
+    };
+    let mut node: N =
+        Node::init_with(&init_body, ctx).context("construct node from init message failed")?;
+
+    let drain = |node: &mut N, output: &mut _| -> anyhow::Result<()> {
+        while let Ok(msg) = rx.try_recv() {
+            step_or_reply(node, msg, output, &step_ctx).context("step msg error")?;
+        }
+        Ok(())
+    };
+    let step = |node: &mut N, msg, output: &mut _| -> anyhow::Result<()> {
+        step_or_reply(node, msg, output, &step_ctx).context("step msg error")?;
+        drain(node, output)
+    };
+    let run = || -> anyhow::Result<()> {
+        node.after_init(&mut output)?;
+        for msg in early {
+            step(&mut node, msg, &mut output)?;
+        }
+        for line in lines {
+            let line = line.context("Maelstrom input from STDIN could not be read")?;
+            if peer_limit.rejects(&line) {
+                continue;
+            }
+            if let Some(reply) = features.intercept(&line) {
+                reply.send(output.get_mut())?;
+                continue;
+            }
+            if let Some(reply) = metrics
+                .as_ref()
+                .and_then(|metrics| metrics.intercept(&line, &rpc))
+            {
+                reply.send(output.get_mut())?;
+                continue;
+            }
+            if rpc.reap() > 0 {
+                // the callbacks may have queued messages for the node
+                drain(&mut node, &mut output)?;
+            }
+            if rpc.dispatch(&line) {
+                // the callback may have queued messages for the node
+                drain(&mut node, &mut output)?;
+                continue;
+            }
+            match codec.decode::<Message<MessageType>>(&line) {
+                Ok(msg) => step(&mut node, msg, &mut output)?,
+                Err(e) => reject_malformed::<MessageType>(
+                    &line,
+                    &e,
+                    config.reply_not_supported,
+                    &mut output,
+                )?,
+            }
+        }
+        node.on_shutdown(&mut output)?;
+        Ok(output.flush()?)
+    };
+    let ran = run();
+    if let Some(metrics) = &metrics {
+        metrics.log_report(&rpc);
+    }
+    match ran {
+        Err(e) if is_broken_pipe(&e) => {
+            node_log!(Level::Info, "output closed, shutting down");
+            Ok(())
+        }
+        result => result,
     }
+}
 
-    let (tx, rx) = std::sync::mpsc::channel();
+/// Whether the error comes from writing to an output whose reader went away.
+fn is_broken_pipe(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        let kind = match cause.downcast_ref::<serde_json::Error>() {
+            Some(e) => e.io_error_kind(),
+            None => cause.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+        };
+        kind == Some(std::io::ErrorKind::BrokenPipe)
+    })
+}
 
-    let mut node: N = Node::init_from(init_body, tx.clone())
-        .context("construct node from init message failed")
-        .expect("Fail to construct the node from init msg");
+/// Input which retries transient read errors a bounded number of times, backing off a
+/// little longer each time, before giving up. Retrying at the buffer level never loses
+/// the part of a line read before the error.
+struct Retrying<R> {
+    inner: R,
+    max_attempts: usize,
+    backoff: Duration,
+}
 
-    let jh = std::thread::spawn(move || {
-        let mut stdout = stdout().lock();
-        for msg in rx {
-            node.step(msg, &mut stdout).expect("step msg error");
+impl<R> Retrying<R> {
+    const MAX_ATTEMPTS: usize = 5;
+    const BACKOFF: Duration = Duration::from_millis(10);
+
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            max_attempts: Self::MAX_ATTEMPTS,
+            backoff: Self::BACKOFF,
         }
-    });
+    }
+}
+
+impl<R: BufRead> std::io::Read for Retrying<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
 
-    let stdin =
-        serde_json::Deserializer::from_reader(&mut stdin).into_iter::<Message<MessageType>>();
-    for line in stdin {
-        let msg = line.context("Maelstrom input from STDIN could not be read")?;
-        if let Err(_) = tx.send(msg) {
-            return Ok::<_, anyhow::Error>(());
+impl<R: BufRead> BufRead for Retrying<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        let mut attempt = 1;
+        loop {
+            match self.inner.fill_buf() {
+                Ok(_) => break,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock
+                    ) && attempt < self.max_attempts =>
+                {
+                    node_log!(
+                        Level::Warn,
+                        "retry reading input after {e} ({attempt}/{})",
+                        self.max_attempts
+                    );
+                    std::thread::sleep(self.backoff * attempt as u32);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
         }
+        // hands out what the successful call above buffered
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
     }
+}
+
+/// Frame the input by lines, dropping the blank ones. Lines which aren't valid UTF-8
+/// are reported to STDERR and skipped, transient read errors are retried.
+fn framed(input: impl BufRead) -> impl Iterator<Item = std::io::Result<String>> {
+    Retrying::new(input)
+        .split(b'\n')
+        .filter_map(|line| match line {
+            Ok(line) => match String::from_utf8(line) {
+                Ok(line) if line.trim().is_empty() => None,
+                Ok(line) => Some(Ok(line)),
+                Err(e) => {
+                    node_log!(Level::Warn, "skip non UTF-8 line {:?}: {e}", e.as_bytes());
+                    None
+                }
+            },
+            Err(e) => Some(Err(e)),
+        })
+}
 
-    jh.join().expect("stdout thread error");
-    Ok(())
+/// Wait for init and acknowledge it, with the node's `init_ok_extra` spliced in.
+/// Returns the messages which arrived before it.
+fn handshake<MessageType: DeserializeOwned>(
+    lines: &mut impl Iterator<Item = std::io::Result<String>>,
+    output: &mut impl Write,
+    config: &LoopConfig,
+    codec: &impl Codec,
+    init_ok_extra: impl FnOnce(&InitBody) -> HashMap<String, serde_json::Value>,
+) -> anyhow::Result<(InitBody, Vec<Message<MessageType>>)> {
+    // some harnesses send control messages ahead of init, hold them until the node exists
+    let buffer_cap = config.init_buffer_cap;
+    let mut early = Vec::new();
+    let init_msg = loop {
+        let line = lines
+            .next()
+            .context("the input ended before the init message")?
+            .context("Maelstrom input from STDIN could not be read")?;
+        if let Ok(msg) = serde_json::from_str::<Message<InitMsg>>(&line) {
+            if matches!(msg.body.payload, InitMsg::Init(..)) {
+                break msg;
+            }
+        }
+        match codec.decode::<Message<MessageType>>(&line) {
+            Ok(msg) => early.push(msg),
+            Err(e) => reject_malformed::<MessageType>(
+                &line,
+                &e,
+                config.reply_not_supported,
+                &mut *output,
+            )?,
+        }
+        anyhow::ensure!(
+            early.len() <= buffer_cap,
+            "more than {buffer_cap} messages arrived before init"
+        );
+    };
+
+    let mut init_ok = init_msg.into_init_ok()?;
+    let InitMsg::Init(init_body) = init_msg.body.payload else {
+        unreachable!()
+    };
+    init_body.validate()?;
+    if let InitMsg::InitOk { extra } = &mut init_ok.body.payload {
+        for (key, value) in init_ok_extra(&init_body) {
+            anyhow::ensure!(
+                !matches!(key.as_str(), "type" | "msg_id" | "in_reply_to"),
+                "init_ok_extra can't override the {key} field"
+            );
+            extra.insert(key, value);
+        }
+    }
+    init_ok.send(output)?;
+    log::init(&init_body.node_id);
+    Ok((init_body, early))
 }
 
 #[cfg(test)]
 mod test {
-    use serde::Serialize;
+    use std::{
+        collections::HashMap,
+        io::{BufReader, Write},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        codec::{Codec, JsonCodec},
+        features::Features,
+        main_loop_single_threaded_with_codec, main_loop_single_threaded_with_config,
+        main_loop_single_threaded_with_io, main_loop_with_codec, main_loop_with_config,
+        main_loop_with_io, main_loop_with_middleware,
+        metrics::Counter,
+        middleware::{DedupMiddleware, LogMiddleware, Middleware, SlowStep, Stack},
+        rpc::{NodeContext, Rpc, StepContext},
+        ticker::{spawn_ticker, RoundGuard},
+        Body, IdGen, InitBody, InitError, LoopConfig, MaelstromError, Message, Node,
+    };
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum EchoMessage {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    struct EchoNode {
+        msg_ids: IdGen,
+    }
+
+    impl Node<EchoMessage> for EchoNode {
+        fn init_from(
+            _: &InitBody,
+            _: crossbeam_channel::Sender<Message<EchoMessage>>,
+        ) -> anyhow::Result<Self> {
+            Ok(Self {
+                msg_ids: IdGen::default(),
+            })
+        }
+
+        fn step(
+            &mut self,
+            req: Message<EchoMessage>,
+            output: &mut impl Write,
+        ) -> anyhow::Result<()> {
+            let mut reply = req.into_reply(Some(&self.msg_ids));
+            if let EchoMessage::Echo { echo } = reply.body.payload {
+                reply.body.payload = EchoMessage::EchoOk { echo };
+                reply.send(output)?;
+            }
+            Ok(())
+        }
+    }
+
+    const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#;
+
+    fn echo(msg_id: usize, echo: &str) -> String {
+        format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":{msg_id},"echo":"{echo}"}}}}"#
+        )
+    }
+
+    fn run_echo(input: &str) -> anyhow::Result<Vec<serde_json::Value>> {
+        let mut output = Vec::new();
+        main_loop_with_io::<EchoMessage, EchoNode>(input.as_bytes(), &mut output)?;
+        parse_lines(&output)
+    }
+
+    /// The settings come with the call, tests run in parallel and share the env.
+    fn run_echo_with(input: &str, config: LoopConfig) -> anyhow::Result<Vec<serde_json::Value>> {
+        let mut output = Vec::new();
+        main_loop_with_config::<EchoMessage, EchoNode>(
+            input.as_bytes(),
+            &mut output,
+            Stack::default(),
+            config,
+        )?;
+        parse_lines(&output)
+    }
+
+    fn parse_lines(output: &[u8]) -> anyhow::Result<Vec<serde_json::Value>> {
+        output
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| Ok(serde_json::from_slice(line)?))
+            .collect()
+    }
+
+    #[test]
+    fn test_middleware_stack() -> anyhow::Result<()> {
+        struct Count(Arc<AtomicUsize>, Arc<AtomicUsize>);
+        impl<M> Middleware<M> for Count {
+            fn before(&mut self, _: &Message<M>) -> bool {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            fn after(&mut self, _: &Message<M>, result: &anyhow::Result<()>) {
+                assert!(result.is_ok());
+                self.1.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let (before, after) = (Arc::default(), Arc::default());
+        let stack = Stack::default()
+            .with(LogMiddleware::new(std::io::sink()))
+            .with(DedupMiddleware::new(16))
+            .with(Count(Arc::clone(&before), Arc::clone(&after)));
+        let input = [INIT.to_string(), echo(2, "a"), echo(2, "a"), echo(3, "b")].join("\n");
+        let mut output = Vec::new();
+        main_loop_with_middleware::<EchoMessage, EchoNode>(input.as_bytes(), &mut output, stack)?;
+
+        let replies = parse_lines(&output)?;
+        assert_eq!(replies.len(), 3, "the redelivered request is dropped");
+        assert_eq!(replies[2]["body"]["in_reply_to"], 3);
+        assert_eq!(before.load(Ordering::Relaxed), 2);
+        assert_eq!(after.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+
+    /// Accept the first `budget` bytes, then behave like a pipe whose reader is gone.
+    struct ClosingPipe {
+        budget: usize,
+    }
+
+    impl Write for ClosingPipe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.budget == 0 {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            let n = buf.len().min(self.budget);
+            self.budget -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_closed_output_shuts_down_cleanly() -> anyhow::Result<()> {
+        let input = [INIT.to_string(), echo(2, "a"), echo(3, "b"), echo(4, "c")].join("\n");
+        let output = ClosingPipe { budget: INIT.len() };
+        main_loop_with_io::<EchoMessage, EchoNode>(input.as_bytes(), output)?;
+        let output = ClosingPipe { budget: INIT.len() };
+        main_loop_single_threaded_with_io::<EchoMessage, EchoNode>(input.as_bytes(), output)?;
+        Ok(())
+    }
 
-    use crate::{Body, InitBody, InitMsg, Message};
+    #[test]
+    fn test_slow_step() -> anyhow::Result<()> {
+        let delay = std::time::Duration::from_millis(20);
+        let input = [INIT.to_string(), echo(2, "a"), echo(3, "b"), echo(4, "c")].join("\n");
+        let mut output = Vec::new();
+        let start = std::time::Instant::now();
+        main_loop_with_middleware::<EchoMessage, EchoNode>(
+            input.as_bytes(),
+            &mut output,
+            Stack::default().with(SlowStep::new(delay)),
+        )?;
+        assert!(start.elapsed() >= delay * 3);
+        let replies = parse_lines(&output)?;
+        let echoes = replies[1..]
+            .iter()
+            .map(|reply| reply["body"]["echo"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(echoes, ["a", "b", "c"]);
+        Ok(())
+    }
 
     #[test]
-    fn name() -> anyhow::Result<()> {
-        let init = InitMsg::Init(InitBody {
-            node_id: "n1".to_string(),
-            node_ids: vec!["n1".to_string(), "n2".to_string()],
+    fn test_full_step_queue_blocks_reader() -> anyhow::Result<()> {
+        /// Hands out one line at a time, counting those read so far.
+        struct Lines {
+            lines: std::collections::VecDeque<Vec<u8>>,
+            read: Arc<AtomicUsize>,
+        }
+
+        impl std::io::Read for Lines {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = std::io::BufRead::fill_buf(self)?.len().min(buf.len());
+                buf[..n].copy_from_slice(&self.lines[0][..n]);
+                std::io::BufRead::consume(self, n);
+                Ok(n)
+            }
+        }
+
+        impl std::io::BufRead for Lines {
+            fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+                Ok(self.lines.front().map_or(&[], |line| line.as_slice()))
+            }
+
+            fn consume(&mut self, amt: usize) {
+                if let Some(line) = self.lines.front_mut() {
+                    line.drain(..amt);
+                    if line.is_empty() {
+                        self.lines.pop_front();
+                        self.read.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+
+        let cap = 1;
+        let mut lines = vec![INIT.to_string()];
+        lines.extend((2..8).map(|msg_id| echo(msg_id, "a")));
+        let read = Arc::new(AtomicUsize::new(0));
+        let input = Lines {
+            lines: lines
+                .iter()
+                .map(|line| format!("{line}\n").into())
+                .collect(),
+            read: Arc::clone(&read),
+        };
+        let node = std::thread::spawn(move || {
+            let mut output = Vec::new();
+            main_loop_with_config::<EchoMessage, EchoNode>(
+                input,
+                &mut output,
+                Stack::default().with(SlowStep::new(Duration::from_millis(200))),
+                LoopConfig {
+                    step_queue_cap: cap,
+                    ..Default::default()
+                },
+            )
+            .map(|()| output)
         });
-        let msg = Message {
-            src: "c1".to_string(),
-            dst: "n1".to_string(),
-            body: Body {
-                payload: init,
-                id: Some(1),
-                in_reply_to: Some(1),
+
+        // while the first echo is stepped: init, that echo, `cap` queued behind it and
+        // the one the reader waits to queue
+        std::thread::sleep(Duration::from_millis(50));
+        let stalled = read.load(Ordering::Relaxed);
+        assert!(stalled <= cap + 3, "{stalled} lines read");
+        assert!(stalled < lines.len());
+
+        let output = node.join().expect("loop thread panicked")?;
+        assert_eq!(read.load(Ordering::Relaxed), lines.len());
+        assert_eq!(parse_lines(&output)?.len(), lines.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue_depth_grows_behind_slow_step() -> anyhow::Result<()> {
+        let metrics = r#"{"src":"c1","dest":"n1","body":{"type":"metrics","msg_id":99}}"#;
+        let mut input = vec![INIT.to_string()];
+        input.extend((2..12).map(|msg_id| echo(msg_id, "a")));
+        input.push(metrics.to_string());
+        let mut output = Vec::new();
+        main_loop_with_config::<EchoMessage, EchoNode>(
+            input.join("\n").as_bytes(),
+            &mut output,
+            Stack::default().with(SlowStep::new(std::time::Duration::from_millis(10))),
+            LoopConfig {
+                metrics: true,
+                ..Default::default()
             },
+        )?;
+        let replies = parse_lines(&output)?;
+        let metrics_ok = replies
+            .iter()
+            .find(|reply| reply["body"]["type"] == "metrics_ok")
+            .expect("metrics answered");
+        // the reader answers while the echoes still wait for the step thread
+        let max_depth = metrics_ok["body"]["max_queue_depth"].as_u64().unwrap();
+        assert!(max_depth > 1, "max queue depth {max_depth}");
+        assert!(metrics_ok["body"]["queue_depth"].as_u64().unwrap() <= max_depth);
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffered_output_flushed_per_batch() -> anyhow::Result<()> {
+        /// Counts the writes reaching it through the `BufWriter`.
+        struct Counting<'a> {
+            bytes: &'a mut Vec<u8>,
+            writes: usize,
+        }
+
+        impl Write for Counting<'_> {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.writes += 1;
+                self.bytes.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut input = vec![INIT.to_string()];
+        input.extend((2..12).map(|msg_id| echo(msg_id, "a")));
+        let mut bytes = Vec::new();
+        let mut output = std::io::BufWriter::new(Counting {
+            bytes: &mut bytes,
+            writes: 0,
+        });
+        // the step is slow enough for the echoes to queue up behind it
+        main_loop_with_middleware::<EchoMessage, EchoNode>(
+            input.join("\n").as_bytes(),
+            &mut output,
+            Stack::default().with(SlowStep::new(std::time::Duration::from_millis(5))),
+        )?;
+        let writes = output.get_ref().writes;
+        drop(output);
+        let replies = parse_lines(&bytes)?;
+        assert_eq!(replies.len(), 11);
+        assert!(
+            writes < replies.len(),
+            "{writes} writes for {} replies",
+            replies.len()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_shutdown_with_running_timer() -> anyhow::Result<()> {
+        struct Ticking {
+            inner: EchoNode,
+            round: Arc<RoundGuard>,
+        }
+        impl Node<EchoMessage> for Ticking {
+            fn init_from(
+                init: &InitBody,
+                tx: crossbeam_channel::Sender<Message<EchoMessage>>,
+            ) -> anyhow::Result<Self> {
+                let round = Arc::new(RoundGuard::default());
+                let tick = || Message {
+                    src: "n1".to_string(),
+                    dst: "n1".to_string(),
+                    body: Body {
+                        id: None,
+                        in_reply_to: None,
+                        lamport: None,
+                        op_id: None,
+                        payload: EchoMessage::EchoOk {
+                            echo: "tick".to_string(),
+                        },
+                    },
+                };
+                spawn_ticker(
+                    Duration::from_millis(1),
+                    Arc::clone(&round),
+                    tx.clone(),
+                    tick,
+                );
+                Ok(Self {
+                    inner: EchoNode::init_from(init, tx)?,
+                    round,
+                })
+            }
+
+            fn step(
+                &mut self,
+                req: Message<EchoMessage>,
+                output: &mut impl Write,
+            ) -> anyhow::Result<()> {
+                if req.src == req.dst {
+                    self.round.finish();
+                    return Ok(());
+                }
+                self.inner.step(req, output)
+            }
+
+            fn on_shutdown(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+                let bye = Message {
+                    src: "n1".to_string(),
+                    dst: "c1".to_string(),
+                    body: Body {
+                        id: Some(self.inner.msg_ids.next()),
+                        in_reply_to: None,
+                        lamport: None,
+                        op_id: None,
+                        payload: EchoMessage::Echo {
+                            echo: "bye".to_string(),
+                        },
+                    },
+                };
+                bye.send(output)
+            }
+        }
+
+        let input = [INIT.to_string(), echo(2, "a")].join("\n");
+        let mut output = std::io::BufWriter::new(Vec::new());
+        // returns although the ticker keeps the node's sender alive
+        main_loop_with_io::<EchoMessage, Ticking>(input.as_bytes(), &mut output)?;
+        // flushed before returning, not when the BufWriter drops
+        let replies = parse_lines(output.get_ref())?;
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[1]["body"]["echo"], "a");
+        assert_eq!(replies[2]["body"]["echo"], "bye");
+
+        let mut output = Vec::new();
+        main_loop_single_threaded_with_io::<EchoMessage, Ticking>(input.as_bytes(), &mut output)?;
+        assert_eq!(parse_lines(&output)?[2]["body"]["echo"], "bye");
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_node_missing_from_node_ids() {
+        let init = r#"{"src":"c0","dest":"n3","body":{"type":"init","msg_id":1,"node_id":"n3","node_ids":["n1","n2"]}}"#;
+        let mut output = Vec::new();
+        let err = main_loop_with_io::<EchoMessage, EchoNode>(init.as_bytes(), &mut output)
+            .expect_err("init should be rejected");
+        assert_eq!(
+            err.downcast_ref::<InitError>(),
+            Some(&InitError::NodeNotInCluster {
+                node_id: "n3".to_string(),
+                node_ids: vec!["n1".to_string(), "n2".to_string()],
+            })
+        );
+        assert!(err.to_string().contains("n3 is not part of the cluster"));
+        assert!(output.is_empty(), "no init_ok for a rejected init");
+    }
+
+    #[test]
+    fn test_input_without_init_is_an_error() {
+        let input = [echo(2, "early"), "not json".to_string()].join("\n");
+        let err = main_loop_with_io::<EchoMessage, EchoNode>(input.as_bytes(), Vec::new())
+            .expect_err("there is no init");
+        assert!(err.to_string().contains("ended before the init message"));
+        let err = main_loop_single_threaded_with_io::<EchoMessage, EchoNode>(
+            input.as_bytes(),
+            Vec::new(),
+        )
+        .expect_err("there is no init");
+        assert!(err.to_string().contains("ended before the init message"));
+    }
+
+    #[test]
+    fn test_single_threaded_loop() -> anyhow::Result<()> {
+        let input = [echo(2, "early"), INIT.to_string(), echo(3, "late")].join("\n");
+        let mut output = Vec::new();
+        main_loop_single_threaded_with_io::<EchoMessage, EchoNode>(input.as_bytes(), &mut output)?;
+        let replies = parse_lines(&output)?;
+        assert_eq!(
+            replies,
+            run_echo(&input)?,
+            "both loops should produce the same output"
+        );
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[2]["body"]["echo"], "late");
+        assert_eq!(replies[2]["body"]["in_reply_to"], 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_loops_decode_with_codec() -> anyhow::Result<()> {
+        /// JSON, counting the lines it decodes.
+        #[derive(Default)]
+        struct Counting(AtomicUsize);
+        impl Codec for &Counting {
+            fn encode<T: Serialize>(
+                &self,
+                value: &T,
+                output: &mut impl Write,
+            ) -> anyhow::Result<()> {
+                JsonCodec.encode(value, output)
+            }
+
+            fn decode<T: serde::de::DeserializeOwned>(&self, line: &str) -> anyhow::Result<T> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                JsonCodec.decode(line)
+            }
+        }
+
+        // init is always JSON, the message ahead of it goes through the codec
+        let input = [echo(2, "a"), INIT.to_string(), echo(3, "b"), echo(4, "c")].join("\n");
+        let counting = Counting::default();
+        let mut output = Vec::new();
+        main_loop_with_codec::<EchoMessage, EchoNode>(
+            input.as_bytes(),
+            &mut output,
+            Stack::default(),
+            LoopConfig::default(),
+            &counting,
+        )?;
+        assert_eq!(parse_lines(&output)?.len(), 4);
+        assert_eq!(counting.0.load(Ordering::Relaxed), 3);
+
+        let counting = Counting::default();
+        let mut output = Vec::new();
+        main_loop_single_threaded_with_codec::<EchoMessage, EchoNode>(
+            input.as_bytes(),
+            &mut output,
+            LoopConfig::default(),
+            &counting,
+        )?;
+        assert_eq!(parse_lines(&output)?.len(), 4);
+        assert_eq!(counting.0.load(Ordering::Relaxed), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_queues_message_for_itself() -> anyhow::Result<()> {
+        /// Answers `later:<echo>` once it comes back around, by queueing it again.
+        struct Deferring {
+            msg_ids: IdGen,
+        }
+        impl Node<EchoMessage> for Deferring {
+            fn init_from(
+                _: &InitBody,
+                _: crossbeam_channel::Sender<Message<EchoMessage>>,
+            ) -> anyhow::Result<Self> {
+                Ok(Self {
+                    msg_ids: IdGen::default(),
+                })
+            }
+
+            fn step_with(
+                &mut self,
+                mut req: Message<EchoMessage>,
+                output: &mut impl Write,
+                ctx: &StepContext<'_, EchoMessage>,
+            ) -> anyhow::Result<()> {
+                let EchoMessage::Echo { echo } = &req.body.payload else {
+                    return Ok(());
+                };
+                if let Some(echo) = echo.strip_prefix("later:") {
+                    req.body.payload = EchoMessage::Echo {
+                        echo: echo.to_string(),
+                    };
+                    return Ok(ctx.tx.send(req)?);
+                }
+                let echo = echo.clone();
+                req.reply_with(&self.msg_ids, EchoMessage::EchoOk { echo })
+                    .send(output)
+            }
+        }
+
+        let input = [INIT.to_string(), echo(2, "later:a"), echo(3, "b")].join("\n");
+        let mut output = Vec::new();
+        main_loop_single_threaded_with_io::<EchoMessage, Deferring>(input.as_bytes(), &mut output)?;
+        // stepped right after the message which queued it
+        let replies = parse_lines(&output)?;
+        let echoes = replies[1..]
+            .iter()
+            .map(|reply| {
+                (
+                    reply["body"]["echo"].clone(),
+                    reply["body"]["in_reply_to"].clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            echoes,
+            [
+                (serde_json::json!("a"), serde_json::json!(2)),
+                (serde_json::json!("b"), serde_json::json!(3))
+            ]
+        );
+
+        let mut output = Vec::new();
+        main_loop_with_io::<EchoMessage, Deferring>(input.as_bytes(), &mut output)?;
+        let mut echoes = parse_lines(&output)?[1..]
+            .iter()
+            .map(|reply| {
+                reply["body"]["echo"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        echoes.sort();
+        assert_eq!(echoes, ["a", "b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_internal_messages_go_to_on_internal() -> anyhow::Result<()> {
+        /// Raises an internal event per echo, answering the client from there.
+        struct Raising;
+        impl Node<EchoMessage> for Raising {
+            fn init_from(
+                _: &InitBody,
+                _: crossbeam_channel::Sender<Message<EchoMessage>>,
+            ) -> anyhow::Result<Self> {
+                Ok(Self)
+            }
+
+            fn step_with(
+                &mut self,
+                req: Message<EchoMessage>,
+                _: &mut impl Write,
+                ctx: &StepContext<'_, EchoMessage>,
+            ) -> anyhow::Result<()> {
+                assert!(!req.is_internal(), "internal message stepped");
+                Ok(ctx.tx.send(Message::internal(req.body.payload))?)
+            }
+
+            fn on_internal(
+                &mut self,
+                payload: EchoMessage,
+                output: &mut impl Write,
+                _: &StepContext<'_, EchoMessage>,
+            ) -> anyhow::Result<()> {
+                let EchoMessage::Echo { echo } = payload else {
+                    return Ok(());
+                };
+                Message {
+                    src: "n1".to_string(),
+                    dst: "c1".to_string(),
+                    ..Message::internal(EchoMessage::EchoOk {
+                        echo: format!("internal {echo}"),
+                    })
+                }
+                .send(output)?;
+                // nobody to answer, an error raised here is only logged
+                Err(MaelstromError::Crash.because("no one hears this").into())
+            }
+        }
+
+        let input = [INIT.to_string(), echo(2, "a")].join("\n");
+        let mut output = Vec::new();
+        main_loop_single_threaded_with_io::<EchoMessage, Raising>(input.as_bytes(), &mut output)?;
+        let replies = parse_lines(&output)?;
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[1]["body"]["echo"], "internal a");
+        assert_eq!(replies[1]["body"]["in_reply_to"], serde_json::Value::Null);
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_line_is_skipped() -> anyhow::Result<()> {
+        let input = [
+            INIT.to_string(),
+            echo(2, "first"),
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo""#.to_string(),
+            echo(3, "second"),
+        ]
+        .join("\n");
+        let replies = run_echo(&input)?;
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["body"]["type"], "init_ok");
+        assert_eq!(replies[1]["body"]["echo"], "first");
+        assert_eq!(replies[2]["body"]["echo"], "second");
+        assert_eq!(replies[2]["body"]["in_reply_to"], 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_garbage_between_messages_is_survived() -> anyhow::Result<()> {
+        let input = [
+            "garbage before init".to_string(),
+            INIT.to_string(),
+            "not json at all".to_string(),
+            echo(2, "first"),
+            "[1, 2, 3]".to_string(),
+            r#"{"src":"c1","dest":"n1"}"#.to_string(),
+            r#"{"src":"c1","dest":"n1","body":{"type":"frobnicate","msg_id":3}}"#.to_string(),
+            "}{".to_string(),
+            echo(4, "second"),
+        ]
+        .join("\n");
+        let config = LoopConfig {
+            reply_not_supported: true,
+            ..Default::default()
+        };
+        let mut threaded = Vec::new();
+        main_loop_with_config::<EchoMessage, EchoNode>(
+            input.as_bytes(),
+            &mut threaded,
+            Stack::default(),
+            config.clone(),
+        )?;
+        let mut single = Vec::new();
+        main_loop_single_threaded_with_config::<EchoMessage, EchoNode>(
+            input.as_bytes(),
+            &mut single,
+            config,
+        )?;
+        for output in [threaded, single] {
+            // only the line with a msg_id gets an error, the rest are just skipped
+            let replies = parse_lines(&output)?;
+            let kinds = replies
+                .iter()
+                .map(|reply| reply["body"]["type"].as_str().unwrap_or_default())
+                .collect::<Vec<_>>();
+            assert_eq!(kinds, ["init_ok", "echo_ok", "error", "echo_ok"]);
+            assert_eq!(replies[2]["body"]["code"], 10);
+            assert_eq!(replies[2]["body"]["in_reply_to"], 3);
+            assert_eq!(replies[2]["dest"], "c1");
+            assert_eq!(replies[3]["body"]["echo"], "second");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_utf8_line_is_skipped() -> anyhow::Result<()> {
+        let mut input = Vec::new();
+        input.extend_from_slice(INIT.as_bytes());
+        input.extend_from_slice(b"\n{\"src\":\"c1\",\xff\xfe}\n");
+        input.extend_from_slice(echo(2, "after").as_bytes());
+        let mut output = Vec::new();
+        main_loop_with_io::<EchoMessage, EchoNode>(input.as_slice(), &mut output)?;
+        let replies = parse_lines(&output)?;
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[1]["body"]["echo"], "after");
+        Ok(())
+    }
+
+    #[test]
+    fn test_mistyped_request_gets_error_reply() -> anyhow::Result<()> {
+        let input = [
+            INIT.to_string(),
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":5}}"#.to_string(),
+            echo(3, "fine"),
+        ]
+        .join("\n");
+        let replies = run_echo(&input)?;
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[1]["body"]["type"], "error");
+        assert_eq!(replies[1]["body"]["code"], 12);
+        assert_eq!(replies[1]["body"]["in_reply_to"], 2);
+        assert_eq!(replies[1]["dest"], "c1");
+        assert_eq!(replies[2]["body"]["echo"], "fine");
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_type_gets_not_supported() -> anyhow::Result<()> {
+        let input = [
+            INIT.to_string(),
+            r#"{"src":"c1","dest":"n1","body":{"type":"frobnicate","msg_id":2}}"#.to_string(),
+            // a known type with a bad field is still malformed
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3}}"#.to_string(),
+            echo(4, "fine"),
+        ]
+        .join("\n");
+        let mut output = Vec::new();
+        let config = LoopConfig {
+            reply_not_supported: true,
+            ..Default::default()
+        };
+        main_loop_with_config::<EchoMessage, EchoNode>(
+            input.as_bytes(),
+            &mut output,
+            Stack::default(),
+            config,
+        )?;
+        let mut replies = parse_lines(&output)?;
+        assert_eq!(replies.len(), 4);
+        let malformed = replies.remove(2);
+        assert_eq!(malformed["body"]["code"], 12);
+        assert_eq!(replies[1]["body"]["type"], "error");
+        assert_eq!(replies[1]["body"]["code"], 10);
+        assert_eq!(replies[1]["body"]["in_reply_to"], 2);
+        assert!(replies[1]["body"]["text"]
+            .as_str()
+            .unwrap()
+            .contains("frobnicate"));
+        assert_eq!(replies[2]["body"]["echo"], "fine");
+        Ok(())
+    }
+
+    #[test]
+    fn test_node_counters_and_bytes_in_metrics() -> anyhow::Result<()> {
+        struct Counting {
+            echo: EchoNode,
+            echoes: Counter,
+        }
+        impl Node<EchoMessage> for Counting {
+            fn init_from(
+                _: &InitBody,
+                _: crossbeam_channel::Sender<Message<EchoMessage>>,
+            ) -> anyhow::Result<Self> {
+                unreachable!("the loop builds nodes with init_with")
+            }
+
+            fn init_with(init: &InitBody, ctx: NodeContext<EchoMessage>) -> anyhow::Result<Self> {
+                Ok(Self {
+                    echoes: ctx.counter("echoes"),
+                    echo: EchoNode::init_from(init, ctx.tx)?,
+                })
+            }
+
+            fn step(
+                &mut self,
+                req: Message<EchoMessage>,
+                output: &mut impl Write,
+            ) -> anyhow::Result<()> {
+                self.echoes.incr();
+                self.echo.step(req, output)
+            }
+        }
+
+        let request = |msg_id: usize, kind: &str| {
+            format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"{kind}","msg_id":{msg_id}}}}}"#)
         };
-        let stdout = std::io::stdout().lock();
-        let mut output = serde_json::Serializer::new(stdout);
-        msg.serialize(&mut output)?;
+        let input = [
+            INIT.to_string(),
+            echo(2, "a"),
+            echo(3, "b"),
+            request(4, "metrics"),
+            request(5, "metrics_reset"),
+            request(6, "metrics"),
+        ]
+        .join("\n");
+        let mut output = Vec::new();
+        let config = LoopConfig {
+            metrics: true,
+            ..Default::default()
+        };
+        main_loop_single_threaded_with_config::<EchoMessage, Counting>(
+            input.as_bytes(),
+            &mut output,
+            config,
+        )?;
+        let lines = output
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+        let replies = parse_lines(&output)?;
+        let metrics_ok = &replies[3]["body"];
+        assert_eq!(metrics_ok["counters"], serde_json::json!({"echoes": 2}));
+        // the two echo_ok and their newlines, not init_ok written before the loop
+        let echoed = lines[1].len() + lines[2].len() + 2;
+        assert_eq!(metrics_ok["bytes_sent"], echoed);
+        assert_eq!(
+            replies[5]["body"]["counters"],
+            serde_json::json!({"echoes": 0})
+        );
+        assert_eq!(replies[5]["body"]["bytes_sent"], 0);
+
+        // a counter without metrics counts nothing and costs nothing
+        Counter::default().incr();
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics_and_reset() -> anyhow::Result<()> {
+        let request = |msg_id: usize, kind: &str| {
+            format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"{kind}","msg_id":{msg_id}}}}}"#)
+        };
+        let input = [
+            INIT.to_string(),
+            echo(2, "a"),
+            echo(3, "b"),
+            request(4, "metrics"),
+            request(5, "metrics_reset"),
+            echo(6, "c"),
+            request(7, "metrics"),
+        ]
+        .join("\n");
+        let mut output = Vec::new();
+        let config = LoopConfig {
+            metrics: true,
+            ..Default::default()
+        };
+        main_loop_single_threaded_with_config::<EchoMessage, EchoNode>(
+            input.as_bytes(),
+            &mut output,
+            config.clone(),
+        )?;
+        let replies = parse_lines(&output)?;
+        assert_eq!(replies.len(), 7);
+        assert_eq!(replies[3]["body"]["type"], "metrics_ok");
+        assert_eq!(replies[3]["body"]["in_reply_to"], 4);
+        assert_eq!(
+            replies[3]["body"]["received"],
+            serde_json::json!({"echo": 2})
+        );
+        assert_eq!(
+            replies[3]["body"]["sent"],
+            serde_json::json!({"echo_ok": 2})
+        );
+        assert_eq!(replies[4]["body"]["type"], "metrics_reset_ok");
+        assert_eq!(
+            replies[6]["body"]["received"],
+            serde_json::json!({"echo": 1})
+        );
+        assert_eq!(
+            replies[6]["body"]["sent"],
+            serde_json::json!({"echo_ok": 1})
+        );
+
+        // the reader answers ahead of the step thread, only what it read is exact
+        let replies = run_echo_with(&input, config)?;
+        assert_eq!(replies.len(), 7);
+        let metrics_ok = replies
+            .iter()
+            .filter(|reply| reply["body"]["type"] == "metrics_ok")
+            .collect::<Vec<_>>();
+        assert_eq!(
+            metrics_ok[0]["body"]["received"],
+            serde_json::json!({"echo": 2})
+        );
+        assert_eq!(
+            metrics_ok[1]["body"]["received"],
+            serde_json::json!({"echo": 1})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_messages_during_after_init_are_stepped_after_it() -> anyhow::Result<()> {
+        struct SlowStart(EchoNode);
+        impl Node<EchoMessage> for SlowStart {
+            fn init_from(
+                init: &InitBody,
+                tx: crossbeam_channel::Sender<Message<EchoMessage>>,
+            ) -> anyhow::Result<Self> {
+                Ok(Self(EchoNode::init_from(init, tx)?))
+            }
+
+            fn step(
+                &mut self,
+                req: Message<EchoMessage>,
+                output: &mut impl Write,
+            ) -> anyhow::Result<()> {
+                self.0.step(req, output)
+            }
+
+            fn after_init(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                Message {
+                    src: "n1".to_string(),
+                    dst: "c0".to_string(),
+                    body: Body {
+                        id: None,
+                        in_reply_to: None,
+                        lamport: None,
+                        op_id: None,
+                        payload: EchoMessage::EchoOk {
+                            echo: "ready".to_string(),
+                        },
+                    },
+                }
+                .send(output)
+            }
+        }
+
+        let input = [INIT.to_string(), echo(2, "a"), echo(3, "b")].join("\n");
+        let mut output = Vec::new();
+        main_loop_with_io::<EchoMessage, SlowStart>(input.as_bytes(), &mut output)?;
+        let replies = parse_lines(&output)?;
+        let kinds = replies
+            .iter()
+            .map(|reply| reply["body"]["echo"].as_str().unwrap_or("init_ok"))
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, ["init_ok", "ready", "a", "b"]);
+
+        let mut output = Vec::new();
+        main_loop_single_threaded_with_io::<EchoMessage, SlowStart>(input.as_bytes(), &mut output)?;
+        assert_eq!(parse_lines(&output)?, replies);
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_error_is_replied() -> anyhow::Result<()> {
+        struct Picky(EchoNode);
+        impl Node<EchoMessage> for Picky {
+            fn init_from(
+                init: &InitBody,
+                tx: crossbeam_channel::Sender<Message<EchoMessage>>,
+            ) -> anyhow::Result<Self> {
+                Ok(Self(EchoNode::init_from(init, tx)?))
+            }
+
+            fn step(
+                &mut self,
+                req: Message<EchoMessage>,
+                output: &mut impl Write,
+            ) -> anyhow::Result<()> {
+                if matches!(&req.body.payload, EchoMessage::Echo { echo } if echo == "missing") {
+                    return Err(MaelstromError::KeyDoesNotExist
+                        .because("no such key")
+                        .into());
+                }
+                self.0.step(req, output)
+            }
+        }
+
+        let input = [INIT.to_string(), echo(2, "missing"), echo(3, "b")].join("\n");
+        let mut output = Vec::new();
+        main_loop_with_io::<EchoMessage, Picky>(input.as_bytes(), &mut output)?;
+        let replies = parse_lines(&output)?;
+        assert_eq!(
+            replies[1]["body"],
+            serde_json::json!({"type": "error", "code": 20, "text": "no such key", "msg_id": null, "in_reply_to": 2})
+        );
+        assert_eq!(replies[2]["body"]["echo"], "b");
+
+        let mut output = Vec::new();
+        main_loop_single_threaded_with_io::<EchoMessage, Picky>(input.as_bytes(), &mut output)?;
+        assert_eq!(parse_lines(&output)?, replies);
+        assert_eq!(
+            MaelstromError::from_code(20),
+            Some(MaelstromError::KeyDoesNotExist)
+        );
+        assert!(!MaelstromError::Crash.is_definite());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reply_goes_to_rpc_callback() -> anyhow::Result<()> {
+        struct Caller(EchoNode);
+        impl Node<EchoMessage> for Caller {
+            fn init_from(
+                _: &InitBody,
+                _: crossbeam_channel::Sender<Message<EchoMessage>>,
+            ) -> anyhow::Result<Self> {
+                unreachable!("the loop builds nodes with init_with")
+            }
+
+            fn init_with(init: &InitBody, ctx: NodeContext<EchoMessage>) -> anyhow::Result<Self> {
+                let tx = ctx.tx.clone();
+                // answer the reply like a client asked to echo it, which shows it
+                // reached the callback; stepped, echo_ok would be ignored
+                ctx.rpc
+                    .register(100, move |reply: anyhow::Result<Message<EchoMessage>>| {
+                        let EchoMessage::EchoOk { echo } = reply.expect("echo reply").body.payload
+                        else {
+                            panic!("expected echo_ok");
+                        };
+                        let msg = Message {
+                            src: "c1".to_string(),
+                            dst: "n1".to_string(),
+                            body: Body {
+                                id: Some(9),
+                                in_reply_to: None,
+                                lamport: None,
+                                op_id: None,
+                                payload: EchoMessage::Echo {
+                                    echo: format!("called back with {echo}"),
+                                },
+                            },
+                        };
+                        tx.send(msg).expect("node is alive");
+                    });
+                Ok(Self(EchoNode::init_from(init, ctx.tx)?))
+            }
+
+            fn step(
+                &mut self,
+                req: Message<EchoMessage>,
+                output: &mut impl Write,
+            ) -> anyhow::Result<()> {
+                self.0.step(req, output)
+            }
+
+            fn after_init(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+                Message {
+                    src: "n1".to_string(),
+                    dst: "n2".to_string(),
+                    body: Body {
+                        id: Some(100),
+                        in_reply_to: None,
+                        lamport: None,
+                        op_id: None,
+                        payload: EchoMessage::Echo {
+                            echo: "ping".to_string(),
+                        },
+                    },
+                }
+                .send(output)
+            }
+        }
+
+        let reply =
+            r#"{"src":"n2","dest":"n1","body":{"type":"echo_ok","in_reply_to":100,"echo":"ping"}}"#;
+        let input = [INIT.to_string(), reply.to_string()].join("\n");
+        for single_threaded in [false, true] {
+            let mut output = Vec::new();
+            if single_threaded {
+                main_loop_single_threaded_with_io::<EchoMessage, Caller>(
+                    input.as_bytes(),
+                    &mut output,
+                )?;
+            } else {
+                main_loop_with_io::<EchoMessage, Caller>(input.as_bytes(), &mut output)?;
+            }
+            let replies = parse_lines(&output)?;
+            assert_eq!(replies.len(), 3);
+            assert_eq!(replies[1]["body"]["type"], "echo");
+            assert_eq!(replies[2]["body"]["echo"], "called back with ping");
+        }
+        Ok(())
+    }
+
+    /// Answers an echo with what n2 echoes back, blocking in `Rpc::call` meanwhile.
+    struct Fetcher {
+        msg_ids: IdGen,
+        rpc: Rpc,
+    }
+
+    impl Node<EchoMessage> for Fetcher {
+        fn init_from(
+            _: &InitBody,
+            _: crossbeam_channel::Sender<Message<EchoMessage>>,
+        ) -> anyhow::Result<Self> {
+            unreachable!("the loop builds nodes with init_with")
+        }
+
+        fn init_with(_: &InitBody, ctx: NodeContext<EchoMessage>) -> anyhow::Result<Self> {
+            Ok(Self {
+                msg_ids: IdGen::default(),
+                rpc: ctx.rpc,
+            })
+        }
+
+        fn step(
+            &mut self,
+            req: Message<EchoMessage>,
+            output: &mut impl Write,
+        ) -> anyhow::Result<()> {
+            let EchoMessage::Echo { echo } = &req.body.payload else {
+                return Ok(());
+            };
+            let ask = EchoMessage::Echo { echo: echo.clone() };
+            let fetched: Message<EchoMessage> =
+                self.rpc
+                    .call("n2", ask, output, std::time::Duration::from_secs(5))?;
+            req.reply_with(&self.msg_ids, fetched.body.payload)
+                .send(output)
+        }
+    }
+
+    /// An output the test reads while the loop writes to it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_blocking_rpc_from_step() -> anyhow::Result<()> {
+        let (input, mut feed) = std::io::pipe()?;
+        let output = Shared::default();
+        let node = {
+            let output = output.clone();
+            std::thread::spawn(move || {
+                main_loop_with_io::<EchoMessage, Fetcher>(BufReader::new(input), output)
+            })
+        };
+        writeln!(feed, "{INIT}\n{}", echo(2, "x"))?;
+        // answer the call only once it went out, as a real peer would
+        while !String::from_utf8_lossy(&output.0.lock().unwrap()).contains(r#""dest":"n2""#) {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        // the reader answers a malformed request meanwhile without waiting on the step
+        writeln!(
+            feed,
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":3}}}}"#
+        )?;
+        let reply = format!(
+            r#"{{"src":"n2","dest":"n1","body":{{"type":"echo_ok","in_reply_to":{},"echo":"from n2"}}}}"#,
+            crate::rpc::FIRST_CALL_ID
+        );
+        writeln!(feed, "{reply}")?;
+        drop(feed);
+        node.join().expect("loop panicked")?;
+
+        let replies = parse_lines(&output.0.lock().unwrap())?;
+        assert_eq!(replies.len(), 4);
+        assert_eq!(replies[2]["body"]["in_reply_to"], 2);
+        assert_eq!(replies[2]["body"]["echo"], "from n2");
+        assert_eq!(replies[3]["body"]["in_reply_to"], 3);
+        assert_eq!(replies[3]["body"]["type"], "error");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_reply_passes_full_step_queue() -> anyhow::Result<()> {
+        let (input, mut feed) = std::io::pipe()?;
+        let output = Shared::default();
+        let node = {
+            let output = output.clone();
+            std::thread::spawn(move || {
+                main_loop_with_config::<EchoMessage, Fetcher>(
+                    BufReader::new(input),
+                    output,
+                    Stack::default(),
+                    LoopConfig {
+                        step_queue_cap: 1,
+                        ..Default::default()
+                    },
+                )
+            })
+        };
+        writeln!(feed, "{INIT}\n{}", echo(2, "x"))?;
+        while !String::from_utf8_lossy(&output.0.lock().unwrap()).contains(r#""dest":"n2""#) {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        // far more than the queue holds, stepped without a reply once the call is done
+        for msg_id in 3..20 {
+            writeln!(
+                feed,
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"echo_ok","msg_id":{msg_id},"echo":"a"}}}}"#
+            )?;
+        }
+        let started = std::time::Instant::now();
+        let reply = format!(
+            r#"{{"src":"n2","dest":"n1","body":{{"type":"echo_ok","in_reply_to":{},"echo":"from n2"}}}}"#,
+            crate::rpc::FIRST_CALL_ID
+        );
+        writeln!(feed, "{reply}")?;
+        drop(feed);
+        node.join().expect("loop panicked")?;
+        assert!(
+            started.elapsed() < std::time::Duration::from_secs(1),
+            "the reply waited out the call's timeout"
+        );
+
+        let replies = parse_lines(&output.0.lock().unwrap())?;
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[2]["body"]["in_reply_to"], 2);
+        assert_eq!(replies[2]["body"]["echo"], "from n2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_ok_extra() -> anyhow::Result<()> {
+        struct Versioned(EchoNode);
+        impl Node<EchoMessage> for Versioned {
+            fn init_from(
+                init: &InitBody,
+                tx: crossbeam_channel::Sender<Message<EchoMessage>>,
+            ) -> anyhow::Result<Self> {
+                Ok(Self(EchoNode::init_from(init, tx)?))
+            }
+
+            fn step(
+                &mut self,
+                req: Message<EchoMessage>,
+                output: &mut impl Write,
+            ) -> anyhow::Result<()> {
+                self.0.step(req, output)
+            }
+
+            fn init_ok_extra(_: &InitBody) -> HashMap<String, serde_json::Value> {
+                [("protocol".to_string(), "v2".into())].into()
+            }
+        }
+
+        let mut output = Vec::new();
+        main_loop_with_io::<EchoMessage, Versioned>(INIT.as_bytes(), &mut output)?;
+        let init_ok = parse_lines(&output)?.remove(0);
+        assert_eq!(
+            init_ok["body"],
+            serde_json::json!({"type": "init_ok", "msg_id": null, "in_reply_to": 1, "protocol": "v2"})
+        );
+
+        let init_ok = run_echo(INIT)?.remove(0);
+        assert_eq!(
+            init_ok["body"],
+            serde_json::json!({"type": "init_ok", "msg_id": null, "in_reply_to": 1})
+        );
+        Ok(())
+    }
+
+    /// Input failing with the scripted errors, one per read, before serving `data`.
+    struct Flaky<'a> {
+        errors: Vec<std::io::ErrorKind>,
+        data: &'a [u8],
+    }
+
+    impl std::io::Read for Flaky<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.errors.pop() {
+                Some(kind) => Err(kind.into()),
+                None => self.data.read(buf),
+            }
+        }
+    }
+
+    #[test]
+    fn test_transient_input_errors_are_retried() -> anyhow::Result<()> {
+        use std::io::ErrorKind::{Interrupted, WouldBlock};
+
+        let input = [INIT.to_string(), echo(2, "a")].join("\n");
+        let flaky = Flaky {
+            errors: vec![WouldBlock, Interrupted],
+            data: input.as_bytes(),
+        };
+        let mut output = Vec::new();
+        main_loop_with_io::<EchoMessage, EchoNode>(std::io::BufReader::new(flaky), &mut output)?;
+        assert_eq!(parse_lines(&output)?.len(), 2);
+
+        let broken = Flaky {
+            errors: vec![WouldBlock; 100],
+            data: input.as_bytes(),
+        };
+        let result = main_loop_with_io::<EchoMessage, EchoNode>(
+            std::io::BufReader::new(broken),
+            std::io::sink(),
+        );
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_features_reflect_env_flags() -> anyhow::Result<()> {
+        let env = |name: &str| match name {
+            "QUORUM_WRITE" => Some("1".to_string()),
+            "GOSSIP_MAX_PER_TICK" => Some("2".to_string()),
+            _ => None,
+        };
+        let config = LoopConfig {
+            features: Features::from_vars(env),
+            ..Default::default()
+        };
+        let features = r#"{"src":"c1","dest":"n1","body":{"type":"features","msg_id":4}}"#;
+        let input = [INIT.to_string(), features.to_string(), echo(5, "a")].join("\n");
+        let replies = run_echo_with(&input, config)?;
+
+        assert_eq!(replies.len(), 3, "features reached the node: {replies:?}");
+        let features_ok = &replies[1]["body"];
+        assert_eq!(features_ok["type"], "features_ok");
+        assert_eq!(features_ok["in_reply_to"], 4);
+        assert_eq!(
+            features_ok["protocol_version"],
+            crate::features::PROTOCOL_VERSION
+        );
+        assert_eq!(features_ok["flags"]["quorum_write"], true);
+        assert_eq!(features_ok["flags"]["gossip_digest"], true);
+        assert_eq!(features_ok["settings"]["gossip_max_per_tick"], "2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics_serialize_time() -> anyhow::Result<()> {
+        let metrics = r#"{"src":"c1","dest":"n1","body":{"type":"metrics","msg_id":9}}"#;
+        let serialize_nanos = |echo: &str| -> anyhow::Result<u64> {
+            let input = [INIT.to_string(), self::echo(2, echo), metrics.to_string()].join("\n");
+            let mut output = Vec::new();
+            let config = LoopConfig {
+                metrics: true,
+                ..Default::default()
+            };
+            main_loop_single_threaded_with_config::<EchoMessage, EchoNode>(
+                input.as_bytes(),
+                &mut output,
+                config,
+            )?;
+            let metrics_ok = parse_lines(&output)?.remove(2);
+            assert_eq!(
+                metrics_ok["body"]["serialize_nanos_total"],
+                metrics_ok["body"]["serialize_nanos"]["echo_ok"]
+            );
+            Ok(metrics_ok["body"]["serialize_nanos"]["echo_ok"]
+                .as_u64()
+                .unwrap())
+        };
+        let small = serialize_nanos("x")?;
+        let large = serialize_nanos(&"x".repeat(1 << 20))?;
+        assert!(
+            large > small,
+            "{large}ns for a large reply, {small}ns for a small one"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_oversized_peer_message_is_dropped() -> anyhow::Result<()> {
+        let config = LoopConfig {
+            max_peer_message_bytes: 4096,
+            ..Default::default()
+        };
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#;
+        let from_peer = |msg_id: usize, echo: &str| self::echo(msg_id, echo).replace("c1", "n2");
+        let input = [
+            init.to_string(),
+            from_peer(2, &"x".repeat(8192)),
+            from_peer(3, "small"),
+            // clients aren't limited
+            echo(4, &"y".repeat(8192)),
+        ]
+        .join("\n");
+        for replies in [run_echo_with(&input, config.clone())?, {
+            let mut output = Vec::new();
+            main_loop_single_threaded_with_config::<EchoMessage, EchoNode>(
+                input.as_bytes(),
+                &mut output,
+                config,
+            )?;
+            parse_lines(&output)?
+        }] {
+            let answered = replies
+                .iter()
+                .skip(1)
+                .map(|reply| reply["body"]["in_reply_to"].as_u64().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(answered, [3, 4]);
+        }
         Ok(())
     }
 
     #[test]
-    fn serde_from_str() -> anyhow::Result<()> {
-        let content = r#"""{"src":"c1","dest":"n1",
-        "body":{"type":"init","node_id":"n1","node_ids":["n1","n2"],"msg_id":1,"in_reply_to":1}}"""#;
-        let msg: Message<InitMsg> = serde_json::from_str(content)?;
-        println!("{msg:?}");
+    fn test_messages_before_init_are_buffered() -> anyhow::Result<()> {
+        let input = [echo(2, "early"), INIT.to_string(), echo(3, "late")].join("\n");
+        let replies = run_echo(&input)?;
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["body"]["type"], "init_ok");
+        assert_eq!(replies[1]["body"]["echo"], "early");
+        assert_eq!(replies[1]["body"]["in_reply_to"], 2);
+        assert_eq!(replies[2]["body"]["echo"], "late");
         Ok(())
     }
 }
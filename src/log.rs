@@ -0,0 +1,116 @@
+//! JSON-lines logging to STDERR, which Maelstrom keeps per node in `node-logs/`.
+//!
+//! Each record is one line like
+//! `{"ts_ms":1700000000000,"level":"info","node":"n1","msg":"..."}`, written with a
+//! single call on the locked STDERR so records from different threads never
+//! interleave. Nothing is ever written to STDOUT, which carries the protocol.
+
+use std::{
+    fmt::Display,
+    io::Write,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+/// The id every record carries, set once the init handshake is done.
+static NODE_ID: OnceLock<String> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    ts_ms: u128,
+    level: Level,
+    /// `None` before init
+    node: Option<&'a str>,
+    msg: String,
+    /// structured payload of `node_log_data` records
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
+}
+
+/// Tag the records of this process with `node_id`. The loops call it after init; a
+/// process runs a single node, so only the first id sticks.
+pub fn init(node_id: &str) {
+    let _ = NODE_ID.set(node_id.to_string());
+}
+
+/// The line `node_log` writes, without its newline.
+pub fn format_record(level: Level, msg: impl Display) -> String {
+    format_data_record(level, msg, None)
+}
+
+fn format_data_record(level: Level, msg: impl Display, data: Option<serde_json::Value>) -> String {
+    let record = Record {
+        ts_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis()),
+        level,
+        node: NODE_ID.get().map(String::as_str),
+        msg: msg.to_string(),
+        data,
+    };
+    serde_json::to_string(&record).expect("a log record always serializes")
+}
+
+/// Write one record to STDERR. Failing to log is ignored, there is nowhere left to
+/// report it.
+pub fn node_log(level: Level, msg: impl Display) {
+    write_record(format_record(level, msg));
+}
+
+/// Like `node_log`, with `data` as JSON in the record's `data` field rather than
+/// formatted into `msg`.
+pub fn node_log_data(level: Level, msg: impl Display, data: &impl Serialize) {
+    let data = serde_json::to_value(data).ok();
+    write_record(format_data_record(level, msg, data));
+}
+
+fn write_record(mut line: String) {
+    line.push('\n');
+    let _ = std::io::stderr().lock().write_all(line.as_bytes());
+}
+
+/// `node_log` with `format!` arguments, e.g.
+/// `node_log!(Level::Warn, "gossip to {peer} timed out")`.
+#[macro_export]
+macro_rules! node_log {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log::node_log($level, format_args!($($arg)+))
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_data_record, format_record, init, Level};
+
+    #[test]
+    fn test_record_is_one_json_line() -> anyhow::Result<()> {
+        init("n1");
+        let line = format_record(Level::Warn, "peer n2\nunreachable");
+        assert!(!line.contains('\n'));
+        let record = serde_json::from_str::<serde_json::Value>(&line)?;
+        assert_eq!(record["level"], "warn");
+        // another test's loop may have set the id first
+        assert!(record["node"].is_string());
+        assert_eq!(record["msg"], "peer n2\nunreachable");
+        assert!(record["ts_ms"].as_u64().is_some_and(|ts| ts > 0));
+        assert!(record.get("data").is_none());
+
+        let data = serde_json::json!({"sent": {"gossip": 3}});
+        let line = format_data_record(Level::Info, "metrics", Some(data.clone()));
+        let record = serde_json::from_str::<serde_json::Value>(&line)?;
+        assert_eq!(record["msg"], "metrics");
+        assert_eq!(record["data"], data);
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    log::{node_log_data, Level},
+    rpc::Rpc,
+    Message,
+};
+
+/// Per message type counts of the traffic after init, enabled by `METRICS=1`.
+///
+/// The loop answers `metrics` with the counts so far and `metrics_reset` by zeroing
+/// them, so a single phase of a run can be measured. Those requests never reach the
+/// node and aren't counted themselves.
+///
+/// Under `main_loop` it also tracks how many messages wait for the step thread, and
+/// the most that ever did. A high-water mark that keeps growing means the node can't
+/// keep up with its input.
+///
+/// Nodes count their own events, e.g. gossip rounds, on a `Counter` from
+/// `NodeContext::counter`. The loop logs the whole report to STDERR at shutdown.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counts: Mutex<Counts>,
+    queued: AtomicUsize,
+    max_queued: AtomicUsize,
+    bytes_sent: AtomicU64,
+    /// the node's own counters by name
+    counters: Mutex<BTreeMap<String, Arc<AtomicU64>>>,
+}
+
+/// A counter of the node's own, see `NodeContext::counter`. Counting is a relaxed
+/// atomic add, or nothing at all without `METRICS=1`.
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Option<Arc<AtomicU64>>);
+
+impl Counter {
+    pub fn incr(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        if let Some(count) = &self.0 {
+            count.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counts {
+    pub received: BTreeMap<String, u64>,
+    pub sent: BTreeMap<String, u64>,
+    /// time from a sent message's first byte to its last, i.e. serializing it
+    pub serialize_nanos: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum MetricsMsg {
+    Metrics,
+    MetricsOk {
+        received: BTreeMap<String, u64>,
+        sent: BTreeMap<String, u64>,
+        serialize_nanos: BTreeMap<String, u64>,
+        serialize_nanos_total: u64,
+        /// messages waiting for the step thread now, and at most since the last reset
+        queue_depth: usize,
+        max_queue_depth: usize,
+        /// rpc requests awaiting their reply, and how long the oldest did so far
+        rpc_pending: usize,
+        rpc_oldest_pending_ms: Option<u64>,
+        /// everything the node wrote, answers to `features` and `metrics` aside
+        bytes_sent: u64,
+        /// the node's own counters
+        counters: BTreeMap<String, u64>,
+    },
+    MetricsReset,
+    MetricsResetOk,
+}
+
+/// Just the `type` of a message, whatever else its body holds.
+#[derive(Deserialize)]
+struct Kind {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+impl Metrics {
+    pub fn from_env() -> Option<Self> {
+        std::env::var("METRICS")
+            .is_ok_and(|flag| flag == "1")
+            .then(Self::default)
+    }
+
+    pub fn snapshot(&self) -> Counts {
+        self.counts().clone()
+    }
+
+    pub fn reset(&self) {
+        *self.counts() = Counts::default();
+        self.max_queued.store(self.queue_depth(), Ordering::Relaxed);
+        self.bytes_sent.store(0, Ordering::Relaxed);
+        for count in self.counters_lock().values() {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// The node's counter `name`, created at zero on first use.
+    pub fn counter(&self, name: &str) -> Counter {
+        let mut counters = self.counters_lock();
+        Counter(Some(Arc::clone(
+            counters.entry(name.to_string()).or_default(),
+        )))
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn max_queue_depth(&self) -> usize {
+        self.max_queued.load(Ordering::Relaxed)
+    }
+
+    /// The step thread's queues hold `depth` messages, as their `len()` read after a
+    /// message was queued or taken off.
+    pub(crate) fn observe_queue_depth(&self, depth: usize) {
+        self.queued.store(depth, Ordering::Relaxed);
+        self.max_queued.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    fn counts(&self) -> std::sync::MutexGuard<'_, Counts> {
+        self.counts.lock().expect("metrics lock poisoned")
+    }
+
+    fn counters_lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Arc<AtomicU64>>> {
+        self.counters
+            .lock()
+            .expect("metrics counters lock poisoned")
+    }
+
+    /// Everything counted so far, as `metrics` is answered.
+    pub fn report(&self, rpc: &Rpc) -> MetricsMsg {
+        let Counts {
+            received,
+            sent,
+            serialize_nanos,
+        } = self.snapshot();
+        let stats = rpc.stats();
+        MetricsMsg::MetricsOk {
+            received,
+            sent,
+            serialize_nanos_total: serialize_nanos.values().sum(),
+            serialize_nanos,
+            queue_depth: self.queue_depth(),
+            max_queue_depth: self.max_queue_depth(),
+            rpc_pending: stats.pending,
+            rpc_oldest_pending_ms: stats
+                .oldest
+                .map(|oldest| u64::try_from(oldest.as_millis()).unwrap_or(u64::MAX)),
+            bytes_sent: self.bytes_sent(),
+            counters: self
+                .counters_lock()
+                .iter()
+                .map(|(name, count)| (name.clone(), count.load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+
+    /// Count a received line, or answer it if it's a metrics request. Returns the
+    /// reply if it was one, in which case the node shouldn't see it.
+    pub(crate) fn intercept(&self, line: &str, rpc: &Rpc) -> Option<Message<MetricsMsg>> {
+        let msg = serde_json::from_str::<Message<Kind>>(line).ok()?;
+        if !matches!(msg.body.payload.kind.as_str(), "metrics" | "metrics_reset") {
+            *self
+                .counts()
+                .received
+                .entry(msg.body.payload.kind)
+                .or_default() += 1;
+            return None;
+        }
+        let req = serde_json::from_str::<Message<MetricsMsg>>(line).ok()?;
+        let mut reply = req.into_reply(None);
+        reply.body.payload = match reply.body.payload {
+            MetricsMsg::MetricsReset => {
+                self.reset();
+                MetricsMsg::MetricsResetOk
+            }
+            _ => self.report(rpc),
+        };
+        Some(reply)
+    }
+
+    /// Log `report` to STDERR as JSON, once the loop is done.
+    pub(crate) fn log_report(&self, rpc: &Rpc) {
+        node_log_data(Level::Info, "metrics at shutdown", &self.report(rpc));
+    }
+
+    fn record_sent(&self, line: &[u8], serialize: Duration) {
+        if let Ok(msg) = serde_json::from_slice::<Message<Kind>>(line) {
+            let mut counts = self.counts();
+            let nanos = u64::try_from(serialize.as_nanos()).unwrap_or(u64::MAX);
+            *counts
+                .serialize_nanos
+                .entry(msg.body.payload.kind.clone())
+                .or_default() += nanos;
+            *counts.sent.entry(msg.body.payload.kind).or_default() += 1;
+        }
+    }
+}
+
+/// Writer counting every line written through it by message type, a pass-through
+/// without metrics. `Message::send` serializes straight into the writer, so the time
+/// between a line's first and last write is the time spent serializing it.
+pub(crate) struct Metered<'a, W> {
+    inner: W,
+    metrics: Option<&'a Metrics>,
+    line: Vec<u8>,
+    line_started: Option<Instant>,
+}
+
+impl<'a, W: Write> Metered<'a, W> {
+    pub(crate) fn new(inner: W, metrics: Option<&'a Metrics>) -> Self {
+        Self {
+            inner,
+            metrics,
+            line: Vec::new(),
+            line_started: None,
+        }
+    }
+
+    /// The underlying writer, for traffic which shouldn't be counted.
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl<W: Write> Write for Metered<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.metrics.is_some() && self.line_started.is_none() {
+            self.line_started = Some(Instant::now());
+        }
+        let n = self.inner.write(buf)?;
+        if let Some(metrics) = self.metrics {
+            metrics.bytes_sent.fetch_add(n as u64, Ordering::Relaxed);
+            self.line.extend_from_slice(&buf[..n]);
+            while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+                let started = self.line_started.take().unwrap_or_else(Instant::now);
+                metrics.record_sent(&self.line[..end], started.elapsed());
+                self.line.drain(..=end);
+                if !self.line.is_empty() {
+                    self.line_started = Some(Instant::now());
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Debug,
+    io::Write,
+    time::Duration,
+};
+
+use crate::Message;
+
+/// A cross-cutting layer run by `main_loop` around every `Node::step`.
+pub trait Middleware<M>: Send {
+    /// Called before the node steps `msg`, returning `false` drops the message.
+    fn before(&mut self, _msg: &Message<M>) -> bool {
+        true
+    }
+
+    /// Called once the node stepped `msg`.
+    fn after(&mut self, _msg: &Message<M>, _result: &anyhow::Result<()>) {}
+}
+
+/// Middleware layers, `before` runs in the order they were added and `after` in reverse.
+pub struct Stack<M> {
+    layers: Vec<Box<dyn Middleware<M>>>,
+}
+
+impl<M> Default for Stack<M> {
+    fn default() -> Self {
+        Self { layers: Vec::new() }
+    }
+}
+
+impl<M> Stack<M> {
+    pub fn with(mut self, layer: impl Middleware<M> + 'static) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Run `step` wrapped by every layer, unless one of them drops the message.
+    pub fn run(
+        &mut self,
+        msg: Message<M>,
+        step: impl FnOnce(Message<M>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()>
+    where
+        M: Clone,
+    {
+        if self.layers.is_empty() {
+            return step(msg);
+        }
+        if !self.layers.iter_mut().all(|layer| layer.before(&msg)) {
+            return Ok(());
+        }
+        let result = step(msg.clone());
+        self.layers
+            .iter_mut()
+            .rev()
+            .for_each(|layer| layer.after(&msg, &result));
+        result
+    }
+}
+
+/// Log every message and failed step to the given writer, STDERR by default.
+pub struct LogMiddleware<W> {
+    output: W,
+}
+
+impl LogMiddleware<std::io::Stderr> {
+    pub fn stderr() -> Self {
+        Self {
+            output: std::io::stderr(),
+        }
+    }
+}
+
+impl<W: Write + Send> LogMiddleware<W> {
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+}
+
+impl<M: Debug, W: Write + Send> Middleware<M> for LogMiddleware<W> {
+    fn before(&mut self, msg: &Message<M>) -> bool {
+        let _ = writeln!(
+            self.output,
+            "recv {} -> {}: {:?}",
+            msg.src, msg.dst, msg.body
+        );
+        true
+    }
+
+    fn after(&mut self, msg: &Message<M>, result: &anyhow::Result<()>) {
+        if let Err(e) = result {
+            let _ = writeln!(
+                self.output,
+                "step {:?} from {} failed: {e:#}",
+                msg.body.id, msg.src
+            );
+        }
+    }
+}
+
+/// Drop redelivered requests, identified by `(src, msg_id)`. Only the latest
+/// `capacity` ids are remembered.
+pub struct DedupMiddleware {
+    capacity: usize,
+    seen: HashSet<(String, usize)>,
+    order: VecDeque<(String, usize)>,
+}
+
+impl DedupMiddleware {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+}
+
+impl<M> Middleware<M> for DedupMiddleware {
+    fn before(&mut self, msg: &Message<M>) -> bool {
+        let Some(id) = msg.body.id else {
+            return true;
+        };
+        let key = (msg.src.clone(), id);
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Sleep before every step, simulating a slow handler to exercise the loop under load.
+/// `main_loop` installs it when `SIMULATE_STEP_DELAY_MS` is set.
+pub struct SlowStep {
+    delay: Duration,
+}
+
+impl SlowStep {
+    pub fn new(delay: Duration) -> Self {
+        Self { delay }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        std::env::var("SIMULATE_STEP_DELAY_MS")
+            .ok()
+            .and_then(|delay| delay.parse().ok())
+            .map(|delay| Self::new(Duration::from_millis(delay)))
+    }
+}
+
+impl<M> Middleware<M> for SlowStep {
+    fn before(&mut self, _: &Message<M>) -> bool {
+        std::thread::sleep(self.delay);
+        true
+    }
+}
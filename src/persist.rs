@@ -0,0 +1,529 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use serde_json::Value;
+
+use crate::{
+    kv::{KvClient, KvError, LIN_KV},
+    rpc::Rpc,
+    MaelstromError,
+};
+
+/// Durable key/value storage for a node's state, values are JSON. `output` is the
+/// step's, a store backed by a service sends its requests there; the others ignore it.
+pub trait Store: Send {
+    fn get(&mut self, key: &str, output: &mut dyn Write) -> anyhow::Result<Option<Value>>;
+
+    fn put(&mut self, key: &str, value: Value, output: &mut dyn Write) -> anyhow::Result<()>;
+
+    /// Swap `from` for `to`, `None` meaning the key doesn't exist yet. Returns whether
+    /// the swap happened.
+    fn cas(
+        &mut self,
+        key: &str,
+        from: Option<&Value>,
+        to: Value,
+        output: &mut dyn Write,
+    ) -> anyhow::Result<bool>;
+}
+
+/// Pick the backend named by `STORE`: `mem`, `lin-kv` or `file:<dir>`. Unset means
+/// the node keeps its state in memory only.
+pub fn store_from_env(rpc: &Rpc) -> anyhow::Result<Option<Box<dyn Store>>> {
+    let Ok(store) = std::env::var("STORE") else {
+        return Ok(None);
+    };
+    let store: Box<dyn Store> = match store.as_str() {
+        "mem" => Box::new(MemStore::default()),
+        "lin-kv" => Box::new(KvStore::new(KvClient::new(LIN_KV, rpc.clone()))),
+        _ => match store.strip_prefix("file:") {
+            Some(dir) => Box::new(FileStore::open(dir)?),
+            None => anyhow::bail!("unknown STORE {store:?}, expected mem, lin-kv or file:<dir>"),
+        },
+    };
+    Ok(Some(store))
+}
+
+/// In-memory store, for tests. Clones share the contents, so a clone handed to a new
+/// node instance plays the part of the disk surviving a restart.
+#[derive(Debug, Clone, Default)]
+pub struct MemStore {
+    values: Arc<Mutex<HashMap<String, Value>>>,
+}
+
+impl MemStore {
+    fn values(&self) -> std::sync::MutexGuard<'_, HashMap<String, Value>> {
+        self.values.lock().expect("mem store lock poisoned")
+    }
+}
+
+impl Store for MemStore {
+    fn get(&mut self, key: &str, _output: &mut dyn Write) -> anyhow::Result<Option<Value>> {
+        Ok(self.values().get(key).cloned())
+    }
+
+    fn put(&mut self, key: &str, value: Value, _output: &mut dyn Write) -> anyhow::Result<()> {
+        self.values().insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn cas(
+        &mut self,
+        key: &str,
+        from: Option<&Value>,
+        to: Value,
+        _output: &mut dyn Write,
+    ) -> anyhow::Result<bool> {
+        let mut values = self.values();
+        if values.get(key) != from {
+            return Ok(false);
+        }
+        values.insert(key.to_string(), to);
+        Ok(true)
+    }
+}
+
+/// One file per key under a directory, each replaced atomically by write-then-rename.
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn open(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("create store directory {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        anyhow::ensure!(
+            !key.is_empty() && !key.contains(['/', '\\']) && !key.starts_with('.'),
+            "store key {key:?} isn't a plain file name"
+        );
+        Ok(self.dir.join(key))
+    }
+}
+
+impl Store for FileStore {
+    fn get(&mut self, key: &str, _output: &mut dyn Write) -> anyhow::Result<Option<Value>> {
+        let path = self.path(key)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let value = std::fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+        Ok(Some(serde_json::from_slice(&value).with_context(|| {
+            format!("corrupted store value {}", path.display())
+        })?))
+    }
+
+    fn put(&mut self, key: &str, value: Value, _output: &mut dyn Write) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, value.to_string())
+            .with_context(|| format!("write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &path).with_context(|| format!("commit {}", path.display()))
+    }
+
+    fn cas(
+        &mut self,
+        key: &str,
+        from: Option<&Value>,
+        to: Value,
+        output: &mut dyn Write,
+    ) -> anyhow::Result<bool> {
+        // the node is the file's only writer, a read then a write can't race
+        if self.get(key, output)?.as_ref() != from {
+            return Ok(false);
+        }
+        self.put(key, to, output)?;
+        Ok(true)
+    }
+}
+
+/// A key in one of Maelstrom's kv services, lin-kv from `store_from_env`. The requests
+/// go out through the step's output, so they're written and counted like every other
+/// line the node sends.
+pub struct KvStore {
+    client: KvClient<Value>,
+}
+
+impl KvStore {
+    pub fn new(client: KvClient<Value>) -> Self {
+        Self { client }
+    }
+}
+
+impl Store for KvStore {
+    fn get(&mut self, key: &str, mut output: &mut dyn Write) -> anyhow::Result<Option<Value>> {
+        Ok(self.client.read(key, &mut output)?)
+    }
+
+    fn put(&mut self, key: &str, value: Value, mut output: &mut dyn Write) -> anyhow::Result<()> {
+        Ok(self.client.write(key, value, &mut output)?)
+    }
+
+    /// A missing key is created by a cas with `create_if_not_exists`, whose `from` is
+    /// null; the service would also swap a key which holds null. A key still missing
+    /// holds no `from`, the swap just didn't happen.
+    fn cas(
+        &mut self,
+        key: &str,
+        from: Option<&Value>,
+        to: Value,
+        mut output: &mut dyn Write,
+    ) -> anyhow::Result<bool> {
+        let create = from.is_none();
+        let from = from.cloned().unwrap_or(Value::Null);
+        match self.client.cas(key, from, to, create, &mut output) {
+            Ok(()) => Ok(true),
+            Err(KvError::CasFailed(_)) => Ok(false),
+            Err(KvError::Service { code, .. })
+                if code == MaelstromError::KeyDoesNotExist.code() =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// A monotonic id counter which survives restarts.
+///
+/// Ids are reserved from the backing file in chunks: before an id beyond the current
+/// reservation is handed out, the end of the next chunk is written to disk. The file
+/// therefore always holds a bound above every id issued so far, and a restarted node
+/// resumes from that bound without ever reusing an id. Without a file it's a plain
+/// in-memory counter.
+#[derive(Debug)]
+pub struct PersistentIds {
+    path: Option<PathBuf>,
+    chunk: usize,
+    next: usize,
+    reserved: usize,
+}
+
+impl PersistentIds {
+    pub const DEFAULT_CHUNK: usize = 1024;
+
+    pub fn open(path: Option<PathBuf>, chunk: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(chunk > 0, "reservation chunk should be positive");
+        let start = match &path {
+            Some(path) if path.exists() => std::fs::read_to_string(path)
+                .with_context(|| format!("read msg_id reservation {}", path.display()))?
+                .trim()
+                .parse()
+                .with_context(|| format!("corrupted msg_id reservation {}", path.display()))?,
+            _ => 1,
+        };
+        Ok(Self {
+            path,
+            chunk,
+            next: start,
+            reserved: start,
+        })
+    }
+
+    pub fn next_id(&mut self) -> anyhow::Result<usize> {
+        if self.next >= self.reserved {
+            self.reserve(self.next + self.chunk)?;
+        }
+        let id = self.next;
+        self.next += 1;
+        Ok(id)
+    }
+
+    fn reserve(&mut self, upto: usize) -> anyhow::Result<()> {
+        if let Some(path) = &self.path {
+            // write aside then rename, a crash never leaves a truncated reservation behind
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, upto.to_string())
+                .with_context(|| format!("write msg_id reservation {}", tmp.display()))?;
+            std::fs::rename(&tmp, path)
+                .with_context(|| format!("commit msg_id reservation {}", path.display()))?;
+        }
+        self.reserved = upto;
+        Ok(())
+    }
+}
+
+/// Client op ids already applied, which survive restarts so a resend after one is a
+/// no-op.
+///
+/// Only the latest `window` ids are remembered. Every id is appended to the backing
+/// file, one per line, and once the file holds twice the window it's rewritten with
+/// just the window. Without a file the ids only live in memory.
+///
+/// A line may also carry the caller's state after the op, tab separated, so the
+/// state and the ids applied to it are written together; the latest state is handed
+/// back by `state` after a restart.
+#[derive(Debug)]
+pub struct AppliedOps {
+    path: Option<PathBuf>,
+    window: usize,
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+    /// the latest state logged, see `apply_once_with`
+    state: Option<String>,
+    /// lines in the backing file
+    logged: usize,
+}
+
+impl AppliedOps {
+    pub const DEFAULT_WINDOW: usize = 4096;
+
+    pub fn open(path: Option<PathBuf>, window: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(window > 0, "op id window should be positive");
+        let mut ops = Self {
+            path,
+            window,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            state: None,
+            logged: 0,
+        };
+        if let Some(path) = ops.path.as_ref().filter(|path| path.exists()) {
+            let log = std::fs::read_to_string(path)
+                .with_context(|| format!("read applied op ids {}", path.display()))?;
+            for line in log.lines().filter(|line| !line.is_empty()) {
+                let (op_id, state) = match line.split_once('\t') {
+                    Some((op_id, state)) => (op_id, Some(state)),
+                    None => (line, None),
+                };
+                if !op_id.is_empty() {
+                    ops.remember(op_id.to_string());
+                }
+                if let Some(state) = state {
+                    ops.state = Some(state.to_string());
+                }
+                ops.logged += 1;
+            }
+        }
+        Ok(ops)
+    }
+
+    /// Record `op_id` as applied, `false` if it was applied already.
+    pub fn apply_once(&mut self, op_id: &str) -> anyhow::Result<bool> {
+        if !self.admit(op_id)? {
+            return Ok(false);
+        }
+        self.log(op_id)?;
+        Ok(true)
+    }
+
+    /// Like `apply_once`, logging `state` with the id: what the caller's state will be
+    /// once the op is applied.
+    pub fn apply_once_with(&mut self, op_id: &str, state: &str) -> anyhow::Result<bool> {
+        anyhow::ensure!(!state.contains('\n'), "state {state:?} spans lines");
+        if !self.admit(op_id)? {
+            return Ok(false);
+        }
+        self.state = Some(state.to_string());
+        self.log(&format!("{op_id}\t{state}"))?;
+        Ok(true)
+    }
+
+    /// Log `state` on its own, for a change which came without an op id.
+    pub fn checkpoint(&mut self, state: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(!state.contains('\n'), "state {state:?} spans lines");
+        self.state = Some(state.to_string());
+        self.log(&format!("\t{state}"))
+    }
+
+    /// The latest state logged, restored from the backing file after a restart.
+    pub fn state(&self) -> Option<&str> {
+        self.state.as_deref()
+    }
+
+    /// Whether `op_id` can be logged: a non-empty line without tabs. Handlers check
+    /// client ids with it up front, to answer a bad one as a malformed request.
+    pub fn is_valid_op_id(op_id: &str) -> bool {
+        !op_id.is_empty() && !op_id.contains(['\n', '\t'])
+    }
+
+    /// Remember `op_id`, `false` if it was applied already.
+    fn admit(&mut self, op_id: &str) -> anyhow::Result<bool> {
+        if self.seen.contains(op_id) {
+            return Ok(false);
+        }
+        anyhow::ensure!(
+            Self::is_valid_op_id(op_id),
+            "op id {op_id:?} should be a non-empty line without tabs"
+        );
+        self.remember(op_id.to_string());
+        Ok(true)
+    }
+
+    fn log(&mut self, line: &str) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.logged >= 2 * self.window {
+            return self.compact();
+        }
+        let mut log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open applied op ids {}", path.display()))?;
+        writeln!(log, "{line}")
+            .with_context(|| format!("log applied op id to {}", path.display()))?;
+        self.logged += 1;
+        Ok(())
+    }
+
+    fn remember(&mut self, op_id: String) {
+        if self.seen.insert(op_id.clone()) {
+            self.order.push_back(op_id);
+        }
+        if self.order.len() > self.window {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+    }
+
+    /// Rewrite the backing file with only the window, aside then renamed like the ids.
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        let mut log = self
+            .order
+            .iter()
+            .fold(String::new(), |log, op_id| log + op_id + "\n");
+        if let Some(state) = &self.state {
+            log = log + "\t" + state + "\n";
+        }
+        std::fs::write(&tmp, log)
+            .with_context(|| format!("write applied op ids {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("commit applied op ids {}", path.display()))?;
+        self.logged = self.order.len() + usize::from(self.state.is_some());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::{
+        kv::{KvClient, LIN_KV},
+        rpc::Rpc,
+        test_util::FakeKv,
+    };
+
+    use super::{AppliedOps, FileStore, KvStore, MemStore, PersistentIds, Store};
+
+    #[test]
+    fn test_ids_stay_ahead_after_restart() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("msg_id_restart_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut ids = PersistentIds::open(Some(path.clone()), 4)?;
+        let issued = (0..10)
+            .map(|_| ids.next_id())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert!(issued.windows(2).all(|w| w[0] < w[1]));
+        drop(ids);
+
+        let mut restarted = PersistentIds::open(Some(path.clone()), 4)?;
+        let next = restarted.next_id()?;
+        assert!(issued.iter().all(|id| *id < next));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_applied_ops_survive_restart() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("applied_ops_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut ops = AppliedOps::open(Some(path.clone()), 8)?;
+        assert!(ops.apply_once("a")?);
+        assert!(!ops.apply_once("a")?);
+        drop(ops);
+        let mut restarted = AppliedOps::open(Some(path.clone()), 8)?;
+        assert!(!restarted.apply_once("a")?);
+
+        // the log is bounded by the window, only the newest ids are remembered
+        for i in 0..100 {
+            assert!(restarted.apply_once(&format!("op{i}"))?);
+        }
+        assert!(std::fs::read_to_string(&path)?.lines().count() <= 16);
+        let mut restarted = AppliedOps::open(Some(path.clone()), 8)?;
+        assert!(!restarted.apply_once("op99")?);
+        assert!(restarted.apply_once("op0")?);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_applied_ops_state_survives_compaction() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("applied_state_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut ops = AppliedOps::open(Some(path.clone()), 4)?;
+        for i in 0..20 {
+            assert!(ops.apply_once_with(&format!("op{i}"), &i.to_string())?);
+        }
+        ops.checkpoint("42")?;
+        assert!(!ops.apply_once_with("op19", "0")?);
+        drop(ops);
+
+        let restarted = AppliedOps::open(Some(path.clone()), 4)?;
+        assert_eq!(restarted.state(), Some("42"));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_stores() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("file_store_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let rpc = Rpc::new("n1");
+        let mut wire = FakeKv::new(rpc.clone());
+        let stores: [Box<dyn Store>; 3] = [
+            Box::new(MemStore::default()),
+            Box::new(FileStore::open(&dir)?),
+            Box::new(KvStore::new(KvClient::new(LIN_KV, rpc))),
+        ];
+        for mut store in stores {
+            let out = &mut wire;
+            assert_eq!(store.get("set", out)?, None);
+            assert!(!store.cas("set", Some(&json!([])), json!([1]), out)?);
+            assert!(store.cas("set", None, json!([1]), out)?);
+            assert!(!store.cas("set", None, json!([2]), out)?);
+            assert!(store.cas("set", Some(&json!([1])), json!([1, 2]), out)?);
+            store.put("other", json!("x"), out)?;
+            assert_eq!(store.get("set", out)?, Some(json!([1, 2])));
+            assert_eq!(store.get("other", out)?, Some(json!("x")));
+        }
+        // the kv store's requests went through the output it was handed
+        assert_eq!(wire.requests, 8);
+        // the files outlive the store
+        let out = &mut std::io::sink();
+        assert_eq!(FileStore::open(&dir)?.get("set", out)?, Some(json!([1, 2])));
+        assert!(FileStore::open(&dir)?
+            .put("../escape", json!(1), out)
+            .is_err());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_ids() -> anyhow::Result<()> {
+        let mut ids = PersistentIds::open(None, 1)?;
+        assert_eq!(ids.next_id()?, 1);
+        assert_eq!(ids.next_id()?, 2);
+        Ok(())
+    }
+}
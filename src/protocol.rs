@@ -0,0 +1,5 @@
+//! Maelstrom's wire protocol: the message envelope, and the init handshake opening
+//! every run. Both are re-exported at the crate root.
+
+pub mod init;
+pub mod message;
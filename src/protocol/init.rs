@@ -0,0 +1,287 @@
+//! The init handshake, and the cluster membership it hands the node.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::message::{Body, Message};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum InitMsg {
+    Init(InitBody),
+    InitOk {
+        /// fields a node adds to its init_ok, see `Node::init_ok_extra`
+        #[serde(flatten)]
+        extra: serde_json::Map<String, serde_json::Value>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InitBody {
+    pub node_id: String,
+    pub node_ids: Vec<String>,
+    /// any other init argument, e.g. a seed some harness passes, see `get_extra`
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+impl InitBody {
+    /// The extra init argument `key`, `None` if the init didn't carry it.
+    pub fn get_extra<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<Option<T>> {
+        self.extra
+            .get(key)
+            .map(|value| T::deserialize(value))
+            .transpose()
+            .with_context(|| format!("init argument {key}"))
+    }
+
+    /// Catch a malformed init before the node is built on top of it.
+    pub fn validate(&self) -> Result<(), InitError> {
+        if !self.node_ids.contains(&self.node_id) {
+            return Err(InitError::NodeNotInCluster {
+                node_id: self.node_id.clone(),
+                node_ids: self.node_ids.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InitError {
+    /// the node's own id is missing from the cluster membership
+    NodeNotInCluster {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+}
+
+impl std::fmt::Display for InitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InitError::NodeNotInCluster { node_id, node_ids } => write!(
+                f,
+                "node {node_id} is not part of the cluster node_ids {node_ids:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InitError {}
+
+/// The cluster as the init message described it, to catch node to node messages
+/// addressed to ourselves or to a node which isn't part of it.
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    node_id: String,
+    node_ids: HashSet<String>,
+}
+
+impl Cluster {
+    pub fn new(init: &InitBody) -> Self {
+        Self {
+            node_id: init.node_id.clone(),
+            node_ids: init.node_ids.iter().cloned().collect(),
+        }
+    }
+
+    pub fn contains(&self, node_id: &str) -> bool {
+        self.node_ids.contains(node_id)
+    }
+
+    /// Send `msg` to another node of the cluster, failing rather than sending it into
+    /// the void. Client ids aren't cluster members, replies to them go through the
+    /// unchecked `Message::send`.
+    pub fn send_checked<M: Serialize>(
+        &self,
+        msg: &Message<M>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        self.check(msg)?;
+        msg.send(output)
+    }
+
+    /// The check `send_checked` runs, for messages written some other way.
+    pub fn check<M>(&self, msg: &Message<M>) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            msg.dst != self.node_id,
+            "node {} sending to itself",
+            self.node_id
+        );
+        anyhow::ensure!(
+            self.contains(&msg.dst),
+            "{} is not a node of the cluster",
+            msg.dst
+        );
+        Ok(())
+    }
+}
+
+impl Message<InitMsg> {
+    pub fn into_init_ok(&self) -> anyhow::Result<Self> {
+        match &self.body.payload {
+            InitMsg::Init(..) => Ok(Message {
+                src: self.dst.clone(),
+                dst: self.src.clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: self.body.id,
+                    lamport: None,
+                    op_id: None,
+                    payload: InitMsg::InitOk {
+                        extra: Default::default(),
+                    },
+                },
+            }),
+            InitMsg::InitOk { .. } => anyhow::bail!("can't convert from init_ok messag"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Serialize;
+    use serde_json::json;
+
+    use crate::{
+        protocol::message::{Body, Message},
+        test_util::assert_wire_format,
+    };
+
+    use super::{Cluster, InitBody, InitError, InitMsg};
+
+    const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"msg_id":1,"in_reply_to":null,"type":"init","node_id":"n1","node_ids":["n1","n2"]}}"#;
+
+    #[test]
+    fn test_init_wire_format() -> anyhow::Result<()> {
+        let init = InitMsg::Init(InitBody {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+            extra: Default::default(),
+        });
+        let msg = Message {
+            src: "c0".to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                payload: init,
+                id: Some(1),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+            },
+        };
+        assert_wire_format(&msg, INIT);
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_ok_answers_init() -> anyhow::Result<()> {
+        let init: Message<InitMsg> = serde_json::from_str(INIT)?;
+        let init_ok = init.into_init_ok()?;
+        assert_wire_format(
+            &init_ok,
+            r#"{"src":"n1","dest":"c0","body":{"msg_id":null,"in_reply_to":1,"type":"init_ok"}}"#,
+        );
+        assert!(init_ok.into_init_ok().is_err(), "init_ok answers nothing");
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_keeps_extra_arguments() -> anyhow::Result<()> {
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"],"seed":42}}"#;
+        let msg = serde_json::from_str::<Message<InitMsg>>(init)?;
+        let InitMsg::Init(body) = msg.body.payload else {
+            panic!("expected an init, got {:?}", msg.body.payload);
+        };
+        assert_eq!(body.extra.keys().collect::<Vec<_>>(), ["seed"]);
+        assert_eq!(body.get_extra::<u64>("seed")?, Some(42));
+        assert_eq!(body.get_extra::<u64>("workload")?, None);
+        assert!(body.get_extra::<String>("seed").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut init = InitBody {
+            node_id: "n3".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+            extra: Default::default(),
+        };
+        assert_eq!(
+            init.validate(),
+            Err(InitError::NodeNotInCluster {
+                node_id: "n3".to_string(),
+                node_ids: vec!["n1".to_string(), "n2".to_string()],
+            })
+        );
+        init.node_ids.push("n3".to_string());
+        assert_eq!(init.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_send_checked() -> anyhow::Result<()> {
+        let cluster = Cluster::new(&InitBody {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+            extra: Default::default(),
+        });
+        let to = |dst: &str| Message {
+            src: "n1".to_string(),
+            dst: dst.to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload: json!({"type": "echo", "echo": "hi"}),
+            },
+        };
+        let mut output = Vec::new();
+        cluster.send_checked(&to("n2"), &mut output)?;
+        let to_self = cluster.send_checked(&to("n1"), &mut output);
+        assert!(to_self.is_err_and(|e| e.to_string().contains("sending to itself")));
+        let to_client = cluster.send_checked(&to("c1"), &mut output);
+        assert!(to_client.is_err_and(|e| e.to_string().contains("c1 is not a node")));
+        assert_eq!(output.iter().filter(|b| **b == b'\n').count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn name() -> anyhow::Result<()> {
+        let init = InitMsg::Init(InitBody {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+            extra: Default::default(),
+        });
+        let msg = Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                payload: init,
+                id: Some(1),
+                in_reply_to: Some(1),
+                lamport: None,
+                op_id: None,
+            },
+        };
+        let stdout = std::io::stdout().lock();
+        let mut output = serde_json::Serializer::new(stdout);
+        msg.serialize(&mut output)?;
+        Ok(())
+    }
+
+    #[test]
+    fn serde_from_str() -> anyhow::Result<()> {
+        let content = r#"{"src":"c1","dest":"n1",
+        "body":{"type":"init","node_id":"n1","node_ids":["n1","n2"],"msg_id":1,"in_reply_to":1}}"#;
+        let msg: Message<InitMsg> = serde_json::from_str(content)?;
+        println!("{msg:?}");
+        Ok(())
+    }
+}
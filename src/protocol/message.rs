@@ -0,0 +1,318 @@
+//! The envelope every Maelstrom message travels in, and the replies built from it.
+
+use std::{
+    convert::Infallible,
+    io::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    codec::{Codec, JsonCodec},
+    MaelstromError,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message<MessageType> {
+    pub src: String,
+    #[serde(rename = "dest")]
+    pub dst: String,
+    pub body: Body<MessageType>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Body<MessageType> {
+    #[serde(rename = "msg_id")]
+    pub id: Option<usize>,
+    /// other harnesses' names are accepted, Maelstrom's is always written
+    #[serde(alias = "reply_to", alias = "correlation_id")]
+    pub in_reply_to: Option<usize>,
+    /// sender's Lamport time, for the nodes that order by it, absent otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lamport: Option<u64>,
+    /// the logical operation this carries, for handlers to dedup by, see `SeenSet`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub op_id: Option<Uuid>,
+    #[serde(flatten)]
+    pub payload: MessageType,
+}
+
+/// Hands out a node's msg_ids, from any thread.
+#[derive(Debug)]
+pub struct IdGen(AtomicUsize);
+
+impl IdGen {
+    pub fn starting_at(first: usize) -> Self {
+        Self(AtomicUsize::new(first))
+    }
+
+    pub fn next(&self) -> usize {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl Default for IdGen {
+    /// Starts at 1, the way Maelstrom's own clients number their messages.
+    fn default() -> Self {
+        Self::starting_at(1)
+    }
+}
+
+impl<M: Serialize> Message<M> {
+    /// The reply to this message. An internal message answers no one, its "reply"
+    /// never claims to be in reply to anything.
+    pub fn into_reply(self, msg_ids: Option<&IdGen>) -> Self {
+        let in_reply_to = if self.is_internal() {
+            None
+        } else {
+            self.body.id
+        };
+        Self {
+            src: self.dst,
+            dst: self.src,
+            body: Body {
+                payload: self.body.payload,
+                id: msg_ids.map(IdGen::next),
+                in_reply_to,
+                lamport: None,
+                op_id: None,
+            },
+        }
+    }
+
+    /// The reply to this message carrying `payload`, ready to `send`.
+    pub fn reply_with(self, msg_ids: &IdGen, payload: M) -> Self {
+        let mut reply = self.into_reply(Some(msg_ids));
+        reply.body.payload = payload;
+        reply
+    }
+
+    pub fn send(&self, output: &mut impl Write) -> anyhow::Result<()> {
+        self.send_with(&JsonCodec, output)
+    }
+
+    /// Like `send`, encoded by `codec` instead of as JSON.
+    pub fn send_with(&self, codec: &impl Codec, output: &mut impl Write) -> anyhow::Result<()> {
+        codec
+            .encode(self, &mut *output)
+            .context("serde to broadcast_ok message filed")?;
+        output.write_all(b"\n").context("flush message error")
+    }
+}
+
+/// A request payload, typed with the payload of its ok reply. `reply_ok` and
+/// `handle_rpc` only take that type, so a node can't answer with the wrong one, and
+/// its message type holds the requests alone, without `*_ok` arms to rule out.
+///
+/// Nodes whose message type also carries node to node traffic, like broadcast_3b's
+/// gossip or counter's replication, keep a single enum and reply with `reply_with`.
+pub trait Request {
+    type Ok;
+}
+
+impl<R: Request> Message<R> {
+    /// The reply to this request carrying `ok`, ready to `send`. Like `into_reply`,
+    /// an internal message's reply is in reply to nothing.
+    pub fn reply_ok(self, msg_ids: Option<&IdGen>, ok: R::Ok) -> Message<R::Ok> {
+        match self.map_reply(msg_ids, |_| Ok::<_, Infallible>(ok)) {
+            Ok(reply) => reply,
+            Err(never) => match never {},
+        }
+    }
+
+    /// The reply carrying what `ok` makes of the request's payload. The payload is
+    /// moved in, so its large fields end up in the reply without a copy.
+    fn map_reply<E>(
+        self,
+        msg_ids: Option<&IdGen>,
+        ok: impl FnOnce(R) -> Result<R::Ok, E>,
+    ) -> Result<Message<R::Ok>, E> {
+        let in_reply_to = if self.is_internal() {
+            None
+        } else {
+            self.body.id
+        };
+        Ok(Message {
+            src: self.dst,
+            dst: self.src,
+            body: Body {
+                payload: ok(self.body.payload)?,
+                id: msg_ids.map(IdGen::next),
+                in_reply_to,
+                lamport: None,
+                op_id: None,
+            },
+        })
+    }
+}
+
+/// Answer `req` with the ok `handler` makes of its payload. A failing handler sends
+/// nothing, its error is passed on; the loops reply a `RequestError` as an `error`.
+pub fn handle_rpc<R>(
+    req: Message<R>,
+    msg_ids: Option<&IdGen>,
+    output: &mut impl Write,
+    handler: impl FnOnce(R) -> anyhow::Result<R::Ok>,
+) -> anyhow::Result<()>
+where
+    R: Request,
+    R::Ok: Serialize,
+{
+    req.map_reply(msg_ids, handler)?.send(output)
+}
+
+impl<M> Message<M> {
+    /// An event the node raises for itself, e.g. a timer tick. It comes from no one
+    /// and expects no reply; the loops step it with `Node::on_internal`.
+    pub fn internal(payload: M) -> Self {
+        Self {
+            src: String::new(),
+            dst: String::new(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload,
+            },
+        }
+    }
+
+    /// Whether this came from `internal` rather than over the wire, where every
+    /// message names its sender.
+    pub fn is_internal(&self) -> bool {
+        self.src.is_empty()
+    }
+
+    /// Build a Maelstrom `error` reply to this request.
+    pub fn into_error(self, code: MaelstromError, text: impl Into<String>) -> Message<ErrorMsg> {
+        Message {
+            src: self.dst,
+            dst: self.src,
+            body: Body {
+                id: None,
+                in_reply_to: self.body.id,
+                lamport: None,
+                op_id: None,
+                payload: ErrorMsg::Error {
+                    code: code.code(),
+                    text: text.into(),
+                },
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorMsg {
+    Error { code: u64, text: String },
+}
+
+#[cfg(test)]
+mod test {
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+
+    use crate::MaelstromError;
+
+    use super::{handle_rpc, Body, IdGen, Message, Request};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Read {
+        Read { key: u64 },
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum ReadOk {
+        ReadOk { value: u64 },
+    }
+
+    impl Request for Read {
+        type Ok = ReadOk;
+    }
+
+    #[test]
+    fn test_id_gen_unique_across_threads() {
+        let ids = IdGen::default();
+        let mut handed_out = std::thread::scope(|scope| {
+            let workers = (0..4)
+                .map(|_| scope.spawn(|| (0..100).map(|_| ids.next()).collect::<Vec<_>>()))
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("worker panicked"))
+                .collect::<Vec<_>>()
+        });
+        handed_out.sort_unstable();
+        assert_eq!(handed_out, (1..=400).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_in_reply_to_aliases() -> anyhow::Result<()> {
+        for field in ["in_reply_to", "reply_to", "correlation_id"] {
+            let content = format!(r#"{{"type":"echo_ok","msg_id":2,"{field}":7,"echo":"x"}}"#);
+            let body: Body<Value> = serde_json::from_str(&content)?;
+            assert_eq!(body.in_reply_to, Some(7));
+            let encoded = serde_json::to_value(&body)?;
+            assert_eq!(encoded["in_reply_to"], 7);
+            assert!(encoded.get("reply_to").is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_internal_message_replies_to_nothing() {
+        let mut tick = Message::internal(json!({"type": "tick"}));
+        assert!(tick.is_internal());
+        tick.body.id = Some(7);
+        assert_eq!(tick.into_reply(None).body.in_reply_to, None);
+
+        let echo = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":7,"echo":"a"}}"#;
+        let request = serde_json::from_str::<Message<Value>>(echo).unwrap();
+        assert!(!request.is_internal());
+        let reply = request.into_reply(None);
+        assert_eq!(reply.body.in_reply_to, Some(7));
+        assert_eq!(reply.dst, "c1");
+    }
+
+    #[test]
+    fn test_handle_rpc_replies_the_ok_type() -> anyhow::Result<()> {
+        let read = r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":3,"key":7}}"#;
+        let read = serde_json::from_str::<Message<Read>>(read)?;
+        let mut output = Vec::new();
+        handle_rpc(
+            read,
+            Some(&IdGen::default()),
+            &mut output,
+            |Read::Read { key }| Ok(ReadOk::ReadOk { value: key * 2 }),
+        )?;
+        let reply = serde_json::from_slice::<Message<ReadOk>>(&output)?;
+        assert_eq!(reply.body.in_reply_to, Some(3));
+        assert_eq!((reply.src.as_str(), reply.dst.as_str()), ("n1", "c1"));
+        assert!(matches!(reply.body.payload, ReadOk::ReadOk { value: 14 }));
+
+        let tick = Message::internal(Read::Read { key: 1 });
+        assert_eq!(
+            tick.reply_ok(None, ReadOk::ReadOk { value: 1 })
+                .body
+                .in_reply_to,
+            None
+        );
+
+        let failed = handle_rpc(
+            Message::internal(Read::Read { key: 1 }),
+            None,
+            &mut output,
+            |_| Err(MaelstromError::KeyDoesNotExist.because("no such key"))?,
+        );
+        assert!(failed.is_err());
+        Ok(())
+    }
+}
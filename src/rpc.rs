@@ -0,0 +1,671 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use crossbeam_channel::{Receiver, Sender};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    clock::Clock,
+    latency::EmaLatency,
+    metrics::{Counter, Metrics},
+    Body, Cluster, Message,
+};
+
+/// Takes the raw reply line, each callback parses it into the reply type it expects.
+/// Gets an error instead if the reply didn't come before the deadline.
+type Callback = Box<dyn FnOnce(anyhow::Result<&str>) + Send>;
+
+/// A registered callback, with when its request went out and when `reap` gives up on
+/// it.
+struct Pending {
+    sent: Instant,
+    deadline: Instant,
+    callback: Callback,
+}
+
+/// How long `register` waits for a reply before the callback is reaped.
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(30);
+
+/// How `Rpc::call` retries a request which got no reply in time, or a Maelstrom
+/// timeout (code 0) or node-not-found (code 1) error back. The wait before a retry
+/// starts at `base_delay` and doubles with each retry, up to `max_delay`. Once
+/// `max_attempts` are spent the last failure is returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// tries in all, the first one included
+    pub max_attempts: usize,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// A single attempt, how `call` behaves unless given a policy.
+    pub const NEVER: Self = Self {
+        max_attempts: 1,
+        base_delay: Duration::ZERO,
+        max_delay: Duration::ZERO,
+    };
+
+    /// The wait before retry number `retry`, counted from 0.
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::NEVER
+    }
+}
+
+/// Just the type and code of a reply, to tell a transient error apart.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ErrorProbe {
+    Error {
+        code: u64,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// The registry at a glance, to spot replies which never come.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RpcStats {
+    /// requests awaiting their reply
+    pub pending: usize,
+    /// how long the oldest of them has been waiting
+    pub oldest: Option<Duration>,
+}
+
+/// Callbacks waiting for the replies to requests a node sent, keyed by the request's
+/// `msg_id`.
+///
+/// The loop hands a reply whose `in_reply_to` matches a registered callback to it
+/// instead of `Node::step`, before parsing it as the node's message type. So replies
+/// the node doesn't model, like a kv service's, can be awaited too. Callbacks run on
+/// the reader thread and should only hand the reply over, e.g. through the node's
+/// `Sender` or shared state.
+#[derive(Clone)]
+pub struct Rpc {
+    node_id: String,
+    /// ids of the requests sent by `call`, see `FIRST_CALL_ID`
+    next_call_id: Arc<AtomicUsize>,
+    callbacks: Arc<Mutex<HashMap<usize, Pending>>>,
+    /// round trips from registering a request to dispatching its reply
+    latency: Arc<Mutex<EmaLatency>>,
+    /// how `call` retries, see `with_retry`
+    retry: RetryPolicy,
+    /// callers blocked in `call` or `scatter_gather` right now
+    blocked: Arc<AtomicUsize>,
+    /// holds a token once a caller starts blocking, see `on_block`
+    wake: (Sender<()>, Receiver<()>),
+}
+
+/// A caller blocked on its replies, counted in `Rpc::blocked` until dropped.
+struct Blocked<'a>(&'a AtomicUsize);
+
+impl Drop for Blocked<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// `call` numbers its requests from here, far from the ids nodes count up from 1, so
+/// a reply to one of the node's own requests never lands on a waiting call.
+pub const FIRST_CALL_ID: usize = 1 << 32;
+
+/// Just where a line replies to, whatever else it holds.
+#[derive(Deserialize)]
+struct Probe {
+    body: ProbeBody,
+}
+
+#[derive(Deserialize)]
+struct ProbeBody {
+    #[serde(alias = "reply_to", alias = "correlation_id")]
+    in_reply_to: Option<usize>,
+}
+
+impl Rpc {
+    pub fn new(node_id: impl Into<String>) -> Self {
+        Self {
+            node_id: node_id.into(),
+            next_call_id: Arc::new(AtomicUsize::new(FIRST_CALL_ID)),
+            callbacks: Default::default(),
+            latency: Default::default(),
+            retry: RetryPolicy::NEVER,
+            blocked: Default::default(),
+            wake: crossbeam_channel::bounded(1),
+        }
+    }
+
+    /// This registry, with `call` retrying by `policy`. The registry is shared with
+    /// the clones, the policy isn't.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Send `payload` to `dst` and block until the reply arrives, failing after
+    /// `timeout`.
+    ///
+    /// Replies are picked up by the reader thread of `main_loop`, so this works from
+    /// `Node::step` there. It can't work under `main_loop_single_threaded`, where the
+    /// calling thread is the one that would read the reply; every call times out.
+    ///
+    /// Calling the node itself deadlocks until the timeout: the request is queued
+    /// behind the very step which waits for its reply. The same goes for any cycle of
+    /// nodes calling each other from `step`.
+    ///
+    /// With a `RetryPolicy` from `with_retry`, a request timing out or answered with a
+    /// transient error is sent again, each time as a new request with its own msg_id.
+    /// Only idempotent requests should be retried: a lost reply may well have been
+    /// applied.
+    pub fn call<Req, Resp>(
+        &self,
+        dst: impl Into<String>,
+        payload: Req,
+        output: &mut impl Write,
+        timeout: Duration,
+    ) -> anyhow::Result<Message<Resp>>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned + Send + 'static,
+    {
+        let dst = dst.into();
+        let mut retry = 0;
+        loop {
+            let reply = self.call_once(&dst, &payload, output, timeout)?;
+            let transient = match &reply {
+                Ok(reply) => matches!(
+                    ErrorProbe::deserialize(&reply.body.payload),
+                    Ok(ErrorProbe::Error { code: 0 | 1 })
+                ),
+                Err(_) => true,
+            };
+            if !transient || retry + 1 >= self.retry.max_attempts {
+                return reply.and_then(|reply| {
+                    serde_json::from_value(serde_json::to_value(reply)?)
+                        .context("unexpected rpc reply")
+                });
+            }
+            std::thread::sleep(self.retry.delay(retry as u32));
+            retry += 1;
+        }
+    }
+
+    /// A single attempt of `call`: the reply, or the timeout error as the inner error.
+    /// Only failing to send is returned as the outer error.
+    fn call_once(
+        &self,
+        dst: &str,
+        payload: &impl Serialize,
+        output: &mut impl Write,
+        timeout: Duration,
+    ) -> anyhow::Result<anyhow::Result<Message<Value>>> {
+        let msg_id = self.next_call_id();
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.register_until(msg_id, timeout, move |reply| {
+            // the caller may have timed out and gone
+            let _ = tx.send(reply);
+        });
+        let request = Message {
+            src: self.node_id.clone(),
+            dst: dst.to_string(),
+            body: Body {
+                id: Some(msg_id),
+                in_reply_to: None,
+                lamport: None,
+                op_id: None,
+                payload,
+            },
+        };
+        let sent = request.send(output).and_then(|()| Ok(output.flush()?));
+        if let Err(e) = sent {
+            self.cancel(msg_id);
+            return Err(e).with_context(|| format!("send rpc to {dst}"));
+        }
+        let _blocked = self.block();
+        Ok(match rx.recv_timeout(timeout) {
+            Ok(reply) => reply.with_context(|| format!("rpc {msg_id} to {dst}")),
+            Err(_) => {
+                self.cancel(msg_id);
+                Err(anyhow::anyhow!(
+                    "rpc {msg_id} to {dst} timed out after {timeout:?}"
+                ))
+            }
+        })
+    }
+
+    /// Send `payload` to all of `dsts` at once and wait up to `timeout` for their
+    /// replies, returned by sender. A destination which didn't reply in time, or not
+    /// with a `Resp`, is missing; whether what came is enough is the caller's call.
+    ///
+    /// Blocks like `call`, with the same caveats about calling from `Node::step`.
+    pub fn scatter_gather<Req, Resp>(
+        &self,
+        dsts: impl IntoIterator<Item = String>,
+        payload: &Req,
+        output: &mut impl Write,
+        timeout: Duration,
+    ) -> anyhow::Result<HashMap<String, Message<Resp>>>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned + Send + 'static,
+    {
+        let deadline = Instant::now() + timeout;
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut awaited = Vec::new();
+        let sent = (|| {
+            for dst in dsts {
+                let msg_id = self.next_call_id();
+                let (tx, from) = (tx.clone(), dst.clone());
+                self.register_until(msg_id, timeout, move |reply: anyhow::Result<_>| {
+                    // the caller may have timed out and gone
+                    let _ = tx.send((from, reply));
+                });
+                awaited.push(msg_id);
+                let request = Message {
+                    src: self.node_id.clone(),
+                    dst,
+                    body: Body {
+                        id: Some(msg_id),
+                        in_reply_to: None,
+                        lamport: None,
+                        op_id: None,
+                        payload,
+                    },
+                };
+                request
+                    .send(output)
+                    .with_context(|| format!("send rpc to {}", request.dst))?;
+            }
+            Ok::<_, anyhow::Error>(output.flush()?)
+        })();
+        drop(tx);
+        let mut replies = HashMap::new();
+        let _blocked = self.block();
+        if sent.is_ok() {
+            for _ in 0..awaited.len() {
+                let left = deadline.saturating_duration_since(Instant::now());
+                match rx.recv_timeout(left) {
+                    Ok((dst, Ok(reply))) => {
+                        replies.insert(dst, reply);
+                    }
+                    Ok((_, Err(_))) => {}
+                    Err(_) => break,
+                }
+            }
+        }
+        for msg_id in awaited {
+            self.cancel(msg_id);
+        }
+        sent.map(|()| replies)
+    }
+
+    /// Call `callback` with the reply to the request sent as `msg_id`, or the error
+    /// parsing it as a `Resp`. Register before sending the request, or the reply may
+    /// arrive first and go to `step`. Gives up after `DEFAULT_DEADLINE`, see
+    /// `register_until`.
+    pub fn register<Resp: DeserializeOwned>(
+        &self,
+        msg_id: usize,
+        callback: impl FnOnce(anyhow::Result<Message<Resp>>) + Send + 'static,
+    ) {
+        self.register_until(msg_id, DEFAULT_DEADLINE, callback)
+    }
+
+    /// Like `register`, but once `timeout` passed without a reply the next `reap`
+    /// drops the callback, calling it with an error.
+    pub fn register_until<Resp: DeserializeOwned>(
+        &self,
+        msg_id: usize,
+        timeout: Duration,
+        callback: impl FnOnce(anyhow::Result<Message<Resp>>) + Send + 'static,
+    ) {
+        let callback = move |line: anyhow::Result<&str>| {
+            callback(
+                line.and_then(|line| serde_json::from_str(line).context("unexpected rpc reply")),
+            );
+        };
+        let sent = Instant::now();
+        let pending = Pending {
+            sent,
+            deadline: sent + timeout,
+            callback: Box::new(callback),
+        };
+        self.callbacks().insert(msg_id, pending);
+    }
+
+    /// A msg_id for a request the node awaits the reply to, see `FIRST_CALL_ID`.
+    pub(crate) fn next_call_id(&self) -> usize {
+        self.next_call_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Stop waiting for a reply, returns whether one was still awaited.
+    pub fn cancel(&self, msg_id: usize) -> bool {
+        self.callbacks().remove(&msg_id).is_some()
+    }
+
+    /// How many requests still await their reply.
+    pub fn pending(&self) -> usize {
+        self.callbacks().len()
+    }
+
+    /// How many requests await their reply, and since when the oldest does.
+    pub fn stats(&self) -> RpcStats {
+        let callbacks = self.callbacks();
+        RpcStats {
+            pending: callbacks.len(),
+            oldest: callbacks
+                .values()
+                .map(|pending| pending.sent)
+                .min()
+                .map(|sent| sent.elapsed()),
+        }
+    }
+
+    /// When the next `reap` has a callback to drop, if any is registered.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.callbacks()
+            .values()
+            .map(|pending| pending.deadline)
+            .min()
+    }
+
+    /// Drop the callbacks whose deadline passed, each called with a timeout error.
+    /// Returns how many there were. The loops reap as they go, `main_loop` on its step
+    /// thread once `next_deadline` passed, so a reply that never comes doesn't stay in
+    /// the registry forever.
+    pub fn reap(&self) -> usize {
+        let now = Instant::now();
+        let expired = {
+            let mut callbacks = self.callbacks();
+            let ids = callbacks
+                .iter()
+                .filter(|(_, pending)| pending.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect::<Vec<_>>();
+            ids.into_iter()
+                .filter_map(|msg_id| Some((msg_id, callbacks.remove(&msg_id)?)))
+                .collect::<Vec<_>>()
+        };
+        let reaped = expired.len();
+        // the lock is released, a callback may register a retry
+        for (msg_id, pending) in expired {
+            let waited = now - pending.sent;
+            (pending.callback)(Err(anyhow::anyhow!(
+                "no reply to rpc {msg_id} after {waited:?}"
+            )));
+        }
+        reaped
+    }
+
+    /// The round trips observed so far, see `AdaptiveInterval::pace`.
+    pub fn latency(&self) -> EmaLatency {
+        self.latency
+            .lock()
+            .expect("rpc latency lock poisoned")
+            .clone()
+    }
+
+    /// Run the callback awaiting the reply on `line`, returns whether there was one.
+    pub(crate) fn dispatch(&self, line: &str) -> bool {
+        // no need to look into the line if nothing is awaited
+        if self.callbacks().is_empty() {
+            return false;
+        }
+        let callback = serde_json::from_str::<Probe>(line)
+            .ok()
+            .and_then(|probe| probe.body.in_reply_to)
+            .and_then(|msg_id| self.callbacks().remove(&msg_id));
+        match callback {
+            // the lock is released, a callback may register the next request
+            Some(Pending { sent, callback, .. }) => {
+                self.latency
+                    .lock()
+                    .expect("rpc latency lock poisoned")
+                    .observe(sent.elapsed());
+                callback(Ok(line));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether a caller is blocked on a reply right now, which only the reader can
+    /// hand over.
+    pub(crate) fn blocked(&self) -> bool {
+        self.blocked.load(Ordering::Acquire) > 0
+    }
+
+    /// Ready whenever a caller started blocking on a reply since it was last read, so
+    /// a reader waiting on a full queue can go back to reading.
+    pub(crate) fn on_block(&self) -> &Receiver<()> {
+        &self.wake.1
+    }
+
+    fn block(&self) -> Blocked<'_> {
+        self.blocked.fetch_add(1, Ordering::AcqRel);
+        // a token already waiting wakes the reader just as well
+        let _ = self.wake.0.try_send(());
+        Blocked(&self.blocked)
+    }
+
+    /// Drop the callbacks still waiting, once no reply can arrive anymore. A callback
+    /// may hold the node's `Sender`, which would keep the loop waiting on its channel.
+    pub(crate) fn abandon(&self) {
+        self.callbacks().clear();
+    }
+
+    fn callbacks(&self) -> std::sync::MutexGuard<'_, HashMap<usize, Pending>> {
+        self.callbacks.lock().expect("rpc callbacks lock poisoned")
+    }
+}
+
+/// Handles from the loop a node is built with.
+pub struct NodeContext<M> {
+    /// messages sent here are stepped like received ones, e.g. timer ticks
+    pub tx: Sender<Message<M>>,
+    pub rpc: Rpc,
+    /// membership from the init message, see `Cluster::send_checked`
+    pub cluster: Cluster,
+    /// wall time, a `MockClock` in tests
+    pub clock: Arc<dyn Clock>,
+    /// the loop's traffic counts with `METRICS=1`
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+impl<M> NodeContext<M> {
+    /// See `Rpc::stats`.
+    pub fn rpc_stats(&self) -> RpcStats {
+        self.rpc.stats()
+    }
+
+    /// The node's own counter `name`, reported along the loop's metrics. It counts
+    /// nothing without `METRICS=1`.
+    pub fn counter(&self, name: &str) -> Counter {
+        self.metrics
+            .as_ref()
+            .map_or_else(Counter::default, |metrics| metrics.counter(name))
+    }
+}
+
+/// Handles from the loop a single step gets, see `Node::step_with`.
+pub struct StepContext<'a, M> {
+    /// messages sent here are stepped after the ones already queued, e.g. a deferred
+    /// retry
+    pub tx: &'a Sender<Message<M>>,
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use serde_json::{json, Value};
+
+    use crate::{latency::AdaptiveInterval, Body, Message};
+
+    use super::{Rpc, RpcStats, FIRST_CALL_ID};
+
+    fn reply(in_reply_to: Option<usize>) -> String {
+        let reply = Message {
+            src: "n2".to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                id: None,
+                in_reply_to,
+                lamport: None,
+                op_id: None,
+                payload: json!({"type": "read_ok"}),
+            },
+        };
+        serde_json::to_string(&reply).unwrap()
+    }
+
+    #[test]
+    fn test_dispatch_to_registered_callback() {
+        let rpc = Rpc::new("n1");
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&replies);
+        rpc.register(7, move |reply: anyhow::Result<Message<Value>>| {
+            seen.lock().unwrap().push(reply.unwrap().body.in_reply_to)
+        });
+        rpc.register(8, |_: anyhow::Result<Message<Value>>| {
+            panic!("cancelled callback ran")
+        });
+        assert!(rpc.cancel(8));
+        assert_eq!(rpc.pending(), 1);
+
+        assert!(!rpc.dispatch(&reply(None)));
+        assert!(!rpc.dispatch(&reply(Some(8))));
+        assert!(rpc.dispatch(&reply(Some(7))));
+        // a callback runs once, a duplicate reply goes to step
+        assert!(!rpc.dispatch(&reply(Some(7))));
+        assert_eq!(*replies.lock().unwrap(), [Some(7)]);
+        assert_eq!(rpc.pending(), 0);
+    }
+
+    #[test]
+    fn test_call_blocks_until_reply() -> anyhow::Result<()> {
+        let rpc = Rpc::new("n1");
+        let peer = rpc.clone();
+        let replier = std::thread::spawn(move || {
+            while peer.pending() == 0 {
+                std::thread::yield_now();
+            }
+            assert!(peer.dispatch(&reply(Some(FIRST_CALL_ID))));
+        });
+        let mut output = Vec::new();
+        let reply: Message<Value> = rpc.call(
+            "seq-kv",
+            json!({"type": "read", "key": "counter"}),
+            &mut output,
+            Duration::from_secs(5),
+        )?;
+        replier.join().expect("replier panicked");
+        assert_eq!(reply.body.in_reply_to, Some(FIRST_CALL_ID));
+        let request = serde_json::from_slice::<Message<Value>>(&output)?;
+        assert_eq!(request.dst, "seq-kv");
+        assert_eq!(request.body.id, Some(FIRST_CALL_ID));
+
+        let timed_out = rpc.call::<_, Value>(
+            "n2",
+            json!({"type": "read"}),
+            &mut output,
+            Duration::from_millis(20),
+        );
+        assert!(timed_out.is_err());
+        assert_eq!(rpc.pending(), 0, "a timed out call is still waiting");
+        Ok(())
+    }
+
+    #[test]
+    fn test_scatter_gather_returns_what_came_in_time() -> anyhow::Result<()> {
+        let rpc = Rpc::new("n1");
+        let peer = rpc.clone();
+        let replier = std::thread::spawn(move || {
+            while peer.pending() < 2 {
+                std::thread::yield_now();
+            }
+            // n2 answers, n3 never does
+            assert!(peer.dispatch(&reply(Some(FIRST_CALL_ID))));
+        });
+        let mut output = Vec::new();
+        let replies = rpc.scatter_gather::<_, Value>(
+            ["n2", "n3"].map(String::from),
+            &json!({"type": "read"}),
+            &mut output,
+            Duration::from_millis(50),
+        )?;
+        replier.join().expect("replier panicked");
+        assert_eq!(replies.keys().collect::<Vec<_>>(), ["n2"]);
+        assert_eq!(replies["n2"].body.in_reply_to, Some(FIRST_CALL_ID));
+        let requests = serde_json::Deserializer::from_slice(&output)
+            .into_iter::<Message<Value>>()
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(requests.len(), 2, "requests go out before any reply");
+        assert_eq!(rpc.pending(), 0, "the missing reply is still awaited");
+        Ok(())
+    }
+
+    #[test]
+    fn test_replies_pace_the_interval() {
+        let rpc = Rpc::new("n1");
+        let policy = AdaptiveInterval {
+            min: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let pace = policy.clone().pace(&rpc);
+        assert_eq!(pace(), policy.base);
+
+        // instant replies, once warmed up the interval drops to the floor
+        for msg_id in 0..5 {
+            rpc.register(msg_id, |_: anyhow::Result<Message<Value>>| ());
+            assert!(rpc.dispatch(&reply(Some(msg_id))));
+        }
+        assert!(rpc.latency().average().is_some());
+        assert!(pace() < policy.base);
+    }
+
+    #[test]
+    fn test_unanswered_rpc_reaped_after_deadline() {
+        let rpc = Rpc::new("n1");
+        let outcome = Arc::new(Mutex::new(None));
+        let seen = Arc::clone(&outcome);
+        rpc.register_until(
+            3,
+            Duration::from_millis(10),
+            move |reply: anyhow::Result<Message<Value>>| {
+                *seen.lock().unwrap() = Some(reply.is_err())
+            },
+        );
+        rpc.register(4, |_: anyhow::Result<Message<Value>>| ());
+        assert_eq!(rpc.stats().pending, 2);
+
+        // nothing expired yet
+        assert_eq!(rpc.reap(), 0);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(rpc.stats().oldest >= Some(Duration::from_millis(20)));
+        assert_eq!(rpc.reap(), 1);
+        assert_eq!(*outcome.lock().unwrap(), Some(true));
+        assert!(matches!(rpc.stats(), RpcStats { pending: 1, .. }));
+        // a late reply goes to step
+        assert!(!rpc.dispatch(&reply(Some(3))));
+    }
+}
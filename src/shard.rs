@@ -0,0 +1,65 @@
+//! Key ownership for sharded state. Every node computes the owner on its own, so the
+//! hash must be the same in every process: `std`'s `RandomState` is seeded per
+//! process and would make nodes disagree.
+
+use crate::digest::mix;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// 64 bit FNV-1a, deterministic across processes and platforms.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// The node owning `key`, by rendezvous hashing: the node whose hash combined with the
+/// key's is highest. FNV barely mixes its last bytes, so the hash is finalized before
+/// comparing. It doesn't depend on the order of `node_ids`, and removing a node
+/// only moves the keys it owned.
+pub fn owner<'a>(key: &str, node_ids: &'a [String]) -> Option<&'a str> {
+    node_ids
+        .iter()
+        .max_by_key(|node| {
+            let mut bytes = Vec::with_capacity(key.len() + node.len() + 1);
+            bytes.extend_from_slice(key.as_bytes());
+            bytes.push(0);
+            bytes.extend_from_slice(node.as_bytes());
+            (mix(fnv1a(&bytes)), node.as_str())
+        })
+        .map(String::as_str)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{fnv1a, owner};
+
+    #[test]
+    fn test_fnv1a_is_fixed() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn test_nodes_agree_on_owners() {
+        let node_ids = (0..5).map(|i| format!("n{i}")).collect::<Vec<_>>();
+        let mut shuffled = node_ids.clone();
+        shuffled.rotate_left(2);
+        shuffled.swap(0, 3);
+
+        let mut owned = HashMap::<&str, usize>::new();
+        for key in (0..1000).map(|i| format!("k{i}")) {
+            let mine = owner(&key, &node_ids).unwrap();
+            assert_eq!(Some(mine), owner(&key, &shuffled));
+            *owned.entry(mine).or_default() += 1;
+        }
+        // every node gets a fair share
+        assert_eq!(owned.len(), 5);
+        assert!(owned.values().all(|n| (100..300).contains(n)), "{owned:?}");
+        assert_eq!(owner("k", &[]), None);
+    }
+}
@@ -0,0 +1,394 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
+    sync::Arc,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    clock::{Clock, SystemClock},
+    kv::{KvMsg, LIN_KV, LWW_KV, SEQ_KV},
+    rpc::{NodeContext, Rpc, StepContext},
+    Cluster, InitBody, MaelstromError, Message, Node,
+};
+
+/// Assert `msg` serializes to exactly `golden`, and that `golden` deserializes back
+/// into a message with the very same wire form.
+pub fn assert_wire_format<M>(msg: &Message<M>, golden: &str)
+where
+    M: Serialize + DeserializeOwned,
+{
+    let encoded = serde_json::to_string(msg).expect("serialize message failed");
+    assert_eq!(encoded, golden, "wire format drifted");
+    let decoded: Message<M> = serde_json::from_str(golden).expect("deserialize golden failed");
+    let reencoded = serde_json::to_string(&decoded).expect("serialize message failed");
+    assert_eq!(reencoded, golden, "wire format doesn't round trip");
+}
+
+/// Assert `reply` answers `request`: it references the request's msg_id and goes back
+/// the way the request came.
+pub fn assert_replies_to<M, R>(reply: &Message<M>, request: &Message<R>) {
+    assert!(request.body.id.is_some(), "the request expects no reply");
+    assert_eq!(
+        reply.body.in_reply_to, request.body.id,
+        "reply doesn't reference the request"
+    );
+    assert_eq!(
+        reply.src, request.dst,
+        "reply isn't sent by the request's receiver"
+    );
+    assert_eq!(
+        reply.dst, request.src,
+        "reply isn't sent to the request's sender"
+    );
+}
+
+/// Hand the reply `line` to the rpc callback awaiting it, the way the loop's reader
+/// would; returns whether one was.
+pub fn dispatch_reply(rpc: &Rpc, line: &str) -> bool {
+    rpc.dispatch(line)
+}
+
+/// An output playing Maelstrom's kv services, all backed by one map. A request sent to
+/// a service is answered right away through `rpc`, the way the loop's reader would
+/// dispatch the reply; every other line is kept in `sent`.
+pub struct FakeKv {
+    rpc: Rpc,
+    store: HashMap<String, Value>,
+    /// keys whose next request fails, see `fail_once`
+    failing: HashSet<String>,
+    /// requests left to go unanswered, see `drop_replies`
+    dropping: usize,
+    line: Vec<u8>,
+    pub sent: Vec<String>,
+    /// kv requests answered so far
+    pub requests: usize,
+    /// the cas among them
+    pub cas: usize,
+}
+
+impl FakeKv {
+    pub fn new(rpc: Rpc) -> Self {
+        Self {
+            rpc,
+            store: HashMap::new(),
+            failing: HashSet::new(),
+            dropping: 0,
+            line: Vec::new(),
+            sent: Vec::new(),
+            requests: 0,
+            cas: 0,
+        }
+    }
+
+    /// Fail the next request on `key` with a crash, leaving the store as it was.
+    pub fn fail_once(&mut self, key: impl Into<String>) {
+        self.failing.insert(key.into());
+    }
+
+    /// What `key` holds, as a read would return it.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.store.get(&Value::from(key).to_string())
+    }
+
+    /// Leave the next `count` requests unanswered, as if their replies were lost.
+    pub fn drop_replies(&mut self, count: usize) {
+        self.dropping = count;
+    }
+
+    fn answer(&mut self, req: Message<KvMsg<Value>>) -> KvMsg<Value> {
+        let error = |code: MaelstromError, text: String| KvMsg::Error {
+            code: code.code(),
+            text,
+        };
+        if let KvMsg::Read { key } | KvMsg::Write { key, .. } | KvMsg::Cas { key, .. } =
+            &req.body.payload
+        {
+            if key.as_str().is_some_and(|key| self.failing.remove(key)) {
+                return error(MaelstromError::Crash, format!("injected failure on {key}"));
+            }
+        }
+        let missing = |key: &Value| error(MaelstromError::KeyDoesNotExist, format!("{key}"));
+        match req.body.payload {
+            KvMsg::Read { key } => match self.store.get(&key.to_string()) {
+                Some(value) => KvMsg::ReadOk {
+                    value: value.clone(),
+                },
+                None => missing(&key),
+            },
+            KvMsg::Write { key, value } => {
+                self.store.insert(key.to_string(), value);
+                KvMsg::WriteOk
+            }
+            KvMsg::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.store.get(&key.to_string()) {
+                Some(value) if *value == from => {
+                    self.store.insert(key.to_string(), to);
+                    KvMsg::CasOk
+                }
+                Some(value) => error(
+                    MaelstromError::PreconditionFailed,
+                    format!("expected {from}, had {value}"),
+                ),
+                None if create_if_not_exists => {
+                    self.store.insert(key.to_string(), to);
+                    KvMsg::CasOk
+                }
+                None => missing(&key),
+            },
+            payload => error(
+                MaelstromError::NotSupported,
+                format!("not a kv request {payload:?}"),
+            ),
+        }
+    }
+}
+
+impl FakeKv {
+    /// The reply to `line` if it's a kv request, for a test which plays the services
+    /// on a real loop's input rather than through `rpc`.
+    pub fn reply_to(&mut self, line: &str) -> Option<String> {
+        let request = kv_request(line)?;
+        self.requests += 1;
+        self.cas += usize::from(matches!(request.body.payload, KvMsg::Cas { .. }));
+        let mut reply = request.clone().into_reply(None);
+        reply.body.payload = self.answer(request);
+        Some(serde_json::to_string(&reply).expect("serialize kv reply failed"))
+    }
+}
+
+fn kv_request(line: &str) -> Option<Message<KvMsg<Value>>> {
+    serde_json::from_str::<Message<KvMsg<Value>>>(line)
+        .ok()
+        .filter(|msg| [SEQ_KV, LIN_KV, LWW_KV].contains(&msg.dst.as_str()))
+}
+
+impl Write for FakeKv {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        if !self.line.ends_with(b"\n") {
+            return Ok(buf.len());
+        }
+        let line = String::from_utf8(std::mem::take(&mut self.line)).expect("lines are UTF-8");
+        if self.dropping > 0 && kv_request(&line).is_some() {
+            self.dropping -= 1;
+            return Ok(buf.len());
+        }
+        match self.reply_to(&line) {
+            Some(reply) => assert!(self.rpc.dispatch(&reply), "nobody awaits the kv reply"),
+            None => self.sent.push(line),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A cluster of nodes run in memory, in rounds: the messages a round's steps send
+/// between nodes are delivered in a later round, unless dropped. Randomness comes from
+/// a seeded RNG, so a run replays exactly.
+///
+/// Nodes are stepped directly, their timers aren't run: inject the ticks with `tick`.
+pub struct Network<M, N> {
+    nodes: BTreeMap<String, N>,
+    /// (round due, message) in the order sent
+    in_flight: Vec<(usize, Message<M>)>,
+    round: usize,
+    rng: StdRng,
+    drop_probability: f64,
+    /// extra rounds a message may take, picked uniformly up to this
+    max_latency: usize,
+    max_rounds: usize,
+    /// (from, to) links dropping everything, see `cut`
+    cut: HashSet<(String, String)>,
+    /// messages sent outside the cluster, e.g. replies to clients
+    pub outside: Vec<Message<M>>,
+    pub dropped: usize,
+}
+
+impl<M, N> Network<M, N>
+where
+    M: Serialize + DeserializeOwned,
+    N: Node<M>,
+{
+    pub const DEFAULT_MAX_ROUNDS: usize = 1000;
+
+    /// Init a node for each of `node_ids`.
+    pub fn new(node_ids: &[&str]) -> anyhow::Result<Self> {
+        Self::with_clocks(node_ids, |_| Arc::new(SystemClock))
+    }
+
+    /// Init a node for each of `node_ids`, with the clock `clock` gives for its id,
+    /// e.g. a `MockClock::with_offset` per node to skew them.
+    pub fn with_clocks(
+        node_ids: &[&str],
+        clock: impl Fn(&str) -> Arc<dyn Clock>,
+    ) -> anyhow::Result<Self> {
+        let cluster = node_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let nodes = cluster
+            .iter()
+            .map(|node_id| {
+                let init = InitBody {
+                    node_id: node_id.clone(),
+                    node_ids: cluster.clone(),
+                    extra: Default::default(),
+                };
+                // the receiver is dropped, so timer threads stop at their first tick
+                let (tx, _) = crossbeam_channel::unbounded();
+                let ctx = NodeContext {
+                    tx,
+                    rpc: Rpc::new(node_id),
+                    cluster: Cluster::new(&init),
+                    clock: clock(node_id),
+                    metrics: None,
+                };
+                Ok((node_id.clone(), N::init_with(&init, ctx)?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            nodes,
+            in_flight: Vec::new(),
+            round: 0,
+            rng: StdRng::seed_from_u64(0),
+            drop_probability: 0.0,
+            max_latency: 0,
+            max_rounds: Self::DEFAULT_MAX_ROUNDS,
+            cut: HashSet::new(),
+            outside: Vec::new(),
+            dropped: 0,
+        })
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// Drop each message between nodes with `probability`, within `0.0..=1.0`.
+    pub fn with_drop_probability(mut self, probability: f64) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&probability),
+            "drop probability {probability} isn't within 0..=1"
+        );
+        self.drop_probability = probability;
+        Ok(self)
+    }
+
+    /// Delay each message between nodes by up to `rounds` extra rounds.
+    pub fn with_max_latency(mut self, rounds: usize) -> Self {
+        self.max_latency = rounds;
+        self
+    }
+
+    /// `run` fails past this many rounds.
+    pub fn with_max_rounds(mut self, rounds: usize) -> Self {
+        self.max_rounds = rounds;
+        self
+    }
+
+    /// Drop every message from `from` to `to` until `heal`, one way only, e.g. to lose
+    /// the acks of gossip which still gets through.
+    pub fn cut(&mut self, from: &str, to: &str) {
+        self.cut.insert((from.to_string(), to.to_string()));
+    }
+
+    pub fn heal(&mut self, from: &str, to: &str) {
+        self.cut.remove(&(from.to_string(), to.to_string()));
+    }
+
+    pub fn node(&self, node_id: &str) -> &N {
+        &self.nodes[node_id]
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = (&String, &N)> {
+        self.nodes.iter()
+    }
+
+    /// Deliver `msg` next round, never dropped, e.g. a client request.
+    pub fn send(&mut self, msg: Message<M>) {
+        self.in_flight.push((self.round, msg));
+    }
+
+    /// Deliver `payload` to every node next round, the way their timers would.
+    pub fn tick(&mut self, payload: impl Fn() -> M) {
+        let node_ids = self.nodes.keys().cloned().collect::<Vec<_>>();
+        for node_id in node_ids {
+            // addressed only so the network knows where to deliver it
+            self.send(Message {
+                dst: node_id,
+                ..Message::internal(payload())
+            });
+        }
+    }
+
+    /// Deliver the messages due, returning how many were.
+    pub fn step_round(&mut self) -> anyhow::Result<usize> {
+        let round = self.round;
+        self.round += 1;
+        let (due, later) = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(at, _)| *at <= round);
+        self.in_flight = later;
+        let delivered = due.len();
+        for (_, msg) in due {
+            let node = self
+                .nodes
+                .get_mut(&msg.dst)
+                .ok_or_else(|| anyhow::anyhow!("no node {} in the network", msg.dst))?;
+            let mut output = Vec::new();
+            let (tx, queued) = crossbeam_channel::unbounded();
+            let ctx = StepContext { tx: &tx };
+            if msg.is_internal() {
+                node.on_internal(msg.body.payload, &mut output, &ctx)?;
+            } else {
+                node.step_with(msg, &mut output, &ctx)?;
+            }
+            for sent in serde_json::Deserializer::from_slice(&output).into_iter() {
+                self.route(sent?);
+            }
+            // what the node sent itself arrives next round, like a client request
+            for msg in queued.try_iter() {
+                self.send(msg);
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Step rounds until no message is in flight, returning how many ran.
+    pub fn run(&mut self) -> anyhow::Result<usize> {
+        let start = self.round;
+        while !self.in_flight.is_empty() {
+            anyhow::ensure!(
+                self.round - start < self.max_rounds,
+                "still busy after {} rounds",
+                self.max_rounds
+            );
+            self.step_round()?;
+        }
+        Ok(self.round - start)
+    }
+
+    fn route(&mut self, msg: Message<M>) {
+        let cut = self
+            .cut
+            .iter()
+            .any(|(from, to)| *from == msg.src && *to == msg.dst);
+        if !self.nodes.contains_key(&msg.dst) {
+            self.outside.push(msg);
+        } else if cut || self.rng.gen_bool(self.drop_probability) {
+            self.dropped += 1;
+        } else {
+            let latency = self.rng.gen_range(0..=self.max_latency);
+            self.in_flight.push((self.round + latency, msg));
+        }
+    }
+}
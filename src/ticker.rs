@@ -0,0 +1,103 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crossbeam_channel::Sender;
+
+use crate::Message;
+
+/// Keeps at most one timer-driven gossip round in flight. A tick while a round is still
+/// queued or running is coalesced into it and counted as skipped, so a node which can't
+/// keep up with the interval doesn't pile alerts up in its channel.
+#[derive(Debug, Default)]
+pub struct RoundGuard {
+    in_flight: AtomicBool,
+    skipped: AtomicUsize,
+}
+
+impl RoundGuard {
+    /// Claim the next round, `false` if one is in flight already.
+    pub fn try_start(&self) -> bool {
+        let started = self
+            .in_flight
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+        if !started {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        started
+    }
+
+    /// Called by the node once it finished the round.
+    pub fn finish(&self) {
+        self.in_flight.store(false, Ordering::Release);
+    }
+
+    /// Ticks coalesced into a round in flight so far.
+    pub fn skipped(&self) -> usize {
+        self.skipped.load(Ordering::Relaxed)
+    }
+}
+
+/// Send `alert()` to the node every `interval`, unless its previous round isn't done.
+/// The thread stops once the node is gone: its channel is closed, or it dropped its
+/// handle on `guard`, even with a round left in flight.
+pub fn spawn_ticker<M: Send + 'static>(
+    interval: Duration,
+    guard: Arc<RoundGuard>,
+    tx: Sender<Message<M>>,
+    alert: impl Fn() -> Message<M> + Send + 'static,
+) {
+    spawn_paced_ticker(move || interval, guard, tx, alert)
+}
+
+/// Like `spawn_ticker`, but asks `pace` for the interval before every tick, e.g. an
+/// `AdaptiveInterval` following the observed latency.
+pub fn spawn_paced_ticker<M: Send + 'static>(
+    pace: impl Fn() -> Duration + Send + 'static,
+    guard: Arc<RoundGuard>,
+    tx: Sender<Message<M>>,
+    alert: impl Fn() -> Message<M> + Send + 'static,
+) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(pace());
+        if Arc::strong_count(&guard) == 1 {
+            break;
+        }
+        if guard.try_start() && tx.send(alert()).is_err() {
+            // nobody is left to end the round
+            guard.finish();
+            break;
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use super::{spawn_ticker, RoundGuard};
+    use crate::Message;
+
+    #[test]
+    fn test_slow_round_coalesces_ticks() -> anyhow::Result<()> {
+        let guard = Arc::new(RoundGuard::default());
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let alert = || Message::internal(());
+        spawn_ticker(Duration::from_millis(2), Arc::clone(&guard), tx, alert);
+
+        // the round takes many intervals, the ticks meanwhile are folded into it
+        rx.recv_timeout(Duration::from_secs(5))?;
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+        assert!(guard.skipped() > 0);
+
+        guard.finish();
+        rx.recv_timeout(Duration::from_secs(5))?;
+        Ok(())
+    }
+}
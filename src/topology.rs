@@ -0,0 +1,200 @@
+//! Topologies derived from the init `node_ids` alone, for experiments which don't wait
+//! for Maelstrom's `topology` message.
+
+use std::collections::HashMap;
+
+/// Children of each inner node of `spanning_tree`.
+pub const TREE_FANOUT: usize = 4;
+
+/// Adjacency of the nodes, like the `topology` message carries.
+pub type Topology = HashMap<String, Vec<String>>;
+
+/// The topology picked by `TOPOLOGY=tree|grid` to use instead of Maelstrom's, `None`
+/// when unset or anything else.
+pub fn from_env(node_ids: &[String]) -> Option<Topology> {
+    match std::env::var("TOPOLOGY").ok()?.as_str() {
+        "tree" => {
+            let root = node_ids.iter().min()?;
+            Some(spanning_tree(node_ids, root))
+        }
+        "grid" => Some(grid(node_ids)),
+        _ => None,
+    }
+}
+
+/// A tree over `nodes` hanging from `root`, each node linked to its parent and up to
+/// `TREE_FANOUT` children, so a message crosses it in a logarithmic number of hops.
+/// The other nodes are placed in sorted order, breadth first.
+pub fn spanning_tree(nodes: &[String], root: &str) -> Topology {
+    let mut order = nodes
+        .iter()
+        .filter(|node| *node != root)
+        .collect::<Vec<_>>();
+    order.sort();
+    let root = root.to_string();
+    order.insert(0, &root);
+    let mut topology = order
+        .iter()
+        .map(|node| (node.to_string(), Vec::new()))
+        .collect::<Topology>();
+    for (at, node) in order.iter().enumerate().skip(1) {
+        let parent = order[(at - 1) / TREE_FANOUT];
+        link(&mut topology, parent, node);
+    }
+    topology
+}
+
+/// The sorted `nodes` laid out row by row on the smallest square which fits them, each
+/// linked to the nodes above, below, left and right of it.
+pub fn grid(nodes: &[String]) -> Topology {
+    let mut order = nodes.iter().collect::<Vec<_>>();
+    order.sort();
+    let width = (1..)
+        .find(|width| width * width >= order.len())
+        .unwrap_or(1);
+    let mut topology = order
+        .iter()
+        .map(|node| (node.to_string(), Vec::new()))
+        .collect::<Topology>();
+    for (at, node) in order.iter().enumerate() {
+        if at % width + 1 < width {
+            if let Some(right) = order.get(at + 1) {
+                link(&mut topology, node, right);
+            }
+        }
+        if let Some(below) = order.get(at + width) {
+            link(&mut topology, node, below);
+        }
+    }
+    topology
+}
+
+/// The nodes below `root` in `tree`, coming from `parent`, `root` not included.
+pub fn subtree(tree: &Topology, root: &str, parent: &str) -> Vec<String> {
+    let mut below = Vec::new();
+    let mut frontier = vec![(root, parent)];
+    while let Some((node, from)) = frontier.pop() {
+        for child in tree.get(node).into_iter().flatten() {
+            if child != from {
+                below.push(child.clone());
+                frontier.push((child, node));
+            }
+        }
+    }
+    below
+}
+
+fn link(topology: &mut Topology, a: &str, b: &str) {
+    topology
+        .entry(a.to_string())
+        .or_default()
+        .push(b.to_string());
+    topology
+        .entry(b.to_string())
+        .or_default()
+        .push(a.to_string());
+}
+
+/// Predecessor and successor of `self_id` on the ring of the sorted `node_ids`,
+/// wrapping around at the ends. A single node is its own neighbor on both sides.
+///
+/// Panics if `self_id` isn't in `node_ids`, which init validation already rules out.
+pub fn ring_neighbors(node_ids: &[String], self_id: &str) -> (String, String) {
+    let mut ring = node_ids.iter().collect::<Vec<_>>();
+    ring.sort();
+    let at = ring
+        .iter()
+        .position(|id| *id == self_id)
+        .unwrap_or_else(|| panic!("{self_id} isn't in the cluster {node_ids:?}"));
+    let predecessor = ring[(at + ring.len() - 1) % ring.len()];
+    let successor = ring[(at + 1) % ring.len()];
+    (predecessor.clone(), successor.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::{grid, ring_neighbors, spanning_tree, subtree, Topology, TREE_FANOUT};
+
+    /// Whether every node reaches every other one over the links.
+    fn connected(topology: &Topology) -> bool {
+        let Some(start) = topology.keys().next() else {
+            return true;
+        };
+        let mut seen = HashSet::from([start]);
+        let mut frontier = vec![start];
+        while let Some(node) = frontier.pop() {
+            for neighbor in &topology[node] {
+                if seen.insert(neighbor) {
+                    frontier.push(neighbor);
+                }
+            }
+        }
+        seen.len() == topology.len()
+    }
+
+    #[test]
+    fn test_tree_and_grid() {
+        let nodes = (0..25).map(|i| format!("n{i}")).collect::<Vec<_>>();
+
+        let tree = spanning_tree(&nodes, "n7");
+        assert_eq!(tree.len(), 25);
+        assert!(connected(&tree));
+        assert_eq!(tree["n7"].len(), TREE_FANOUT);
+        assert!(tree
+            .values()
+            .all(|neighbors| neighbors.len() <= TREE_FANOUT + 1));
+        // a tree has one link less than nodes, each counted from both ends
+        assert_eq!(tree.values().map(Vec::len).sum::<usize>(), 2 * 24);
+
+        let mesh = grid(&nodes);
+        assert_eq!(mesh.len(), 25);
+        assert!(connected(&mesh));
+        assert!(mesh.values().all(|neighbors| neighbors.len() <= 4));
+        assert_eq!(mesh["n0"].len(), 2);
+
+        let single = ["n0".to_string()];
+        assert_eq!(spanning_tree(&single, "n0")["n0"], Vec::<String>::new());
+        assert_eq!(grid(&single)["n0"], Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_subtree() {
+        let nodes = (0..25).map(|i| format!("n{i}")).collect::<Vec<_>>();
+        let tree = spanning_tree(&nodes, "n0");
+        let below = tree["n0"]
+            .iter()
+            .map(|child| subtree(&tree, child, "n0"))
+            .collect::<Vec<_>>();
+        // the children's subtrees split the other nodes between them
+        assert_eq!(
+            below.iter().map(Vec::len).sum::<usize>(),
+            25 - 1 - TREE_FANOUT
+        );
+        let mut all = below.concat();
+        all.extend(tree["n0"].iter().cloned());
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 24);
+        assert!(!all.contains(&"n0".to_string()));
+
+        assert_eq!(subtree(&tree, "n0", "none").len(), 24);
+        assert_eq!(subtree(&tree, "n24", &tree["n24"][0]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_ring_neighbors() {
+        let ids = ["n2", "n0", "n3", "n1"].map(String::from);
+        let neighbors = |id| ring_neighbors(&ids, id);
+        assert_eq!(neighbors("n0"), ("n3".to_string(), "n1".to_string()));
+        assert_eq!(neighbors("n1"), ("n0".to_string(), "n2".to_string()));
+        assert_eq!(neighbors("n3"), ("n2".to_string(), "n0".to_string()));
+
+        let single = ["n0".to_string()];
+        assert_eq!(
+            ring_neighbors(&single, "n0"),
+            ("n0".to_string(), "n0".to_string())
+        );
+    }
+}